@@ -27,7 +27,8 @@ fn main() {
         clean_session: true,
         last_will: None,
         username: None,
-        password: None
+        password: None,
+        properties: Vec::new()
     }));
     println!("{:?}", connect);
     writer.write_packet(&connect).unwrap();
@@ -42,6 +43,7 @@ fn main() {
         retain: false,
         topic_name: "/a/b".to_owned(),
         pid: Some(PacketIdentifier(10)),
+        properties: Vec::new(),
         payload: Arc::new("Hello world".to_string().into_bytes())
     }));
     println!("{:?}", publish);
@@ -26,7 +26,8 @@ fn main() {
         clean_session: true,
         last_will: None,
         username: None,
-        password: None
+        password: None,
+        properties: Vec::new()
     }));
     println!("{:?}", connect);
     writer.write_packet(&connect).unwrap();
@@ -38,8 +39,9 @@ fn main() {
     let subscribe = Packet::Subscribe(Box::new(Subscribe {
         pid: PacketIdentifier(260),
         topics: vec![
-            mqtt3::SubscribeTopic { topic_path: "/a/b".to_owned(), qos: QoS::ExactlyOnce }
-        ]
+            mqtt3::SubscribeTopic { topic_path: "/a/b".to_owned(), qos: QoS::ExactlyOnce, no_local: false, retain_as_published: false, retain_handling: mqtt3::RetainHandling::SendAtSubscribe }
+        ],
+        properties: Vec::new()
     }));
     println!("{:?}", subscribe);
     writer.write_packet(&subscribe).unwrap();
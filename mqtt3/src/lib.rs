@@ -7,6 +7,7 @@ mod read;
 mod write;
 mod topic;
 mod msg;
+mod property;
 
 use thiserror::Error;
 
@@ -21,12 +22,15 @@ pub use mqtt::{
     Packet,
     Connect,
     Connack,
+    Disconnect,
+    Auth,
     Publish,
     Subscribe,
     Suback,
     Unsubscribe,
     SubscribeTopic,
-    SubscribeReturnCodes
+    SubscribeReturnCodes,
+    SubscribeReasonCode
 };
 
 pub use topic::{
@@ -35,12 +39,16 @@ pub use topic::{
     ToTopicPath
 };
 
+pub use property::Property;
+
 pub use read::MqttRead;
 pub use write::MqttWrite;
 
 const MULTIPLIER: usize = 0x80 * 0x80 * 0x80 * 0x80;
 const MAX_PAYLOAD_SIZE: usize = 268435455;
 
+use std::cmp;
+use std::convert::TryFrom;
 use std::fmt;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -58,6 +66,7 @@ impl Protocol {
             },
             "MQTT" => match level {
                 4 => Ok(Protocol::MQTT(4)),
+                5 => Ok(Protocol::MQTT(5)),
                 _ => Err(MQError::UnsupportedProtocolVersion)
             },
             _ => Err(MQError::UnsupportedProtocolName)
@@ -79,7 +88,15 @@ impl Protocol {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// "MQTT v4", for logs
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} v{}", self.name(), self.level())
+    }
+}
+
+/// Ordered by delivery guarantee, `AtMostOnce` is the lowest
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum QoS {
     AtMostOnce,
     AtLeastOnce,
@@ -110,16 +127,52 @@ impl QoS {
     }
 
     pub fn min(&self, other: QoS) -> QoS {
+        cmp::min(*self, other)
+    }
+
+    pub fn max(&self, other: QoS) -> QoS {
+        cmp::max(*self, other)
+    }
+}
+
+impl TryFrom<u8> for QoS {
+    type Error = MQError;
+
+    fn try_from(byte: u8) -> Result<QoS> {
+        QoS::from_u8(byte)
+    }
+}
+
+impl From<QoS> for u8 {
+    fn from(qos: QoS) -> u8 {
+        qos.to_u8()
+    }
+}
+
+/// MQTT 5: which retained messages the broker sends when subscribing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetainHandling {
+    SendAtSubscribe,
+    // only when the subscription didn't exist yet
+    SendAtNewSubscribe,
+    DoNotSend
+}
+
+impl RetainHandling {
+    pub fn from_u8(byte: u8) -> Result<RetainHandling> {
+        match byte {
+            0 => Ok(RetainHandling::SendAtSubscribe),
+            1 => Ok(RetainHandling::SendAtNewSubscribe),
+            2 => Ok(RetainHandling::DoNotSend),
+            _ => Err(MQError::IncorrectPacketFormat)
+        }
+    }
+
+    pub fn to_u8(&self) -> u8 {
         match *self {
-            QoS::AtMostOnce => QoS::AtMostOnce,
-            QoS::AtLeastOnce => {
-                if other == QoS::AtMostOnce {
-                    QoS::AtMostOnce
-                } else {
-                    QoS::AtLeastOnce
-                }
-            },
-            QoS::ExactlyOnce => other
+            RetainHandling::SendAtSubscribe => 0,
+            RetainHandling::SendAtNewSubscribe => 1,
+            RetainHandling::DoNotSend => 2
         }
     }
 }
@@ -139,7 +192,9 @@ pub enum PacketType {
 	Unsuback,
 	Pingreq,
 	Pingresp,
-	Disconnect
+	Disconnect,
+	// MQTT 5 only
+	Auth
 }
 
 impl PacketType {
@@ -158,7 +213,8 @@ impl PacketType {
             PacketType::Unsuback => 11,
             PacketType::Pingreq => 12,
             PacketType::Pingresp => 13,
-            PacketType::Disconnect => 14
+            PacketType::Disconnect => 14,
+            PacketType::Auth => 15
         }
     }
 
@@ -178,6 +234,7 @@ impl PacketType {
             12 => Ok(PacketType::Pingreq),
             13 => Ok(PacketType::Pingresp),
             14 => Ok(PacketType::Disconnect),
+            15 => Ok(PacketType::Auth),
             _ => Err(MQError::UnsupportedPacketType)
         }
     }
@@ -186,6 +243,18 @@ impl PacketType {
     pub fn from_hd(hd: u8) -> Result<PacketType> {
         Self::from_u8(hd >> 4)
     }
+
+    /// The reserved flag bits the type must be sent with, None for PUBLISH
+    /// whose flags carry dup, QoS and retain
+    pub fn fixed_flags(&self) -> Option<u8> {
+        match *self {
+            PacketType::Publish => None,
+            PacketType::Pubrel |
+            PacketType::Subscribe |
+            PacketType::Unsubscribe => Some(0b0010),
+            _ => Some(0b0000)
+        }
+    }
 }
 
 impl fmt::Display for PacketType {
@@ -246,9 +315,56 @@ impl ConnectReturnCode {
             _ => Err(MQError::UnsupportedConnectReturnCode)
         }
     }
+
+    pub fn to_reason_code(&self) -> u8 {
+        match *self {
+            ConnectReturnCode::Accepted => 0x00,
+            ConnectReturnCode::RefusedProtocolVersion => 0x84,
+            ConnectReturnCode::RefusedIdentifierRejected => 0x85,
+            ConnectReturnCode::ServerUnavailable => 0x88,
+            ConnectReturnCode::BadUsernamePassword => 0x86,
+            ConnectReturnCode::NotAuthorized => 0x87
+        }
+    }
+
+    /// Whether connecting again later may be accepted. A refusal about the
+    /// client itself is answered the same way every time.
+    pub fn is_retryable(&self) -> bool {
+        *self == ConnectReturnCode::ServerUnavailable
+    }
+
+    /// Refused for the credentials or their permissions
+    pub fn is_auth_failure(&self) -> bool {
+        matches!(*self, ConnectReturnCode::BadUsernamePassword | ConnectReturnCode::NotAuthorized)
+    }
+
+    /// Maps an MQTT 5 CONNACK reason code to its MQTT 3.1.1 counterpart.
+    /// Refusals of what the client sent, which retrying won't change, end
+    /// up as `RefusedProtocolVersion`; the server's own trouble, moving
+    /// and limits as `ServerUnavailable`. Several codes share a
+    /// counterpart, `Connack::reason_code` keeps the one sent.
+    pub fn from_reason_code(code: u8) -> Result<ConnectReturnCode> {
+        match code {
+            0x00 => Ok(ConnectReturnCode::Accepted),
+            // malformed packet, protocol error, unsupported protocol version,
+            // will topic name invalid, packet too large, payload format
+            // invalid, retain or QoS not supported
+            0x81 | 0x82 | 0x84 | 0x90 | 0x95 | 0x99 | 0x9A | 0x9B => Ok(ConnectReturnCode::RefusedProtocolVersion),
+            0x85 => Ok(ConnectReturnCode::RefusedIdentifierRejected),
+            // bad user name or password, bad authentication method
+            0x86 | 0x8C => Ok(ConnectReturnCode::BadUsernamePassword),
+            // not authorized, banned
+            0x87 | 0x8A => Ok(ConnectReturnCode::NotAuthorized),
+            // unspecified, implementation specific, unavailable, busy, quota
+            // exceeded, use another server, server moved, connection rate
+            // exceeded
+            0x80 | 0x83 | 0x88 | 0x89 | 0x97 | 0x9C | 0x9D | 0x9F => Ok(ConnectReturnCode::ServerUnavailable),
+            _ => Err(MQError::UnsupportedConnectReturnCode)
+        }
+    }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct PacketIdentifier(pub u16);
 
 impl PacketIdentifier {
@@ -269,12 +385,18 @@ pub struct Header {
 }
 
 impl Header {
+    /// Fails with `IncorrectPacketFormat` when the flags aren't the ones
+    /// the packet type reserves
     pub fn new(hd: u8, len: usize) -> Result<Header> {
-        Ok(Header {
-            hd: hd,
-            typ: (PacketType::from_hd(hd))?,
-            len: len
-        })
+        let typ = PacketType::from_hd(hd)?;
+        match typ.fixed_flags() {
+            Some(flags) if hd & 0x0F != flags => Err(MQError::IncorrectPacketFormat),
+            _ => Ok(Header {
+                hd,
+                typ,
+                len
+            })
+        }
     }
 
     #[inline]
@@ -296,23 +418,64 @@ impl Header {
 #[derive(Debug, Clone, PartialEq)]
 pub struct LastWill {
     pub topic: String,
-    pub message: String,
+    pub message: Vec<u8>,
     pub qos: QoS,
-    pub retain: bool
+    pub retain: bool,
+    // MQTT 5 Will Properties, not written for older protocols
+    pub properties: Vec<Property>
 }
 
 #[cfg(test)]
 mod test {
-    use super::{QoS, Protocol, PacketIdentifier};
+    use std::convert::TryFrom;
+    use super::{QoS, Protocol, PacketIdentifier, Header, PacketType, MQError, ConnectReturnCode};
 
     #[test]
     fn protocol_test() {
         assert_eq!(Protocol::new("MQTT", 4).unwrap(), Protocol::MQTT(4));
+        assert_eq!(Protocol::new("MQTT", 5).unwrap(), Protocol::MQTT(5));
         assert_eq!(Protocol::new("MQIsdp", 3).unwrap(), Protocol::MQIsdp(3));
         assert_eq!(Protocol::MQIsdp(3).name(), "MQIsdp");
         assert_eq!(Protocol::MQTT(4).name(), "MQTT");
         assert_eq!(Protocol::MQTT(3).level(), 3);
         assert_eq!(Protocol::MQTT(4).level(), 4);
+        assert_eq!(Protocol::MQTT(5).to_string(), "MQTT v5");
+        assert_eq!(Protocol::MQIsdp(3).to_string(), "MQIsdp v3");
+    }
+
+    #[test]
+    fn connect_return_code_retryable_test() {
+        assert!(ConnectReturnCode::ServerUnavailable.is_retryable());
+        assert!(!ConnectReturnCode::BadUsernamePassword.is_retryable());
+        assert!(!ConnectReturnCode::NotAuthorized.is_retryable());
+        assert!(!ConnectReturnCode::RefusedProtocolVersion.is_retryable());
+        assert!(!ConnectReturnCode::RefusedIdentifierRejected.is_retryable());
+        assert!(ConnectReturnCode::BadUsernamePassword.is_auth_failure());
+        assert!(ConnectReturnCode::NotAuthorized.is_auth_failure());
+        assert!(!ConnectReturnCode::ServerUnavailable.is_auth_failure());
+        // MQTT 5 "Server busy" is mapped to a retryable refusal as well
+        assert!(ConnectReturnCode::from_reason_code(0x89).unwrap().is_retryable());
+    }
+
+    #[test]
+    fn connect_return_code_from_reason_code_test() {
+        for &(code, expected) in &[(0x00, ConnectReturnCode::Accepted),
+                                   (0x80, ConnectReturnCode::ServerUnavailable),
+                                   (0x82, ConnectReturnCode::RefusedProtocolVersion),
+                                   (0x84, ConnectReturnCode::RefusedProtocolVersion),
+                                   (0x85, ConnectReturnCode::RefusedIdentifierRejected),
+                                   (0x87, ConnectReturnCode::NotAuthorized),
+                                   (0x8A, ConnectReturnCode::NotAuthorized),
+                                   (0x8C, ConnectReturnCode::BadUsernamePassword),
+                                   (0x90, ConnectReturnCode::RefusedProtocolVersion),
+                                   (0x97, ConnectReturnCode::ServerUnavailable),
+                                   (0x9D, ConnectReturnCode::ServerUnavailable),
+                                   (0x9F, ConnectReturnCode::ServerUnavailable)] {
+            assert_eq!(ConnectReturnCode::from_reason_code(code).unwrap(), expected);
+        }
+        // not a CONNACK reason
+        assert!(ConnectReturnCode::from_reason_code(0x8E).is_err());
+        assert!(ConnectReturnCode::from_reason_code(0x01).is_err());
     }
 
     #[test]
@@ -325,10 +488,59 @@ mod test {
         assert_eq!(QoS::ExactlyOnce.min(QoS::ExactlyOnce), QoS::ExactlyOnce);
     }
 
+    #[test]
+    fn qos_max_test() {
+        assert_eq!(QoS::AtMostOnce.max(QoS::AtMostOnce), QoS::AtMostOnce);
+        assert_eq!(QoS::AtMostOnce.max(QoS::AtLeastOnce), QoS::AtLeastOnce);
+        assert_eq!(QoS::AtLeastOnce.max(QoS::ExactlyOnce), QoS::ExactlyOnce);
+        assert_eq!(QoS::ExactlyOnce.max(QoS::AtMostOnce), QoS::ExactlyOnce);
+    }
+
+    #[test]
+    fn qos_conversion_test() {
+        assert_eq!(QoS::try_from(0).unwrap(), QoS::AtMostOnce);
+        assert_eq!(QoS::try_from(1).unwrap(), QoS::AtLeastOnce);
+        assert_eq!(QoS::try_from(2).unwrap(), QoS::ExactlyOnce);
+        match QoS::try_from(3) {
+            Err(MQError::UnsupportedQualityOfService) => (),
+            other => panic!("expected UnsupportedQualityOfService, got {:?}", other)
+        }
+        for qos in [QoS::AtMostOnce, QoS::AtLeastOnce, QoS::ExactlyOnce].iter() {
+            assert_eq!(u8::from(*qos), qos.to_u8());
+            assert_eq!(QoS::try_from(u8::from(*qos)).unwrap(), *qos);
+        }
+    }
+
+    #[test]
+    fn qos_ord_test() {
+        assert!(QoS::AtMostOnce < QoS::AtLeastOnce);
+        assert!(QoS::AtLeastOnce < QoS::ExactlyOnce);
+        let mut levels = vec![QoS::ExactlyOnce, QoS::AtMostOnce, QoS::AtLeastOnce];
+        levels.sort();
+        assert_eq!(levels, vec![QoS::AtMostOnce, QoS::AtLeastOnce, QoS::ExactlyOnce]);
+    }
+
     #[test]
     fn packet_identifier_test() {
         let pid = PacketIdentifier::zero();
         assert_eq!(pid, PacketIdentifier(0));
         assert_eq!(pid.next(), PacketIdentifier(1));
     }
+
+    #[test]
+    fn header_flags_test() {
+        assert_eq!(Header::new(0x82, 6).unwrap().typ, PacketType::Subscribe);
+        // SUBSCRIBE without its reserved 0010
+        match Header::new(0x80, 6) {
+            Err(MQError::IncorrectPacketFormat) => (),
+            other => panic!("expected IncorrectPacketFormat, got {:?}", other)
+        }
+        // PUBACK with flags
+        match Header::new(0x42, 2) {
+            Err(MQError::IncorrectPacketFormat) => (),
+            other => panic!("expected IncorrectPacketFormat, got {:?}", other)
+        }
+        // PUBLISH flags are dup, QoS and retain
+        assert!(Header::new(0x3B, 10).is_ok());
+    }
 }
@@ -22,21 +22,31 @@ pub enum MQError {
     UnsupportedPacketType,
     #[error("Unsupported Connect Return Code")]
     UnsupportedConnectReturnCode,
+    #[error("Unsupported Property")]
+    UnsupportedProperty,
     #[error("Payload Size Incorrect")]
     PayloadSizeIncorrect,
     #[error("Payload Too Long")]
     PayloadTooLong,
     #[error("Payload Required")]
     PayloadRequired,
+    #[error("Password Without Username")]
+    PasswordWithoutUsername,
     #[error("Topic Name Must Not Contain Utf8")]
     TopicNameMustNotContainNonUtf8(#[from] FromUtf8Error),
     #[error("Topic Name Must Not Contain Wildcard")]
     TopicNameMustNotContainWildcard,
+    #[error("Topic Name Must Not Be Shared")]
+    TopicNameMustNotBeShared,
     #[error("Malformed Remaining Length")]
     MalformedRemainingLength,
+    /// The stream ended before a packet started, a clean close
     #[error("Unexpected EOF")]
     UnexpectedEof,
-    #[error("uh oh: `{0}`")]
+    /// The stream ended inside the fixed header, more data would complete it
+    #[error("Incomplete Packet")]
+    IncompletePacket,
+    #[error("I/O error: {0}")]
     Io(#[from] io::Error)
 }
 
@@ -1,5 +1,6 @@
+use std::fmt;
 use std::sync::Arc;
-use super::{QoS, LastWill, PacketIdentifier, Protocol, ConnectReturnCode};
+use super::{QoS, LastWill, PacketIdentifier, Protocol, ConnectReturnCode, Property, RetainHandling};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Packet {
@@ -16,7 +17,50 @@ pub enum Packet {
 	Unsuback(PacketIdentifier),
 	Pingreq,
 	Pingresp,
-	Disconnect
+	Disconnect(Disconnect),
+	Auth(Auth)
+}
+
+/// One line summary for logs, payloads are left out
+impl fmt::Display for Packet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Packet::Connect(ref connect) => {
+                write!(f, "CONNECT client_id={} keep_alive={}", connect.client_id, connect.keep_alive)
+            }
+            Packet::Connack(ref connack) => {
+                write!(f, "CONNACK code={} session_present={}", connack.code, connack.session_present)
+            }
+            Packet::Publish(ref publish) => {
+                write!(f, "PUBLISH topic={} qos={} len={}",
+                       publish.topic_name, publish.qos.to_u8(), publish.payload.len())?;
+                if let Some(pid) = publish.pid {
+                    write!(f, " pid={}", pid.0)?;
+                }
+                Ok(())
+            }
+            Packet::Puback(pid) => write!(f, "PUBACK pid={}", pid.0),
+            Packet::Pubrec(pid) => write!(f, "PUBREC pid={}", pid.0),
+            Packet::Pubrel(pid) => write!(f, "PUBREL pid={}", pid.0),
+            Packet::Pubcomp(pid) => write!(f, "PUBCOMP pid={}", pid.0),
+            Packet::Subscribe(ref subscribe) => {
+                write!(f, "SUBSCRIBE pid={} topics={}", subscribe.pid.0, subscribe.topics.len())
+            }
+            Packet::Suback(ref suback) => {
+                write!(f, "SUBACK pid={} codes={}", suback.pid.0, suback.return_codes.len())
+            }
+            Packet::Unsubscribe(ref unsubscribe) => {
+                write!(f, "UNSUBSCRIBE pid={} topics={}", unsubscribe.pid.0, unsubscribe.topics.len())
+            }
+            Packet::Unsuback(pid) => write!(f, "UNSUBACK pid={}", pid.0),
+            Packet::Pingreq => f.write_str("PINGREQ"),
+            Packet::Pingresp => f.write_str("PINGRESP"),
+            Packet::Disconnect(ref disconnect) => {
+                write!(f, "DISCONNECT code={:#04x}", disconnect.reason_code)
+            }
+            Packet::Auth(ref auth) => write!(f, "AUTH code={:#04x}", auth.reason_code)
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -27,13 +71,39 @@ pub struct Connect {
 	pub clean_session: bool,
     pub last_will: Option<LastWill>,
     pub username: Option<String>,
-    pub password: Option<String>
+    pub password: Option<String>,
+    // MQTT 5 only, not written for older protocols
+    pub properties: Vec<Property>
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Connack {
     pub session_present: bool,
-    pub code: ConnectReturnCode
+    pub code: ConnectReturnCode,
+    // MQTT 5 Reason Code as sent, `code` is its MQTT 3.1.1 counterpart
+    pub reason_code: Option<u8>,
+    // MQTT 5 Reason String
+    pub reason: Option<String>,
+    // MQTT 5 Server Keep Alive, in seconds, replaces the one of the CONNECT
+    pub server_keep_alive: Option<u16>
+}
+
+/// Reason code and string are MQTT 5 only, older protocols send a bare
+/// DISCONNECT which reads back as the normal disconnection
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Disconnect {
+    // 0x00 Normal disconnection
+    pub reason_code: u8,
+    pub reason: Option<String>
+}
+
+/// MQTT 5 enhanced authentication exchange, an empty AUTH reads back as
+/// success without properties
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Auth {
+    // 0x00 Success, 0x18 Continue authentication, 0x19 Re-authenticate
+    pub reason_code: u8,
+    pub properties: Vec<Property>
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -43,6 +113,8 @@ pub struct Publish {
     pub retain: bool,
     pub topic_name: String,
     pub pid: Option<PacketIdentifier>,
+    // MQTT 5 only, not written for older protocols
+    pub properties: Vec<Property>,
     pub payload: Arc<Vec<u8>>
 }
 
@@ -50,13 +122,21 @@ pub struct Publish {
 pub struct Subscribe {
     pub pid: PacketIdentifier,
 	// (topic path, qos)
-	pub topics: Vec<SubscribeTopic>
+	pub topics: Vec<SubscribeTopic>,
+    // MQTT 5 only, not written for older protocols
+    pub properties: Vec<Property>
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct SubscribeTopic {
 	pub topic_path: String,
-	pub qos: QoS
+	pub qos: QoS,
+    // MQTT 5 only: the broker doesn't send our own publishes back
+    pub no_local: bool,
+    // MQTT 5 only: forwarded messages keep their RETAIN flag
+    pub retain_as_published: bool,
+    // MQTT 5 only
+    pub retain_handling: RetainHandling
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -67,10 +147,63 @@ pub struct Suback {
 	pub return_codes: Vec<SubscribeReturnCodes>
 }
 
+/// Outcome of a single subscription. More MQTT 5 outcomes may be told apart
+/// in later versions, so matches need a wildcard arm.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum SubscribeReturnCodes {
 	Success(QoS),
-	Failure
+	// 0x80, the only failure before MQTT 5
+	Failure,
+	// MQTT 5 reason of a refused subscription
+	Refused(SubscribeReasonCode)
+}
+
+impl SubscribeReturnCodes {
+    pub fn is_success(&self) -> bool {
+        matches!(*self, SubscribeReturnCodes::Success(_))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubscribeReasonCode {
+    ImplementationSpecificError,
+    NotAuthorized,
+    TopicFilterInvalid,
+    PacketIdentifierInUse,
+    QuotaExceeded,
+    SharedSubscriptionsNotSupported,
+    SubscriptionIdentifiersNotSupported,
+    WildcardSubscriptionsNotSupported
+}
+
+impl SubscribeReasonCode {
+    pub fn to_u8(&self) -> u8 {
+        match *self {
+            SubscribeReasonCode::ImplementationSpecificError => 0x83,
+            SubscribeReasonCode::NotAuthorized => 0x87,
+            SubscribeReasonCode::TopicFilterInvalid => 0x8F,
+            SubscribeReasonCode::PacketIdentifierInUse => 0x91,
+            SubscribeReasonCode::QuotaExceeded => 0x97,
+            SubscribeReasonCode::SharedSubscriptionsNotSupported => 0x9E,
+            SubscribeReasonCode::SubscriptionIdentifiersNotSupported => 0xA1,
+            SubscribeReasonCode::WildcardSubscriptionsNotSupported => 0xA2
+        }
+    }
+
+    pub fn from_u8(byte: u8) -> Option<SubscribeReasonCode> {
+        match byte {
+            0x83 => Some(SubscribeReasonCode::ImplementationSpecificError),
+            0x87 => Some(SubscribeReasonCode::NotAuthorized),
+            0x8F => Some(SubscribeReasonCode::TopicFilterInvalid),
+            0x91 => Some(SubscribeReasonCode::PacketIdentifierInUse),
+            0x97 => Some(SubscribeReasonCode::QuotaExceeded),
+            0x9E => Some(SubscribeReasonCode::SharedSubscriptionsNotSupported),
+            0xA1 => Some(SubscribeReasonCode::SubscriptionIdentifiersNotSupported),
+            0xA2 => Some(SubscribeReasonCode::WildcardSubscriptionsNotSupported),
+            _ => None
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -78,3 +211,26 @@ pub struct Unsubscribe {
     pub pid: PacketIdentifier,
 	pub topics: Vec<String>
 }
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use super::{Packet, Publish};
+    use super::super::{QoS, PacketIdentifier};
+
+    #[test]
+    fn packet_display_test() {
+        let publish = Packet::Publish(Box::new(Publish {
+            dup: false,
+            qos: QoS::AtLeastOnce,
+            retain: false,
+            topic_name: "a/b".to_owned(),
+            pid: None,
+            properties: Vec::new(),
+            payload: Arc::new(vec![0; 1048576])
+        }));
+        assert_eq!(publish.to_string(), "PUBLISH topic=a/b qos=1 len=1048576");
+        assert_eq!(Packet::Puback(PacketIdentifier(10)).to_string(), "PUBACK pid=10");
+        assert_eq!(Packet::Pingreq.to_string(), "PINGREQ");
+    }
+}
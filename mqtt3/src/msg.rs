@@ -1,14 +1,28 @@
+use std::str;
 use std::sync::Arc;
+use std::time::Duration;
 use std::vec::Vec;
-use {Publish, TopicPath, PacketIdentifier, QoS, LastWill, MQError, Result};
+use {Publish, TopicPath, PacketIdentifier, QoS, LastWill, MQError, Property, Result};
 
+/// Cloning a message is cheap: the topic and the payload are shared, only
+/// the reference counts are bumped.
 #[derive(Debug, Clone)]
 pub struct Message {
-    pub topic: TopicPath,
+    pub topic: Arc<TopicPath>,
     pub qos: QoS,
+    // On delivery: the retained message handed out on subscribe rather
+    // than a live publish
     pub retain: bool,
     // Only for QoS 1,2
     pub pid: Option<PacketIdentifier>,
+    // MQTT 5 Message Expiry Interval
+    pub expiry: Option<Duration>,
+    // MQTT 5 Payload Format Indicator: the payload is UTF-8 text rather
+    // than bytes, always false before MQTT 5
+    pub utf8: bool,
+    // MQTT 5 Subscription Identifiers of the subscriptions the message
+    // matched, as echoed by the broker
+    pub subscription_ids: Vec<u32>,
     pub payload: Arc<Vec<u8>>
 }
 
@@ -18,11 +32,27 @@ impl Message {
         if topic.wildcards {
             return Err(MQError::TopicNameMustNotContainWildcard);
         }
+        let expiry = publish.properties.iter().filter_map(|property| {
+            match *property {
+                Property::MessageExpiryInterval(secs) => Some(Duration::from_secs(secs as u64)),
+                _ => None
+            }
+        }).next();
+        let utf8 = publish.properties.contains(&Property::PayloadFormatIndicator(1));
+        let subscription_ids = publish.properties.iter().filter_map(|property| {
+            match *property {
+                Property::SubscriptionIdentifier(id) => Some(id),
+                _ => None
+            }
+        }).collect();
         Ok(Box::new(Message {
-            topic: topic,
+            topic: Arc::new(topic),
             qos: publish.qos,
             retain: publish.retain,
             pid: publish.pid,
+            expiry,
+            utf8,
+            subscription_ids,
             payload: publish.payload.clone()
         }))
     }
@@ -31,26 +61,51 @@ impl Message {
         let topic = TopicPath::from(last_will.topic);
 
         Box::new(Message {
-            topic: topic,
+            topic: Arc::new(topic),
             qos: last_will.qos,
             retain: last_will.retain,
             pid: None,
-            payload: Arc::new(last_will.message.into_bytes())
+            expiry: None,
+            utf8: false,
+            subscription_ids: Vec::new(),
+            payload: Arc::new(last_will.message)
         })
     }
 
     pub fn to_pub(&self, qos: Option<QoS>, dup: bool) -> Box<Publish> {
         let qos = qos.unwrap_or(self.qos);
+        let mut properties = Vec::new();
+        if self.utf8 {
+            properties.push(Property::PayloadFormatIndicator(1));
+        }
+        if let Some(expiry) = self.expiry {
+            properties.push(Property::MessageExpiryInterval(expiry.as_secs() as u32));
+        }
         Box::new(Publish {
             dup: dup,
             qos: qos,
             retain: self.retain,
             topic_name: self.topic.path.clone(),
             pid: self.pid,
+            properties,
             payload: self.payload.clone()
         })
     }
 
+    /// The topic name, borrowed from the message
+    pub fn topic_str(&self) -> &str {
+        self.topic.as_str()
+    }
+
+    /// The payload as text, None when it isn't valid UTF-8
+    pub fn payload_str(&self) -> Option<&str> {
+        str::from_utf8(&self.payload).ok()
+    }
+
+    pub fn payload_bytes(&self) -> &[u8] {
+        &self.payload
+    }
+
     pub fn transform(&self, pid: Option<PacketIdentifier>, qos: Option<QoS>) -> Box<Message> {
         let pid = pid.or(self.pid);
         let qos = qos.unwrap_or(self.qos);
@@ -59,6 +114,9 @@ impl Message {
             qos: qos,
             retain: self.retain,
             pid: pid,
+            expiry: self.expiry,
+            utf8: self.utf8,
+            subscription_ids: self.subscription_ids.clone(),
             payload: self.payload.clone()
         })
     }
@@ -68,15 +126,31 @@ impl Message {
 mod test {
     use std::sync::Arc;
     use super::{Message};
-    use {Publish, QoS, ToTopicPath, PacketIdentifier};
+    use {Publish, QoS, ToTopicPath, PacketIdentifier, LastWill, Property};
+
+    #[test]
+    fn message_topic_str_test() {
+        let msg = Message::from_last_will(LastWill {
+            topic: "a/b".to_owned(),
+            message: Vec::new(),
+            qos: QoS::AtMostOnce,
+            retain: false,
+            properties: Vec::new()
+        });
+        assert_eq!(msg.topic_str(), "a/b");
+        assert_eq!(msg.topic_str().as_ptr(), msg.topic.path.as_ptr());
+    }
 
     #[test]
     fn message_to_pub_test() {
         let msg = Message {
-            topic: "/a/b".to_topic_path().unwrap(),
+            topic: Arc::new("/a/b".to_topic_path().unwrap()),
             qos: QoS::AtLeastOnce,
             retain: false,
             pid: Some(PacketIdentifier(1)),
+            expiry: None,
+            utf8: false,
+            subscription_ids: Vec::new(),
             payload: Arc::new(vec![0x80, 0x40])
         };
         let publish = msg.to_pub(None, false);
@@ -87,6 +161,7 @@ mod test {
             retain: false,
             topic_name: "/a/b".to_owned(),
             pid: Some(PacketIdentifier(1)),
+            properties: Vec::new(),
             payload: Arc::new(vec![0x80, 0x40])
         }));
     }
@@ -99,6 +174,7 @@ mod test {
             retain: true,
             topic_name: "/a/b/c".to_owned(),
             pid: Some(PacketIdentifier(2)),
+            properties: Vec::new(),
             payload: Arc::new(vec![0x10, 0x20, 0x30])
         });
         let msg = Message::from_pub(publish).unwrap();
@@ -108,6 +184,71 @@ mod test {
         assert_eq!(msg.pid, Some(PacketIdentifier(2)));
         assert_eq!(msg.payload, Arc::new(vec![0x10, 0x20, 0x30]));
         assert!(msg.retain);
+        assert!(!msg.utf8);
+    }
+
+    #[test]
+    fn message_payload_format_test() {
+        let publish = Box::new(Publish {
+            dup: false,
+            qos: QoS::AtMostOnce,
+            retain: false,
+            topic_name: "/a".to_owned(),
+            pid: None,
+            properties: vec![Property::PayloadFormatIndicator(1)],
+            payload: Arc::new(b"text".to_vec())
+        });
+        let msg = Message::from_pub(publish.clone()).unwrap();
+        assert!(msg.utf8);
+        assert_eq!(msg.to_pub(None, false), publish);
+    }
+
+    #[test]
+    fn message_subscription_ids_test() {
+        let publish = Box::new(Publish {
+            dup: false,
+            qos: QoS::AtMostOnce,
+            retain: false,
+            topic_name: "/a".to_owned(),
+            pid: None,
+            properties: vec![Property::SubscriptionIdentifier(1), Property::SubscriptionIdentifier(7)],
+            payload: Arc::new(Vec::new())
+        });
+        let msg = Message::from_pub(publish).unwrap();
+        assert_eq!(msg.subscription_ids, vec![1, 7]);
+        // only for the broker to send
+        assert!(msg.to_pub(None, false).properties.is_empty());
+    }
+
+    #[test]
+    fn message_payload_str_test() {
+        let mut msg = Message::from_last_will(LastWill {
+            topic: "/a/b".to_owned(),
+            message: b"text".to_vec(),
+            qos: QoS::AtMostOnce,
+            retain: false,
+            properties: Vec::new()
+        });
+        assert_eq!(msg.payload_str(), Some("text"));
+        msg.payload = Arc::new(vec![0xFF, 0xFE]);
+        assert_eq!(msg.payload_str(), None);
+        assert_eq!(msg.payload_bytes(), &[0xFF, 0xFE]);
+    }
+
+    #[test]
+    fn message_clone_shares_topic_test() {
+        let msg = Message::from_last_will(LastWill {
+            topic: "/a/b".to_owned(),
+            message: vec![0x01],
+            qos: QoS::AtLeastOnce,
+            retain: false,
+            properties: Vec::new()
+        });
+        let clone = msg.clone();
+        assert!(Arc::ptr_eq(&msg.topic, &clone.topic));
+        assert!(Arc::ptr_eq(&msg.payload, &clone.payload));
+        let transformed = msg.transform(Some(PacketIdentifier(3)), None);
+        assert!(Arc::ptr_eq(&msg.topic, &transformed.topic));
     }
 
     #[test]
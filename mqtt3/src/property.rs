@@ -0,0 +1,142 @@
+/// MQTT 5 property, carried by the variable header of most packets
+#[derive(Debug, Clone, PartialEq)]
+pub enum Property {
+    PayloadFormatIndicator(u8),
+    MessageExpiryInterval(u32),
+    ContentType(String),
+    ResponseTopic(String),
+    CorrelationData(Vec<u8>),
+    SubscriptionIdentifier(u32),
+    SessionExpiryInterval(u32),
+    AssignedClientIdentifier(String),
+    ServerKeepAlive(u16),
+    AuthenticationMethod(String),
+    AuthenticationData(Vec<u8>),
+    RequestProblemInformation(u8),
+    WillDelayInterval(u32),
+    RequestResponseInformation(u8),
+    ResponseInformation(String),
+    ServerReference(String),
+    ReasonString(String),
+    ReceiveMaximum(u16),
+    TopicAliasMaximum(u16),
+    TopicAlias(u16),
+    MaximumQoS(u8),
+    RetainAvailable(u8),
+    UserProperty(String, String),
+    MaximumPacketSize(u32),
+    WildcardSubscriptionAvailable(u8),
+    SubscriptionIdentifierAvailable(u8),
+    SharedSubscriptionAvailable(u8)
+}
+
+impl Property {
+    pub fn id(&self) -> u8 {
+        match *self {
+            Property::PayloadFormatIndicator(_) => 0x01,
+            Property::MessageExpiryInterval(_) => 0x02,
+            Property::ContentType(_) => 0x03,
+            Property::ResponseTopic(_) => 0x08,
+            Property::CorrelationData(_) => 0x09,
+            Property::SubscriptionIdentifier(_) => 0x0B,
+            Property::SessionExpiryInterval(_) => 0x11,
+            Property::AssignedClientIdentifier(_) => 0x12,
+            Property::ServerKeepAlive(_) => 0x13,
+            Property::AuthenticationMethod(_) => 0x15,
+            Property::AuthenticationData(_) => 0x16,
+            Property::RequestProblemInformation(_) => 0x17,
+            Property::WillDelayInterval(_) => 0x18,
+            Property::RequestResponseInformation(_) => 0x19,
+            Property::ResponseInformation(_) => 0x1A,
+            Property::ServerReference(_) => 0x1C,
+            Property::ReasonString(_) => 0x1F,
+            Property::ReceiveMaximum(_) => 0x21,
+            Property::TopicAliasMaximum(_) => 0x22,
+            Property::TopicAlias(_) => 0x23,
+            Property::MaximumQoS(_) => 0x24,
+            Property::RetainAvailable(_) => 0x25,
+            Property::UserProperty(_, _) => 0x26,
+            Property::MaximumPacketSize(_) => 0x27,
+            Property::WildcardSubscriptionAvailable(_) => 0x28,
+            Property::SubscriptionIdentifierAvailable(_) => 0x29,
+            Property::SharedSubscriptionAvailable(_) => 0x2A
+        }
+    }
+
+    /// Encoded size, identifier included
+    pub fn encoded_len(&self) -> usize {
+        1 + match *self {
+            Property::PayloadFormatIndicator(_) |
+            Property::RequestProblemInformation(_) |
+            Property::RequestResponseInformation(_) |
+            Property::MaximumQoS(_) |
+            Property::RetainAvailable(_) |
+            Property::WildcardSubscriptionAvailable(_) |
+            Property::SubscriptionIdentifierAvailable(_) |
+            Property::SharedSubscriptionAvailable(_) => 1,
+            Property::ServerKeepAlive(_) |
+            Property::ReceiveMaximum(_) |
+            Property::TopicAliasMaximum(_) |
+            Property::TopicAlias(_) => 2,
+            Property::MessageExpiryInterval(_) |
+            Property::SessionExpiryInterval(_) |
+            Property::WillDelayInterval(_) |
+            Property::MaximumPacketSize(_) => 4,
+            Property::SubscriptionIdentifier(id) => varint_len(id as usize),
+            Property::ContentType(ref s) |
+            Property::ResponseTopic(ref s) |
+            Property::AssignedClientIdentifier(ref s) |
+            Property::AuthenticationMethod(ref s) |
+            Property::ResponseInformation(ref s) |
+            Property::ServerReference(ref s) |
+            Property::ReasonString(ref s) => 2 + s.len(),
+            Property::CorrelationData(ref b) |
+            Property::AuthenticationData(ref b) => 2 + b.len(),
+            Property::UserProperty(ref k, ref v) => 4 + k.len() + v.len()
+        }
+    }
+}
+
+/// Size of the properties without their length prefix
+pub fn properties_len(properties: &[Property]) -> usize {
+    properties.iter().fold(0, |len, property| len + property.encoded_len())
+}
+
+/// Size of the properties with their length prefix
+pub fn properties_block_len(properties: &[Property]) -> usize {
+    let len = properties_len(properties);
+    varint_len(len) + len
+}
+
+/// Size of a variable byte integer
+pub fn varint_len(value: usize) -> usize {
+    match value {
+        0..=127 => 1,
+        128..=16383 => 2,
+        16384..=2097151 => 3,
+        _ => 4
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Property, properties_block_len, varint_len};
+
+    #[test]
+    fn property_len_test() {
+        assert_eq!(Property::MessageExpiryInterval(10).encoded_len(), 5);
+        assert_eq!(Property::SubscriptionIdentifier(200).encoded_len(), 3);
+        assert_eq!(Property::UserProperty("a".to_owned(), "bc".to_owned()).encoded_len(), 8);
+        assert_eq!(properties_block_len(&[]), 1);
+        assert_eq!(properties_block_len(&[Property::TopicAlias(1)]), 4);
+    }
+
+    #[test]
+    fn varint_len_test() {
+        assert_eq!(varint_len(0), 1);
+        assert_eq!(varint_len(127), 1);
+        assert_eq!(varint_len(128), 2);
+        assert_eq!(varint_len(16384), 3);
+        assert_eq!(varint_len(268435455), 4);
+    }
+}
@@ -1,14 +1,22 @@
-use std::io::{BufReader, Read, Take, Cursor};
+use std::cmp;
+use std::io::{self, Read, Take, Cursor};
+#[cfg(feature = "std-net")]
+use std::io::BufReader;
+#[cfg(feature = "std-net")]
 use std::net::TcpStream;
+use std::mem;
 use std::sync::Arc;
 use byteorder::{ReadBytesExt, BigEndian};
-use {MQError, Result, ConnectReturnCode, SubscribeTopic, SubscribeReturnCodes};
-use {PacketType, Header, QoS, LastWill, Protocol, PacketIdentifier, MULTIPLIER};
+use {MQError, Result, ConnectReturnCode, SubscribeTopic, SubscribeReturnCodes, SubscribeReasonCode};
+use {PacketType, Header, QoS, LastWill, Protocol, PacketIdentifier, Property, RetainHandling, MULTIPLIER};
+use property::properties_block_len;
 
 use mqtt::{
     Packet,
     Connect,
     Connack,
+    Disconnect,
+    Auth,
     Publish,
     Subscribe,
     Suback,
@@ -17,6 +25,22 @@ use mqtt::{
 
 pub trait MqttRead: ReadBytesExt {
     fn read_packet(&mut self) -> Result<Packet> {
+        self.read_packet_with(Protocol::MQTT(4))
+    }
+
+    /// Reads a packet framed for `protocol`. CONNECT carries its own
+    /// protocol level and is read the same way by every protocol.
+    ///
+    /// A stream ending before the first byte gives `UnexpectedEof`, one
+    /// ending within the fixed header `IncompletePacket`.
+    fn read_packet_with(&mut self, protocol: Protocol) -> Result<Packet> {
+        self.read_packet_into(protocol, &mut Vec::new())
+    }
+
+    /// Reads a packet like `read_packet_with`, a PUBLISH payload goes into
+    /// `buf` first, so its capacity is used instead of a new allocation.
+    /// The payload leaves `buf` empty, other packets don't touch it.
+    fn read_packet_into(&mut self, protocol: Protocol, buf: &mut Vec<u8>) -> Result<Packet> {
         let hd = self.read_u8()?;
         let len = self.read_remaining_length()?;
         let header = Header::new(hd, len)?;
@@ -26,57 +50,79 @@ pub trait MqttRead: ReadBytesExt {
             return match header.typ {
                 PacketType::Pingreq => Ok(Packet::Pingreq),
                 PacketType::Pingresp => Ok(Packet::Pingresp),
+                PacketType::Disconnect => Ok(Packet::Disconnect(Disconnect::default())),
+                PacketType::Auth if protocol.level() >= 5 => Ok(Packet::Auth(Auth::default())),
+                PacketType::Auth => Err(MQError::UnsupportedPacketType),
                 _ => Err(MQError::PayloadRequired)
             };
         }
         let mut raw_packet = self.take(len as u64);
+        let v5 = protocol.level() >= 5;
 
-        match header.typ {
+        let packet = match header.typ {
             PacketType::Connect => Ok(Packet::Connect(raw_packet.read_connect(header)?)),
-            PacketType::Connack => Ok(Packet::Connack(raw_packet.read_connack(header)?)),
-            PacketType::Publish => Ok(Packet::Publish(raw_packet.read_publish(header)?)),
+            PacketType::Connack => Ok(Packet::Connack(raw_packet.read_connack(header, protocol)?)),
+            PacketType::Publish => Ok(Packet::Publish(raw_packet.read_publish(header, protocol, buf)?)),
             PacketType::Puback => {
-                if len != 2 {
-                    return Err(MQError::PayloadSizeIncorrect)
-                }
-                let pid = raw_packet.read_u16::<BigEndian>()?;
-                Ok(Packet::Puback(PacketIdentifier(pid)))
+                let pid = raw_packet.read_ack(len, v5)?;
+                Ok(Packet::Puback(pid))
             },
             PacketType::Pubrec => {
-                if len != 2 {
-                    return Err(MQError::PayloadSizeIncorrect)
-                }
-                let pid = raw_packet.read_u16::<BigEndian>()?;
-                Ok(Packet::Pubrec(PacketIdentifier(pid)))
+                let pid = raw_packet.read_ack(len, v5)?;
+                Ok(Packet::Pubrec(pid))
             },
             PacketType::Pubrel => {
-                if len != 2 {
-                    return Err(MQError::PayloadSizeIncorrect)
-                }
-                let pid = raw_packet.read_u16::<BigEndian>()?;
-                Ok(Packet::Pubrel(PacketIdentifier(pid)))
+                let pid = raw_packet.read_ack(len, v5)?;
+                Ok(Packet::Pubrel(pid))
             },
             PacketType::Pubcomp => {
-                if len != 2 {
-                    return Err(MQError::PayloadSizeIncorrect)
-                }
-                let pid = raw_packet.read_u16::<BigEndian>()?;
-                Ok(Packet::Pubcomp(PacketIdentifier(pid)))
+                let pid = raw_packet.read_ack(len, v5)?;
+                Ok(Packet::Pubcomp(pid))
             },
-            PacketType::Subscribe => Ok(Packet::Subscribe(raw_packet.read_subscribe(header)?)),
-            PacketType::Suback => Ok(Packet::Suback(raw_packet.read_suback(header)?)),
-            PacketType::Unsubscribe => Ok(Packet::Unsubscribe(raw_packet.read_unsubscribe(header)?)),
+            PacketType::Subscribe => Ok(Packet::Subscribe(raw_packet.read_subscribe(header, protocol)?)),
+            PacketType::Suback => Ok(Packet::Suback(raw_packet.read_suback(header, protocol)?)),
+            PacketType::Unsubscribe => Ok(Packet::Unsubscribe(raw_packet.read_unsubscribe(header, protocol)?)),
             PacketType::Unsuback => {
-                if len != 2 {
-                    return Err(MQError::PayloadSizeIncorrect)
-                }
-                let pid = raw_packet.read_u16::<BigEndian>()?;
-                Ok(Packet::Unsuback(PacketIdentifier(pid)))
+                // MQTT 5 adds properties and reason codes
+                let pid = raw_packet.read_ack(len, v5)?;
+                Ok(Packet::Unsuback(pid))
             },
             PacketType::Pingreq => Err(MQError::IncorrectPacketFormat),
             PacketType::Pingresp => Err(MQError::IncorrectPacketFormat),
-            _ => Err(MQError::UnsupportedPacketType)
+            PacketType::Disconnect => Ok(Packet::Disconnect(raw_packet.read_disconnect(len, protocol)?)),
+            PacketType::Auth => Ok(Packet::Auth(raw_packet.read_auth(len, protocol)?)),
+        }?;
+        // bytes the parser doesn't know would be taken for the next packet
+        io::copy(&mut raw_packet, &mut io::sink())?;
+        Ok(packet)
+    }
+
+    /// Reads a packet together with its bytes as they were on the wire,
+    /// fixed header included, for forwarding without encoding it again
+    fn read_packet_raw(&mut self) -> Result<(Packet, Vec<u8>)> {
+        self.read_packet_raw_with(Protocol::MQTT(4))
+    }
+
+    fn read_packet_raw_with(&mut self, protocol: Protocol) -> Result<(Packet, Vec<u8>)> {
+        let mut recorder = Recorder {
+            inner: self,
+            bytes: Vec::new()
+        };
+        let packet = recorder.read_packet_with(protocol)?;
+        Ok((packet, recorder.bytes))
+    }
+
+    // Packet identifier of an acknowledgement. MQTT 5 may follow it with a
+    // reason code and properties, which aren't exposed yet.
+    fn read_ack(&mut self, len: usize, v5: bool) -> Result<PacketIdentifier> {
+        if len < 2 || (!v5 && len != 2) {
+            return Err(MQError::PayloadSizeIncorrect)
         }
+        let pid = self.read_u16::<BigEndian>()?;
+        if len > 2 {
+            self.read_payload(len - 2)?;
+        }
+        Ok(PacketIdentifier(pid))
     }
 
     fn read_connect(&mut self, _: Header) -> Result<Box<Connect>> {
@@ -86,6 +132,11 @@ pub trait MqttRead: ReadBytesExt {
 
         let connect_flags = self.read_u8()?;
         let keep_alive = self.read_u16::<BigEndian>()?;
+        let properties = if protocol.level() >= 5 {
+            self.read_properties()?
+        } else {
+            Vec::new()
+        };
         let client_id = self.read_mqtt_string()?;
 
         let last_will = match connect_flags & 0b100 {
@@ -96,18 +147,28 @@ pub trait MqttRead: ReadBytesExt {
                 None
             },
             _ => {
+                let will_properties = if protocol.level() >= 5 {
+                    self.read_properties()?
+                } else {
+                    Vec::new()
+                };
                 let will_topic = self.read_mqtt_string()?;
-                let will_message = self.read_mqtt_string()?;
+                let will_message = self.read_mqtt_bytes()?;
                 let will_qod = QoS::from_u8((connect_flags & 0b11000) >> 3)?;
                 Some(LastWill {
                     topic: will_topic,
                     message: will_message,
                     qos: will_qod,
-                    retain: (connect_flags & 0b00100000) != 0
+                    retain: (connect_flags & 0b00100000) != 0,
+                    properties: will_properties
                 })
             }
         };
 
+        if protocol.level() < 5 && (connect_flags & 0b11000000) == 0b01000000 {
+            return Err(MQError::PasswordWithoutUsername);
+        }
+
         let username = match connect_flags & 0b10000000 {
             0 => None,
             _ => Some(self.read_mqtt_string()?)
@@ -126,12 +187,34 @@ pub trait MqttRead: ReadBytesExt {
                 clean_session: (connect_flags & 0b10) != 0,
                 last_will: last_will,
                 username: username,
-                password: password
+                password,
+                properties
             }
         ))
     }
 
-    fn read_connack(&mut self, header: Header) -> Result<Connack> {
+    fn read_connack(&mut self, header: Header, protocol: Protocol) -> Result<Connack> {
+        if protocol.level() >= 5 {
+            if header.len < 3 {
+                return Err(MQError::PayloadSizeIncorrect)
+            }
+            let flags = self.read_u8()?;
+            let reason_code = self.read_u8()?;
+            let properties = self.read_properties()?;
+            let server_keep_alive = properties.iter().filter_map(|property| {
+                match *property {
+                    Property::ServerKeepAlive(secs) => Some(secs),
+                    _ => None
+                }
+            }).next();
+            return Ok(Connack {
+                session_present: (flags & 0x01) == 1,
+                code: ConnectReturnCode::from_reason_code(reason_code)?,
+                reason_code: Some(reason_code),
+                reason: reason_string(properties),
+                server_keep_alive
+            })
+        }
         if header.len != 2 {
             return Err(MQError::PayloadSizeIncorrect)
         }
@@ -139,64 +222,157 @@ pub trait MqttRead: ReadBytesExt {
         let return_code = self.read_u8()?;
         Ok(Connack {
             session_present: (flags & 0x01) == 1,
-            code: ConnectReturnCode::from_u8(return_code)?
+            code: ConnectReturnCode::from_u8(return_code)?,
+            reason_code: None,
+            reason: None,
+            server_keep_alive: None
         })
     }
 
-    fn read_publish(&mut self, header: Header) -> Result<Box<Publish>> {
+    fn read_auth(&mut self, len: usize, protocol: Protocol) -> Result<Auth> {
+        if protocol.level() < 5 {
+            return Err(MQError::UnsupportedPacketType);
+        }
+        let reason_code = self.read_u8()?;
+        let properties = if len > 1 {
+            self.read_properties()?
+        } else {
+            Vec::new()
+        };
+        Ok(Auth {
+            reason_code,
+            properties
+        })
+    }
+
+    fn read_disconnect(&mut self, len: usize, protocol: Protocol) -> Result<Disconnect> {
+        if protocol.level() < 5 {
+            return Err(MQError::PayloadSizeIncorrect);
+        }
+        let reason_code = self.read_u8()?;
+        let properties = if len > 1 {
+            self.read_properties()?
+        } else {
+            Vec::new()
+        };
+        Ok(Disconnect {
+            reason_code,
+            reason: reason_string(properties)
+        })
+    }
+
+    fn read_publish(&mut self, header: Header, protocol: Protocol, buf: &mut Vec<u8>) -> Result<Box<Publish>> {
         let topic_name = self.read_mqtt_string();
+        let mut header_len = 2 + topic_name.as_ref().map_or(0, |topic| topic.len());
         // Packet identifier exists where QoS > 0
-        let pid = if header.qos().unwrap() != QoS::AtMostOnce {
+        // QoS bits 11 are reserved and rejected here
+        let qos = header.qos()?;
+        let pid = if qos != QoS::AtMostOnce {
+            header_len += 2;
             Some(PacketIdentifier(self.read_u16::<BigEndian>()?))
         } else {
             None
         };
-        let mut payload = Vec::new();
-        (self.read_to_end(&mut payload)?);
+        let properties = if protocol.level() >= 5 {
+            let properties = self.read_properties()?;
+            header_len += properties_block_len(&properties);
+            properties
+        } else {
+            Vec::new()
+        };
+        // sized up front, growing while reading reallocates a large payload
+        // several times. Only up to PAYLOAD_RESERVE though, past that the
+        // payload grows with the bytes that actually arrive, a forged
+        // remaining length mustn't allocate hundreds of megabytes.
+        buf.clear();
+        buf.reserve_exact(cmp::min(header.len.saturating_sub(header_len), PAYLOAD_RESERVE));
+        (self.read_to_end(buf)?);
+        let payload = mem::take(buf);
 
         Ok(Box::new(
             Publish {
                 dup: header.dup(),
-                qos: (header.qos()?),
+                qos,
                 retain: header.retain(),
                 topic_name: (topic_name)?,
                 pid: pid,
+                properties,
                 payload: Arc::new(payload)
             }
         ))
     }
 
-    fn read_subscribe(&mut self, header: Header) -> Result<Box<Subscribe>> {
+    fn read_subscribe(&mut self, header: Header, protocol: Protocol) -> Result<Box<Subscribe>> {
         let pid = self.read_u16::<BigEndian>()?;
-        let mut remaining_bytes = header.len - 2;
+        let mut remaining_bytes = shrink(header.len, 2)?;
+        let properties = if protocol.level() >= 5 {
+            let properties = self.read_properties()?;
+            remaining_bytes = shrink(remaining_bytes, properties_block_len(&properties))?;
+            properties
+        } else {
+            Vec::new()
+        };
+        // at least one topic is required
+        if remaining_bytes == 0 {
+            return Err(MQError::IncorrectPacketFormat);
+        }
         let mut topics = Vec::with_capacity(1);
 
         while remaining_bytes > 0 {
             let topic_filter = self.read_mqtt_string()?;
-            let requested_qod = self.read_u8()?;
-            remaining_bytes -= topic_filter.len() + 3;
-            topics.push(SubscribeTopic { topic_path: topic_filter, qos: (QoS::from_u8(requested_qod)?) });
+            let options = self.read_u8()?;
+            remaining_bytes = shrink(remaining_bytes, topic_filter.len() + 3)?;
+            // MQTT 5 packs the subscription options next to the QoS, the
+            // top two bits are reserved
+            let (qos, no_local, retain_as_published, retain_handling) = if protocol.level() >= 5 {
+                if options & 0xC0 != 0 {
+                    return Err(MQError::IncorrectPacketFormat);
+                }
+                (options & 0x03, options & 0x04 != 0, options & 0x08 != 0, RetainHandling::from_u8((options >> 4) & 0x03)?)
+            } else {
+                (options, false, false, RetainHandling::SendAtSubscribe)
+            };
+            topics.push(SubscribeTopic {
+                topic_path: topic_filter,
+                qos: QoS::from_u8(qos)?,
+                no_local,
+                retain_as_published,
+                retain_handling
+            });
         };
 
         Ok(Box::new(Subscribe {
             pid: PacketIdentifier(pid),
-            topics: topics
+            topics,
+            properties
         }))
     }
 
-    fn read_suback(&mut self, header: Header) -> Result<Box<Suback>> {
+    fn read_suback(&mut self, header: Header, protocol: Protocol) -> Result<Box<Suback>> {
         let pid = self.read_u16::<BigEndian>()?;
-        let mut remaining_bytes = header.len - 2;
+        let mut remaining_bytes = shrink(header.len, 2)?;
+        if protocol.level() >= 5 {
+            remaining_bytes = shrink(remaining_bytes, properties_block_len(&self.read_properties()?))?;
+        }
         let mut return_codes = Vec::with_capacity(remaining_bytes);
 
         while remaining_bytes > 0 {
             let return_code = self.read_u8()?;
             if return_code >> 7 == 1 {
-                return_codes.push(SubscribeReturnCodes::Failure)
+                // unknown reasons fall back to the plain failure
+                let reason = if protocol.level() >= 5 {
+                    SubscribeReasonCode::from_u8(return_code)
+                } else {
+                    None
+                };
+                return_codes.push(match reason {
+                    Some(reason) => SubscribeReturnCodes::Refused(reason),
+                    None => SubscribeReturnCodes::Failure
+                })
             } else {
                 return_codes.push(SubscribeReturnCodes::Success(QoS::from_u8(return_code & 0x3)?));
             }
-            remaining_bytes -= 1
+            remaining_bytes -= 1;
         };
 
         Ok(Box::new(Suback {
@@ -205,14 +381,21 @@ pub trait MqttRead: ReadBytesExt {
         }))
     }
 
-    fn read_unsubscribe(&mut self, header: Header) -> Result<Box<Unsubscribe>> {
+    fn read_unsubscribe(&mut self, header: Header, protocol: Protocol) -> Result<Box<Unsubscribe>> {
         let pid = self.read_u16::<BigEndian>()?;
-        let mut remaining_bytes = header.len - 2;
+        let mut remaining_bytes = shrink(header.len, 2)?;
+        if protocol.level() >= 5 {
+            remaining_bytes = shrink(remaining_bytes, properties_block_len(&self.read_properties()?))?;
+        }
+        // at least one topic is required
+        if remaining_bytes == 0 {
+            return Err(MQError::IncorrectPacketFormat);
+        }
         let mut topics = Vec::with_capacity(1);
 
         while remaining_bytes > 0 {
             let topic_filter = self.read_mqtt_string()?;
-            remaining_bytes -= topic_filter.len() + 2;
+            remaining_bytes = shrink(remaining_bytes, topic_filter.len() + 2)?;
             topics.push(topic_filter);
         };
 
@@ -222,6 +405,59 @@ pub trait MqttRead: ReadBytesExt {
         }))
     }
 
+    fn read_properties(&mut self) -> Result<Vec<Property>> {
+        let mut remaining_bytes = self.read_remaining_length()?;
+        let mut properties = Vec::new();
+
+        while remaining_bytes > 0 {
+            let property = self.read_property()?;
+            if property.encoded_len() > remaining_bytes {
+                return Err(MQError::IncorrectPacketFormat);
+            }
+            remaining_bytes -= property.encoded_len();
+            properties.push(property);
+        }
+
+        Ok(properties)
+    }
+
+    fn read_property(&mut self) -> Result<Property> {
+        let property = match self.read_u8()? {
+            0x01 => Property::PayloadFormatIndicator(self.read_u8()?),
+            0x02 => Property::MessageExpiryInterval(self.read_u32::<BigEndian>()?),
+            0x03 => Property::ContentType(self.read_mqtt_string()?),
+            0x08 => Property::ResponseTopic(self.read_mqtt_string()?),
+            0x09 => Property::CorrelationData(self.read_mqtt_bytes()?),
+            0x0B => Property::SubscriptionIdentifier(self.read_remaining_length()? as u32),
+            0x11 => Property::SessionExpiryInterval(self.read_u32::<BigEndian>()?),
+            0x12 => Property::AssignedClientIdentifier(self.read_mqtt_string()?),
+            0x13 => Property::ServerKeepAlive(self.read_u16::<BigEndian>()?),
+            0x15 => Property::AuthenticationMethod(self.read_mqtt_string()?),
+            0x16 => Property::AuthenticationData(self.read_mqtt_bytes()?),
+            0x17 => Property::RequestProblemInformation(self.read_u8()?),
+            0x18 => Property::WillDelayInterval(self.read_u32::<BigEndian>()?),
+            0x19 => Property::RequestResponseInformation(self.read_u8()?),
+            0x1A => Property::ResponseInformation(self.read_mqtt_string()?),
+            0x1C => Property::ServerReference(self.read_mqtt_string()?),
+            0x1F => Property::ReasonString(self.read_mqtt_string()?),
+            0x21 => Property::ReceiveMaximum(self.read_u16::<BigEndian>()?),
+            0x22 => Property::TopicAliasMaximum(self.read_u16::<BigEndian>()?),
+            0x23 => Property::TopicAlias(self.read_u16::<BigEndian>()?),
+            0x24 => Property::MaximumQoS(self.read_u8()?),
+            0x25 => Property::RetainAvailable(self.read_u8()?),
+            0x26 => {
+                let key = self.read_mqtt_string()?;
+                Property::UserProperty(key, self.read_mqtt_string()?)
+            },
+            0x27 => Property::MaximumPacketSize(self.read_u32::<BigEndian>()?),
+            0x28 => Property::WildcardSubscriptionAvailable(self.read_u8()?),
+            0x29 => Property::SubscriptionIdentifierAvailable(self.read_u8()?),
+            0x2A => Property::SharedSubscriptionAvailable(self.read_u8()?),
+            _ => return Err(MQError::UnsupportedProperty)
+        };
+        Ok(property)
+    }
+
     fn read_payload(&mut self, len: usize) -> Result<Box<Vec<u8>>> {
         let mut payload = Box::new(Vec::with_capacity(len));
         self.take(len as u64).read_to_end(&mut *payload)?;
@@ -229,10 +465,15 @@ pub trait MqttRead: ReadBytesExt {
     }
 
     fn read_mqtt_string(&mut self) -> Result<String> {
+        Ok(String::from_utf8(self.read_mqtt_bytes()?)?)
+    }
+
+    fn read_mqtt_bytes(&mut self) -> Result<Vec<u8>> {
         let len = (self.read_u16::<BigEndian>()?) as usize;
-        let mut data = Vec::with_capacity(len);
-        self.take(len as u64).read_to_end(&mut data)?;
-        Ok(String::from_utf8(data)?)
+        // a string cut short by the end of the packet is malformed
+        let mut data = vec![0; len];
+        self.read_exact(&mut data)?;
+        Ok(data)
     }
 
     fn read_remaining_length(&mut self) -> Result<usize> {
@@ -242,7 +483,13 @@ pub trait MqttRead: ReadBytesExt {
 
 
         while !done {
-            let byte = (self.read_u8()?) as usize;
+            // the packet type byte was read already
+            let byte = self.read_u8().map_err(|err| {
+                match MQError::from(err) {
+                    MQError::UnexpectedEof => MQError::IncompletePacket,
+                    err => err
+                }
+            })? as usize;
             len += (byte & 0x7F) * mult;
             mult *= 0x80;
             if mult > MULTIPLIER {
@@ -255,21 +502,60 @@ pub trait MqttRead: ReadBytesExt {
     }
 }
 
+// Most a PUBLISH payload is presized for before its bytes arrive
+const PAYLOAD_RESERVE: usize = 64 * 1024;
+
+fn reason_string(properties: Vec<Property>) -> Option<String> {
+    properties.into_iter().filter_map(|property| {
+        match property {
+            Property::ReasonString(reason) => Some(reason),
+            _ => None
+        }
+    }).next()
+}
+
+// What's left of the remaining length once `by` bytes are read, a packet
+// whose fields don't fit in it is malformed
+fn shrink(remaining: usize, by: usize) -> Result<usize> {
+    remaining.checked_sub(by).ok_or(MQError::IncorrectPacketFormat)
+}
+
+// Keeps a copy of everything read through it
+struct Recorder<'a, R: 'a + ?Sized> {
+    inner: &'a mut R,
+    bytes: Vec<u8>
+}
+
+impl<'a, R: Read + ?Sized> Read for Recorder<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.bytes.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+}
+
+impl<'a, R: Read + ?Sized> MqttRead for Recorder<'a, R> {}
+
+#[cfg(feature = "std-net")]
 impl MqttRead for TcpStream {}
 impl MqttRead for Cursor<Vec<u8>> {}
 impl<T: Read> MqttRead for Take<T> where T: Read {}
+#[cfg(feature = "std-net")]
 impl<T: Read> MqttRead for BufReader<T> {}
 
 #[cfg(test)]
 mod test {
     use std::io::Cursor;
     use std::sync::Arc;
-    use super::MqttRead;
+    use super::{shrink, MqttRead, PAYLOAD_RESERVE};
     use {Protocol, LastWill, QoS, PacketIdentifier, ConnectReturnCode, SubscribeTopic, SubscribeReturnCodes};
+    use {MQError, MqttWrite, Property, RetainHandling, SubscribeReasonCode};
     use mqtt::{
         Packet,
         Connect,
         Connack,
+        Disconnect,
+        Auth,
         Publish,
         Subscribe,
         Suback,
@@ -300,12 +586,14 @@ mod test {
             clean_session: true,
             last_will: Some(LastWill {
                 topic: "/a".to_owned(),
-                message: "offline".to_owned(),
+                message: b"offline".to_vec(),
                 retain: false,
-                qos: QoS::AtLeastOnce
+                qos: QoS::AtLeastOnce,
+                properties: Vec::new()
             }),
             username: Some("rust".to_owned()),
-            password: Some("mq".to_owned())
+            password: Some("mq".to_owned()),
+            properties: Vec::new()
         })));
     }
 
@@ -329,7 +617,8 @@ mod test {
             clean_session: false,
             last_will: None,
             username: None,
-            password: None
+            password: None,
+            properties: Vec::new()
         })));
     }
 
@@ -340,10 +629,39 @@ mod test {
 
         assert_eq!(packet, Packet::Connack(Connack {
             session_present: true,
-            code: ConnectReturnCode::Accepted
+            code: ConnectReturnCode::Accepted,
+            reason_code: None,
+            reason: None,
+            server_keep_alive: None
         }));
     }
 
+    #[test]
+    fn read_packet_burst_test() {
+        // SUBACK and two retained publishes in one read
+        let mut stream = Cursor::new(vec![
+            0b10010000, 0x03, 0x00, 0x01, 0x00,
+            0b00110001, 0x04, 0x00, 0x01, 'a' as u8, 0x01,
+            0b00110001, 0x04, 0x00, 0x01, 'b' as u8, 0x02
+        ]);
+        match stream.read_packet().unwrap() {
+            Packet::Suback(suback) => assert_eq!(suback.pid, PacketIdentifier(1)),
+            packet => panic!("expected SUBACK, got {:?}", packet)
+        }
+        for topic in ["a", "b"].iter() {
+            match stream.read_packet().unwrap() {
+                Packet::Publish(publish) => assert_eq!(publish.topic_name, *topic),
+                packet => panic!("expected PUBLISH, got {:?}", packet)
+            }
+        }
+        assert!(stream.read_packet().is_err());
+
+        // MQTT 5 CONNACK with a byte past its properties, then PINGRESP
+        let mut stream = Cursor::new(vec![0b00100000, 0x04, 0x00, 0x00, 0x00, 0xFF, 0b11010000, 0x00]);
+        assert!(stream.read_packet_with(Protocol::MQTT(5)).is_ok());
+        assert_eq!(stream.read_packet_with(Protocol::MQTT(5)).unwrap(), Packet::Pingresp);
+    }
+
     #[test]
     fn read_packet_publish_qos1_test() {
         let mut stream = Cursor::new(vec![
@@ -361,6 +679,7 @@ mod test {
             retain: false,
             topic_name: "a/b".to_owned(),
             pid: Some(PacketIdentifier(10)),
+            properties: Vec::new(),
             payload: Arc::new(vec![0xF1, 0xF2, 0xF3, 0xF4])
         })));
     }
@@ -381,10 +700,157 @@ mod test {
             retain: false,
             topic_name: "a/b".to_owned(),
             pid: None,
+            properties: Vec::new(),
             payload: Arc::new(vec![0x01, 0x02])
         })));
     }
 
+    #[test]
+    fn read_packet_publish_empty_payload_test() {
+        // clears the retained message of 'a'
+        let mut stream = Cursor::new(vec![
+            0b00110001, 3,
+            0x00, 0x01, 'a' as u8, // topic name = 'a'
+            0b00110000, 3,
+            0x00, 0x01, 'b' as u8 // topic name = 'b'
+        ]);
+
+        assert_eq!(stream.read_packet().unwrap(), Packet::Publish(Box::new(Publish {
+            dup: false,
+            qos: QoS::AtMostOnce,
+            retain: true,
+            topic_name: "a".to_owned(),
+            pid: None,
+            properties: Vec::new(),
+            payload: Arc::new(Vec::new())
+        })));
+        // the empty payload didn't swallow the next packet
+        match stream.read_packet().unwrap() {
+            Packet::Publish(publish) => {
+                assert_eq!(publish.topic_name, "b");
+                assert!(publish.payload.is_empty());
+            }
+            packet => panic!("expected PUBLISH, got {:?}", packet)
+        }
+    }
+
+    #[test]
+    fn read_packet_publish_v5_test() {
+        let mut stream = Cursor::new(vec![
+            0b00110010, 14,
+            0x00, 0x03, 'a' as u8, '/' as u8, 'b' as u8, // topic name = 'a/b'
+            0x00, 0x0a, // pid = 10
+            0x05, 0x02, 0x00, 0x00, 0x00, 0x3c, // message expiry = 60 sec
+            0x01
+        ]);
+
+        let packet = stream.read_packet_with(Protocol::MQTT(5)).unwrap();
+
+        assert_eq!(packet, Packet::Publish(Box::new(Publish {
+            dup: false,
+            qos: QoS::AtLeastOnce,
+            retain: false,
+            topic_name: "a/b".to_owned(),
+            pid: Some(PacketIdentifier(10)),
+            properties: vec![Property::MessageExpiryInterval(60)],
+            payload: Arc::new(vec![0x01])
+        })));
+    }
+
+    #[test]
+    fn read_packet_into_test() {
+        let mut stream = Cursor::new(vec![
+            0b00110010, 11,
+            0x00, 0x03, 'a' as u8, '/' as u8, 'b' as u8, // topic name = 'a/b'
+            0x00, 0x0a, // pid = 10
+            0xF1, 0xF2, 0xF3, 0xF4,
+            0b01000000, 0x02, 0x00, 0x0A,
+            0b00110000, 0x05, 0x00, 0x01, 'a' as u8, 0x01, 0x02
+        ]);
+        let mut buf = Vec::with_capacity(64);
+        let ptr = buf.as_ptr();
+
+        match stream.read_packet_into(Protocol::MQTT(4), &mut buf).unwrap() {
+            Packet::Publish(publish) => {
+                assert_eq!(*publish.payload, vec![0xF1, 0xF2, 0xF3, 0xF4]);
+                assert_eq!(publish.payload.as_ptr(), ptr);
+            }
+            packet => panic!("expected PUBLISH, got {:?}", packet)
+        }
+        assert_eq!(buf.capacity(), 0);
+
+        buf.reserve(16);
+        let ptr = buf.as_ptr();
+        assert_eq!(stream.read_packet_into(Protocol::MQTT(4), &mut buf).unwrap(),
+                   Packet::Puback(PacketIdentifier(10)));
+        assert_eq!(buf.as_ptr(), ptr);
+
+        // without a buffer the payload is sized exactly
+        match stream.read_packet_into(Protocol::MQTT(4), &mut Vec::new()).unwrap() {
+            Packet::Publish(publish) => assert_eq!(publish.payload.capacity(), 2),
+            packet => panic!("expected PUBLISH, got {:?}", packet)
+        }
+    }
+
+    #[test]
+    fn read_packet_publish_forged_length_test() {
+        // a remaining length of 256 MiB over a handful of bytes
+        let mut stream = Cursor::new(vec![
+            0b00110000, 0xFF, 0xFF, 0xFF, 0x7F,
+            0x00, 0x03, 'a' as u8, '/' as u8, 'b' as u8,
+            0x01, 0x02
+        ]);
+        match stream.read_packet().unwrap() {
+            Packet::Publish(publish) => {
+                assert_eq!(*publish.payload, vec![0x01, 0x02]);
+                assert!(publish.payload.capacity() <= PAYLOAD_RESERVE);
+            }
+            packet => panic!("expected PUBLISH, got {:?}", packet)
+        }
+    }
+
+    #[test]
+    fn read_packet_v5_ack_test() {
+        // PUBACK with a reason code and empty properties, CONNACK with properties
+        let mut stream = Cursor::new(vec![
+            0b01000000, 0x04, 0x00, 0x0A, 0x10, 0x00,
+            0b00100000, 0x06, 0x00, 0x00, 0x03, 0x21, 0x00, 0x0A
+        ]);
+
+        assert_eq!(stream.read_packet_with(Protocol::MQTT(5)).unwrap(),
+                   Packet::Puback(PacketIdentifier(10)));
+        assert_eq!(stream.read_packet_with(Protocol::MQTT(5)).unwrap(), Packet::Connack(Connack {
+            session_present: false,
+            code: ConnectReturnCode::Accepted,
+            reason_code: Some(0x00),
+            reason: None,
+            server_keep_alive: None
+        }));
+    }
+
+    #[test]
+    fn read_packet_reserved_qos_test() {
+        fn assert_reserved_qos(bytes: Vec<u8>) {
+            match Cursor::new(bytes).read_packet() {
+                Err(MQError::UnsupportedQualityOfService) => (),
+                other => panic!("expected UnsupportedQualityOfService, got {:?}", other)
+            }
+        }
+        // CONNECT with will QoS 3
+        assert_reserved_qos(vec![0x10, 18,
+            0x00, 0x04, 'M' as u8, 'Q' as u8, 'T' as u8, 'T' as u8, 0x04,
+            0b00011110, 0x00, 0x0a,
+            0x00, 0x01, 'c' as u8,
+            0x00, 0x01, 'a' as u8,
+            0x00, 0x00]);
+        // PUBLISH with QoS bits 11
+        assert_reserved_qos(vec![0b00110110, 5, 0x00, 0x01, 'a' as u8, 0x00, 0x01]);
+        // SUBSCRIBE requesting QoS 3
+        assert_reserved_qos(vec![0b10000010, 6, 0x00, 0x01, 0x00, 0x01, 'a' as u8, 0x03]);
+        // SUBACK granting QoS 3
+        assert_reserved_qos(vec![0b10010000, 3, 0x00, 0x01, 0x03]);
+    }
+
     #[test]
     fn read_packet_puback_test() {
         let mut stream = Cursor::new(vec![0b01000000, 0x02, 0x00, 0x0A]);
@@ -411,10 +877,11 @@ mod test {
         assert_eq!(packet, Packet::Subscribe(Box::new(Subscribe {
             pid: PacketIdentifier(260),
             topics: vec![
-                SubscribeTopic { topic_path: "a/+".to_owned(), qos: QoS::AtMostOnce },
-                SubscribeTopic { topic_path: "#".to_owned(), qos: QoS::AtLeastOnce },
-                SubscribeTopic { topic_path: "a/b/c".to_owned(), qos: QoS::ExactlyOnce }
-            ]
+                SubscribeTopic { topic_path: "a/+".to_owned(), qos: QoS::AtMostOnce, no_local: false, retain_as_published: false, retain_handling: RetainHandling::SendAtSubscribe },
+                SubscribeTopic { topic_path: "#".to_owned(), qos: QoS::AtLeastOnce, no_local: false, retain_as_published: false, retain_handling: RetainHandling::SendAtSubscribe },
+                SubscribeTopic { topic_path: "a/b/c".to_owned(), qos: QoS::ExactlyOnce, no_local: false, retain_as_published: false, retain_handling: RetainHandling::SendAtSubscribe }
+            ],
+            properties: Vec::new()
         })));
     }
 
@@ -440,6 +907,70 @@ mod test {
         })));
     }
 
+    #[test]
+    fn read_packet_eof_test() {
+        match Cursor::new(Vec::new()).read_packet() {
+            Err(MQError::UnexpectedEof) => (),
+            other => panic!("expected UnexpectedEof, got {:?}", other)
+        }
+        // after the packet type
+        match Cursor::new(vec![0x30]).read_packet() {
+            Err(MQError::IncompletePacket) => (),
+            other => panic!("expected IncompletePacket, got {:?}", other)
+        }
+        // between the remaining length bytes
+        match Cursor::new(vec![0x30, 0x86]).read_packet() {
+            Err(MQError::IncompletePacket) => (),
+            other => panic!("expected IncompletePacket, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn read_packet_no_topics_test() {
+        for packet in [vec![0x82, 0x02, 0x00, 0x0F], vec![0xA2, 0x02, 0x00, 0x0F]] {
+            match Cursor::new(packet).read_packet() {
+                Err(MQError::IncorrectPacketFormat) => (),
+                other => panic!("expected IncorrectPacketFormat, got {:?}", other)
+            }
+        }
+    }
+
+    #[test]
+    fn read_packet_overlong_fields_test() {
+        for (packet, protocol) in [
+            // a remaining length too short for the packet identifier
+            (vec![0x82, 0x01, 0x00, 0x0F], Protocol::MQTT(4)),
+            (vec![0x90, 0x01, 0x00, 0x0F], Protocol::MQTT(4)),
+            (vec![0xA2, 0x00, 0x00, 0x0F], Protocol::MQTT(4)),
+            // a topic longer than the packet
+            (vec![0x82, 0x04, 0x00, 0x0F, 0x00, 0x01, b'a', 0x00], Protocol::MQTT(4)),
+            (vec![0xA2, 0x04, 0x00, 0x0F, 0x00, 0x01, b'a'], Protocol::MQTT(4)),
+            // properties longer than the packet
+            (vec![0x90, 0x03, 0x00, 0x0F, 0x03, 0x0B, 0x01, 0x00], Protocol::MQTT(5))] {
+            assert!(Cursor::new(packet).read_packet_with(protocol).is_err());
+        }
+        assert_eq!(shrink(3, 2).unwrap(), 1);
+        match shrink(2, 3) {
+            Err(MQError::IncorrectPacketFormat) => (),
+            other => panic!("expected IncorrectPacketFormat, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn read_packet_raw_test() {
+        // the remaining length takes two bytes where one would do
+        let publish = vec![0b00110000, 0x86, 0x00, 0x00, 0x03, 'a' as u8, '/' as u8, 'b' as u8, 0x01];
+        let mut stream = Cursor::new([&publish[..], &[0xD0, 0x00]].concat());
+
+        let (packet, bytes) = stream.read_packet_raw().unwrap();
+        match packet {
+            Packet::Publish(ref publish) => assert_eq!(publish.topic_name, "a/b"),
+            _ => panic!("expected PUBLISH, got {:?}", packet)
+        }
+        assert_eq!(bytes, publish);
+        assert_eq!(stream.read_packet_raw().unwrap(), (Packet::Pingresp, vec![0xD0, 0x00]));
+    }
+
     #[test]
     fn read_packet_suback_test() {
         let mut stream = Cursor::new(vec![
@@ -455,4 +986,171 @@ mod test {
             return_codes: vec![SubscribeReturnCodes::Success(QoS::AtLeastOnce), SubscribeReturnCodes::Failure]
         })));
     }
+
+    #[test]
+    fn read_packet_suback_v5_test() {
+        let bytes = vec![
+            0x90, 8,
+            0x00, 0x0F, // pid = 15
+            0x00, // properties
+            0x02, 0x80, 0x87, 0x97, 0xFF
+        ];
+
+        let mut stream = Cursor::new(bytes.clone());
+        let packet = stream.read_packet_with(Protocol::MQTT(5)).unwrap();
+        assert_eq!(packet, Packet::Suback(Box::new(Suback {
+            pid: PacketIdentifier(15),
+            return_codes: vec![
+                SubscribeReturnCodes::Success(QoS::ExactlyOnce),
+                SubscribeReturnCodes::Failure,
+                SubscribeReturnCodes::Refused(SubscribeReasonCode::NotAuthorized),
+                SubscribeReturnCodes::Refused(SubscribeReasonCode::QuotaExceeded),
+                SubscribeReturnCodes::Failure
+            ]
+        })));
+
+        // and back, older protocols only know 0x80
+        let mut stream = Cursor::new(Vec::new());
+        stream.write_packet_with(&packet, Protocol::MQTT(5)).unwrap();
+        assert_eq!(&stream.get_ref()[5..], &[0x02, 0x80, 0x87, 0x97, 0x80]);
+        let mut stream = Cursor::new(Vec::new());
+        stream.write_packet(&packet).unwrap();
+        assert_eq!(&stream.get_ref()[4..], &[0x02, 0x80, 0x80, 0x80, 0x80]);
+    }
+
+    #[test]
+    fn read_packet_subscription_identifier_test() {
+        let subscribe = Packet::Subscribe(Box::new(Subscribe {
+            pid: PacketIdentifier(1),
+            topics: vec![SubscribeTopic { topic_path: "a".to_owned(), qos: QoS::AtMostOnce, no_local: false, retain_as_published: false, retain_handling: RetainHandling::SendAtSubscribe }],
+            properties: vec![Property::SubscriptionIdentifier(200)]
+        }));
+        let mut stream = Cursor::new(Vec::new());
+        stream.write_packet_with(&subscribe, Protocol::MQTT(5)).unwrap();
+        assert_eq!(stream.get_ref().clone(), vec![0x82, 10, 0x00, 0x01, 0x03, 0x0B, 0xC8, 0x01, 0x00, 0x01, 'a' as u8, 0x00]);
+        stream.set_position(0);
+        assert_eq!(stream.read_packet_with(Protocol::MQTT(5)).unwrap(), subscribe);
+    }
+
+    #[test]
+    fn read_packet_no_local_test() {
+        let subscribe = Packet::Subscribe(Box::new(Subscribe {
+            pid: PacketIdentifier(1),
+            topics: vec![SubscribeTopic { topic_path: "a".to_owned(), qos: QoS::AtLeastOnce, no_local: true, retain_as_published: false, retain_handling: RetainHandling::SendAtSubscribe }],
+            properties: Vec::new()
+        }));
+        let mut stream = Cursor::new(Vec::new());
+        stream.write_packet_with(&subscribe, Protocol::MQTT(5)).unwrap();
+        assert_eq!(stream.get_ref()[stream.get_ref().len() - 1], 0x05);
+        stream.set_position(0);
+        assert_eq!(stream.read_packet_with(Protocol::MQTT(5)).unwrap(), subscribe);
+
+        // there's no No Local before MQTT 5
+        let mut stream = Cursor::new(Vec::new());
+        stream.write_packet(&subscribe).unwrap();
+        assert_eq!(stream.get_ref()[stream.get_ref().len() - 1], 0x01);
+    }
+
+    #[test]
+    fn read_packet_subscription_options_test() {
+        let subscribe = Packet::Subscribe(Box::new(Subscribe {
+            pid: PacketIdentifier(1),
+            topics: vec![SubscribeTopic {
+                topic_path: "a".to_owned(),
+                qos: QoS::ExactlyOnce,
+                no_local: false,
+                retain_as_published: true,
+                retain_handling: RetainHandling::DoNotSend
+            }],
+            properties: Vec::new()
+        }));
+        let mut stream = Cursor::new(Vec::new());
+        stream.write_packet_with(&subscribe, Protocol::MQTT(5)).unwrap();
+        assert_eq!(stream.get_ref()[stream.get_ref().len() - 1], 0x2A);
+        stream.set_position(0);
+        assert_eq!(stream.read_packet_with(Protocol::MQTT(5)).unwrap(), subscribe);
+
+        // reserved bits and Retain Handling 3 are protocol errors
+        for &options in [0x41, 0x81, 0x31].iter() {
+            let mut stream = Cursor::new(vec![0x82, 7, 0x00, 0x01, 0x00, 0x00, 0x01, 'a' as u8, options]);
+            match stream.read_packet_with(Protocol::MQTT(5)) {
+                Err(MQError::IncorrectPacketFormat) => (),
+                other => panic!("expected IncorrectPacketFormat for {:#x}, got {:?}", options, other)
+            }
+        }
+    }
+
+    #[test]
+    fn read_packet_server_keep_alive_test() {
+        let connack = Packet::Connack(Connack {
+            session_present: false,
+            code: ConnectReturnCode::Accepted,
+            reason_code: Some(0x00),
+            reason: None,
+            server_keep_alive: Some(20)
+        });
+        let mut stream = Cursor::new(Vec::new());
+        stream.write_packet_with(&connack, Protocol::MQTT(5)).unwrap();
+        assert_eq!(stream.get_ref().clone(), vec![0x20, 6, 0x00, 0x00, 3, 0x13, 0x00, 20]);
+        stream.set_position(0);
+        assert_eq!(stream.read_packet_with(Protocol::MQTT(5)).unwrap(), connack);
+    }
+
+    #[test]
+    fn read_packet_reason_string_test() {
+        let connack = Packet::Connack(Connack {
+            session_present: false,
+            code: ConnectReturnCode::NotAuthorized,
+            reason_code: Some(0x87),
+            reason: Some("banned".to_owned()),
+            server_keep_alive: None
+        });
+        let mut stream = Cursor::new(Vec::new());
+        stream.write_packet_with(&connack, Protocol::MQTT(5)).unwrap();
+        assert_eq!(stream.get_ref()[..5], [0x20, 12, 0x00, 0x87, 9]);
+        stream.set_position(0);
+        assert_eq!(stream.read_packet_with(Protocol::MQTT(5)).unwrap(), connack);
+
+        let disconnect = Packet::Disconnect(Disconnect {
+            reason_code: 0x8E,
+            reason: Some("taken over".to_owned())
+        });
+        let mut stream = Cursor::new(Vec::new());
+        stream.write_packet_with(&disconnect, Protocol::MQTT(5)).unwrap();
+        stream.set_position(0);
+        assert_eq!(stream.read_packet_with(Protocol::MQTT(5)).unwrap(), disconnect);
+
+        // older protocols send a bare DISCONNECT
+        let mut stream = Cursor::new(Vec::new());
+        stream.write_packet(&disconnect).unwrap();
+        assert_eq!(stream.get_ref().clone(), vec![0xe0, 0]);
+        stream.set_position(0);
+        assert_eq!(stream.read_packet().unwrap(), Packet::Disconnect(Disconnect::default()));
+    }
+
+    #[test]
+    fn read_packet_auth_test() {
+        let auth = Packet::Auth(Auth {
+            reason_code: 0x18,
+            properties: vec![Property::AuthenticationMethod("SCRAM-SHA-1".to_owned()),
+                             Property::AuthenticationData(vec![1, 2, 3])]
+        });
+        let mut stream = Cursor::new(Vec::new());
+        stream.write_packet_with(&auth, Protocol::MQTT(5)).unwrap();
+        assert_eq!(stream.get_ref()[..3], [0xf0, 22, 0x18]);
+        stream.set_position(0);
+        assert_eq!(stream.read_packet_with(Protocol::MQTT(5)).unwrap(), auth);
+
+        let mut stream = Cursor::new(vec![0xf0, 0x00]);
+        assert_eq!(stream.read_packet_with(Protocol::MQTT(5)).unwrap(), Packet::Auth(Auth::default()));
+
+        // there's no AUTH before MQTT 5
+        let mut stream = Cursor::new(Vec::new());
+        assert!(stream.write_packet(&auth).is_err());
+        let mut stream = Cursor::new(vec![0xf0, 0x00]);
+        match stream.read_packet() {
+            Err(MQError::UnsupportedPacketType) => (),
+            other => panic!("expected UnsupportedPacketType, got {:?}", other)
+        }
+    }
 }
@@ -2,6 +2,7 @@ use std::vec::IntoIter;
 use {MQError, Result};
 
 const TOPIC_PATH_DELIMITER: char = '/';
+const SHARED_PREFIX: &str = "$share/";
 
 use self::Topic::{
     Normal,
@@ -82,14 +83,34 @@ pub struct TopicPath {
     pub path: String,
     // Should be false for Topic Name
     pub wildcards: bool,
+    // Group of a `$share/<group>/<filter>` subscription
+    shared: Option<String>,
     topics: Vec<Topic>
 }
 
 impl TopicPath {
+    /// An owned copy of the path, `as_str` borrows it
     pub fn path(&self) -> String {
         self.path.clone()
     }
 
+    pub fn as_str(&self) -> &str {
+        &self.path
+    }
+
+    /// The share name of a `$share/<group>/<filter>` path
+    pub fn shared_group(&self) -> Option<&str> {
+        self.shared.as_deref()
+    }
+
+    /// The path without the `$share/<group>/` prefix
+    pub fn filter(&self) -> &str {
+        match self.shared {
+            Some(ref group) => &self.path[SHARED_PREFIX.len() + group.len() + 1..],
+            None => &self.path
+        }
+    }
+
     pub fn get(&self, index: usize) -> Option<&Topic> {
         self.topics.get(index)
     }
@@ -102,6 +123,55 @@ impl TopicPath {
         self.topics.len()
     }
 
+    /// Iterates the levels of the filter as written. Empty levels are kept,
+    /// so `a//b` has three levels and both `/a` and `a/` have two.
+    pub fn levels(&self) -> impl Iterator<Item=&str> {
+        self.filter().split(TOPIC_PATH_DELIMITER)
+    }
+
+    /// Whether the topic name `name` matches this filter. Wildcards at the
+    /// first level don't match names starting with `$`.
+    pub fn is_match(&self, name: &TopicPath) -> bool {
+        match (self.topics.first(), name.topics.first()) {
+            (Some(&SingleWildcard), Some(&System(_))) |
+            (Some(&MultiWildcard), Some(&System(_))) => return false,
+            _ => ()
+        }
+        let mut levels = name.topics.iter();
+        for topic in self.topics.iter() {
+            match (topic, levels.next()) {
+                (&MultiWildcard, _) => return true,
+                (&SingleWildcard, Some(_)) => (),
+                (filter, Some(level)) if filter == level => (),
+                _ => return false
+            }
+        }
+        levels.next().is_none()
+    }
+
+    /// Whether every name the filter `filter` matches is matched by this
+    /// filter too: `#` covers any rest, `+` one level other than `#` and
+    /// anything else only itself. So `a/#` covers `a/+` and `a/+` covers
+    /// `a/b`, but `a/+` doesn't cover `a/#`.
+    pub fn covers(&self, filter: &TopicPath) -> bool {
+        match (self.topics.first(), filter.topics.first()) {
+            (Some(&SingleWildcard), Some(&System(_))) |
+            (Some(&MultiWildcard), Some(&System(_))) => return false,
+            _ => ()
+        }
+        let mut levels = filter.topics.iter();
+        for topic in self.topics.iter() {
+            match (topic, levels.next()) {
+                (&MultiWildcard, _) => return true,
+                (&SingleWildcard, Some(&MultiWildcard)) => return false,
+                (&SingleWildcard, Some(_)) => (),
+                (topic, Some(level)) if topic == level => (),
+                _ => return false
+            }
+        }
+        levels.next().is_none()
+    }
+
     pub fn is_final(&self, index: usize) -> bool {
         let len = self.topics.len();
         len == 0 || len-1 == index
@@ -115,8 +185,29 @@ impl TopicPath {
     }
 
     pub fn from_str<T: AsRef<str>>(path: T) -> Result<TopicPath> {
+        // strings are prefixed with a u16 length
+        if path.as_ref().len() > u16::MAX as usize {
+            return Err(MQError::PayloadTooLong);
+        }
+        let (shared, filter) = if path.as_ref().starts_with(SHARED_PREFIX) {
+            let rest = &path.as_ref()[SHARED_PREFIX.len()..];
+            match rest.find(TOPIC_PATH_DELIMITER) {
+                Some(pos) => {
+                    let (group, filter) = (&rest[..pos], &rest[pos+1..]);
+                    if group.is_empty() || group.contains("+") || group.contains("#") ||
+                       filter.is_empty() {
+                        return Err(MQError::InvalidTopicPath);
+                    }
+                    (Some(String::from(group)), filter)
+                }
+                None => return Err(MQError::InvalidTopicPath)
+            }
+        } else {
+            (None, path.as_ref())
+        };
+
         let mut valid = true;
-        let topics: Vec<Topic> = path.as_ref().split(TOPIC_PATH_DELIMITER).map( |topic| {
+        let topics: Vec<Topic> = filter.split(TOPIC_PATH_DELIMITER).map( |topic| {
             match topic {
                 "+" => Topic::SingleWildcard,
                 "#" => Topic::MultiWildcard,
@@ -147,6 +238,7 @@ impl TopicPath {
 
         Ok(TopicPath {
             path: String::from(path.as_ref()),
+            shared,
             topics: topics,
             wildcards: wildcards
         })
@@ -179,15 +271,20 @@ impl Into<String> for TopicPath {
     }
 }
 
+/// Topic arguments: `&str`, `String`, `&String` and `TopicPath`
 pub trait ToTopicPath {
     fn to_topic_path(&self) -> Result<TopicPath>;
 
     fn to_topic_name(&self) -> Result<TopicPath> {
         let topic_name = self.to_topic_path()?;
-        match topic_name.wildcards {
-            false => Ok(topic_name),
-            true => Err(MQError::TopicNameMustNotContainWildcard)
+        if topic_name.wildcards {
+            return Err(MQError::TopicNameMustNotContainWildcard);
+        }
+        // shared subscriptions exist only on the subscribe side
+        if topic_name.shared.is_some() {
+            return Err(MQError::TopicNameMustNotBeShared);
         }
+        Ok(topic_name)
     }
 }
 
@@ -203,6 +300,18 @@ impl ToTopicPath for String {
     }
 }
 
+impl ToTopicPath for &String {
+    fn to_topic_path(&self) -> Result<TopicPath> {
+        TopicPath::from_str(self.as_str())
+    }
+}
+
+impl ToTopicPath for &TopicPath {
+    fn to_topic_path(&self) -> Result<TopicPath> {
+        Ok((*self).clone())
+    }
+}
+
 impl<'a> ToTopicPath for &'a str {
     fn to_topic_path(&self) -> Result<TopicPath> {
         TopicPath::from_str(*self)
@@ -211,7 +320,8 @@ impl<'a> ToTopicPath for &'a str {
 
 #[cfg(test)]
 mod test {
-    use super::{TopicPath, Topic};
+    use super::{TopicPath, Topic, ToTopicPath};
+    use MQError;
 
     #[test]
     fn topic_path_test() {
@@ -241,4 +351,97 @@ mod test {
         assert!(TopicPath::from_str("wro#ng").is_err());
         assert!(TopicPath::from_str("w/r/o/n/g+").is_err());
     }
+
+    #[test]
+    fn topic_levels_test() {
+        let topic = TopicPath::from("a//b");
+        assert_eq!(topic.levels().collect::<Vec<&str>>(), vec!["a", "", "b"]);
+        let topic = TopicPath::from("/a");
+        assert_eq!(topic.levels().collect::<Vec<&str>>(), vec!["", "a"]);
+        let topic = TopicPath::from("a/");
+        assert_eq!(topic.levels().collect::<Vec<&str>>(), vec!["a", ""]);
+        let topic = TopicPath::from("a/+/#");
+        assert_eq!(topic.levels().count(), topic.len());
+    }
+
+    #[test]
+    fn is_match_test() {
+        let filter = TopicPath::from("a/+/c");
+        assert!(filter.is_match(&TopicPath::from("a/b/c")));
+        assert!(filter.is_match(&TopicPath::from("a//c")));
+        assert!(!filter.is_match(&TopicPath::from("a/b")));
+        assert!(!filter.is_match(&TopicPath::from("a/b/c/d")));
+
+        let filter = TopicPath::from("a/#");
+        assert!(filter.is_match(&TopicPath::from("a")));
+        assert!(filter.is_match(&TopicPath::from("a/b/c")));
+        assert!(!filter.is_match(&TopicPath::from("b/a")));
+
+        assert!(!TopicPath::from("#").is_match(&TopicPath::from("$SYS/a")));
+        assert!(!TopicPath::from("+/a").is_match(&TopicPath::from("$SYS/a")));
+        assert!(TopicPath::from("$SYS/#").is_match(&TopicPath::from("$SYS/a")));
+    }
+
+    #[test]
+    fn covers_test() {
+        let filter = TopicPath::from("a/+");
+        assert!(filter.covers(&TopicPath::from("a/b")));
+        assert!(filter.covers(&TopicPath::from("a/+")));
+        assert!(!filter.covers(&TopicPath::from("a/#")));
+        assert!(!filter.covers(&TopicPath::from("a/+/c")));
+        assert!(!filter.covers(&TopicPath::from("a")));
+
+        let filter = TopicPath::from("a/#");
+        assert!(filter.covers(&TopicPath::from("a")));
+        assert!(filter.covers(&TopicPath::from("a/+/c")));
+        assert!(filter.covers(&TopicPath::from("a/#")));
+        assert!(!filter.covers(&TopicPath::from("b/#")));
+
+        assert!(!TopicPath::from("a/b").covers(&TopicPath::from("a/+")));
+        assert!(!TopicPath::from("#").covers(&TopicPath::from("$SYS/#")));
+        assert!(TopicPath::from("$SYS/#").covers(&TopicPath::from("$SYS/+")));
+    }
+
+    #[test]
+    fn topic_name_test() {
+        assert!("a/b".to_topic_name().is_ok());
+        match "a".repeat(70000).to_topic_name() {
+            Err(MQError::PayloadTooLong) => (),
+            other => panic!("expected PayloadTooLong, got {:?}", other)
+        }
+        match "a/+/b".to_topic_name() {
+            Err(MQError::TopicNameMustNotContainWildcard) => (),
+            other => panic!("expected wildcard error, got {:?}", other)
+        }
+        match "a/#".to_topic_name() {
+            Err(MQError::TopicNameMustNotContainWildcard) => (),
+            other => panic!("expected wildcard error, got {:?}", other)
+        }
+        match "$share/group/a/b".to_topic_name() {
+            Err(MQError::TopicNameMustNotBeShared) => (),
+            other => panic!("expected shared error, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn shared_topic_path_test() {
+        let topic = TopicPath::from("$share/group/a/+");
+        assert_eq!(topic.path, "$share/group/a/+");
+        assert_eq!(topic.shared_group(), Some("group"));
+        assert_eq!(topic.filter(), "a/+");
+        assert!(topic.wildcards);
+        assert_eq!(topic.levels().collect::<Vec<&str>>(), vec!["a", "+"]);
+        let mut iter = topic.into_iter();
+        assert_eq!(iter.next().unwrap(), Topic::Normal("a".to_string()));
+        assert_eq!(iter.next().unwrap(), Topic::SingleWildcard);
+        assert!(iter.next().is_none());
+
+        assert!(TopicPath::from_str("$share/group").is_err());
+        assert!(TopicPath::from_str("$share/group/").is_err());
+        assert!(TopicPath::from_str("$share//a").is_err());
+        assert!(TopicPath::from_str("$share/gr+oup/a").is_err());
+        assert!(TopicPath::from_str("$share/gr#oup/a").is_err());
+        assert!(TopicPath::from_str("$share/group/a+").is_err());
+        assert!(TopicPath::from("$shared/a").shared_group().is_none());
+    }
 }
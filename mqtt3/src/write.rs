@@ -1,17 +1,41 @@
 use byteorder::{WriteBytesExt, BigEndian};
-use std::io::{BufWriter, Write, Cursor};
+use std::io::Cursor;
+#[cfg(feature = "std-net")]
+use std::io::{BufWriter, Write};
+#[cfg(feature = "std-net")]
 use std::net::TcpStream;
 use {Packet, QoS, MQError, Result, MAX_PAYLOAD_SIZE, SubscribeTopic, SubscribeReturnCodes};
+use {Protocol, Property, Publish};
+use property::{properties_len, properties_block_len};
 
 pub trait MqttWrite: WriteBytesExt {
     fn write_packet(&mut self, packet: &Packet) -> Result<()> {
+        self.write_packet_with(packet, Protocol::MQTT(4))
+    }
+
+    /// Writes a packet framed for `protocol`. CONNECT is always framed for
+    /// the protocol it asks for.
+    fn write_packet_with(&mut self, packet: &Packet, protocol: Protocol) -> Result<()> {
+        let v5 = protocol.level() >= 5;
+        check_lengths(packet)?;
         match packet {
             &Packet::Connect(ref connect) => {
-                self.write_u8(0b00010000)?;
                 let prot_name = connect.protocol.name();
+                let connect_v5 = connect.protocol.level() >= 5;
+                // only MQTT 5 allows a password alone, e.g. a bearer token
+                if !connect_v5 && connect.password.is_some() && connect.username.is_none() {
+                    return Err(MQError::PasswordWithoutUsername);
+                }
+                self.write_u8(0b00010000)?;
                 let mut len = 8 + prot_name.len() + connect.client_id.len();
+                if connect_v5 {
+                    len += properties_block_len(&connect.properties);
+                }
                 if let Some(ref last_will) = connect.last_will {
                     len += 4 + last_will.topic.len() + last_will.message.len();
+                    if connect_v5 {
+                        len += properties_block_len(&last_will.properties);
+                    }
                 }
                 if let Some(ref username) = connect.username {
                     len += 2 + username.len();
@@ -41,10 +65,16 @@ pub trait MqttWrite: WriteBytesExt {
                 }
                 self.write_u8(connect_flags)?;
                 self.write_u16::<BigEndian>(connect.keep_alive)?;
+                if connect_v5 {
+                    self.write_properties(&connect.properties)?;
+                }
                 self.write_mqtt_string(connect.client_id.as_ref())?;
                 if let Some(ref last_will) = connect.last_will {
+                    if connect_v5 {
+                        self.write_properties(&last_will.properties)?;
+                    }
                     self.write_mqtt_string(last_will.topic.as_ref())?;
-                    self.write_mqtt_string(last_will.message.as_ref())?;
+                    self.write_mqtt_bytes(last_will.message.as_ref())?;
                 }
                 if let Some(ref username) = connect.username {
                     self.write_mqtt_string(username)?;
@@ -55,22 +85,20 @@ pub trait MqttWrite: WriteBytesExt {
                 Ok(())
             },
 			&Packet::Connack(ref connack) => {
-                self.write(&[0x20, 0x02, connack.session_present as u8, connack.code.to_u8()])?;
+                if v5 {
+                    let mut properties = reason_properties(&connack.reason);
+                    properties.extend(connack.server_keep_alive.map(Property::ServerKeepAlive));
+                    self.write_u8(0x20)?;
+                    self.write_remaining_length(2 + properties_block_len(&properties))?;
+                    self.write_all(&[connack.session_present as u8, connack.reason_code.unwrap_or_else(|| connack.code.to_reason_code())])?;
+                    self.write_properties(&properties)?;
+                } else {
+                    self.write_all(&[0x20, 0x02, connack.session_present as u8, connack.code.to_u8()])?;
+                }
                 Ok(())
             },
 			&Packet::Publish(ref publish) => {
-                self.write_u8(0b00110000 | publish.retain as u8 | (publish.qos.to_u8() << 1) | ((publish.dup as u8) << 3))?;
-                let mut len = publish.topic_name.len() + 2 + publish.payload.len();
-                if publish.qos != QoS::AtMostOnce && None != publish.pid {
-                    len += 2;
-                }
-                self.write_remaining_length(len)?;
-                self.write_mqtt_string(publish.topic_name.as_str())?;
-                if publish.qos != QoS::AtMostOnce {
-                    if let Some(pid) = publish.pid {
-                        self.write_u16::<BigEndian>(pid.0)?;
-                    }
-                }
+                self.write_publish_header(publish, publish.payload.len(), protocol)?;
                 self.write(&publish.payload.as_ref())?;
                 Ok(())
             },
@@ -95,34 +123,67 @@ pub trait MqttWrite: WriteBytesExt {
                 Ok(())
             },
 			&Packet::Subscribe(ref subscribe) => {
+                if subscribe.topics.is_empty() {
+                    return Err(MQError::IncorrectPacketFormat);
+                }
                 self.write(&[0x82])?;
-                let len = 2 + subscribe.topics.iter().fold(0, |s, ref t| s + t.topic_path.len() + 3);
+                let mut len = 2 + subscribe.topics.iter().fold(0, |s, t| s + t.topic_path.len() + 3);
+                if v5 {
+                    len += properties_block_len(&subscribe.properties);
+                }
                 self.write_remaining_length(len)?;
                 self.write_u16::<BigEndian>(subscribe.pid.0)?;
+                if v5 {
+                    self.write_properties(&subscribe.properties)?;
+                }
                 for topic in subscribe.topics.as_ref() as &Vec<SubscribeTopic> {
                     self.write_mqtt_string(topic.topic_path.as_str())?;
-                    self.write_u8(topic.qos.to_u8())?;
+                    let options = if v5 {
+                        let no_local = if topic.no_local { 0x04 } else { 0 };
+                        let retain_as_published = if topic.retain_as_published { 0x08 } else { 0 };
+                        no_local | retain_as_published | (topic.retain_handling.to_u8() << 4)
+                    } else {
+                        0
+                    };
+                    self.write_u8(topic.qos.to_u8() | options)?;
                 }
                 Ok(())
             },
 			&Packet::Suback(ref suback) => {
                 self.write(&[0x90])?;
-                self.write_remaining_length(suback.return_codes.len() + 2)?;
-                self.write_u16::<BigEndian>(suback.pid.0)?;
+                if v5 {
+                    self.write_remaining_length(suback.return_codes.len() + 2 + properties_block_len(&[]))?;
+                    self.write_u16::<BigEndian>(suback.pid.0)?;
+                    self.write_properties(&[])?;
+                } else {
+                    self.write_remaining_length(suback.return_codes.len() + 2)?;
+                    self.write_u16::<BigEndian>(suback.pid.0)?;
+                }
                 let payload: Vec<u8> = suback.return_codes.iter().map({ |&code|
                     match code {
                         SubscribeReturnCodes::Success(qos) => qos.to_u8(),
-                        SubscribeReturnCodes::Failure => 0x80
+                        SubscribeReturnCodes::Failure => 0x80,
+                        SubscribeReturnCodes::Refused(reason) if v5 => reason.to_u8(),
+                        SubscribeReturnCodes::Refused(_) => 0x80
                     }
                 }).collect();
                 self.write(&payload)?;
                 Ok(())
             },
 			&Packet::Unsubscribe(ref unsubscribe) => {
+                if unsubscribe.topics.is_empty() {
+                    return Err(MQError::IncorrectPacketFormat);
+                }
                 self.write(&[0xA2])?;
-                let len = 2 + unsubscribe.topics.iter().fold(0, |s, ref topic| s + topic.len() + 2);
+                let mut len = 2 + unsubscribe.topics.iter().fold(0, |s, topic| s + topic.len() + 2);
+                if v5 {
+                    len += properties_block_len(&[]);
+                }
                 self.write_remaining_length(len)?;
                 self.write_u16::<BigEndian>(unsubscribe.pid.0)?;
+                if v5 {
+                    self.write_properties(&[])?;
+                }
                 for topic in unsubscribe.topics.as_ref() as &Vec<String> {
                     self.write_mqtt_string(topic.as_str())?;
                 }
@@ -141,16 +202,120 @@ pub trait MqttWrite: WriteBytesExt {
                 self.write(&[0xd0, 0])?;
                 Ok(())
             },
-			&Packet::Disconnect => {
-                self.write(&[0xe0, 0])?;
+			Packet::Disconnect(disconnect) => {
+                if v5 && (disconnect.reason_code != 0 || disconnect.reason.is_some()) {
+                    let properties = reason_properties(&disconnect.reason);
+                    self.write_u8(0xe0)?;
+                    self.write_remaining_length(1 + properties_block_len(&properties))?;
+                    self.write_u8(disconnect.reason_code)?;
+                    self.write_properties(&properties)?;
+                } else {
+                    self.write_all(&[0xe0, 0])?;
+                }
+                Ok(())
+            }
+			Packet::Auth(auth) => {
+                if !v5 {
+                    return Err(MQError::UnsupportedPacketType);
+                }
+                if auth.reason_code != 0 || !auth.properties.is_empty() {
+                    self.write_u8(0xf0)?;
+                    self.write_remaining_length(1 + properties_block_len(&auth.properties))?;
+                    self.write_u8(auth.reason_code)?;
+                    self.write_properties(&auth.properties)?;
+                } else {
+                    self.write_all(&[0xf0, 0])?;
+                }
                 Ok(())
             }
         }
     }
 
+    /// Writes a PUBLISH up to its payload, framed for a payload of
+    /// `payload_len` bytes which the caller writes next. `publish.payload`
+    /// is not written.
+    fn write_publish_header(&mut self, publish: &Publish, payload_len: usize, protocol: Protocol) -> Result<()> {
+        let v5 = protocol.level() >= 5;
+        let mut len = publish.topic_name.len() + 2 + payload_len;
+        if publish.qos != QoS::AtMostOnce && publish.pid.is_some() {
+            len += 2;
+        }
+        if v5 {
+            len += properties_block_len(&publish.properties);
+        }
+        // nothing is written for a PUBLISH which can't be
+        check_mqtt_len(publish.topic_name.len())?;
+        check_properties(&publish.properties)?;
+        if len > MAX_PAYLOAD_SIZE {
+            return Err(MQError::PayloadTooLong);
+        }
+        self.write_u8(0b00110000 | publish.retain as u8 | (publish.qos.to_u8() << 1) | ((publish.dup as u8) << 3))?;
+        self.write_remaining_length(len)?;
+        self.write_mqtt_string(publish.topic_name.as_str())?;
+        if publish.qos != QoS::AtMostOnce {
+            if let Some(pid) = publish.pid {
+                self.write_u16::<BigEndian>(pid.0)?;
+            }
+        }
+        if v5 {
+            self.write_properties(&publish.properties)?;
+        }
+        Ok(())
+    }
+
+    fn write_properties(&mut self, properties: &[Property]) -> Result<()> {
+        self.write_remaining_length(properties_len(properties))?;
+        for property in properties {
+            self.write_property(property)?;
+        }
+        Ok(())
+    }
+
+    fn write_property(&mut self, property: &Property) -> Result<()> {
+        self.write_u8(property.id())?;
+        match *property {
+            Property::PayloadFormatIndicator(value) |
+            Property::RequestProblemInformation(value) |
+            Property::RequestResponseInformation(value) |
+            Property::MaximumQoS(value) |
+            Property::RetainAvailable(value) |
+            Property::WildcardSubscriptionAvailable(value) |
+            Property::SubscriptionIdentifierAvailable(value) |
+            Property::SharedSubscriptionAvailable(value) => self.write_u8(value)?,
+            Property::ServerKeepAlive(value) |
+            Property::ReceiveMaximum(value) |
+            Property::TopicAliasMaximum(value) |
+            Property::TopicAlias(value) => self.write_u16::<BigEndian>(value)?,
+            Property::MessageExpiryInterval(value) |
+            Property::SessionExpiryInterval(value) |
+            Property::WillDelayInterval(value) |
+            Property::MaximumPacketSize(value) => self.write_u32::<BigEndian>(value)?,
+            Property::SubscriptionIdentifier(id) => self.write_remaining_length(id as usize)?,
+            Property::ContentType(ref s) |
+            Property::ResponseTopic(ref s) |
+            Property::AssignedClientIdentifier(ref s) |
+            Property::AuthenticationMethod(ref s) |
+            Property::ResponseInformation(ref s) |
+            Property::ServerReference(ref s) |
+            Property::ReasonString(ref s) => self.write_mqtt_string(s)?,
+            Property::CorrelationData(ref b) |
+            Property::AuthenticationData(ref b) => self.write_mqtt_bytes(b)?,
+            Property::UserProperty(ref key, ref value) => {
+                self.write_mqtt_string(key)?;
+                self.write_mqtt_string(value)?;
+            }
+        }
+        Ok(())
+    }
+
     fn write_mqtt_string(&mut self, string: &str) -> Result<()> {
-        self.write_u16::<BigEndian>(string.len() as u16)?;
-        self.write(string.as_bytes())?;
+        self.write_mqtt_bytes(string.as_bytes())
+    }
+
+    fn write_mqtt_bytes(&mut self, bytes: &[u8]) -> Result<()> {
+        check_mqtt_len(bytes.len())?;
+        self.write_u16::<BigEndian>(bytes.len() as u16)?;
+        self.write_all(bytes)?;
         Ok(())
     }
 
@@ -176,22 +341,100 @@ pub trait MqttWrite: WriteBytesExt {
     }
 }
 
+#[cfg(feature = "std-net")]
 impl MqttWrite for TcpStream {}
 impl MqttWrite for Cursor<Vec<u8>> {}
+#[cfg(feature = "std-net")]
 impl<T: Write> MqttWrite for BufWriter<T> {}
 
+fn reason_properties(reason: &Option<String>) -> Vec<Property> {
+    reason.iter().map(|reason| Property::ReasonString(reason.clone())).collect()
+}
+
+// Strings and binary data have a two byte length
+fn check_mqtt_len(len: usize) -> Result<()> {
+    if len > u16::MAX as usize {
+        Err(MQError::PayloadTooLong)
+    } else {
+        Ok(())
+    }
+}
+
+fn check_properties(properties: &[Property]) -> Result<()> {
+    for property in properties {
+        match *property {
+            Property::ContentType(ref s) |
+            Property::ResponseTopic(ref s) |
+            Property::AssignedClientIdentifier(ref s) |
+            Property::AuthenticationMethod(ref s) |
+            Property::ResponseInformation(ref s) |
+            Property::ServerReference(ref s) |
+            Property::ReasonString(ref s) => check_mqtt_len(s.len())?,
+            Property::CorrelationData(ref b) |
+            Property::AuthenticationData(ref b) => check_mqtt_len(b.len())?,
+            Property::UserProperty(ref key, ref value) => {
+                check_mqtt_len(key.len())?;
+                check_mqtt_len(value.len())?;
+            }
+            _ => ()
+        }
+    }
+    Ok(())
+}
+
+// Finds the strings too long to encode before the first byte is written,
+// a writer straight to the socket would be left with part of a packet
+fn check_lengths(packet: &Packet) -> Result<()> {
+    match *packet {
+        Packet::Connect(ref connect) => {
+            check_mqtt_len(connect.client_id.len())?;
+            if let Some(ref last_will) = connect.last_will {
+                check_mqtt_len(last_will.topic.len())?;
+                check_mqtt_len(last_will.message.len())?;
+                check_properties(&last_will.properties)?;
+            }
+            for string in connect.username.iter().chain(connect.password.iter()) {
+                check_mqtt_len(string.len())?;
+            }
+            check_properties(&connect.properties)
+        }
+        Packet::Connack(ref connack) => check_properties(&reason_properties(&connack.reason)),
+        Packet::Publish(ref publish) => {
+            check_mqtt_len(publish.topic_name.len())?;
+            check_properties(&publish.properties)
+        }
+        Packet::Subscribe(ref subscribe) => {
+            for topic in &subscribe.topics {
+                check_mqtt_len(topic.topic_path.len())?;
+            }
+            check_properties(&subscribe.properties)
+        }
+        Packet::Unsubscribe(ref unsubscribe) => {
+            for topic in &unsubscribe.topics {
+                check_mqtt_len(topic.len())?;
+            }
+            Ok(())
+        }
+        Packet::Disconnect(ref disconnect) => check_properties(&reason_properties(&disconnect.reason)),
+        Packet::Auth(ref auth) => check_properties(&auth.properties),
+        _ => Ok(())
+    }
+}
+
 #[cfg(test)]
 mod test {
     use std::io::Cursor;
     use std::sync::Arc;
     use super::MqttWrite;
+    use super::super::{MqttRead, MQError, Property, RetainHandling};
     use super::super::{Protocol, LastWill, QoS, PacketIdentifier, ConnectReturnCode, SubscribeTopic};
     use super::super::mqtt::{
         Packet,
         Connect,
         Connack,
         Publish,
-        Subscribe
+        Subscribe,
+        Unsubscribe
     };
 
     #[test]
@@ -203,12 +446,14 @@ mod test {
             clean_session: true,
             last_will: Some(LastWill {
                 topic: "/a".to_owned(),
-                message: "offline".to_owned(),
+                message: b"offline".to_vec(),
                 retain: false,
-                qos: QoS::AtLeastOnce
+                qos: QoS::AtLeastOnce,
+                properties: Vec::new()
             }),
             username: Some("rust".to_owned()),
-            password: Some("mq".to_owned())
+            password: Some("mq".to_owned()),
+            properties: Vec::new()
         }));
 
         let mut stream = Cursor::new(Vec::new());
@@ -236,7 +481,8 @@ mod test {
             clean_session: false,
             last_will: None,
             username: None,
-            password: None
+            password: None,
+            properties: Vec::new()
         }));
 
         let mut stream = Cursor::new(Vec::new());
@@ -251,11 +497,220 @@ mod test {
         ]);
     }
 
+    #[test]
+    fn write_packet_connect_binary_will_test() {
+        let connect = Packet::Connect(Box::new(Connect {
+            protocol: Protocol::MQTT(4),
+            keep_alive: 10,
+            client_id: "test".to_owned(),
+            clean_session: true,
+            last_will: Some(LastWill {
+                topic: "/a".to_owned(),
+                message: vec![0x08, 0xff, 0x00, 0xfe],
+                retain: false,
+                qos: QoS::AtMostOnce,
+                properties: Vec::new()
+            }),
+            username: None,
+            password: None,
+            properties: Vec::new()
+        }));
+
+        let mut stream = Cursor::new(Vec::new());
+        stream.write_packet(&connect).unwrap();
+
+        assert_eq!(stream.get_ref().clone(), vec![0x10, 26,
+            0x00, 0x04, 'M' as u8, 'Q' as u8, 'T' as u8, 'T' as u8,
+            0x04,
+            0b00000110, // -username, -password, -will retain, will qos=0, +last_will, +clean_session
+            0x00, 0x0a, // 10 sec
+            0x00, 0x04, 't' as u8, 'e' as u8, 's' as u8, 't' as u8, // client_id
+            0x00, 0x02, '/' as u8, 'a' as u8, // will topic = '/a'
+            0x00, 0x04, 0x08, 0xff, 0x00, 0xfe // will msg isn't UTF-8
+        ]);
+
+        stream.set_position(0);
+        assert_eq!(stream.read_packet().unwrap(), connect);
+    }
+
+    #[test]
+    fn write_packet_v5_test() {
+        let publish = Packet::Publish(Box::new(Publish {
+            dup: false,
+            qos: QoS::AtMostOnce,
+            retain: false,
+            topic_name: "a/b".to_owned(),
+            pid: None,
+            properties: vec![Property::MessageExpiryInterval(60),
+                             Property::UserProperty("k".to_owned(), "v".to_owned())],
+            payload: Arc::new(vec![0x01])
+        }));
+
+        let mut stream = Cursor::new(Vec::new());
+        stream.write_packet_with(&publish, Protocol::MQTT(5)).unwrap();
+        assert_eq!(stream.get_ref().clone(), vec![0b00110000, 19,
+            0x00, 0x03, 'a' as u8, '/' as u8, 'b' as u8, // topic name = 'a/b'
+            0x0c, // properties length
+            0x02, 0x00, 0x00, 0x00, 0x3c, // message expiry = 60 sec
+            0x26, 0x00, 0x01, 'k' as u8, 0x00, 0x01, 'v' as u8, // user property k = v
+            0x01
+        ]);
+        stream.set_position(0);
+        assert_eq!(stream.read_packet_with(Protocol::MQTT(5)).unwrap(), publish);
+
+        // older protocols don't carry properties
+        let mut stream = Cursor::new(Vec::new());
+        stream.write_packet(&publish).unwrap();
+        assert_eq!(stream.get_ref().clone(), vec![0b00110000, 6,
+            0x00, 0x03, 'a' as u8, '/' as u8, 'b' as u8, 0x01]);
+    }
+
+    #[test]
+    fn write_packet_connect_v5_test() {
+        let connect = Packet::Connect(Box::new(Connect {
+            protocol: Protocol::MQTT(5),
+            keep_alive: 10,
+            client_id: "test".to_owned(),
+            clean_session: true,
+            last_will: None,
+            username: None,
+            password: None,
+            properties: Vec::new()
+        }));
+
+        let mut stream = Cursor::new(Vec::new());
+        stream.write_packet(&connect).unwrap();
+
+        assert_eq!(stream.get_ref().clone(), vec![0x10, 17,
+            0x00, 0x04, 'M' as u8, 'Q' as u8, 'T' as u8, 'T' as u8,
+            0x05,
+            0b00000010, // +clean_session
+            0x00, 0x0a, // 10 sec
+            0x00, // properties length
+            0x00, 0x04, 't' as u8, 'e' as u8, 's' as u8, 't' as u8 // client_id
+        ]);
+        stream.set_position(0);
+        assert_eq!(stream.read_packet().unwrap(), connect);
+    }
+
+    #[test]
+    fn write_packet_connect_v5_will_properties_test() {
+        let connect = Packet::Connect(Box::new(Connect {
+            protocol: Protocol::MQTT(5),
+            keep_alive: 10,
+            client_id: "t".to_owned(),
+            clean_session: true,
+            last_will: Some(LastWill {
+                topic: "a".to_owned(),
+                message: b"x".to_vec(),
+                retain: false,
+                qos: QoS::AtMostOnce,
+                properties: vec![Property::WillDelayInterval(60)]
+            }),
+            username: None,
+            password: None,
+            properties: Vec::new()
+        }));
+
+        let mut stream = Cursor::new(Vec::new());
+        stream.write_packet(&connect).unwrap();
+        assert_eq!(stream.get_ref().clone(), vec![0x10, 26,
+            0x00, 0x04, 'M' as u8, 'Q' as u8, 'T' as u8, 'T' as u8,
+            0x05,
+            0b00000110, // +last_will, +clean_session
+            0x00, 0x0a, // 10 sec
+            0x00, // properties length
+            0x00, 0x01, 't' as u8, // client_id
+            0x05, 0x18, 0x00, 0x00, 0x00, 60, // will delay interval 60
+            0x00, 0x01, 'a' as u8, // will topic
+            0x00, 0x01, 'x' as u8 // will message
+        ]);
+        stream.set_position(0);
+        assert_eq!(stream.read_packet().unwrap(), connect);
+    }
+
+    #[test]
+    fn write_packet_connect_v5_properties_test() {
+        let mut connect = Connect {
+            protocol: Protocol::MQTT(5),
+            keep_alive: 10,
+            client_id: "test".to_owned(),
+            clean_session: true,
+            last_will: None,
+            username: None,
+            password: None,
+            properties: vec![Property::UserProperty("a".to_owned(), "b".to_owned())]
+        };
+
+        let mut stream = Cursor::new(Vec::new());
+        stream.write_packet(&Packet::Connect(Box::new(connect.clone()))).unwrap();
+        assert_eq!(stream.get_ref().clone(), vec![0x10, 24,
+            0x00, 0x04, 'M' as u8, 'Q' as u8, 'T' as u8, 'T' as u8,
+            0x05,
+            0b00000010, // +clean_session
+            0x00, 0x0a, // 10 sec
+            0x07, 0x26, 0x00, 0x01, 'a' as u8, 0x00, 0x01, 'b' as u8, // user property a=b
+            0x00, 0x04, 't' as u8, 'e' as u8, 's' as u8, 't' as u8 // client_id
+        ]);
+        stream.set_position(0);
+        assert_eq!(stream.read_packet().unwrap(), Packet::Connect(Box::new(connect.clone())));
+
+        // older protocols don't carry properties
+        connect.protocol = Protocol::MQTT(4);
+        let mut stream = Cursor::new(Vec::new());
+        stream.write_packet(&Packet::Connect(Box::new(connect))).unwrap();
+        assert_eq!(stream.get_ref()[1], 16);
+    }
+
+    #[test]
+    fn write_packet_connect_password_only_test() {
+        let mut connect = Connect {
+            protocol: Protocol::MQTT(4),
+            keep_alive: 10,
+            client_id: "test".to_owned(),
+            clean_session: true,
+            last_will: None,
+            username: None,
+            password: Some("token".to_owned()),
+            properties: Vec::new()
+        };
+
+        let mut stream = Cursor::new(Vec::new());
+        match stream.write_packet(&Packet::Connect(Box::new(connect.clone()))) {
+            Err(MQError::PasswordWithoutUsername) => (),
+            other => panic!("expected PasswordWithoutUsername, got {:?}", other)
+        }
+        assert!(stream.get_ref().is_empty());
+
+        connect.protocol = Protocol::MQTT(5);
+        let connect = Packet::Connect(Box::new(connect));
+        stream.write_packet(&connect).unwrap();
+        assert_eq!(stream.get_ref()[9], 0b01000010); // +password, +clean_session
+        stream.set_position(0);
+        assert_eq!(stream.read_packet().unwrap(), connect);
+
+        // and the decoder refuses it below MQTT 5
+        let mut stream = Cursor::new(vec![0x10, 19,
+            0x00, 0x04, 'M' as u8, 'Q' as u8, 'T' as u8, 'T' as u8,
+            0x04,
+            0b01000010,
+            0x00, 0x0a,
+            0x00, 0x01, 't' as u8,
+            0x00, 0x05, 't' as u8, 'o' as u8, 'k' as u8, 'e' as u8, 'n' as u8]);
+        match stream.read_packet() {
+            Err(MQError::PasswordWithoutUsername) => (),
+            other => panic!("expected PasswordWithoutUsername, got {:?}", other)
+        }
+    }
+
     #[test]
     fn write_packet_connack_test() {
         let connack = Packet::Connack(Connack {
             session_present: true,
-            code: ConnectReturnCode::Accepted
+            code: ConnectReturnCode::Accepted,
+            reason_code: None,
+            reason: None,
+            server_keep_alive: None
         });
 
         let mut stream = Cursor::new(Vec::new());
@@ -272,6 +727,7 @@ mod test {
             retain: false,
             topic_name: "a/b".to_owned(),
             pid: Some(PacketIdentifier(10)),
+            properties: Vec::new(),
             payload: Arc::new(vec![0xF1, 0xF2, 0xF3, 0xF4])
         }));
 
@@ -289,6 +745,7 @@ mod test {
             retain: false,
             topic_name: "a/b".to_owned(),
             pid: None,
+            properties: Vec::new(),
             payload: Arc::new(vec![0xE1, 0xE2, 0xE3, 0xE4])
         }));
 
@@ -298,15 +755,50 @@ mod test {
         assert_eq!(stream.get_ref().clone(), vec![0b00110000, 9, 0x00, 0x03, 'a' as u8, '/' as u8, 'b' as u8, 0xE1, 0xE2, 0xE3, 0xE4]);
     }
 
+    #[test]
+    fn write_packet_publish_topic_too_long_test() {
+        let publish = Packet::Publish(Box::new(Publish {
+            dup: false,
+            qos: QoS::AtMostOnce,
+            retain: false,
+            topic_name: "a".repeat(70000),
+            pid: None,
+            properties: Vec::new(),
+            payload: Arc::new(vec![0xE1])
+        }));
+
+        let mut stream = Cursor::new(Vec::new());
+        match stream.write_packet(&publish) {
+            Err(MQError::PayloadTooLong) => (),
+            other => panic!("expected PayloadTooLong, got {:?}", other)
+        }
+        // not even the fixed header went out
+        assert!(stream.get_ref().is_empty());
+
+        let connect = Packet::Connect(Box::new(Connect {
+            protocol: Protocol::MQTT(4),
+            keep_alive: 10,
+            client_id: "test".to_owned(),
+            clean_session: true,
+            last_will: None,
+            username: Some("u".repeat(70000)),
+            password: None,
+            properties: Vec::new()
+        }));
+        assert!(stream.write_packet(&connect).is_err());
+        assert!(stream.get_ref().is_empty());
+    }
+
     #[test]
     fn write_packet_subscribe_test() {
         let subscribe = Packet::Subscribe(Box::new(Subscribe {
             pid: PacketIdentifier(260),
             topics: vec![
-                SubscribeTopic { topic_path: "a/+".to_owned(), qos: QoS::AtMostOnce },
-                SubscribeTopic { topic_path: "#".to_owned(), qos: QoS::AtLeastOnce },
-                SubscribeTopic { topic_path: "a/b/c".to_owned(), qos: QoS::ExactlyOnce }
-            ]
+                SubscribeTopic { topic_path: "a/+".to_owned(), qos: QoS::AtMostOnce, no_local: false, retain_as_published: false, retain_handling: RetainHandling::SendAtSubscribe },
+                SubscribeTopic { topic_path: "#".to_owned(), qos: QoS::AtLeastOnce, no_local: false, retain_as_published: false, retain_handling: RetainHandling::SendAtSubscribe },
+                SubscribeTopic { topic_path: "a/b/c".to_owned(), qos: QoS::ExactlyOnce, no_local: false, retain_as_published: false, retain_handling: RetainHandling::SendAtSubscribe }
+            ],
+            properties: Vec::new()
         }));
 
         let mut stream = Cursor::new(Vec::new());
@@ -322,4 +814,26 @@ mod test {
             0x02 // qos = 2
         ]);
     }
+
+    #[test]
+    fn write_packet_no_topics_test() {
+        let subscribe = Packet::Subscribe(Box::new(Subscribe {
+            pid: PacketIdentifier(1),
+            topics: Vec::new(),
+            properties: Vec::new()
+        }));
+        let unsubscribe = Packet::Unsubscribe(Box::new(Unsubscribe {
+            pid: PacketIdentifier(1),
+            topics: Vec::new()
+        }));
+
+        for packet in &[subscribe, unsubscribe] {
+            let mut stream = Cursor::new(Vec::new());
+            match stream.write_packet(packet) {
+                Err(MQError::IncorrectPacketFormat) => (),
+                other => panic!("expected IncorrectPacketFormat, got {:?}", other)
+            }
+            assert!(stream.get_ref().is_empty());
+        }
+    }
 }
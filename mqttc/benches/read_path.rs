@@ -0,0 +1,162 @@
+//! What reading packets costs: read calls reaching the stream and heap
+//! allocations per PUBLISH, against a payload grown while reading as it
+//! was before presizing.
+//!
+//!     cargo bench -p mqttc --bench read_path
+
+extern crate mqtt3;
+extern crate mqttc;
+extern crate netopt;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::io::{self, BufReader, Cursor, Read};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use mqtt3::{MqttRead, MqttWrite, Packet, PacketIdentifier, Protocol, Publish, QoS, RetainHandling, Subscribe, SubscribeTopic};
+use mqttc::Connection;
+use netopt::NetworkStream;
+use netopt::mock::MockStream;
+
+const MESSAGES: usize = 10000;
+// The QoS 0 stream the allocations per second are given for
+const RATE: usize = 100000;
+
+// Counts allocations and reallocations
+struct Counting;
+
+static ALLOCS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+// Counts the read calls reaching the inner stream
+struct Reads<R> {
+    inner: R,
+    calls: usize
+}
+
+impl<R: Read> Read for Reads<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.calls += 1;
+        self.inner.read(buf)
+    }
+}
+
+impl<R: Read> MqttRead for Reads<R> {}
+
+fn encode(packet: &Packet) -> Vec<u8> {
+    let mut encoded = Cursor::new(Vec::new());
+    encoded.write_packet(packet).unwrap();
+    encoded.into_inner()
+}
+
+fn subscribe_reads() {
+    let topics = (0..10).map(|i| {
+        SubscribeTopic { topic_path: format!("sensors/{}/#", i), qos: QoS::AtLeastOnce, no_local: false, retain_as_published: false, retain_handling: RetainHandling::SendAtSubscribe }
+    }).collect();
+    let subscribe = encode(&Packet::Subscribe(Box::new(Subscribe {
+        pid: PacketIdentifier(1),
+        topics,
+        properties: Vec::new()
+    })));
+
+    let mut unbuffered = Reads { inner: Cursor::new(subscribe.clone()), calls: 0 };
+    unbuffered.read_packet().unwrap();
+    let mut buffered = BufReader::new(Reads { inner: Cursor::new(subscribe), calls: 0 });
+    buffered.read_packet().unwrap();
+
+    println!("read calls for a SUBSCRIBE with 10 topics");
+    println!("  unbuffered  {:>4}", unbuffered.calls);
+    println!("  buffered    {:>4}", buffered.get_ref().calls);
+}
+
+// MESSAGES QoS 0 PUBLISH packets back to back
+fn publishes(payload: usize) -> Vec<u8> {
+    encode(&Packet::Publish(Box::new(Publish {
+        dup: false,
+        qos: QoS::AtMostOnce,
+        retain: false,
+        topic_name: "sensors/1/temperature".to_owned(),
+        pid: None,
+        properties: Vec::new(),
+        payload: Arc::new(vec![0x5A; payload])
+    }))).repeat(MESSAGES)
+}
+
+// The QoS 0 PUBLISH decode as it was before payloads were presized, the
+// payload grows from an empty Vec while it is read
+fn read_publish_grown<R: MqttRead>(stream: &mut R) -> Box<Publish> {
+    let hd = stream.read_u8().unwrap();
+    let len = stream.read_remaining_length().unwrap();
+    let mut raw_packet = stream.take(len as u64);
+    let topic_name = raw_packet.read_mqtt_string().unwrap();
+    let mut payload = Vec::new();
+    raw_packet.read_to_end(&mut payload).unwrap();
+    Box::new(Publish {
+        dup: false,
+        qos: QoS::AtMostOnce,
+        retain: hd & 0x01 != 0,
+        topic_name,
+        pid: None,
+        properties: Vec::new(),
+        payload: Arc::new(payload)
+    })
+}
+
+// Allocations per PUBLISH decoded the old way, buffered like a Connection
+fn grown_allocs(payload: usize) -> f64 {
+    let mut stream = BufReader::new(Cursor::new(publishes(payload)));
+
+    let before = ALLOCS.load(Ordering::Relaxed);
+    for _ in 0..MESSAGES {
+        assert_eq!(read_publish_grown(&mut stream).payload.len(), payload);
+    }
+    (ALLOCS.load(Ordering::Relaxed) - before) as f64 / MESSAGES as f64
+}
+
+// Allocations per PUBLISH read through a Connection
+fn publish_allocs(payload: usize, reuse: bool) -> f64 {
+    let stream = MockStream::with_vec(publishes(payload));
+    let mut conn = Connection::new(NetworkStream::Mock(stream)).unwrap();
+    conn.set_reuse_payloads(reuse);
+
+    let before = ALLOCS.load(Ordering::Relaxed);
+    for _ in 0..MESSAGES {
+        match conn.read_packet_reusing(Protocol::MQTT(4)).unwrap() {
+            Packet::Publish(publish) => assert_eq!(publish.payload.len(), payload),
+            packet => panic!("expected PUBLISH, got {:?}", packet)
+        }
+    }
+    (ALLOCS.load(Ordering::Relaxed) - before) as f64 / MESSAGES as f64
+}
+
+fn main() {
+    subscribe_reads();
+
+    println!("allocations per QoS 0 PUBLISH, {} read, and per second at {} msg/s", MESSAGES, RATE);
+    println!("  payload  {:>16}{:>16}{:>18}", "grown (before)", "presized", "presized + reuse");
+    for &payload in &[64, 256, 4096, 65536] {
+        let allocs = [grown_allocs(payload), publish_allocs(payload, false), publish_allocs(payload, true)];
+        print!("  {:>5} B", payload);
+        for &per_message in &allocs {
+            print!("  {:>4.1} {:>7}/s", per_message, (per_message * RATE as f64) as usize);
+        }
+        println!();
+    }
+}
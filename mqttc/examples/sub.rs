@@ -6,7 +6,7 @@ use std::env;
 use std::process::exit;
 use std::time::Duration;
 use netopt::NetworkOptions;
-use mqttc::{PubSub, ClientOptions, ReconnectMethod};
+use mqttc::{PubSub, ClientOptions, Error, ReconnectMethod};
 
 fn main() {
     env_logger::init();
@@ -38,6 +38,10 @@ fn main() {
                     None => println!("."),
                 }
             }
+            Err(Error::Disconnected) => {
+                println!("Disconnected");
+                exit(1);
+            }
             Err(_) => continue
         }
     }
@@ -0,0 +1,544 @@
+use std::future::{self, Future};
+use std::io::{self, Cursor, Read};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::time::{self, Instant, Sleep};
+use mqtt3::{self, Message, MqttRead, MqttWrite, Packet, PacketIdentifier, ToTopicPath};
+use error::{Error, Result};
+use session::ClientCore;
+use {ClientOptions, ClientState, PubOpt, ToPayload, ToSubTopics, ToUnSubTopics};
+
+/// The protocol of `Client` over a tokio stream, for async applications
+/// which can't spare a blocking thread per connection. Built with the
+/// `async` feature.
+///
+/// Every call returns a future which resolves once its packets are
+/// written. Packets from the broker are only read while `await` is polled,
+/// which sends the PINGREQs of keep alive as well. There are no
+/// reconnects: once the connection is gone calls fail with
+/// `Error::Disconnected`, `into_options` gives the options back for a new
+/// client. A packet which can't be decoded drops the connection too, the
+/// stream can't be picked up after it.
+///
+/// ```no_run
+/// # extern crate mqttc;
+/// # extern crate tokio;
+/// use mqttc::{AsyncClient, ClientOptions, PubOpt};
+/// use tokio::net::TcpStream;
+/// # fn main() {
+/// let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+/// let stream = rt.block_on(TcpStream::connect("127.0.0.1:1883")).unwrap();
+/// let mut client = rt.block_on(AsyncClient::connect(stream, ClientOptions::new())).unwrap();
+/// rt.block_on(client.subscribe("a/#")).unwrap();
+/// rt.block_on(client.publish("a/b", "hello", PubOpt::at_most_once())).unwrap();
+/// let message = rt.block_on(client.r#await()).unwrap();
+/// # }
+/// ```
+pub struct AsyncClient<S = TcpStream> {
+    core: ClientCore,
+    stream: S,
+    // Bytes read short of a whole packet
+    incoming: Vec<u8>,
+    // Encoded packets the stream hasn't taken yet
+    outgoing: Vec<u8>,
+    // When to PING, pushed back by every write
+    ping_at: Option<Pin<Box<Sleep>>>,
+}
+
+// How long to wait for the CONNACK with keep alive off
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncClient<S> {
+    /// Sends CONNECT over a connected `stream`, resolves once the broker
+    /// accepted it. Fails with `Error::Timeout` when no CONNACK comes
+    /// within keep alive, 30 seconds without it.
+    pub fn connect(stream: S, opts: ClientOptions) -> impl Future<Output = Result<AsyncClient<S>>> {
+        let mut client = AsyncClient {
+            core: ClientCore::new(opts),
+            stream: stream,
+            incoming: Vec::new(),
+            outgoing: Vec::new(),
+            ping_at: None,
+        };
+        let mut failed = client.core.connect().err();
+        let wait = client.core.keep_alive().unwrap_or(HANDSHAKE_TIMEOUT);
+        let mut client = Some(client);
+        // made on the first poll, a timer needs the runtime
+        let mut timeout: Option<Pin<Box<Sleep>>> = None;
+        future::poll_fn(move |cx| {
+            if let Some(err) = failed.take() {
+                return Poll::Ready(Err(err));
+            }
+            let handshake = match client {
+                Some(ref mut client) => client.poll_handshake(cx),
+                None => panic!("AsyncClient::connect polled after completion"),
+            };
+            match handshake {
+                Poll::Ready(Ok(())) => Poll::Ready(Ok(client.take().unwrap())),
+                Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+                Poll::Pending => {
+                    let timeout = timeout.get_or_insert_with(|| Box::pin(time::sleep(wait)));
+                    if timeout.as_mut().poll(cx).is_pending() {
+                        return Poll::Pending;
+                    }
+                    if let Some(ref mut client) = client {
+                        client.core.disconnected();
+                    }
+                    Poll::Ready(Err(Error::Timeout))
+                }
+            }
+        })
+    }
+
+    pub fn publish<'a, T: ToTopicPath, P: ToPayload>(&'a mut self,
+                                                     topic: T,
+                                                     payload: P,
+                                                     pubopt: PubOpt)
+                                                     -> impl Future<Output = Result<Option<PacketIdentifier>>> + 'a {
+        let result = self._connected().and_then(|_| self.core.publish(topic, payload, pubopt, self.core.opts.clock.now()));
+        self._flushed(result)
+    }
+
+    pub fn subscribe<'a, T: ToSubTopics>(&'a mut self, subs: T) -> impl Future<Output = Result<PacketIdentifier>> + 'a {
+        let result = self._connected().and_then(|_| self.core.subscribe(subs, None));
+        self._flushed(result)
+    }
+
+    pub fn unsubscribe<'a, T: ToUnSubTopics>(&'a mut self, unsubs: T) -> impl Future<Output = Result<()>> + 'a {
+        let result = self._connected().and_then(|_| self.core.unsubscribe(unsubs));
+        self._flushed(result)
+    }
+
+    /// Sends the PUBCOMP of a QoS 2 message handed out by `await`
+    pub fn complete<'a>(&'a mut self, pid: PacketIdentifier) -> impl Future<Output = Result<()>> + 'a {
+        let result = self._connected().and_then(|_| self.core.complete(pid));
+        self._flushed(result)
+    }
+
+    /// Reads until a message is received, or until nothing is awaited from
+    /// the broker any more like `Client::await`
+    pub fn r#await<'a>(&'a mut self) -> impl Future<Output = Result<Option<Box<Message>>>> + 'a {
+        let connected = self._connected();
+        let mut failed = connected.err();
+        future::poll_fn(move |cx| {
+            if let Some(err) = failed.take() {
+                return Poll::Ready(Err(err));
+            }
+            self.poll_await(cx)
+        })
+    }
+
+    /// Sends DISCONNECT and shuts the stream down
+    pub fn disconnect(mut self) -> impl Future<Output = Result<()>> {
+        self.core.disconnect();
+        future::poll_fn(move |cx| {
+            match self.poll_flush(cx) {
+                Poll::Ready(Ok(())) => (),
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            }
+            match Pin::new(&mut self.stream).poll_shutdown(cx) {
+                Poll::Ready(result) => {
+                    self.core.disconnected();
+                    Poll::Ready(result.map_err(Error::from))
+                }
+                Poll::Pending => Poll::Pending,
+            }
+        })
+    }
+
+    pub fn core(&self) -> &ClientCore {
+        &self.core
+    }
+
+    pub fn into_options(self) -> ClientOptions {
+        self.core.into_options()
+    }
+
+    fn poll_handshake(&mut self, cx: &mut Context) -> Poll<Result<()>> {
+        loop {
+            if let Poll::Ready(Err(err)) = self.poll_flush(cx) {
+                return Poll::Ready(Err(err));
+            }
+            let packet = match self.poll_packet(cx) {
+                Poll::Ready(Ok(packet)) => packet,
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            };
+            let now = self.core.opts.clock.now();
+            self.core.handle(packet, now)?;
+            if self.core.state() == ClientState::Connected {
+                self._reset_ping();
+                return Poll::Ready(Ok(()));
+            }
+        }
+    }
+
+    fn poll_await(&mut self, cx: &mut Context) -> Poll<Result<Option<Box<Message>>>> {
+        loop {
+            // acks go out while reading goes on, a full socket isn't fatal
+            if let Poll::Ready(Err(err)) = self.poll_flush(cx) {
+                return Poll::Ready(Err(err));
+            }
+            match self.poll_packet(cx) {
+                Poll::Ready(Ok(packet)) => {
+                    let now = self.core.opts.clock.now();
+                    let message = match self.core.handle(packet, now) {
+                        Ok(message) => message,
+                        Err(err) => {
+                            match err {
+                                Error::ConnectionAbort |
+                                Error::ServerDisconnect { .. } |
+                                Error::UnexpectedPacket(_) => self.core.disconnected(),
+                                _ => (),
+                            }
+                            return Poll::Ready(Err(err));
+                        }
+                    };
+                    if let Poll::Ready(Err(err)) = self.poll_flush(cx) {
+                        return Poll::Ready(Err(err));
+                    }
+                    if message.is_some() || self.core.is_normalized() {
+                        return Poll::Ready(Ok(message));
+                    }
+                }
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => {
+                    match self.ping_at {
+                        Some(ref mut ping_at) => {
+                            if ping_at.as_mut().poll(cx).is_pending() {
+                                return Poll::Pending;
+                            }
+                        }
+                        None => return Poll::Pending,
+                    }
+                    // the broker didn't answer the last PINGREQ within keep
+                    // alive, the connection is dead
+                    if self.core.await_ping {
+                        self.core.disconnected();
+                        return Poll::Ready(Err(Error::Timeout));
+                    }
+                    self.core.ping();
+                    self._reset_ping();
+                }
+            }
+        }
+    }
+
+    // Encodes the packets produced by the core and writes them out
+    fn poll_flush(&mut self, cx: &mut Context) -> Poll<Result<()>> {
+        while let Some(packet) = self.core.poll_outgoing() {
+            trace!("{}", packet);
+            let mut encoded = Cursor::new(Vec::new());
+            encoded.write_packet_with(&packet, self.core.opts.protocol)?;
+            self.outgoing.extend_from_slice(encoded.get_ref());
+        }
+        while !self.outgoing.is_empty() {
+            match Pin::new(&mut self.stream).poll_write(cx, &self.outgoing) {
+                Poll::Ready(Ok(0)) => {
+                    return Poll::Ready(Err(self._dropped(io::ErrorKind::WriteZero.into())));
+                }
+                Poll::Ready(Ok(n)) => {
+                    self.outgoing.drain(..n);
+                    self._reset_ping();
+                }
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(self._dropped(err))),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        match Pin::new(&mut self.stream).poll_flush(cx) {
+            Poll::Ready(Ok(())) => Poll::Ready(Ok(())),
+            Poll::Ready(Err(err)) => Poll::Ready(Err(self._dropped(err))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn poll_packet(&mut self, cx: &mut Context) -> Poll<Result<Packet>> {
+        loop {
+            match self._next_packet() {
+                Ok(Some(packet)) => return Poll::Ready(Ok(packet)),
+                Ok(None) => (),
+                // where the next packet starts is lost with it
+                Err(err) => {
+                    error!("{:?}", err);
+                    self.incoming.clear();
+                    self.core.disconnected();
+                    return Poll::Ready(Err(Error::Mqtt(err)));
+                }
+            }
+            let mut chunk = [0; 4096];
+            let mut buf = ReadBuf::new(&mut chunk);
+            match Pin::new(&mut self.stream).poll_read(cx, &mut buf) {
+                Poll::Ready(Ok(())) => {
+                    if buf.filled().is_empty() {
+                        self.core.disconnected();
+                        return Poll::Ready(Err(Error::Disconnected));
+                    }
+                    self.incoming.extend_from_slice(buf.filled());
+                }
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(self._dropped(err))),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+
+    // The first packet of `incoming` once it's all there
+    fn _next_packet(&mut self) -> mqtt3::Result<Option<Packet>> {
+        match frame_len(&self.incoming)? {
+            Some(len) if self.incoming.len() >= len => {
+                let packet = (&self.incoming[..len]).take(len as u64)
+                                                    .read_packet_with(self.core.opts.protocol)?;
+                self.incoming.drain(..len);
+                Ok(Some(packet))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn _flushed<'a, T: 'a>(&'a mut self, result: Result<T>) -> impl Future<Output = Result<T>> + 'a {
+        let mut result = Some(result);
+        future::poll_fn(move |cx| {
+            if let Some(Err(_)) = result {
+                return Poll::Ready(result.take().unwrap());
+            }
+            match self.poll_flush(cx) {
+                Poll::Ready(Ok(())) => Poll::Ready(result.take().unwrap()),
+                Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+                Poll::Pending => Poll::Pending,
+            }
+        })
+    }
+
+    fn _connected(&self) -> Result<()> {
+        match self.core.state() {
+            ClientState::Disconnected => Err(Error::Disconnected),
+            _ => Ok(()),
+        }
+    }
+
+    fn _reset_ping(&mut self) {
+        if let Some(keep_alive) = self.core.keep_alive() {
+            let deadline = Instant::now() + keep_alive;
+            match self.ping_at {
+                Some(ref mut ping_at) => ping_at.as_mut().reset(deadline),
+                None => self.ping_at = Some(Box::pin(time::sleep_until(deadline))),
+            }
+        }
+    }
+
+    fn _dropped(&mut self, err: io::Error) -> Error {
+        error!("{:?}", err);
+        self.core.disconnected();
+        Error::Io(err)
+    }
+}
+
+// Length of the packet at the start of `buf`, None while its fixed header
+// is incomplete
+fn frame_len(buf: &[u8]) -> mqtt3::Result<Option<usize>> {
+    let mut len = 0;
+    for (i, byte) in buf.iter().skip(1).take(4).enumerate() {
+        len += ((byte & 0x7F) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(Some(2 + i + len));
+        }
+    }
+    if buf.len() > 4 {
+        Err(mqtt3::MQError::MalformedRemainingLength)
+    } else {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener};
+    use std::thread::{self, JoinHandle};
+    use std::time::{Duration, Instant};
+    use mqtt3::{Connack, ConnectReturnCode, MQError, MqttRead, MqttWrite, Packet, PacketIdentifier, Publish, QoS};
+    use tokio::net::TcpStream;
+    use tokio::runtime::{Builder, Runtime};
+    use error::Error;
+    use {ClientOptions, PubOpt};
+    use super::{frame_len, AsyncClient};
+
+    // A broker which answers CONNECT with `code`, writes `then` and reads
+    // until the client is gone. Gives back what it read after the CONNECT.
+    fn broker(code: ConnectReturnCode, then: Vec<u8>) -> (SocketAddr, JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let broker = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            match stream.read_packet().unwrap() {
+                Packet::Connect(_) => (),
+                other => panic!("expected CONNECT, got {:?}", other)
+            }
+            stream.write_packet(&Packet::Connack(Connack {
+                session_present: false,
+                code: code,
+                reason_code: None,
+                reason: None,
+                server_keep_alive: None
+            })).unwrap();
+            stream.write_all(&then).unwrap();
+            let mut read = Vec::new();
+            let _ = stream.read_to_end(&mut read);
+            read
+        });
+        (addr, broker)
+    }
+
+    fn connect(rt: &Runtime, addr: SocketAddr, opts: ClientOptions) -> ::error::Result<AsyncClient> {
+        let stream = rt.block_on(TcpStream::connect(addr)).unwrap();
+        rt.block_on(AsyncClient::connect(stream, opts))
+    }
+
+    #[test]
+    fn frame_len_test() {
+        assert_eq!(frame_len(&[]).unwrap(), None);
+        assert_eq!(frame_len(&[0xd0]).unwrap(), None);
+        assert_eq!(frame_len(&[0xd0, 0x00]).unwrap(), Some(2));
+        assert_eq!(frame_len(&[0x30, 0x80, 0x01]).unwrap(), Some(131));
+        assert!(frame_len(&[0x30, 0xff, 0xff, 0xff, 0xff]).is_err());
+    }
+
+    #[test]
+    fn async_client_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let broker = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            match stream.read_packet().unwrap() {
+                Packet::Connect(_) => (),
+                other => panic!("expected CONNECT, got {:?}", other)
+            }
+            stream.write_packet(&Packet::Connack(Connack {
+                session_present: false,
+                code: ConnectReturnCode::Accepted,
+                reason_code: None,
+                reason: None,
+                server_keep_alive: None
+            })).unwrap();
+            match stream.read_packet().unwrap() {
+                Packet::Publish(ref publish) => assert_eq!(publish.topic_name, "a/b"),
+                other => panic!("expected PUBLISH, got {:?}", other)
+            }
+            let message = Publish {
+                dup: false,
+                qos: QoS::AtLeastOnce,
+                retain: false,
+                topic_name: "c".to_owned(),
+                pid: Some(PacketIdentifier(7)),
+                properties: Vec::new(),
+                payload: ::std::sync::Arc::new(b"hi".to_vec())
+            };
+            stream.write_packet(&Packet::Publish(Box::new(message))).unwrap();
+            stream.read_packet().unwrap()
+        });
+
+        let rt = Builder::new_current_thread().enable_all().build().unwrap();
+        let stream = rt.block_on(TcpStream::connect(addr)).unwrap();
+        let mut client = rt.block_on(AsyncClient::connect(stream, ClientOptions::new())).unwrap();
+        rt.block_on(client.publish("a/b", "hello", PubOpt::at_most_once())).unwrap();
+        let message = rt.block_on(client.r#await()).unwrap().unwrap();
+        assert_eq!(message.topic_str(), "c");
+        assert_eq!(*message.payload, b"hi".to_vec());
+        assert_eq!(broker.join().unwrap(), Packet::Puback(PacketIdentifier(7)));
+    }
+
+    #[test]
+    fn async_client_refused_test() {
+        let (addr, broker) = broker(ConnectReturnCode::NotAuthorized, Vec::new());
+        let rt = Builder::new_current_thread().enable_all().build().unwrap();
+        match connect(&rt, addr, ClientOptions::new()) {
+            Err(Error::ConnectionRefused { code: ConnectReturnCode::NotAuthorized, .. }) => (),
+            Err(err) => panic!("expected ConnectionRefused, got {:?}", err),
+            Ok(_) => panic!("expected ConnectionRefused")
+        }
+        broker.join().unwrap();
+    }
+
+    #[test]
+    fn async_client_handshake_timeout_test() {
+        // the broker never answers CONNECT
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let broker = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut read = Vec::new();
+            let _ = stream.read_to_end(&mut read);
+        });
+        let rt = Builder::new_current_thread().enable_all().build().unwrap();
+        let mut opts = ClientOptions::new();
+        opts.set_keep_alive(1);
+        let start = Instant::now();
+        match connect(&rt, addr, opts) {
+            Err(Error::Timeout) => (),
+            Err(err) => panic!("expected Timeout, got {:?}", err),
+            Ok(_) => panic!("connected without CONNACK")
+        }
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        broker.join().unwrap();
+    }
+
+    #[test]
+    fn async_client_server_disconnect_test() {
+        let (addr, broker) = broker(ConnectReturnCode::Accepted, vec![0xE0, 0x00]);
+        let rt = Builder::new_current_thread().enable_all().build().unwrap();
+        let mut client = connect(&rt, addr, ClientOptions::new()).unwrap();
+        match rt.block_on(client.r#await()) {
+            Err(Error::ServerDisconnect { code: 0x00, .. }) => (),
+            other => panic!("expected ServerDisconnect, got {:?}", other)
+        }
+        match rt.block_on(client.publish("a", "x", PubOpt::at_most_once())) {
+            Err(Error::Disconnected) => (),
+            other => panic!("expected Disconnected, got {:?}", other)
+        }
+        drop(client);
+        assert!(broker.join().unwrap().is_empty());
+    }
+
+    #[test]
+    fn async_client_keep_alive_timeout_test() {
+        // PINGREQ is never answered
+        let (addr, broker) = broker(ConnectReturnCode::Accepted, Vec::new());
+        let rt = Builder::new_current_thread().enable_all().build().unwrap();
+        let mut opts = ClientOptions::new();
+        opts.set_keep_alive(1);
+        let mut client = connect(&rt, addr, opts).unwrap();
+        let start = Instant::now();
+        match rt.block_on(client.r#await()) {
+            Err(Error::Timeout) => (),
+            other => panic!("expected Timeout, got {:?}", other)
+        }
+        assert!(start.elapsed() >= Duration::from_secs(2));
+        match rt.block_on(client.r#await()) {
+            Err(Error::Disconnected) => (),
+            other => panic!("expected Disconnected, got {:?}", other)
+        }
+        drop(client);
+        assert_eq!(broker.join().unwrap(), vec![0xC0, 0x00]);
+    }
+
+    #[test]
+    fn async_client_decode_error_test() {
+        let (addr, broker) = broker(ConnectReturnCode::Accepted, vec![0x30, 0xFF, 0xFF, 0xFF, 0xFF]);
+        let rt = Builder::new_current_thread().enable_all().build().unwrap();
+        let mut client = connect(&rt, addr, ClientOptions::new()).unwrap();
+        match rt.block_on(client.r#await()) {
+            Err(Error::Mqtt(MQError::MalformedRemainingLength)) => (),
+            other => panic!("expected MalformedRemainingLength, got {:?}", other)
+        }
+        // the same bytes aren't decoded again
+        match rt.block_on(client.r#await()) {
+            Err(Error::Disconnected) => (),
+            other => panic!("expected Disconnected, got {:?}", other)
+        }
+        drop(client);
+        broker.join().unwrap();
+    }
+}
@@ -0,0 +1,98 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+enum State {
+    Closed,
+    Open(Instant),
+    // one trial is let through, its outcome closes or opens again
+    HalfOpen
+}
+
+/// Opens after `failures` failures within `window`, stays open for
+/// `cooldown` and then lets a single trial through
+pub struct CircuitBreaker {
+    failures: usize,
+    window: Duration,
+    cooldown: Duration,
+    recent: VecDeque<Instant>,
+    state: State
+}
+
+impl CircuitBreaker {
+    pub fn new(failures: u32, window: Duration, cooldown: Duration) -> CircuitBreaker {
+        CircuitBreaker {
+            failures: failures as usize,
+            window,
+            cooldown,
+            recent: VecDeque::new(),
+            state: State::Closed
+        }
+    }
+
+    /// Whether an attempt may go ahead
+    pub fn allow(&mut self, now: Instant) -> bool {
+        match self.state {
+            State::Closed => true,
+            State::Open(since) if now.duration_since(since) >= self.cooldown => {
+                self.state = State::HalfOpen;
+                true
+            }
+            State::Open(_) | State::HalfOpen => false
+        }
+    }
+
+    pub fn success(&mut self) {
+        self.recent.clear();
+        self.state = State::Closed;
+    }
+
+    pub fn failure(&mut self, now: Instant) {
+        if let State::HalfOpen = self.state {
+            self.state = State::Open(now);
+            return;
+        }
+        while self.recent.front().is_some_and(|at| now.duration_since(*at) > self.window) {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(now);
+        if self.recent.len() >= self.failures {
+            self.recent.clear();
+            self.state = State::Open(now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+    use super::CircuitBreaker;
+
+    #[test]
+    fn circuit_breaker_test() {
+        let mut now = Instant::now();
+        let mut breaker = CircuitBreaker::new(2, Duration::from_secs(60), Duration::from_millis(20));
+        breaker.failure(now);
+        assert!(breaker.allow(now));
+        breaker.failure(now);
+        assert!(!breaker.allow(now));
+        assert!(!breaker.allow(now + Duration::from_millis(19)));
+
+        now += Duration::from_millis(20);
+        // a single trial
+        assert!(breaker.allow(now));
+        assert!(!breaker.allow(now));
+        breaker.failure(now);
+        assert!(!breaker.allow(now));
+
+        now += Duration::from_millis(20);
+        assert!(breaker.allow(now));
+        breaker.success();
+        assert!(breaker.allow(now));
+        breaker.failure(now);
+        assert!(breaker.allow(now));
+
+        // failures further apart than the window don't add up
+        breaker.failure(now + Duration::from_secs(61));
+        assert!(breaker.allow(now + Duration::from_secs(61)));
+    }
+}
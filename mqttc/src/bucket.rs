@@ -0,0 +1,56 @@
+use std::cmp;
+use std::time::{Duration, Instant};
+
+/// Token bucket holding up to one second worth of tokens
+pub struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant
+}
+
+impl TokenBucket {
+    pub fn new(per_sec: u32, now: Instant) -> TokenBucket {
+        let per_sec = cmp::max(per_sec, 1);
+        TokenBucket {
+            rate: per_sec as f64,
+            tokens: per_sec as f64,
+            last: now
+        }
+    }
+
+    /// Takes a token, or tells how long until the next one is there
+    pub fn take(&mut self, now: Instant) -> Option<Duration> {
+        let elapsed = now.saturating_duration_since(self.last);
+        self.last = now;
+        let refill = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        self.tokens = (self.tokens + refill * self.rate).min(self.rate);
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            None
+        } else {
+            let wait = (1.0 - self.tokens) / self.rate;
+            Some(Duration::new(wait as u64, (wait.fract() * 1e9) as u32))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+    use super::TokenBucket;
+
+    #[test]
+    fn token_bucket_test() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(10, now);
+        for _ in 0..10 {
+            assert!(bucket.take(now).is_none());
+        }
+        assert_eq!(bucket.take(now), Some(Duration::from_millis(100)));
+
+        let now = now + Duration::from_millis(100);
+        assert!(bucket.take(now).is_none());
+        assert!(bucket.take(now).is_some());
+    }
+}
@@ -1,30 +1,59 @@
-use std::collections::{HashMap, VecDeque};
-use std::io::{Write, ErrorKind};
+use std::cmp;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write, ErrorKind};
+use std::iter;
 use std::net::{SocketAddr, ToSocketAddrs};
 use std::time::{Duration, Instant};
-use std::thread;
-use netopt::NetworkOptions;
+use std::sync::Arc;
+use netopt::{NetworkOptions, NetworkStream, TlsInfo, ToHostName, TransportKind};
+use netopt::mock::MockStream;
 use rand::{self, Rng};
-use mqtt3::{MqttRead, MqttWrite, Message, QoS, SubscribeReturnCodes, SubscribeTopic};
-use mqtt3::{self, Protocol, Packet, ConnectReturnCode, PacketIdentifier, LastWill, ToTopicPath};
+use mqtt3::{Message, QoS, SubscribeReturnCodes, SubscribeTopic};
+use mqtt3::{self, Protocol, Packet, Property, PacketIdentifier, LastWill, ToTopicPath, TopicPath};
 use error::{Error, Result};
-use sub::Subscription;
-use {Connection, PubSub, ClientState, ReconnectMethod, PubOpt, ToPayload, ToSubTopics, ToUnSubTopics};
+use bucket::TokenBucket;
+use breaker::CircuitBreaker;
+use clock::{Clock, SystemClock};
+use session::ClientCore;
+use {CodecRegistry, Connection, OfflineClient, PubSub, ClientState, Event, RateLimitMethod, ReconnectMethod, TakeoverMethod, UnexpectedPacketMethod, PubOpt, ToPayload, ToSubTopics, ToUnSubTopics};
 use store::Store;
 
 // #[derive(Clone)]
 pub struct ClientOptions {
-    protocol: Protocol,
-    keep_alive: Option<Duration>,
-    clean_session: bool,
-    client_id: Option<String>,
+    pub(crate) protocol: Protocol,
+    pub(crate) keep_alive: Option<Duration>,
+    pub(crate) clean_session: bool,
+    pub(crate) client_id: Option<String>,
+    client_id_prefix: String,
+    client_id_source: Option<Box<dyn FnMut() -> u64 + Send>>,
     last_will: Option<LastWill>,
     username: Option<String>,
     password: Option<String>,
     reconnect: ReconnectMethod,
-
-    incomming_store: Option<Box<dyn Store + Send>>,
-    outgoing_store: Option<Box<dyn Store + Send>>,
+    max_reconnect_attempts: Option<u32>,
+    stability_window: Option<Duration>,
+    pub(crate) message_expiry: Option<Duration>,
+    session_expiry: Option<Duration>,
+    flush_threshold: Option<usize>,
+    codecs: Option<CodecRegistry>,
+    publish_rate_limit: Option<u32>,
+    rate_limit_method: RateLimitMethod,
+    circuit_breaker: Option<(u32, Duration, Duration)>,
+    pub(crate) unexpected_packet: UnexpectedPacketMethod,
+    takeover: TakeoverMethod,
+    takeover_detection: Option<(u32, Duration)>,
+    pub(crate) strict_ordering: bool,
+    pub(crate) validate_utf8: bool,
+    pub(crate) no_local: bool,
+    pub(crate) echo_window: Option<Duration>,
+    auto_resubscribe: bool,
+    pub(crate) max_inflight_subscribes: usize,
+    reuse_payloads: bool,
+    connect_properties: Vec<Property>,
+
+    pub(crate) incomming_store: Option<Box<dyn Store + Send>>,
+    pub(crate) outgoing_store: Option<Box<dyn Store + Send>>,
+    pub(crate) clock: Arc<dyn Clock>,
 }
 
 impl ClientOptions {
@@ -42,17 +71,46 @@ impl ClientOptions {
             keep_alive: Some(Duration::new(30, 0)),
             clean_session: true,
             client_id: None,
+            client_id_prefix: "mqttc_".to_owned(),
+            client_id_source: None,
             last_will: None,
             username: None,
             password: None,
             reconnect: ReconnectMethod::ForeverDisconnect,
+            max_reconnect_attempts: None,
+            stability_window: None,
+            message_expiry: None,
+            session_expiry: None,
+            flush_threshold: None,
+            codecs: None,
+            publish_rate_limit: None,
+            rate_limit_method: RateLimitMethod::Block,
+            circuit_breaker: None,
+            takeover: TakeoverMethod::Disconnect,
+            takeover_detection: Some((3, Duration::from_secs(1))),
+            strict_ordering: true,
+            validate_utf8: false,
+            no_local: false,
+            echo_window: None,
+            auto_resubscribe: true,
+            max_inflight_subscribes: 256,
+            reuse_payloads: false,
+            connect_properties: Vec::new(),
+            unexpected_packet: UnexpectedPacketMethod::Disconnect,
             incomming_store: None,
             outgoing_store: None,
+            clock: Arc::new(SystemClock),
         }
     }
 
+    /// 0 disables keep alive, like it does on the wire: no PINGREQ is
+    /// ever sent and reads don't time out.
     pub fn set_keep_alive(&mut self, secs: u16) -> &mut ClientOptions {
-        self.keep_alive = Some(Duration::new(secs as u64, 0));
+        self.keep_alive = if secs == 0 {
+            None
+        } else {
+            Some(Duration::new(secs as u64, 0))
+        };
         self
     }
 
@@ -81,10 +139,44 @@ impl ClientOptions {
         self
     }
 
+    /// Generates `<prefix><16 hex digits>` from a random u64. The former u32
+    /// suffix had even odds of a collision at about 77 thousand clients,
+    /// with 64 bits it takes about 5 billion. The default `mqttc_` prefix
+    /// keeps the id within the 23 bytes every MQTT 3.1.1 broker must accept.
     pub fn generate_client_id(&mut self) -> &mut ClientOptions {
-        let mut rng = rand::thread_rng();
-        let id = rng.gen::<u32>();
-        self.client_id = Some(format!("mqttc_{}", id));
+        let id = match self.client_id_source {
+            Some(ref mut source) => source(),
+            None => rand::thread_rng().gen::<u64>(),
+        };
+        self.client_id = Some(format!("{}{:016x}", self.client_id_prefix, id));
+        self
+    }
+
+    /// Prefix of generated client ids, for brokers whose ACLs match on it.
+    /// Defaults to `mqttc_`.
+    pub fn set_client_id_prefix(&mut self, prefix: String) -> &mut ClientOptions {
+        self.client_id_prefix = prefix;
+        self
+    }
+
+    /// Where the random part of generated client ids comes from, instead of
+    /// `rand::thread_rng()`. A seeded RNG makes them reproducible:
+    ///
+    /// ```
+    /// # extern crate mqttc;
+    /// # extern crate rand;
+    /// use rand::{Rng, SeedableRng};
+    /// use rand::rngs::StdRng;
+    /// # fn main() {
+    /// let mut rng = StdRng::seed_from_u64(42);
+    /// let mut opts = mqttc::ClientOptions::new();
+    /// opts.set_client_id_source(move || rng.gen());
+    /// # }
+    /// ```
+    pub fn set_client_id_source<F>(&mut self, source: F) -> &mut ClientOptions
+        where F: FnMut() -> u64 + Send + 'static
+    {
+        self.client_id_source = Some(Box::new(source));
         self
     }
 
@@ -98,17 +190,32 @@ impl ClientOptions {
         self
     }
 
+    /// Adds a User Property to the CONNECT, e.g. for a broker's custom
+    /// authorizer. Only sent over MQTT 5.
+    pub fn set_connect_user_property(&mut self, key: String, value: String) -> &mut ClientOptions {
+        self.connect_properties.push(Property::UserProperty(key, value));
+        self
+    }
+
+    /// Replaces all CONNECT properties, user properties included, for the
+    /// ones the options don't cover. Only sent over MQTT 5.
+    pub fn set_connect_properties(&mut self, properties: Vec<Property>) -> &mut ClientOptions {
+        self.connect_properties = properties;
+        self
+    }
+
     pub fn set_last_will<T: ToTopicPath, P: ToPayload>(&mut self,
                                                        topic: T,
-                                                       message: String,
+                                                       message: P,
                                                        pub_opt: PubOpt)
                                                        -> Result<()> {
         let topic_name = topic.to_topic_name()?;
         self.last_will = Some(LastWill {
             topic: topic_name.to_topic_name()?.path(),
-            message: message,
+            message: message.to_payload().to_vec(),
             qos: pub_opt.qos(),
             retain: pub_opt.is_retain(),
+            properties: Vec::new(),
         });
         Ok(())
     }
@@ -118,68 +225,299 @@ impl ClientOptions {
         self
     }
 
+    /// Applies to the first connection too: unless it's `ForeverDisconnect`
+    /// a broker that isn't up yet is retried instead of failing `connect`.
     pub fn set_reconnect(&mut self, reconnect: ReconnectMethod) -> &mut ClientOptions {
         self.reconnect = reconnect;
         self
     }
 
-    pub fn connect<A: ToSocketAddrs>(mut self, addr: A, netopt: NetworkOptions) -> Result<Client> {
-        if self.client_id == None {
-            self.generate_client_id();
-        }
+    /// Gives up reconnecting after `attempts` failures in a row, then the
+    /// client stays disconnected and `accept` returns `Error::Disconnected`.
+    /// The counter is reset once a connection proves stable, see
+    /// `set_stability_window`.
+    pub fn set_max_reconnect_attempts(&mut self, attempts: u32) -> &mut ClientOptions {
+        self.max_reconnect_attempts = Some(attempts);
+        self
+    }
+
+    /// A connection which receives a packet after being up this long is
+    /// stable and resets the reconnect attempts. A broker accepting the
+    /// CONNECT and dropping us right after doesn't. The longest reconnect
+    /// delay by default, `max` of `ExponentialBackoff`.
+    pub fn set_stability_window(&mut self, window: Duration) -> &mut ClientOptions {
+        self.stability_window = Some(window);
+        self
+    }
+
+    /// Message Expiry Interval sent with every PUBLISH, the broker drops
+    /// messages it couldn't deliver in time. Only sent over MQTT 5.
+    pub fn set_message_expiry(&mut self, expiry: Duration) -> &mut ClientOptions {
+        self.message_expiry = Some(expiry);
+        self
+    }
+
+    /// How long the broker keeps the session once the connection is gone.
+    /// Only sent over MQTT 5, where a session without it ends with its
+    /// connection even with `clean_session` off. Replaces a Session Expiry
+    /// Interval given to `set_connect_properties`.
+    pub fn set_session_expiry(&mut self, expiry: Duration) -> &mut ClientOptions {
+        self.session_expiry = Some(expiry);
+        self
+    }
+
+    /// Reads a payload into the buffer of the previous message if that was
+    /// dropped already, which spares an allocation per message on a busy
+    /// subscription. The client keeps a reference to the last payload until
+    /// the next read.
+    pub fn set_reuse_payloads(&mut self, reuse: bool) -> &mut ClientOptions {
+        self.reuse_payloads = reuse;
+        self
+    }
+
+    /// Flushes as soon as more than `bytes` are waiting to be written, so
+    /// a burst of publishes doesn't pile up in memory before the next flush.
+    pub fn set_flush_threshold(&mut self, bytes: usize) -> &mut ClientOptions {
+        self.flush_threshold = Some(bytes);
+        self
+    }
+
+    /// Caps publishes per second, bursts of up to a second's worth are let
+    /// through. See `set_rate_limit_method` for what happens past it.
+    pub fn set_publish_rate_limit(&mut self, msgs_per_sec: u32) -> &mut ClientOptions {
+        self.publish_rate_limit = Some(msgs_per_sec);
+        self
+    }
+
+    /// `RateLimitMethod::Block` by default
+    pub fn set_rate_limit_method(&mut self, method: RateLimitMethod) -> &mut ClientOptions {
+        self.rate_limit_method = method;
+        self
+    }
+
+    /// Publishes fail with `Error::CircuitOpen` for `cooldown` once
+    /// `failures` connections dropped within `window` right after a
+    /// publish, before the broker answered anything. A single publish is
+    /// tried after the cooldown: the next packet from the broker closes the
+    /// breaker, another drop opens it again.
+    pub fn set_circuit_breaker(&mut self,
+                               failures: u32,
+                               window: Duration,
+                               cooldown: Duration)
+                               -> &mut ClientOptions {
+        self.circuit_breaker = Some((failures, window, cooldown));
+        self
+    }
+
+    /// `TakeoverMethod::Disconnect` by default, which ends a war of two
+    /// clients with the same id kicking each other out
+    pub fn set_takeover(&mut self, method: TakeoverMethod) -> &mut ClientOptions {
+        self.takeover = method;
+        self
+    }
+
+    /// Connections which drop within `within` after the CONNACK, `drops`
+    /// times in a row, are taken for a session takeover. That's the only
+    /// sign of it before MQTT 5. 3 drops within a second by default, None
+    /// only trusts the MQTT 5 reason code.
+    pub fn set_takeover_detection(&mut self, detection: Option<(u32, Duration)>) -> &mut ClientOptions {
+        self.takeover_detection = detection;
+        self
+    }
+
+    /// PUBACKs have to come in the order the publishes went out, as the spec
+    /// demands within a session, or it's a `ProtocolViolation`. Lenient
+    /// matching by packet identifier works with brokers which reorder them.
+    /// Strict by default.
+    pub fn set_strict_ordering(&mut self, strict: bool) -> &mut ClientOptions {
+        self.strict_ordering = strict;
+        self
+    }
+
+    /// Subscribes again on reconnect unless the broker resumed the session.
+    /// On by default, turn it off to manage subscriptions yourself.
+    pub fn set_auto_resubscribe(&mut self, resubscribe: bool) -> &mut ClientOptions {
+        self.auto_resubscribe = resubscribe;
+        self
+    }
+
+    /// SUBSCRIBEs and UNSUBSCRIBEs waiting for their acks, together. One
+    /// more fails with `Error::InflightLimit`. 256 by default.
+    pub fn set_max_inflight_subscribes(&mut self, max: usize) -> &mut ClientOptions {
+        self.max_inflight_subscribes = max;
+        self
+    }
+
+    /// Warns about messages flagged as UTF-8 text whose payload isn't,
+    /// they are delivered anyway. Off by default.
+    pub fn set_validate_utf8(&mut self, validate: bool) -> &mut ClientOptions {
+        self.validate_utf8 = validate;
+        self
+    }
+
+    /// MQTT 5: every filter is subscribed with No Local, the broker doesn't
+    /// send our own publishes back. Bridges need it to avoid echo loops.
+    /// Older protocols have no such option, see `set_echo_window`.
+    pub fn set_no_local(&mut self, no_local: bool) -> &mut ClientOptions {
+        self.no_local = no_local;
+        self
+    }
+
+    /// Drops incoming messages on a topic we published to within `window`,
+    /// taking them for our own. A heuristic for MQTT 3.1.1 brokers which
+    /// can't do No Local; it also drops anyone else's message on that topic
+    /// meanwhile. Off by default.
+    pub fn set_echo_window(&mut self, window: Option<Duration>) -> &mut ClientOptions {
+        self.echo_window = window;
+        self
+    }
+
+    /// `UnexpectedPacketMethod::Disconnect` by default
+    pub fn set_unexpected_packet(&mut self, method: UnexpectedPacketMethod) -> &mut ClientOptions {
+        self.unexpected_packet = method;
+        self
+    }
+
+    /// Decoders `Client::poll` applies to the payloads of received messages
+    pub fn set_codecs(&mut self, codecs: CodecRegistry) -> &mut ClientOptions {
+        self.codecs = Some(codecs);
+        self
+    }
+
+    pub fn connect<A: ToSocketAddrs + ToHostName>(self, addr: A, netopt: NetworkOptions) -> Result<Client> {
+        let mut client = self.connect_transport(addr, netopt)?;
+        client.handshake()?;
+        Ok(client)
+    }
+
+    /// Connects to an in-memory broker which accepts the CONNECT and stays
+    /// silent, see `OfflineClient`
+    pub fn build_offline(self) -> Result<OfflineClient> {
+        let connack = match self.protocol {
+            Protocol::MQTT(5) => vec![0x20, 0x03, 0x00, 0x00, 0x00],
+            _ => vec![0x20, 0x02, 0x00, 0x00],
+        };
+        let mut stream = MockStream::with_vec(connack);
+        let mut netopt = NetworkOptions::new();
+        netopt.attach(stream.clone());
+        let client = self.connect("127.0.0.1:1883", netopt)?;
+        stream.set_silent(true);
+        Ok(OfflineClient::new(client, stream))
+    }
 
+    /// Opens the network connection only, `Client::handshake` has to be
+    /// called before anything else to send CONNECT and wait for CONNACK.
+    /// A host name in `addr` is the one TLS verifies, reconnecting too.
+    pub fn connect_transport<A: ToSocketAddrs + ToHostName>(self,
+                                                            addr: A,
+                                                            netopt: NetworkOptions)
+                                                            -> Result<Client> {
+        let host = addr.host_name();
         let addr = addr.to_socket_addrs()?.next().expect("Socket address is broken");
 
         info!(" Connecting to {}", addr);
-        let conn = self._reconnect(addr, &netopt)?;
-
-        let mut client = Client {
+        let mut conn = Connection::new(self._first_connect(addr, host.as_ref(), &netopt)?)?;
+        conn.set_reuse_payloads(self.reuse_payloads);
+        let now = self.clock.now();
+
+        Ok(Client {
+            bucket: self.publish_rate_limit.map(|rate| TokenBucket::new(rate, now)),
+            breaker: self.circuit_breaker.map(|(failures, window, cooldown)| {
+                CircuitBreaker::new(failures, window, cooldown)
+            }),
+            unanswered_publish: false,
+            quick_drops: 0,
             addr: addr,
-            state: ClientState::Disconnected,
+            host,
             netopt: netopt,
-            opts: self,
+            core: ClientCore::new(self),
             conn: conn,
-            session_present: false,
-
-            // Queues
-            last_flush: Instant::now(),
-            last_pid: PacketIdentifier::zero(),
-            await_ping: false,
-            incomming_pub: VecDeque::new(),
-            incomming_rec: VecDeque::new(),
-            incomming_rel: VecDeque::new(),
-            outgoing_ack: VecDeque::new(),
-            outgoing_rec: VecDeque::new(),
-            outgoing_comp: VecDeque::new(),
-            await_suback: VecDeque::new(),
-            await_unsuback: VecDeque::new(),
-            subscriptions: HashMap::new(), // Subscriptions
-        };
-
-        // Send CONNECT then wait CONNACK
-        client._handshake()?;
-
-        Ok(client)
+            reconnect_attempts: 0,
+            last_disconnect: None,
+            deadline: None,
+            connected_since: None,
+            uptime: Duration::from_secs(0),
+            last_flush: now,
+            deferred: VecDeque::new(),
+            paused: false,
+        })
     }
 
     fn _reconnect(&self,
                   addr: SocketAddr,
+                  host: Option<&String>,
                   netopt: &NetworkOptions)
-                  -> Result<Connection> {
-        info!("yep");
-        let stream = netopt.connect(addr)?;
-        stream.set_read_timeout(self.keep_alive).unwrap();
-        stream.set_write_timeout(self.keep_alive).unwrap();
-        Ok(Connection::new(stream)?)
+                  -> Result<NetworkStream> {
+        let stream = netopt.connect_to(addr, host.map(|host| host.as_str()))?;
+        stream.set_read_timeout(self.keep_alive)?;
+        stream.set_write_timeout(self.keep_alive)?;
+        Ok(stream)
+    }
+
+    // The first connection is retried like a dropped one is reconnected,
+    // up to `max_reconnect_attempts` retries
+    fn _first_connect(&self,
+                      addr: SocketAddr,
+                      host: Option<&String>,
+                      netopt: &NetworkOptions)
+                      -> Result<NetworkStream> {
+        let mut attempts = 0;
+        loop {
+            let err = match self._reconnect(addr, host, netopt) {
+                Ok(stream) => return Ok(stream),
+                Err(err) => err,
+            };
+            let delay = match self._reconnect_delay(attempts) {
+                Some(delay) => delay,
+                None => return Err(err),
+            };
+            if let Some(max) = self._max_reconnect_attempts() {
+                if attempts >= max {
+                    error!("  Gave up connecting after {} attempts", attempts + 1);
+                    return Err(err);
+                }
+            }
+            attempts += 1;
+            error!("  Connect failed: {:?}", err);
+            info!("  Retry in {} seconds", delay.as_secs());
+            self.clock.sleep(delay);
+        }
+    }
+
+    // The wait before reconnect attempt `attempt`, jitter included
+    fn _reconnect_delay(&self, attempt: u32) -> Option<Duration> {
+        let delay = self.reconnect.delay(attempt)?;
+        match self.reconnect {
+            ReconnectMethod::ExponentialBackoff { jitter, .. } if jitter.as_nanos() > 0 => {
+                let nanos = cmp::min(jitter.as_nanos(), u64::MAX as u128) as u64;
+                Some(delay + Duration::from_nanos(rand::thread_rng().gen_range(0..nanos)))
+            }
+            _ => Some(delay),
+        }
+    }
+
+    fn _max_reconnect_attempts(&self) -> Option<u32> {
+        match self.reconnect {
+            ReconnectMethod::ExponentialBackoff { max_attempts: Some(max), .. } => Some(max),
+            _ => self.max_reconnect_attempts,
+        }
     }
 
-    fn _generate_connect_packet(&self) -> Box<mqtt3::Connect> {
+    pub(crate) fn _generate_connect_packet(&self) -> Box<mqtt3::Connect> {
         let keep_alive = if let Some(dur) = self.keep_alive {
             dur.as_secs() as u16
         } else {
             0
         };
 
+        let mut properties = self.connect_properties.clone();
+        if let Some(expiry) = self.session_expiry {
+            let secs = cmp::min(expiry.as_secs(), u32::MAX as u64) as u32;
+            // the property may appear only once, set_session_expiry wins
+            properties.retain(|property| !matches!(*property, Property::SessionExpiryInterval(_)));
+            properties.push(Property::SessionExpiryInterval(secs));
+        }
+
         Box::new(mqtt3::Connect {
             protocol: self.protocol,
             keep_alive: keep_alive,
@@ -188,32 +526,39 @@ impl ClientOptions {
             last_will: self.last_will.clone(),
             username: self.username.clone(),
             password: self.password.clone(),
+            properties,
         })
     }
 }
 
 pub struct Client {
+    // Publish rate limit
+    bucket: Option<TokenBucket>,
+    breaker: Option<CircuitBreaker>,
+    // A publish went out and nothing came back since
+    unanswered_publish: bool,
+    // Connections in a row which dropped right after the CONNACK
+    quick_drops: u32,
     addr: SocketAddr,
-    state: ClientState,
+    // The broker's host name when connected by name
+    host: Option<String>,
     netopt: NetworkOptions,
-    opts: ClientOptions,
+    // Protocol state, options included
+    core: ClientCore,
     conn: Connection,
-    session_present: bool,
+    reconnect_attempts: u32,
+    // Why the connection dropped the last time
+    last_disconnect: Option<Error>,
+    // Limits reads of a call blocking for a specific packet
+    deadline: Option<Instant>,
 
-    // Queues
     last_flush: Instant,
-    last_pid: PacketIdentifier,
-    await_ping: bool,
-    incomming_pub: VecDeque<Box<Message>>, // QoS 1
-    incomming_rec: VecDeque<Box<Message>>, // QoS 2
-    incomming_rel: VecDeque<PacketIdentifier>, // QoS 2
-    outgoing_ack: VecDeque<Box<Message>>, // QoS 1
-    outgoing_rec: VecDeque<Box<Message>>, // QoS 2
-    outgoing_comp: VecDeque<PacketIdentifier>, // QoS 2
-    await_suback: VecDeque<Box<mqtt3::Subscribe>>,
-    await_unsuback: VecDeque<Box<mqtt3::Unsubscribe>>,
-    // Subscriptions
-    subscriptions: HashMap<String, Subscription>,
+    deferred: VecDeque<Box<Message>>, // received while blocked on an ack
+    // No messages are handed out, see `pause`
+    paused: bool,
+    connected_since: Option<Instant>,
+    // Time spent connected before the current connection
+    uptime: Duration,
 }
 
 impl PubSub for Client {
@@ -226,17 +571,17 @@ impl PubSub for Client {
     }
 
     fn subscribe<S: ToSubTopics>(&mut self, subs: S) -> Result<()> {
-        self._subscribe(subs)?;
+        let _ = self._subscribe(subs)?;
         self._flush()
     }
 
     fn unsubscribe<U: ToUnSubTopics>(&mut self, unsubs: U) -> Result<()> {
-        self._unsubscribe(unsubs)?;
-        self._flush()
+        self.core.unsubscribe(unsubs)?;
+        self._send()
     }
 
     fn disconnect(mut self) -> Result<()> {
-        // self._disconnect();
+        // self.core.disconnect();
         self._flush()
     }
 }
@@ -252,480 +597,1002 @@ impl Client {
                 }
                 Err(e) => {
                     match e {
-                        Error::Timeout => {
-                            if self.state == ClientState::Connected {
-                                if !self.await_ping {
-                                    let _ = self.ping();
-                                } else {
-                                    self._unbind();
-                                }
-                            } else {
-                                return Err(Error::Timeout);
-                            }
-                        }
+                        Error::Timeout => self._keep_alive()?,
                         _ => return Err(e),
                     }
                 }
             }
-            if self._normalized() {
+            if self.core.is_normalized() {
                 return Ok(None);
             }
         }
     }
 
-    pub fn accept(&mut self) -> Result<Option<Box<Message>>> {
-        match self.state {
-            ClientState::Connected | ClientState::Handshake => {
-                // Don't forget to send PING packets in time
-                if let Some(keep_alive) = self.opts.keep_alive {
-                    let elapsed = self.last_flush.elapsed();
-                    if elapsed >= keep_alive {
-                        return Err(Error::Timeout);
-                    }
-                    self.conn.set_read_timeout(Some(keep_alive - elapsed))?;
+    /// Received messages, one `await` per item: PINGREQs keep being sent
+    /// while the iterator waits, and reconnects happen inside of it as
+    /// `ReconnectMethod` says. It ends once the client stays disconnected,
+    /// `last_disconnect_reason` tells why. Other errors are yielded and the
+    /// iteration can go on.
+    pub fn messages<'a>(&'a mut self) -> impl Iterator<Item = Result<Box<Message>>> + 'a {
+        iter::from_fn(move || {
+            loop {
+                match self.r#await() {
+                    Ok(Some(message)) => return Some(Ok(message)),
+                    Ok(None) => (),
+                    Err(Error::Disconnected) => return None,
+                    Err(err) => return Some(Err(err)),
                 }
+            }
+        })
+    }
 
-                match self.conn.read_packet() {
-                    Ok(packet) => {
-                        match self._parse_packet(packet) {
-                            Ok(message) => Ok(message),
-                            Err(err) => {
-                                match err {
-                                    Error::ConnectionAbort => {
-                                        self._unbind();
-                                        Err(Error::ConnectionAbort)
-                                    }
-                                    err => {
-                                        error!("{:?}", err);
-                                        Err(err)
-                                    }
-                                }
-                            }
-                        }
-                    }
-                    Err(err) => {
-                        match err {
-                            mqtt3::MQError::UnexpectedEof => {
-                                error!("{:?}", err);
-                                if self._try_reconnect() {
-                                    Ok(None)
-                                } else {
-                                    Err(Error::Disconnected)
-                                }
-                            }
-                            mqtt3::MQError::Io(e) => {
-                                match e.kind() {
-                                    ErrorKind::WouldBlock | ErrorKind::TimedOut => {
-                                        Err(Error::Timeout)
-                                    }
-                                    ErrorKind::UnexpectedEof |
-                                    ErrorKind::ConnectionRefused |
-                                    ErrorKind::ConnectionReset |
-                                    ErrorKind::ConnectionAborted => {
-                                        error!("{:?}", e);
-                                        self._unbind();
-                                        if self._try_reconnect() {
-                                            Ok(None)
-                                        } else {
-                                            Err(Error::Disconnected)
-                                        }
-                                    }
-                                    _ => {
-                                        error!("{:?}", e);
-                                        self._unbind();
-                                        Err(Error::from(e))
-                                    }
-                                }
-                            }
-                            _ => {
-                                error!("{:?}", err);
-                                Err(Error::from(err))
-                            }
-                        }
-                    }
-                }
+    /// Publishes `len` bytes read from `payload` without holding them in
+    /// memory, they are copied to the socket as they are read. QoS 0 only:
+    /// a QoS 1 or 2 message may have to be sent again, which takes its
+    /// payload, so those fail with `Error::UnsupportedFeature`.
+    ///
+    /// Fails without sending anything while earlier packets can't be
+    /// flushed. A reader shorter than `len` or a failed write leaves a
+    /// partial packet on the wire: the connection is dropped and
+    /// `Error::Disconnected` returned, `last_disconnect_reason` tells why.
+    pub fn publish_stream<T, R>(&mut self,
+                                topic: T,
+                                mut payload: R,
+                                len: usize,
+                                pubopt: PubOpt)
+                                -> Result<()>
+        where T: ToTopicPath,
+              R: Read
+    {
+        if pubopt.qos() != QoS::AtMostOnce {
+            return Err(Error::UnsupportedFeature);
+        }
+        self._rate_limit()?;
+        self._check_breaker()?;
+        self._flush()?;
+        if self.conn.pending() > 0 {
+            return Err(Error::from(io::Error::new(ErrorKind::WouldBlock, "earlier packets are still pending")));
+        }
+        let mut message = self.core._message(topic, Arc::new(Vec::new()), pubopt)?;
+        let now = self.core.opts.clock.now();
+        self.core._track_publish(&mut message, now)?;
+
+        debug!("       Publish {} {} > {} bytes streamed",
+               message.qos.to_u8(),
+               message.topic_str(),
+               len);
+        let publish = message.to_pub(None, false);
+        match self.conn.write_publish_stream(&publish, &mut payload, len, self.core.opts.protocol) {
+            Ok(()) => {
+                self.last_flush = self.core.opts.clock.now();
+                self.unanswered_publish = true;
+                Ok(())
             }
-            ClientState::Disconnected => {
-                if self._try_reconnect() {
-                    Ok(None)
-                } else {
-                    Err(Error::Disconnected)
-                }
+            Err(err) => {
+                error!("{:?}", err);
+                self.last_disconnect = Some(Error::Mqtt(err));
+                self._unbind();
+                Err(Error::Disconnected)
             }
         }
     }
 
-    pub fn reconnect(&mut self) -> Result<()> {
-        if self.state == ClientState::Connected {
-            warn!("mqttc is already connected");
-            return Ok(());
+    /// Publishes and waits up to `timeout` for the broker to acknowledge a
+    /// QoS 1 or 2 message (PUBACK, PUBCOMP), `Error::Timeout` otherwise. The
+    /// message stays pending then, a late ack still completes it. QoS 0
+    /// returns once flushed. Messages received meanwhile are kept for the
+    /// following `accept`/`await` calls.
+    pub fn publish_with_timeout<T, P>(&mut self,
+                                      topic: T,
+                                      payload: P,
+                                      pubopt: PubOpt,
+                                      timeout: Duration)
+                                      -> Result<()>
+        where T: ToTopicPath,
+              P: ToPayload
+    {
+        let pid = self._publish(topic, payload, pubopt)?;
+        self._flush()?;
+        let pid = match pid {
+            Some(pid) => pid,
+            None => return Ok(()),
         };
-        let conn = self.opts._reconnect(self.addr, &self.netopt)?;
-        self.conn = conn;
-        self._handshake()?;
 
-        self._resubscribe();
+        let deadline = self.core.opts.clock.now() + timeout;
+        self.deadline = Some(deadline);
+        let result = self._await_publish_ack(pid, deadline);
+        self.deadline = None;
+        result
+    }
 
-        Ok(())
+    fn _await_publish_ack(&mut self, pid: PacketIdentifier, deadline: Instant) -> Result<()> {
+        loop {
+            let pending = self.core.outgoing_ack.iter().any(|message| message.pid == Some(pid)) ||
+                          self.core.outgoing_rec.iter().any(|message| message.pid == Some(pid)) ||
+                          self.core.outgoing_comp.contains(&pid);
+            if !pending {
+                return Ok(());
+            }
+            match self._accept() {
+                Ok(Some(message)) => self.deferred.push_back(message),
+                Ok(None) => (),
+                Err(Error::Timeout) => {
+                    if self.core.opts.clock.now() >= deadline {
+                        return Err(Error::Timeout);
+                    }
+                    self._keep_alive()?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
     }
 
-    pub fn ping(&mut self) -> Result<()> {
-        debug!("       Pingreq");
-        self.await_ping = true;
-        self._write_packet(&Packet::Pingreq);
-        self._flush()
+    /// Deletes the retained message of `topic`. The broker drops it on an
+    /// empty retained publish, an empty publish without retain is just
+    /// delivered and clears nothing.
+    ///
+    /// ```
+    /// # extern crate mqttc;
+    /// # extern crate netopt;
+    /// # use mqttc::ClientOptions;
+    /// # use netopt::NetworkOptions;
+    /// # use netopt::mock::MockStream;
+    /// # fn main() {
+    /// # let mut stream = MockStream::with_vec(vec![0x20, 0x02, 0x00, 0x00]);
+    /// # let mut netopt = NetworkOptions::new();
+    /// # netopt.attach(stream.clone());
+    /// let mut client = ClientOptions::new().connect("127.0.0.1:1883", netopt).unwrap();
+    /// # stream.take_vec();
+    /// client.clear_retained("a/b").unwrap();
+    /// // QoS 0, retain, no payload
+    /// assert_eq!(stream.take_vec(), vec![0x31, 0x05, 0x00, 0x03, b'a', b'/', b'b']);
+    /// # }
+    /// ```
+    pub fn clear_retained<T: ToTopicPath>(&mut self, topic: T) -> Result<()> {
+        self.publish(topic, Vec::new(), PubOpt::at_most_once() | PubOpt::retain())
     }
 
-    pub fn complete(&mut self, pid: PacketIdentifier) -> Result<()> {
-        let same_pid = self.incomming_rel.pop_back();
-        if same_pid == Some(pid) {
-            self._write_packet(&Packet::Pubcomp(pid));
-            self._flush()?;
+    /// Subscribes and blocks until the SUBACK arrives, returning its codes
+    /// in the order of the requested topics. Messages received meanwhile are
+    /// kept and handed out by the following `accept`/`await` calls.
+    pub fn subscribe_await<S: ToSubTopics>(&mut self,
+                                           subs: S,
+                                           timeout: Duration)
+                                           -> Result<Vec<SubscribeReturnCodes>> {
+        let pid = self._subscribe(subs)?;
+        self._flush()?;
+
+        let deadline = self.core.opts.clock.now() + timeout;
+        self.deadline = Some(deadline);
+        let result = self._await_suback(pid, deadline);
+        self.deadline = None;
+        result
+    }
 
-            if let Some(ref mut store) = self.opts.incomming_store {
-                store.delete(pid)?;
-                Ok(())
-            } else {
-                return Err(Error::IncommingStorageAbsent);
+    /// Subscribes with an MQTT 5 Subscription Identifier, which the broker
+    /// puts on every message matching these filters (`Message::subscription_ids`).
+    /// Decoders registered for the identifier with `CodecRegistry::register_id`
+    /// get those messages without any topic matching.
+    pub fn subscribe_with_id<S: ToSubTopics>(&mut self, subs: S, id: u32) -> Result<()> {
+        if self.core.opts.protocol.level() < 5 {
+            return Err(Error::UnsupportedFeature);
+        }
+        // a variable byte integer, 0 isn't allowed
+        if id == 0 || id > 268_435_455 {
+            return Err(Error::Mqtt(mqtt3::MQError::IncorrectPacketFormat));
+        }
+        self.core.subscribe(subs, Some(id))?;
+        self._send()
+    }
+
+    /// Subscribes only to the filters not held yet, or held with a lower
+    /// granted QoS than requested. Returns the topics actually sent, no
+    /// SUBSCRIBE goes out when that's none.
+    pub fn subscribe_if_new<S: ToSubTopics>(&mut self, subs: S) -> Result<Vec<SubscribeTopic>> {
+        let topics: Vec<SubscribeTopic> = subs.to_subscribe_topics()?.filter(|topic| {
+            match self.core.subscriptions.get(&topic.topic_path) {
+                Some(sub) => sub.qos < topic.qos,
+                None => true,
             }
-        } else {
-            Err(Error::ProtocolViolation)
+        }).collect();
+        if !topics.is_empty() {
+            self._subscribe(topics.clone())?;
+            self._flush()?;
         }
+        Ok(topics)
     }
 
-    pub fn terminate(&mut self) {
-        self._unbind();
+    /// Unsubscribes from every held subscription whose filter is covered by
+    /// `pattern`, e.g. `a/#` covers `a/b` and `a/+`. MQTT unsubscribes by
+    /// exact filter only, so one UNSUBSCRIBE is sent per filter. Returns the
+    /// filters, they leave `subscriptions` as their UNSUBACKs arrive.
+    pub fn unsubscribe_matching<T: ToTopicPath>(&mut self, pattern: T) -> Result<Vec<String>> {
+        let pattern = pattern.to_topic_path()?;
+        let mut filters: Vec<String> = self.core.subscriptions
+                                           .values()
+                                           .filter(|sub| pattern.covers(&sub.topic_path))
+                                           .map(|sub| sub.topic_path.path())
+                                           .collect();
+        filters.sort();
+        for filter in filters.iter() {
+            self.core.unsubscribe(filter.as_str())?;
+            self._write_outbox()?;
+        }
+        self._flush()?;
+        Ok(filters)
     }
 
-    pub fn set_reconnect(&mut self, reconnect: ReconnectMethod) {
-        self.opts.reconnect = reconnect;
+    /// Subscribes to a single filter and blocks until the broker confirmed
+    /// this very subscription, even with other subscriptions in flight.
+    /// Returns the granted code.
+    pub fn subscribe_sync(&mut self,
+                          filter: &str,
+                          qos: QoS,
+                          timeout: Duration)
+                          -> Result<SubscribeReturnCodes> {
+        let mut codes = self.subscribe_await((filter.to_owned(), qos), timeout)?;
+        codes.pop().ok_or(Error::ProtocolViolation)
     }
 
-    pub fn session_present(&self) -> bool {
-        self.session_present
-    }
-
-    fn _normalized(&self) -> bool {
-        (self.state == ClientState::Connected) && (!self.await_ping) &&
-        (self.outgoing_ack.len() == 0) && (self.outgoing_rec.len() == 0) &&
-        (self.incomming_pub.len() == 0) && (self.incomming_rec.len() == 0) &&
-        (self.incomming_rel.len() == 0) && (self.await_suback.len() == 0) &&
-        (self.await_unsuback.len() == 0)
-    }
-
-    fn _parse_packet(&mut self, packet: Packet) -> Result<Option<Box<Message>>> {
-        trace!("{:?}", packet);
-        match self.state {
-            ClientState::Handshake => {
-                match packet {
-                    Packet::Connack(ref connack) => {
-                        if connack.code == ConnectReturnCode::Accepted {
-                            self.session_present = connack.session_present;
-                            self.state = ClientState::Connected;
-                            info!("    Connection accepted");
-                            Ok(None)
-                        } else {
-                            Err(Error::ConnectionRefused(connack.code))
-                        }
-                    }
-                    _ => Err(Error::HandshakeFailed),
-                }
-            }
-            ClientState::Connected => {
-                match packet {
-                    Packet::Connack(_) => Err(Error::AlreadyConnected),
-                    Packet::Publish(ref publish) => {
-                        let message = Message::from_pub(publish.clone())?;
-                        self._handle_message(message)
-                    }
-                    Packet::Puback(pid) => {
-                        if let Some(message) = self.outgoing_ack.pop_front() {
-                            if message.pid == Some(pid) {
-                                Ok(None)
-                            } else {
-                                Err(Error::PacketIdentifierError(crate::error::PacketIdentifierError::UnhandledPuback(pid)))
-                            }
-                        } else {
-                            Err(Error::PacketIdentifierError(crate::error::PacketIdentifierError::UnhandledPuback(pid)))
-                        }
-                    }
-                    Packet::Pubrec(pid) => {
-                        if let Some(message) = self.outgoing_rec.pop_front() {
-                            if message.pid == Some(pid) {
-                                self._write_packet(&Packet::Pubrel(pid));
-                                self._flush()?;
-
-                                self.outgoing_comp.push_back(pid);
-                                if let Some(ref mut store) = self.opts.outgoing_store {
-                                    store.delete(pid)?;
-                                } else {
-                                    return Err(Error::IncommingStorageAbsent);
-                                }
+    /// Subscribes, waits for `n` messages matching `subs` and unsubscribes
+    /// again. Whatever arrived is returned once `timeout` passes, messages
+    /// of other subscriptions are kept for the following `accept`/`await`
+    /// calls. Handy in tests and short scripts.
+    pub fn collect<S: ToSubTopics>(&mut self,
+                                   subs: S,
+                                   n: usize,
+                                   timeout: Duration)
+                                   -> Result<Vec<Message>> {
+        let topics: Vec<SubscribeTopic> = subs.to_subscribe_topics()?.collect();
+        let mut filters = Vec::with_capacity(topics.len());
+        for topic in topics.iter() {
+            filters.push(topic.topic_path.to_topic_path()?);
+        }
+        let unsubs: Vec<String> = topics.iter().map(|topic| topic.topic_path.clone()).collect();
+
+        let deadline = self.core.opts.clock.now() + timeout;
+        let kept = self.deferred.len();
+        let pid = self._subscribe(topics)?;
+        self._flush()?;
+        self.deadline = Some(deadline);
+        let result = self._await_suback(pid, deadline)
+                         .and_then(|_| self._collect(&filters, n, kept, deadline));
+        self.deadline = None;
+        let unsubscribed = self.unsubscribe(unsubs);
+        let messages = result?;
+        unsubscribed?;
+        Ok(messages)
+    }
 
-                                Ok(None)
-                            } else {
-                                Err(Error::PacketIdentifierError(crate::error::PacketIdentifierError::UnhandledPubrec(pid)))
-                            }
-                        } else {
-                            Err(Error::PacketIdentifierError(crate::error::PacketIdentifierError::UnhandledPubrec(pid)))
-                        }
-                    }
-                    Packet::Pubrel(pid) => {
-                        if let Some(message) = self.incomming_rec.pop_front() {
-                            if message.pid == Some(pid) {
-                                let message = if let Some(ref mut store) = self.opts
-                                                                               .incomming_store {
-                                    store.get(pid)?
-                                } else {
-                                    return Err(Error::IncommingStorageAbsent);
-                                };
-                                self.incomming_rel.push_back(pid);
-                                Ok(Some(message))
-                            } else {
-                                Err(Error::PacketIdentifierError(crate::error::PacketIdentifierError::UnhandledPubrel(pid)))
-                            }
-                        } else {
-                            Err(Error::PacketIdentifierError(crate::error::PacketIdentifierError::UnhandledPubrel(pid)))
-                        }
-                    }
-                    Packet::Pubcomp(pid) => {
-                        if let Some(_) = self.outgoing_comp.pop_front() {
-                            Ok(None)
-                        } else {
-                            Err(Error::PacketIdentifierError(crate::error::PacketIdentifierError::UnhandledPubcomp(pid)))
-                        }
-                    }
-                    Packet::Suback(ref suback) => {
-                        if let Some(subscribe) = self.await_suback.pop_front() {
-                            if subscribe.pid == suback.pid {
-                                if subscribe.topics.len() == suback.return_codes.len() {
-                                    let iter = suback.return_codes.iter().zip(&subscribe.topics);
-                                    for (ref code, ref sub_topic) in iter {
-                                        match **code {
-                                            SubscribeReturnCodes::Success(qos) => {
-                                                let sub = Subscription {
-                                                    pid: subscribe.pid,
-                                                    topic_path: sub_topic.topic_path
-                                                                              .to_topic_path()?,
-                                                    qos: qos,
-                                                };
-                                                self.subscriptions
-                                                    .insert(sub_topic.topic_path.clone(), sub);
-                                            }
-                                            SubscribeReturnCodes::Failure => {
-                                                // ignore subscription
-                                            }
-                                        }
-                                    }
-                                    Ok(None)
-                                } else {
-                                    Err(Error::ProtocolViolation)
-                                }
-                            } else {
-                                Err(Error::ProtocolViolation)
-                            }
-                        } else {
-                            Err(Error::ProtocolViolation)
-                        }
-                    }
-                    Packet::Unsuback(pid) => {
-                        if let Some(unsubscribe) = self.await_unsuback.pop_front() {
-                            if unsubscribe.pid == pid {
-                                for topic in unsubscribe.topics.iter() {
-                                    self.subscriptions.remove(topic);
-                                }
-                                Ok(None)
-                            } else {
-                                Err(Error::ProtocolViolation)
-                            }
-                        } else {
-                            Err(Error::ProtocolViolation)
-                        }
+    fn _collect(&mut self,
+                filters: &[TopicPath],
+                n: usize,
+                kept: usize,
+                deadline: Instant)
+                -> Result<Vec<Message>> {
+        let mut messages = Vec::new();
+        // received while the SUBACK was awaited
+        let mut i = kept;
+        while i < self.deferred.len() && messages.len() < n {
+            if filters.iter().any(|filter| filter.is_match(&self.deferred[i].topic)) {
+                messages.extend(self.deferred.remove(i).map(|message| *message));
+            } else {
+                i += 1;
+            }
+        }
+        while messages.len() < n {
+            match self._accept() {
+                Ok(Some(message)) => {
+                    if filters.iter().any(|filter| filter.is_match(&message.topic)) {
+                        messages.push(*message);
+                    } else {
+                        self.deferred.push_back(message);
                     }
-                    Packet::Pingresp => {
-                        self.await_ping = false;
-                        Ok(None)
+                }
+                Ok(None) => (),
+                Err(Error::Timeout) => {
+                    if self.core.opts.clock.now() >= deadline {
+                        break;
                     }
-                    _ => Err(Error::UnrecognizedPacket),
+                    self._keep_alive()?;
                 }
+                Err(e) => return Err(e),
             }
-            ClientState::Disconnected => Err(Error::ConnectionAbort),
         }
+        Ok(messages)
     }
 
-    fn _handle_message(&mut self, message: Box<Message>) -> Result<Option<Box<Message>>> {
-        debug!("       Publish {} {} < {} bytes",
-               message.qos.to_u8(),
-               message.topic.path(),
-               message.payload.len());
-        match message.qos {
-            QoS::AtMostOnce => Ok(Some(message)),
-            QoS::AtLeastOnce => {
-                self.incomming_pub.push_back(message.clone());
-                let pid = message.pid.unwrap();
-                // debug!("        Puback {}", pid.0);
-                self._write_packet(&Packet::Puback(pid));
-                self._flush()?;
-                // FIXME: can be repeated
-                let _ = self.incomming_pub.pop_front();
-
-                Ok(Some(message))
-            }
-            QoS::ExactlyOnce => {
-                self.incomming_rec.push_back(message.clone());
-                let pid = message.pid.unwrap();
-
-                if let Some(ref mut store) = self.opts.incomming_store {
-                    store.put(message)?;
-                } else {
-                    return Err(Error::IncommingStorageAbsent);
+    fn _await_suback(&mut self,
+                     pid: PacketIdentifier,
+                     deadline: Instant)
+                     -> Result<Vec<SubscribeReturnCodes>> {
+        loop {
+            match self.core.last_suback.take() {
+                Some((ack_pid, codes)) if ack_pid == pid => return Ok(codes),
+                _ => (),
+            }
+            // the connection dropped before the SUBACK
+            if !self.core.await_suback.iter().any(|sub| sub.pid == pid) {
+                return Err(Error::Disconnected);
+            }
+            match self._accept() {
+                Ok(Some(message)) => self.deferred.push_back(message),
+                Ok(None) => (),
+                Err(Error::Timeout) => {
+                    if self.core.opts.clock.now() >= deadline {
+                        return Err(Error::Timeout);
+                    }
+                    self._keep_alive()?;
                 }
-
-                self._write_packet(&Packet::Pubrec(pid));
-                self._flush()?;
-
-                Ok(None)
+                Err(e) => return Err(e),
             }
         }
     }
 
-    fn _handshake(&mut self) -> Result<()> {
-        self.state = ClientState::Handshake;
-        // send CONNECT
-        self._connect()?;
-        // wait CONNACK
-        let _ = self.await()?;
-        Ok(())
+    /// Waits up to `timeout` for something to happen on the connection.
+    /// Acks and connection changes are reported from the first CONNACK on,
+    /// `Event::Idle` means the timeout passed without any.
+    pub fn poll(&mut self, timeout: Duration) -> Result<Event> {
+        let deadline = self.core.opts.clock.now() + timeout;
+        self.deadline = Some(deadline);
+        let result = self._poll(deadline);
+        self.deadline = None;
+        result
     }
 
-    fn _try_reconnect(&mut self) -> bool {
-        match self.opts.reconnect {
-            ReconnectMethod::ForeverDisconnect => false,
-            ReconnectMethod::ReconnectAfter(dur) => {
-                info!("  Reconnect in {} seconds", dur.as_secs());
-                thread::sleep(dur);
-                let _ = self.reconnect();
-                true
+    fn _poll(&mut self, deadline: Instant) -> Result<Event> {
+        loop {
+            if let Some(event) = self.core.events.pop_front() {
+                return Ok(event);
             }
-        }
-    }
-
-    fn _connect(&mut self) -> Result<()> {
-        let connect = self.opts._generate_connect_packet();
-        debug!("       Connect {}", connect.client_id);
-        let packet = Packet::Connect(connect);
-        self._write_packet(&packet);
-        self._flush()
-    }
-
-    fn _publish<T: ToTopicPath, P: ToPayload>(&mut self,
-                                              topic: T,
-                                              payload: P,
-                                              pubopt: PubOpt)
-                                              -> Result<()> {
-        let mut message = Box::new(Message {
-            topic: topic.to_topic_name()?,
-            qos: pubopt.qos(),
-            retain: pubopt.is_retain(),
-            pid: None,
-            payload: payload.to_payload(),
-        });
-
-        match message.qos {
-            QoS::AtMostOnce => (),
-            QoS::AtLeastOnce => {
-                message.pid = Some(self._next_pid());
-                self.outgoing_ack.push_back(message.clone());
-            }
-            QoS::ExactlyOnce => {
-                message.pid = Some(self._next_pid());
-                if let Some(ref mut store) = self.opts.outgoing_store {
-                    store.put(message.clone())?;
-                } else {
-                    return Err(Error::OutgoingStorageAbsent);
+            match self.accept() {
+                Ok(Some(message)) => return self._message_event(message),
+                Ok(None) => (),
+                Err(Error::Timeout) => {
+                    if self.core.events.is_empty() && self.core.opts.clock.now() >= deadline {
+                        return Ok(Event::Idle);
+                    }
+                    if self.core.state == ClientState::Connected {
+                        self._keep_alive()?;
+                    }
                 }
-                self.outgoing_rec.push_back(message.clone());
+                // report the drop before the error
+                Err(Error::Disconnected) if !self.core.events.is_empty() => (),
+                Err(e) => return Err(e),
             }
         }
-
-        debug!("       Publish {} {} > {} bytes",
-               message.qos.to_u8(),
-               message.topic.path(),
-               message.payload.len());
-        let packet = Packet::Publish(message.to_pub(None, false));
-        self._write_packet(&packet);
-        Ok(())
     }
 
-    fn _subscribe<S: ToSubTopics>(&mut self, subs: S) -> Result<()> {
-        let iter = subs.to_subscribe_topics()?;
-        let subscribe = Box::new(mqtt3::Subscribe {
-            pid: self._next_pid(),
-            topics: iter.collect(),
-        });
-        debug!("     Subscribe {:?}", subscribe.topics);
-        self.await_suback.push_back(subscribe.clone());
-        self._write_packet(&Packet::Subscribe(subscribe));
-        Ok(())
+    // Messages are decoded on their way out rather than on arrival, QoS 2
+    // messages come back from the store on PUBREL
+    fn _message_event(&self, message: Box<Message>) -> Result<Event> {
+        match self.core.opts.codecs.as_ref().and_then(|codecs| codecs.decode(&message)) {
+            Some(decoded) => Ok(Event::Decoded(message, decoded?)),
+            None => Ok(Event::Message(message)),
+        }
     }
 
-    fn _unsubscribe<U: ToUnSubTopics>(&mut self, unsubs: U) -> Result<()> {
-        let iter = unsubs.to_unsubscribe_topics()?;
-        let unsubscribe = Box::new(mqtt3::Unsubscribe {
-            pid: self._next_pid(),
-            topics: iter.collect(),
-        });
-        debug!("   Unsubscribe {:?}", unsubscribe.topics);
-        self.await_unsuback.push_back(unsubscribe.clone());
-        self._write_packet(&Packet::Unsubscribe(unsubscribe));
-        Ok(())
+    pub fn accept(&mut self) -> Result<Option<Box<Message>>> {
+        if self.paused {
+            return self._accept_paused();
+        }
+        if let Some(message) = self.deferred.pop_front() {
+            return Ok(Some(message));
+        }
+        self._accept()
     }
 
-    fn _resubscribe(&mut self) {
-        let subs: Vec<SubscribeTopic> = self.subscriptions
-                                            .values()
-                                            .map(|sub| sub.to_subscribe_topic())
-                                            .collect();
-        let _ = self._subscribe(subs);
+    /// Stops handing out messages: `accept` leaves the socket alone until
+    /// keep alive is due, so a slow consumer pushes back on the broker
+    /// through TCP. Only what's needed to stay connected is read, messages
+    /// which come in before a PINGRESP are kept for after `resume`.
+    /// Without keep alive nothing is due: a paused `poll` sleeps until its
+    /// timeout, `accept`, `await` and `messages` fail with `Error::Paused`
+    /// right away.
+    pub fn pause(&mut self) {
+        self.paused = true;
     }
 
-    fn _disconnect(&mut self) {
-        self._write_packet(&Packet::Disconnect);
+    pub fn resume(&mut self) {
+        self.paused = false;
     }
 
-    #[inline]
-    fn _write_packet(&mut self, packet: &Packet) {
-        trace!("{:?}", packet);
-        self.conn.write_packet(&packet).unwrap();
+    pub fn is_paused(&self) -> bool {
+        self.paused
     }
 
-    fn _flush(&mut self) -> Result<()> {
-        // TODO: in case of disconnection, trying to reconnect
-        self.conn.flush()?;
-        self.last_flush = Instant::now();
-        Ok(())
+    fn _accept_paused(&mut self) -> Result<Option<Box<Message>>> {
+        // a PINGREQ is out, its PINGRESP has to be read
+        if self.core.state == ClientState::Connected && self.core.await_ping {
+            if let Some(message) = self._accept()? {
+                self.deferred.push_back(message);
+            }
+            return Ok(None);
+        }
+        let now = self.core.opts.clock.now();
+        let mut wait = self.core.keep_alive().map(|keep_alive| {
+            keep_alive.checked_sub(now.saturating_duration_since(self.last_flush)).unwrap_or_default()
+        });
+        if let Some(deadline) = self.deadline {
+            let left = deadline.saturating_duration_since(now);
+            wait = Some(wait.map_or(left, |wait| cmp::min(wait, left)));
+        }
+        match wait {
+            // time to PING
+            Some(wait) => {
+                self.core.opts.clock.sleep(wait);
+                Err(Error::Timeout)
+            }
+            // nothing will ever be due, waiting would block for good
+            None => Err(Error::Paused),
+        }
     }
 
-    fn _unbind(&mut self) {
-        let _ = self.conn.terminate();
-        self.await_unsuback.clear();
-        self.await_suback.clear();
-        self.await_ping = false;
-        self.state = ClientState::Disconnected;
-        info!("  Disconnected {}", self.opts.client_id.clone().unwrap());
+    fn _accept(&mut self) -> Result<Option<Box<Message>>> {
+        loop {
+            match self.core.state {
+                ClientState::Connected | ClientState::Handshake => {
+                    let handshake = self.core.state == ClientState::Handshake;
+                    match self._read() {
+                        // A broken handshake is reported to whoever started it,
+                        // reconnecting from inside of it would recurse into
+                        // another handshake
+                        Err(Error::Disconnected) if !handshake => (),
+                        result => return result,
+                    }
+                }
+                ClientState::Disconnected => {
+                    // Go on reading from the new connection, returning
+                    // Ok(None) here would make an await loop spin
+                    if !self._try_reconnect() {
+                        return Err(Error::Disconnected);
+                    }
+                }
+            }
+        }
+    }
+
+    fn _read(&mut self) -> Result<Option<Box<Message>>> {
+        let now = self.core.opts.clock.now();
+        let mut timeout = None;
+        // Don't forget to send PING packets in time
+        if let Some(keep_alive) = self.core.keep_alive() {
+            let elapsed = now.saturating_duration_since(self.last_flush);
+            if elapsed >= keep_alive {
+                return Err(Error::Timeout);
+            }
+            timeout = Some(keep_alive - elapsed);
+        }
+        if let Some(deadline) = self.deadline {
+            if now >= deadline {
+                return Err(Error::Timeout);
+            }
+            let left = deadline - now;
+            timeout = Some(timeout.map_or(left, |t| cmp::min(t, left)));
+        }
+        self.conn.set_read_timeout(timeout)?;
+
+        match self.conn.read_packet_reusing(self.core.opts.protocol) {
+            Ok(packet) => {
+                if self.unanswered_publish {
+                    self.unanswered_publish = false;
+                    if let Some(ref mut breaker) = self.breaker {
+                        breaker.success();
+                    }
+                }
+                if self.reconnect_attempts > 0 && self._is_stable() {
+                    self.reconnect_attempts = 0;
+                }
+                match self._handle(packet) {
+                    Ok(message) => Ok(message),
+                    Err(err) => {
+                        match err {
+                            Error::ConnectionAbort => {
+                                self.last_disconnect = Some(Error::ConnectionAbort);
+                                self._unbind();
+                                Err(Error::ConnectionAbort)
+                            }
+                            err @ Error::ServerDisconnect { .. } |
+                            err @ Error::UnexpectedPacket(_) => {
+                                error!("{}", err);
+                                Err(self._dropped(err))
+                            }
+                            err => {
+                                error!("{:?}", err);
+                                Err(err)
+                            }
+                        }
+                    }
+                }
+            }
+            Err(err) => {
+                match err {
+                    // a truncated packet can't be picked up again either, the
+                    // bytes read so far are gone with the buffer
+                    mqtt3::MQError::UnexpectedEof |
+                    mqtt3::MQError::IncompletePacket => {
+                        error!("{:?}", err);
+                        Err(self._dropped(Error::Mqtt(err)))
+                    }
+                    mqtt3::MQError::Io(e) => {
+                        match e.kind() {
+                            ErrorKind::WouldBlock | ErrorKind::TimedOut => {
+                                if let Some(timeout) = timeout {
+                                    self.core.opts.clock.read_timed_out(timeout);
+                                }
+                                Err(Error::Timeout)
+                            }
+                            ErrorKind::UnexpectedEof |
+                            ErrorKind::ConnectionRefused |
+                            ErrorKind::ConnectionReset |
+                            ErrorKind::ConnectionAborted => {
+                                error!("{:?}", e);
+                                Err(self._dropped(Error::Io(e)))
+                            }
+                            _ => {
+                                error!("{:?}", e);
+                                let reason = io::Error::new(e.kind(), e.to_string());
+                                self.last_disconnect = Some(Error::Io(reason));
+                                self._unbind();
+                                Err(Error::from(e))
+                            }
+                        }
+                    }
+                    _ => {
+                        error!("{:?}", err);
+                        Err(Error::from(err))
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn reconnect(&mut self) -> Result<()> {
+        if self.core.state == ClientState::Connected {
+            warn!("mqttc is already connected");
+            return Ok(());
+        };
+        let stream = self.core.opts._reconnect(self.addr, self.host.as_ref(), &self.netopt)?;
+        self.conn.reconnect_with(stream);
+        if let Err(err) = self._handshake() {
+            self._unbind();
+            return Err(err);
+        }
+        // a resumed session still has them
+        if self.core.opts.auto_resubscribe && !self.core.session_present {
+            self.core.resubscribe();
+            let _ = self._send();
+        }
+
+        Ok(())
+    }
+
+    /// Writes out the buffered packets. A socket which doesn't take them
+    /// all right now keeps the rest for the next flush.
+    pub fn flush(&mut self) -> Result<()> {
+        self._flush()
+    }
+
+    pub fn ping(&mut self) -> Result<()> {
+        self.core.ping();
+        self._send()
+    }
+
+    /// Sends a PINGREQ and waits up to `timeout` for its PINGRESP, returns
+    /// the round trip time or `Error::Timeout`. Messages received meanwhile
+    /// are kept for the following `accept`/`await` calls.
+    pub fn ping_and_wait(&mut self, timeout: Duration) -> Result<Duration> {
+        let start = self.core.opts.clock.now();
+        self.ping()?;
+        // PINGRESPs of earlier PINGREQs come first
+        let expected = self.core.pingreqs;
+
+        let deadline = start + timeout;
+        self.deadline = Some(deadline);
+        let result = self._await_pingresp(expected, deadline);
+        self.deadline = None;
+        result.map(|_| self.core.opts.clock.now().saturating_duration_since(start))
+    }
+
+    fn _await_pingresp(&mut self, expected: u64, deadline: Instant) -> Result<()> {
+        while self.core.pingresps < expected {
+            match self._accept() {
+                Ok(Some(message)) => self.deferred.push_back(message),
+                Ok(None) => (),
+                Err(Error::Timeout) => {
+                    if self.core.opts.clock.now() >= deadline {
+                        return Err(Error::Timeout);
+                    }
+                    self._keep_alive()?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn complete(&mut self, pid: PacketIdentifier) -> Result<()> {
+        let result = self.core.complete(pid);
+        self._send()?;
+        result
+    }
+
+    /// Gives up on a QoS 2 publish the broker never acknowledged, whether it
+    /// waits for PUBREC or PUBCOMP. The message leaves the outgoing store and
+    /// stops counting against the inflight window. Returns whether `pid` was
+    /// pending at all.
+    pub fn cancel_publish(&mut self, pid: PacketIdentifier) -> Result<bool> {
+        self.core.cancel_publish(pid)
+    }
+
+    /// Sends CONNECT then waits for CONNACK, completes `connect_transport`
+    pub fn handshake(&mut self) -> Result<()> {
+        if self.core.state != ClientState::Disconnected {
+            return Err(Error::AlreadyConnected);
+        }
+        self._handshake()
+    }
+
+    /// Gives back the options, stores included, to connect again once the
+    /// client gave up. A generated client id is kept, so the broker sees
+    /// the same client.
+    pub fn into_options(mut self) -> ClientOptions {
+        self._unbind();
+        self.core.into_options()
+    }
+
+    pub fn terminate(&mut self) {
+        self._unbind();
+    }
+
+    pub fn set_reconnect(&mut self, reconnect: ReconnectMethod) {
+        self.core.opts.reconnect = reconnect;
+    }
+
+    pub fn set_takeover(&mut self, method: TakeoverMethod) {
+        self.core.opts.takeover = method;
+    }
+
+    /// Messages received with QoS 2 and kept until PUBREL
+    pub fn incomming_store(&self) -> Option<&(dyn Store + Send)> {
+        self.core.opts.incomming_store.as_deref()
+    }
+
+    /// Messages published with QoS 1 and 2, kept until PUBACK or PUBREC
+    pub fn outgoing_store(&self) -> Option<&(dyn Store + Send)> {
+        self.core.opts.outgoing_store.as_deref()
+    }
+
+    pub fn session_present(&self) -> bool {
+        self.core.session_present
+    }
+
+    /// The connection is up and the CONNACK accepted it. Nothing is read to
+    /// tell, a connection dropped silently shows up on the next read.
+    pub fn is_connected(&self) -> bool {
+        self.core.state == ClientState::Connected
+    }
+
+    /// Address the client connects to
+    pub fn broker_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    // The time as the client's clock tells, for timers kept outside of it
+    pub(crate) fn now(&self) -> Instant {
+        self.core.opts.clock.now()
+    }
+
+    /// Address of the peer of the current connection, as the socket tells
+    /// it. Falls back to `broker_addr` when it can't, e.g. once the
+    /// connection dropped.
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.conn.get_ref().peer_addr().unwrap_or(self.addr)
+    }
+
+    /// The error which dropped the connection the last time. It survives a
+    /// successful reconnect, so it still tells why the client reconnected.
+    pub fn last_disconnect_reason(&self) -> Option<&Error> {
+        self.last_disconnect.as_ref()
+    }
+
+    /// QoS 1 and 2 messages sent but not fully acknowledged yet
+    pub fn pending_publishes(&self) -> usize {
+        self.core.pending_publishes()
+    }
+
+    /// QoS 1 and 2 messages received whose acknowledgement isn't over yet
+    pub fn pending_incoming(&self) -> usize {
+        self.core.pending_incoming()
+    }
+
+    /// The protocol state behind the connection
+    pub fn core(&self) -> &ClientCore {
+        &self.core
+    }
+
+    /// Negotiated TLS parameters of the current connection, None over
+    /// plain TCP
+    pub fn tls_info(&self) -> Option<TlsInfo> {
+        self.conn.get_ref().tls_info()
+    }
+
+    /// Interval driving the PINGREQs, None when keep alive is off. An
+    /// MQTT 5 broker may have replaced the requested one in its CONNACK.
+    pub fn effective_keep_alive(&self) -> Option<Duration> {
+        self.core.keep_alive()
+    }
+
+    pub fn transport_kind(&self) -> TransportKind {
+        self.conn.get_ref().kind()
+    }
+
+    /// When the current connection got its CONNACK, None while disconnected
+    pub fn connected_since(&self) -> Option<Instant> {
+        self.connected_since
+    }
+
+    /// Time spent connected, summed over every connection of this client
+    pub fn total_uptime(&self) -> Duration {
+        match self.connected_since {
+            Some(since) => self.uptime + self.core.opts.clock.now().saturating_duration_since(since),
+            None => self.uptime,
+        }
+    }
+
+    fn _handshake(&mut self) -> Result<()> {
+        if self.core.state == ClientState::Connected {
+            return Err(Error::AlreadyConnected);
+        }
+        self.core.state = ClientState::Handshake;
+        // send CONNECT
+        self._connect()?;
+        // wait CONNACK
+        let _ = self.await()?;
+        Ok(())
+    }
+
+    // Records why the connection dropped. The MQTT 5 reason 0x8E, or
+    // connections dropping right after the CONNACK time after time, mean
+    // another client connected with the same client id.
+    fn _dropped(&mut self, reason: Error) -> Error {
+        let quick = match (self.connected_since, self.core.opts.takeover_detection) {
+            (Some(since), Some((_, within))) => self.core.opts.clock.now().saturating_duration_since(since) < within,
+            _ => false,
+        };
+        self.quick_drops = if quick { self.quick_drops + 1 } else { 0 };
+        let taken_over = match reason {
+            Error::ServerDisconnect { code: 0x8E, .. } => true,
+            _ => self.core.opts.takeover_detection.is_some_and(|(drops, _)| self.quick_drops >= drops),
+        };
+        self._unbind();
+        if taken_over {
+            error!("{}", Error::SessionTakenOver);
+            self.quick_drops = 0;
+            self.last_disconnect = Some(Error::SessionTakenOver);
+            Error::SessionTakenOver
+        } else {
+            self.last_disconnect = Some(reason);
+            Error::Disconnected
+        }
+    }
+
+    // Up for longer than the stability window
+    fn _is_stable(&self) -> bool {
+        // past the last doubling the delay is the longest one
+        let longest = self.core.opts.reconnect.delay(u32::MAX);
+        let window = self.core.opts.stability_window.unwrap_or(longest.unwrap_or_default());
+        let now = self.core.opts.clock.now();
+        self.connected_since.is_some_and(|since| now.saturating_duration_since(since) >= window)
+    }
+
+    fn _try_reconnect(&mut self) -> bool {
+        // reconnecting would kick the other client, which does the same
+        if let Some(Error::SessionTakenOver) = self.last_disconnect {
+            if self.core.opts.takeover == TakeoverMethod::Disconnect {
+                return false;
+            }
+        }
+        let dur = match self.core.opts._reconnect_delay(self.reconnect_attempts) {
+            Some(dur) => dur,
+            None => return false,
+        };
+        if let Some(max) = self.core.opts._max_reconnect_attempts() {
+            if self.reconnect_attempts >= max {
+                error!("  Gave up reconnecting after {} attempts", self.reconnect_attempts);
+                return false;
+            }
+        }
+        self.reconnect_attempts += 1;
+        info!("  Reconnect in {} seconds", dur.as_secs());
+        self.core.opts.clock.sleep(dur);
+        if let Err(err) = self.reconnect() {
+            error!("  Reconnect failed: {:?}", err);
+            // the broker would refuse every retry the same way
+            let hopeless = match err {
+                // MQTT 5 "Use another server" and "Server moved" send us
+                // elsewhere, asking this one again won't help
+                Error::ConnectionRefused { code, reason_code, .. } => {
+                    !code.is_retryable() || reason_code == Some(0x9C) || reason_code == Some(0x9D)
+                }
+                _ => false
+            };
+            if hopeless {
+                self.last_disconnect = Some(err);
+                return false;
+            }
+        }
+        true
+    }
+
+    fn _connect(&mut self) -> Result<()> {
+        self.core.connect()?;
+        // credentials the protocol can't carry are refused by the encoder
+        self._send()
+    }
+
+    fn _publish<T: ToTopicPath, P: ToPayload>(&mut self,
+                                              topic: T,
+                                              payload: P,
+                                              pubopt: PubOpt)
+                                              -> Result<Option<PacketIdentifier>> {
+        self._rate_limit()?;
+        self._check_breaker()?;
+        let now = self.core.opts.clock.now();
+        let pid = self.core.publish(topic, payload, pubopt, now)?;
+        self._write_outbox()?;
+        self.unanswered_publish = true;
+        match self.core.opts.flush_threshold {
+            Some(threshold) if self.conn.pending() > threshold => self._flush()?,
+            _ => (),
+        }
+        Ok(pid)
+    }
+
+    fn _rate_limit(&mut self) -> Result<()> {
+        let method = self.core.opts.rate_limit_method;
+        if let Some(ref mut bucket) = self.bucket {
+            while let Some(wait) = bucket.take(self.core.opts.clock.now()) {
+                match method {
+                    RateLimitMethod::Block => self.core.opts.clock.sleep(wait),
+                    RateLimitMethod::Fail => return Err(Error::RateLimited),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn _check_breaker(&mut self) -> Result<()> {
+        if let Some(ref mut breaker) = self.breaker {
+            if !breaker.allow(self.core.opts.clock.now()) {
+                return Err(Error::CircuitOpen);
+            }
+        }
+        Ok(())
+    }
+
+    fn _subscribe<S: ToSubTopics>(&mut self, subs: S) -> Result<PacketIdentifier> {
+        let pid = self.core.subscribe(subs, None)?;
+        self._write_outbox()?;
+        Ok(pid)
+    }
+
+    // Takes a packet from the broker and sends whatever the core answers,
+    // even when it failed on the packet
+    fn _handle(&mut self, packet: Packet) -> Result<Option<Box<Message>>> {
+        let handshake = self.core.state == ClientState::Handshake;
+        let now = self.core.opts.clock.now();
+        let result = self.core.handle(packet, now);
+        if handshake && self.core.state == ClientState::Connected {
+            self.connected_since = Some(now);
+        }
+        self._send()?;
+        result
+    }
+
+    // Writes and flushes the packets produced by the core
+    fn _send(&mut self) -> Result<()> {
+        if self._write_outbox()? {
+            self._flush()?;
+        }
+        Ok(())
+    }
+
+    // Encodes the packets produced by the core into the connection buffer,
+    // returns whether there were any
+    fn _write_outbox(&mut self) -> Result<bool> {
+        let mut written = false;
+        while let Some(packet) = self.core.poll_outgoing() {
+            trace!("{}", packet);
+            self.conn.buffer_packet(&packet, self.core.opts.protocol)?;
+            written = true;
+        }
+        Ok(written)
+    }
+
+    fn _flush(&mut self) -> Result<()> {
+        // TODO: in case of disconnection, trying to reconnect
+        match self.conn.flush() {
+            Ok(()) => {
+                self.last_flush = self.core.opts.clock.now();
+                Ok(())
+            }
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                // the socket is full, the rest stays buffered in the
+                // connection and goes out with the next flush
+                debug!("         Flush {} bytes pending", self.conn.pending());
+                Ok(())
+            }
+            Err(e) => Err(Error::from(e)),
+        }
     }
 
-    #[inline]
-    fn _next_pid(&mut self) -> PacketIdentifier {
-        self.last_pid = self.last_pid.next();
-        self.last_pid
+    // accept timed out: it's time to PING, or the broker didn't answer the
+    // last PINGREQ within keep alive and the connection is dead
+    fn _keep_alive(&mut self) -> Result<()> {
+        if self.core.state != ClientState::Connected {
+            return Err(Error::Timeout);
+        }
+        // disabled, the timeout was the caller's
+        if self.core.keep_alive().is_none() {
+            return Ok(());
+        }
+        if !self.core.await_ping {
+            let _ = self.ping();
+        } else {
+            self.last_disconnect = Some(Error::Timeout);
+            self._unbind();
+        }
+        Ok(())
+    }
+
+    fn _unbind(&mut self) {
+        let _ = self.conn.terminate();
+        // the broker may have dropped us for the publish
+        if self.core.state == ClientState::Connected && self.unanswered_publish {
+            if let Some(ref mut breaker) = self.breaker {
+                breaker.failure(self.core.opts.clock.now());
+            }
+        }
+        self.core.disconnected();
+        self.unanswered_publish = false;
+        if let Some(since) = self.connected_since.take() {
+            self.uptime += self.core.opts.clock.now().saturating_duration_since(since);
+        }
+        info!("  Disconnected {}", self.core.opts.client_id.clone().unwrap());
     }
 }
 
 #[cfg(test)]
 mod test {
-    use netopt::NetworkOptions;
+    use netopt::{NetworkOptions, TransportKind};
     use netopt::mock::MockStream;
+    use std::collections::HashMap;
+    use std::io::{self, Read, Write};
+    use std::net::{SocketAddr, TcpListener};
+    use std::sync::{Arc, Mutex};
+    use std::sync::mpsc::{self, Sender};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+    use socket2::{Domain, Socket, Type};
+    use super::{Client, ClientOptions};
+    use clock::{Clock, MockClock};
+    use error::{Error, PacketIdentifierError};
+    use mqtt3::{ConnectReturnCode, MQError, Message, PacketIdentifier, Property, Protocol, Publish, QoS, RetainHandling, SubscribeReturnCodes, SubscribeTopic};
+    use store::{self, Store};
+    use {ClientState, CodecRegistry, Event, RateLimitMethod, PubSub, PubOpt, ReconnectMethod, TakeoverMethod, UnexpectedPacketMethod};
+
+    // Connects through a mock which answers CONNACK and discards the CONNECT
+    fn mock_client(opts: ClientOptions) -> (Client, MockStream) {
+        let mut stream = MockStream::with_vec(vec![0b00100000, 0x02, 0x00, 0x00]);
+        let mut netopt = NetworkOptions::new();
+        netopt.attach(stream.clone());
+        let client = opts.connect("127.0.0.1:1883", netopt).unwrap();
+        stream.take_vec();
+        (client, stream)
+    }
 
     #[test]
     fn client_connect_test() {
@@ -736,4 +1603,1882 @@ mod test {
         // Connect and create MQTT client
         // let client = options.connect("127.0.0.1:1883", netopt).unwrap();
     }
+
+    #[test]
+    fn client_handshake_timeout_test() {
+        // the broker accepts the connection but never answers CONNECT
+        let mut stream = MockStream::new();
+        stream.set_silent(true);
+        let mut netopt = NetworkOptions::new();
+        netopt.attach(stream.clone());
+        let mut opts = ClientOptions::new();
+        opts.set_client_id("test".to_owned());
+        match opts.connect("127.0.0.1:1883", netopt) {
+            Err(Error::Timeout) => (),
+            Err(e) => panic!("expected Timeout, got {:?}", e),
+            Ok(_) => panic!("connected without CONNACK")
+        }
+        // only CONNECT went out, no PINGREQ before CONNACK
+        let written = stream.take_vec();
+        assert_eq!(written.len(), 18);
+        assert_eq!(written[0], 0x10);
+    }
+
+    #[test]
+    fn client_publish_would_block_test() {
+        let (mut client, mut stream) = mock_client(ClientOptions::new());
+        stream.set_write_limit(Some(4));
+        client.publish("a/b", vec![0x01, 0x02], PubOpt::at_most_once()).unwrap();
+        client.publish("a/b", vec![0x03, 0x04], PubOpt::at_most_once()).unwrap();
+        assert_eq!(stream.take_vec(), vec![0b00110000, 7, 0x00, 0x03]);
+
+        stream.set_write_limit(None);
+        client.publish("a/b", vec![0x05, 0x06], PubOpt::at_most_once()).unwrap();
+        assert_eq!(stream.take_vec(), vec![
+            'a' as u8, '/' as u8, 'b' as u8, 0x01, 0x02,
+            0b00110000, 7, 0x00, 0x03, 'a' as u8, '/' as u8, 'b' as u8, 0x03, 0x04,
+            0b00110000, 7, 0x00, 0x03, 'a' as u8, '/' as u8, 'b' as u8, 0x05, 0x06
+        ]);
+    }
+
+    #[test]
+    fn client_publish_clear_retained_test() {
+        let (mut client, mut stream) = mock_client(ClientOptions::new());
+        client.publish("a", "", PubOpt::at_most_once() | PubOpt::retain()).unwrap();
+        assert_eq!(stream.take_vec(), vec![0b00110001, 3, 0x00, 0x01, 'a' as u8]);
+    }
+
+    #[test]
+    fn client_message_expiry_test() {
+        let mut opts = ClientOptions::new();
+        opts.set_protocol(Protocol::MQTT(5));
+        opts.set_message_expiry(Duration::from_secs(60));
+        let mut stream = MockStream::with_vec(vec![0b00100000, 0x03, 0x00, 0x00, 0x00]);
+        let mut netopt = NetworkOptions::new();
+        netopt.attach(stream.clone());
+        let mut client = opts.connect("127.0.0.1:1883", netopt).unwrap();
+        stream.take_vec();
+
+        client.publish("a/b", vec![0x01], PubOpt::at_most_once()).unwrap();
+        assert_eq!(stream.take_vec(), vec![0b00110000, 12,
+            0x00, 0x03, 'a' as u8, '/' as u8, 'b' as u8,
+            0x05, 0x02, 0x00, 0x00, 0x00, 0x3c,
+            0x01]);
+
+        stream.next_vec(vec![0b00110000, 12,
+            0x00, 0x03, 'a' as u8, '/' as u8, 'b' as u8,
+            0x05, 0x02, 0x00, 0x00, 0x00, 0x0a,
+            0x02]);
+        let message = client.accept().unwrap().unwrap();
+        assert_eq!(message.expiry, Some(Duration::from_secs(10)));
+        assert_eq!(*message.payload, vec![0x02]);
+    }
+
+    #[test]
+    fn client_payload_format_test() {
+        let mut opts = ClientOptions::new();
+        opts.set_protocol(Protocol::MQTT(5));
+        opts.set_validate_utf8(true);
+        let mut client = opts.build_offline().unwrap();
+        client.take_written();
+
+        client.publish("a", "x", PubOpt::at_most_once() | PubOpt::utf8()).unwrap();
+        assert_eq!(client.take_written(), vec![0b00110000, 7,
+            0x00, 0x01, 'a' as u8,
+            0x02, 0x01, 0x01,
+            'x' as u8]);
+
+        // invalid text is only warned about
+        client.feed(vec![0b00110000, 7,
+            0x00, 0x01, 'a' as u8,
+            0x02, 0x01, 0x01,
+            0xFF]);
+        let message = client.accept().unwrap().unwrap();
+        assert!(message.utf8);
+        assert_eq!(message.payload_str(), None);
+    }
+
+    #[test]
+    fn client_publish_invalid_topic_test() {
+        let (mut client, mut stream) = mock_client(ClientOptions::new());
+        for topic in &["a/+/b", "a/#", "$share/group/a/b"] {
+            assert!(client.publish(*topic, vec![0x01], PubOpt::at_least_once()).is_err());
+        }
+        assert!(stream.take_vec().is_empty());
+    }
+
+    #[test]
+    fn client_resubscribe_shared_test() {
+        let mut opts = ClientOptions::new();
+        opts.set_client_id("test".to_owned());
+        let (mut client, mut stream) = mock_client(opts);
+        client.subscribe("$share/g/a/+").unwrap();
+        stream.take_vec();
+        stream.next_vec(vec![0x90, 0x03, 0x00, 0x01, 0x02]);
+        assert!(client.accept().unwrap().is_none());
+
+        client.terminate();
+        stream.next_vec(vec![0b00100000, 0x02, 0x00, 0x00]);
+        client.reconnect().unwrap();
+        let written = stream.take_vec();
+        let mut subscribe = vec![0x82, 17, 0x00, 0x02, 0x00, 12];
+        subscribe.extend_from_slice(b"$share/g/a/+");
+        subscribe.push(0x02);
+        assert_eq!(&written[18..], &subscribe[..]);
+    }
+
+    #[test]
+    fn client_resubscribe_session_present_test() {
+        let mut opts = ClientOptions::new();
+        opts.set_client_id("test".to_owned());
+        let (mut client, mut stream) = mock_client(opts);
+        client.subscribe("a").unwrap();
+        stream.next_vec(vec![0x90, 0x03, 0x00, 0x01, 0x01]);
+        assert!(client.accept().unwrap().is_none());
+
+        // the broker kept the subscriptions
+        client.terminate();
+        stream.take_vec();
+        stream.next_vec(vec![0b00100000, 0x02, 0x01, 0x00]);
+        client.reconnect().unwrap();
+        assert!(client.session_present());
+        assert_eq!(stream.take_vec().len(), 18);
+
+        // a new session needs them again
+        client.terminate();
+        stream.next_vec(vec![0b00100000, 0x02, 0x00, 0x00]);
+        client.reconnect().unwrap();
+        assert_eq!(&stream.take_vec()[18..], &[0x82, 6, 0x00, 0x02, 0x00, 0x01, 'a' as u8, 0x01]);
+    }
+
+    #[test]
+    fn client_auto_resubscribe_off_test() {
+        let mut opts = ClientOptions::new();
+        opts.set_client_id("test".to_owned());
+        opts.set_auto_resubscribe(false);
+        let (mut client, mut stream) = mock_client(opts);
+        client.subscribe("a").unwrap();
+        stream.next_vec(vec![0x90, 0x03, 0x00, 0x01, 0x01]);
+        assert!(client.accept().unwrap().is_none());
+
+        client.terminate();
+        stream.take_vec();
+        stream.next_vec(vec![0b00100000, 0x02, 0x00, 0x00]);
+        client.reconnect().unwrap();
+        assert_eq!(stream.take_vec().len(), 18);
+    }
+
+    #[test]
+    fn client_subscribe_with_id_test() {
+        assert!(ClientOptions::new().build_offline().unwrap().subscribe_with_id("a", 1).is_err());
+
+        let mut opts = ClientOptions::new();
+        opts.set_protocol(Protocol::MQTT(5));
+        opts.set_keep_alive(0);
+        let mut client = opts.build_offline().unwrap();
+        assert!(client.subscribe_with_id("a", 0).is_err());
+        client.take_written();
+
+        client.subscribe_with_id("a", 5).unwrap();
+        let subscribe = vec![0x82, 9, 0x00, 0x01, 0x02, 0x0B, 0x05, 0x00, 0x01, 'a' as u8, 0x01];
+        assert_eq!(client.take_written(), subscribe);
+        client.feed(vec![0x90, 0x04, 0x00, 0x01, 0x00, 0x01,
+                         0x30, 0x06, 0x00, 0x01, 'a' as u8, 0x02, 0x0B, 0x05]);
+        assert!(client.accept().unwrap().is_none());
+        assert_eq!(client.accept().unwrap().unwrap().subscription_ids, vec![5]);
+
+        // the identifier is kept for resubscribing
+        client.terminate();
+        client.feed(vec![0x20, 0x03, 0x00, 0x00, 0x00]);
+        client.reconnect().unwrap();
+        let written = client.take_written();
+        assert_eq!(&written[written.len() - subscribe.len()..][..2], &subscribe[..2]);
+        assert_eq!(&written[written.len() - subscribe.len() + 4..], &subscribe[4..]);
+    }
+
+    #[test]
+    fn client_no_local_test() {
+        let mut opts = ClientOptions::new();
+        opts.set_protocol(Protocol::MQTT(5));
+        opts.set_keep_alive(0);
+        opts.set_no_local(true);
+        let mut client = opts.build_offline().unwrap();
+        client.take_written();
+
+        client.subscribe(("a", QoS::AtLeastOnce)).unwrap();
+        let subscribe = vec![0x82, 7, 0x00, 0x01, 0x00, 0x00, 0x01, 'a' as u8, 0x05];
+        assert_eq!(client.take_written(), subscribe);
+        client.feed(vec![0x90, 0x04, 0x00, 0x01, 0x00, 0x01]);
+        assert!(client.accept().unwrap().is_none());
+
+        // and again on resubscribe
+        client.terminate();
+        client.feed(vec![0x20, 0x03, 0x00, 0x00, 0x00]);
+        client.reconnect().unwrap();
+        let written = client.take_written();
+        assert_eq!(written[written.len() - 1], 0x05);
+    }
+
+    #[test]
+    fn client_echo_window_test() {
+        let mut opts = ClientOptions::new();
+        opts.set_keep_alive(0);
+        opts.set_echo_window(Some(Duration::from_secs(60)));
+        let mut client = opts.build_offline().unwrap();
+        client.publish("a", "x", PubOpt::at_most_once()).unwrap();
+        client.take_written();
+
+        // our own message coming back, still acknowledged
+        client.feed(vec![0b00110000, 4, 0x00, 0x01, 'a' as u8, 'x' as u8]);
+        assert!(client.accept().unwrap().is_none());
+        client.feed(vec![0b00110010, 6, 0x00, 0x01, 'a' as u8, 0x00, 0x07, 'x' as u8]);
+        assert!(client.accept().unwrap().is_none());
+        assert_eq!(client.take_written(), vec![0x40, 0x02, 0x00, 0x07]);
+
+        client.feed(vec![0b00110000, 4, 0x00, 0x01, 'b' as u8, 'y' as u8]);
+        assert_eq!(client.accept().unwrap().unwrap().topic_str(), "b");
+    }
+
+    #[test]
+    fn client_max_inflight_subscribes_test() {
+        let mut opts = ClientOptions::new();
+        opts.set_keep_alive(0);
+        opts.set_max_inflight_subscribes(2);
+        let mut client = opts.build_offline().unwrap();
+        client.subscribe("a").unwrap();
+        client.unsubscribe("b").unwrap();
+        client.take_written();
+        match client.subscribe("c") {
+            Err(Error::InflightLimit) => (),
+            other => panic!("expected InflightLimit, got {:?}", other)
+        }
+        assert!(client.unsubscribe("c").is_err());
+        assert!(client.take_written().is_empty());
+
+        client.feed(vec![0x90, 0x03, 0x00, 0x01, 0x01]);
+        assert!(client.accept().unwrap().is_none());
+        client.subscribe("c").unwrap();
+    }
+
+    #[test]
+    fn client_pause_test() {
+        let mut opts = ClientOptions::new();
+        opts.set_keep_alive(1);
+        let clock = MockClock::new();
+        opts.clock = clock.clone();
+        let (mut client, mut stream) = mock_client(opts);
+        stream.next_vec(vec![0b00110000, 4, 0x00, 0x01, 'a' as u8, 'x' as u8,
+                             0xD0, 0x00]);
+        client.pause();
+
+        // nothing is read until it's time to PING
+        let start = clock.now();
+        match client.accept() {
+            Err(Error::Timeout) => (),
+            other => panic!("expected Timeout, got {:?}", other)
+        }
+        assert_eq!(clock.now() - start, Duration::from_secs(1));
+        assert!(stream.take_vec().is_empty());
+
+        // the PINGRESP behind the message keeps us connected
+        assert!(client.r#await().unwrap().is_none());
+        assert_eq!(stream.take_vec(), vec![0xC0, 0x00]);
+        assert_eq!(client.core.state(), ClientState::Connected);
+
+        client.resume();
+        assert_eq!(client.accept().unwrap().unwrap().topic_str(), "a");
+    }
+
+    #[test]
+    fn client_pause_without_keep_alive_test() {
+        let mut opts = ClientOptions::new();
+        opts.set_keep_alive(0);
+        let clock = MockClock::new();
+        opts.clock = clock.clone();
+        let (mut client, mut stream) = mock_client(opts);
+        client.publish("a", "x", PubOpt::at_least_once()).unwrap();
+        stream.take_vec();
+        stream.next_vec(vec![0b00110000, 3, 0x00, 0x01, 'b' as u8]);
+        client.pause();
+
+        // returns at once rather than blocking for good
+        let start = clock.now();
+        match client.accept() {
+            Err(Error::Paused) => (),
+            other => panic!("expected Paused, got {:?}", other)
+        }
+        match client.r#await() {
+            Err(Error::Paused) => (),
+            other => panic!("expected Paused, got {:?}", other)
+        }
+        match client.messages().next() {
+            Some(Err(Error::Paused)) => (),
+            other => panic!("expected Paused, got {:?}", other)
+        }
+        assert_eq!(clock.now(), start);
+        assert!(stream.take_vec().is_empty());
+
+        client.resume();
+        assert_eq!(client.accept().unwrap().unwrap().topic_str(), "b");
+    }
+
+    #[test]
+    fn client_pause_poll_test() {
+        let mut opts = ClientOptions::new();
+        opts.set_keep_alive(0);
+        let clock = MockClock::new();
+        opts.clock = clock.clone();
+        let (mut client, mut stream) = mock_client(opts);
+        match client.poll(Duration::from_secs(0)).unwrap() {
+            Event::Connected => (),
+            other => panic!("expected Connected, got {:?}", other)
+        }
+        client.deferred.push_back(Message::from_pub(Box::new(Publish {
+            dup: false,
+            qos: QoS::AtMostOnce,
+            retain: false,
+            topic_name: "a".to_owned(),
+            pid: None,
+            properties: Vec::new(),
+            payload: Arc::new(vec![0x01])
+        })).unwrap());
+        stream.next_vec(vec![0b00110000, 3, 0x00, 0x01, 'b' as u8]);
+        client.pause();
+
+        // neither the kept message nor the socket, without keep alive
+        // poll sleeps through its timeout
+        let start = clock.now();
+        match client.poll(Duration::from_secs(5)).unwrap() {
+            Event::Idle => (),
+            other => panic!("expected Idle, got {:?}", other)
+        }
+        assert_eq!(clock.now() - start, Duration::from_secs(5));
+        assert_eq!(client.deferred.len(), 1);
+
+        client.resume();
+        match client.poll(Duration::from_secs(5)).unwrap() {
+            Event::Message(ref message) => assert_eq!(message.topic_str(), "a"),
+            other => panic!("expected Message, got {:?}", other)
+        }
+        match client.poll(Duration::from_secs(5)).unwrap() {
+            Event::Message(ref message) => assert_eq!(message.topic_str(), "b"),
+            other => panic!("expected Message, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn client_subscribe_await_test() {
+        let (mut client, mut stream) = mock_client(ClientOptions::new());
+        // a message published before the SUBACK arrived
+        stream.next_vec(vec![0b00110000, 6, 0x00, 0x03, 'a' as u8, '/' as u8, 'b' as u8, 0x01,
+                             0x90, 0x03, 0x00, 0x01, 0x01]);
+        let codes = client.subscribe_await("a/+", Duration::from_secs(1)).unwrap();
+        assert_eq!(codes, vec![SubscribeReturnCodes::Success(QoS::AtLeastOnce)]);
+
+        let message = client.accept().unwrap().unwrap();
+        assert_eq!(message.topic.path, "a/b");
+        assert_eq!(*message.payload, vec![0x01]);
+    }
+
+    #[test]
+    fn client_max_reconnect_attempts_test() {
+        let mut opts = ClientOptions::new();
+        opts.set_reconnect(ReconnectMethod::ReconnectAfter(Duration::from_millis(1)));
+        opts.set_max_reconnect_attempts(3);
+        opts.set_client_id("test".to_owned());
+        let (mut client, mut stream) = mock_client(opts);
+
+        // the broker is gone, every reconnect fails on handshake
+        match client.accept() {
+            Err(Error::Disconnected) => (),
+            other => panic!("expected Disconnected, got {:?}", other)
+        }
+        let connect = vec![0x10, 16,
+            0x00, 0x04, 'M' as u8, 'Q' as u8, 'T' as u8, 'T' as u8, 0x04, 0x02, 0x00, 30,
+            0x00, 0x04, 't' as u8, 'e' as u8, 's' as u8, 't' as u8];
+        assert_eq!(stream.take_vec(), connect.repeat(3));
+
+        for _ in 0..2 {
+            match client.accept() {
+                Err(Error::Disconnected) => (),
+                other => panic!("expected Disconnected, got {:?}", other)
+            }
+        }
+        assert!(stream.take_vec().is_empty());
+    }
+
+    #[test]
+    fn client_reconnect_refused_test() {
+        let mut opts = ClientOptions::new();
+        opts.set_keep_alive(0);
+        opts.set_reconnect(ReconnectMethod::ReconnectAfter(Duration::from_millis(1)));
+        opts.set_max_reconnect_attempts(5);
+        let (mut client, mut stream) = mock_client(opts);
+
+        // bad credentials stay bad, no more attempts
+        client.terminate();
+        stream.take_vec();
+        stream.next_vec(vec![0x20, 0x02, 0x00, 0x04]);
+        match client.accept() {
+            Err(Error::Disconnected) => (),
+            other => panic!("expected Disconnected, got {:?}", other)
+        }
+        assert_eq!(stream.take_vec()[0], 0x10);
+        assert!(stream.take_vec().is_empty());
+        match client.last_disconnect_reason() {
+            Some(&Error::ConnectionRefused { code: ConnectReturnCode::BadUsernamePassword, .. }) => (),
+            other => panic!("expected ConnectionRefused, got {:?}", other)
+        }
+
+        // an unavailable server is tried again
+        client.reconnect_attempts = 0;
+        stream.next_vec(vec![0x20, 0x02, 0x00, 0x03]);
+        match client.accept() {
+            Err(Error::Disconnected) => (),
+            other => panic!("expected Disconnected, got {:?}", other)
+        }
+        assert_eq!(client.reconnect_attempts, 5);
+    }
+
+    #[test]
+    fn client_reconnect_server_moved_test() {
+        let mut opts = ClientOptions::new();
+        opts.set_protocol(Protocol::MQTT(5));
+        opts.set_keep_alive(0);
+        opts.set_reconnect(ReconnectMethod::ReconnectAfter(Duration::from_millis(1)));
+        opts.set_max_reconnect_attempts(5);
+        let mut stream = MockStream::with_vec(vec![0x20, 0x03, 0x00, 0x00, 0x00]);
+        let mut netopt = NetworkOptions::new();
+        netopt.attach(stream.clone());
+        let mut client = opts.connect("127.0.0.1:1883", netopt).unwrap();
+
+        // "Server moved" is unavailable in MQTT 3.1.1 terms but not retried
+        client.terminate();
+        stream.take_vec();
+        stream.next_vec(vec![0x20, 0x03, 0x00, 0x9D, 0x00]);
+        match client.accept() {
+            Err(Error::Disconnected) => (),
+            other => panic!("expected Disconnected, got {:?}", other)
+        }
+        assert_eq!(stream.take_vec()[0], 0x10);
+        assert!(stream.take_vec().is_empty());
+        match client.last_disconnect_reason() {
+            Some(&Error::ConnectionRefused { code: ConnectReturnCode::ServerUnavailable, reason_code: Some(0x9D), .. }) => (),
+            other => panic!("expected ConnectionRefused, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn client_backoff_reconnect_test() {
+        let mut opts = ClientOptions::new();
+        opts.set_keep_alive(0);
+        opts.set_reconnect(ReconnectMethod::ExponentialBackoff {
+            initial: Duration::from_millis(1),
+            max: Duration::from_millis(4),
+            jitter: Duration::from_millis(1),
+            max_attempts: Some(3)
+        });
+        let clock = MockClock::new();
+        opts.clock = clock.clone();
+        let (mut client, mut stream) = mock_client(opts);
+        client.terminate();
+        stream.take_vec();
+
+        // the broker is gone, each delay doubles up to max plus jitter
+        let start = clock.now();
+        match client.accept() {
+            Err(Error::Disconnected) => (),
+            other => panic!("expected Disconnected, got {:?}", other)
+        }
+        assert_eq!(client.reconnect_attempts, 3);
+        let waited = clock.now() - start;
+        assert!(waited >= Duration::from_millis(1 + 2 + 4) && waited < Duration::from_millis(1 + 2 + 4 + 3),
+                "waited {:?}", waited);
+
+        // a CONNACK alone doesn't start over, a connection which dropped
+        // right away would retry at the shortest delay again
+        stream.next_vec(vec![0x20, 0x02, 0x00, 0x00]);
+        client.reconnect().unwrap();
+        assert_eq!(client.reconnect_attempts, 3);
+
+        // staying up for the longest delay does
+        clock.advance(Duration::from_millis(4));
+        stream.next_vec(vec![0xD0, 0x00]);
+        assert!(client.accept().unwrap().is_none());
+        assert_eq!(client.reconnect_attempts, 0);
+    }
+
+    #[test]
+    fn client_retransmit_test() {
+        let mut opts = ClientOptions::new();
+        opts.set_keep_alive(0);
+        opts.set_clean_session(false);
+        opts.set_outgoing_store(Box::new(CountingStore { messages: HashMap::new(), puts: Arc::new(Mutex::new(0)) }));
+        let (mut client, mut stream) = mock_client(opts);
+        client.publish("a", "x", PubOpt::exactly_once()).unwrap();
+        stream.next_vec(vec![0x50, 0x02, 0x00, 0x01]);
+        assert!(client.accept().unwrap().is_none());
+        client.publish("b", "y", PubOpt::at_least_once()).unwrap();
+        client.publish("c", "z", PubOpt::exactly_once()).unwrap();
+
+        client.terminate();
+        stream.take_vec();
+        // the handshake waits for the acks of what's sent again
+        stream.next_vec(vec![0x20, 0x02, 0x01, 0x00, 0x40, 0x02, 0x00, 0x02, 0x50, 0x02, 0x00, 0x03]);
+        client.reconnect().unwrap();
+        let written = stream.take_vec();
+        // in the order of the publishes, the PUBREL of the first one first
+        let expected = vec![0x62, 0x02, 0x00, 0x01,
+                            0x3A, 6, 0x00, 0x01, 'b' as u8, 0x00, 0x02, 'y' as u8,
+                            0x3C, 6, 0x00, 0x01, 'c' as u8, 0x00, 0x03, 'z' as u8,
+                            0x62, 0x02, 0x00, 0x03];
+        assert_eq!(written[0], 0x10);
+        assert_eq!(&written[written.len() - expected.len()..], &expected[..]);
+        assert!(client.core.is_normalized());
+    }
+
+    #[test]
+    fn client_retransmit_no_session_test() {
+        let mut opts = ClientOptions::new();
+        opts.set_keep_alive(0);
+        opts.set_clean_session(false);
+        let puts = Arc::new(Mutex::new(0));
+        opts.set_outgoing_store(Box::new(CountingStore { messages: HashMap::new(), puts: puts.clone() }));
+        let (mut client, mut stream) = mock_client(opts);
+        client.publish("a", "x", PubOpt::at_least_once()).unwrap();
+        client.publish("b", "y", PubOpt::exactly_once()).unwrap();
+        assert_eq!(client.core.pending_publishes(), 2);
+
+        // the broker lost the session despite clean_session off
+        client.terminate();
+        stream.take_vec();
+        stream.next_vec(vec![0x20, 0x02, 0x00, 0x00]);
+        client.reconnect().unwrap();
+        // the CONNECT alone
+        let written = stream.take_vec();
+        assert_eq!(written[0], 0x10);
+        assert_eq!(written.len(), written[1] as usize + 2);
+        assert_eq!(client.core.pending_publishes(), 0);
+        assert!(client.core.outgoing_order.is_empty());
+        assert!(client.core.opts.outgoing_store.as_ref().unwrap().keys().unwrap().is_empty());
+    }
+
+    // Keeps messages in a map which outlives the client, like a disk
+    struct SharedStore(Arc<Mutex<HashMap<PacketIdentifier, Box<Message>>>>);
+
+    impl Store for SharedStore {
+        fn put(&mut self, message: Box<Message>) -> store::Result<()> {
+            self.0.lock().unwrap().insert(message.pid.unwrap(), message);
+            Ok(())
+        }
+
+        fn get(&mut self, pid: PacketIdentifier) -> store::Result<Box<Message>> {
+            self.0.lock().unwrap().get(&pid).cloned().ok_or(store::Error::NotFound(pid))
+        }
+
+        fn delete(&mut self, pid: PacketIdentifier) -> store::Result<()> {
+            self.0.lock().unwrap().remove(&pid).map(|_| ()).ok_or(store::Error::NotFound(pid))
+        }
+
+        fn keys(&self) -> store::Result<Vec<PacketIdentifier>> {
+            let mut keys: Vec<PacketIdentifier> = self.0.lock().unwrap().keys().cloned().collect();
+            keys.sort();
+            Ok(keys)
+        }
+    }
+
+    #[test]
+    fn client_restart_retransmit_test() {
+        let messages = Arc::new(Mutex::new(HashMap::new()));
+        let mut opts = ClientOptions::new();
+        opts.set_keep_alive(0);
+        opts.set_clean_session(false);
+        opts.set_client_id("restart".to_owned());
+        opts.set_outgoing_store(Box::new(SharedStore(messages.clone())));
+        let (mut client, _) = mock_client(opts);
+        client.publish("a", "x", PubOpt::at_least_once()).unwrap();
+        client.publish("b", "y", PubOpt::exactly_once()).unwrap();
+        // the process dies with both unacknowledged
+        drop(client);
+        assert_eq!(messages.lock().unwrap().len(), 2);
+
+        let mut opts = ClientOptions::new();
+        opts.set_keep_alive(0);
+        opts.set_clean_session(false);
+        opts.set_client_id("restart".to_owned());
+        opts.set_outgoing_store(Box::new(SharedStore(messages.clone())));
+        // the handshake waits for the acks of what's sent again
+        let mut stream = MockStream::with_vec(vec![0x20, 0x02, 0x01, 0x00, 0x40, 0x02, 0x00, 0x01,
+                                                   0x50, 0x02, 0x00, 0x02, 0x70, 0x02, 0x00, 0x02]);
+        let mut netopt = NetworkOptions::new();
+        netopt.attach(stream.clone());
+        let mut client = opts.connect("127.0.0.1:1883", netopt).unwrap();
+        let written = stream.take_vec();
+        // the stored publishes with DUP set, then the PUBREL
+        let expected = vec![0x3A, 6, 0x00, 0x01, 'a' as u8, 0x00, 0x01, 'x' as u8,
+                            0x3C, 6, 0x00, 0x01, 'b' as u8, 0x00, 0x02, 'y' as u8,
+                            0x62, 0x02, 0x00, 0x02];
+        assert_eq!(written[0], 0x10);
+        assert_eq!(&written[written.len() - expected.len()..], &expected[..]);
+        assert!(client.core.is_normalized());
+        assert!(messages.lock().unwrap().is_empty());
+        // new publishes don't reuse the restored packet identifiers
+        client.publish("c", "z", PubOpt::at_least_once()).unwrap();
+        assert!(messages.lock().unwrap().contains_key(&PacketIdentifier(3)));
+    }
+
+    #[test]
+    fn client_connect_properties_test() {
+        let mut opts = ClientOptions::new();
+        opts.set_protocol(Protocol::MQTT(5));
+        opts.set_client_id("test".to_owned());
+        opts.set_connect_user_property("a".to_owned(), "b".to_owned());
+        let mut client = opts.build_offline().unwrap();
+        assert_eq!(client.take_written(), vec![0x10, 24,
+            0x00, 0x04, 'M' as u8, 'Q' as u8, 'T' as u8, 'T' as u8, 0x05, 0x02, 0x00, 30,
+            0x07, 0x26, 0x00, 0x01, 'a' as u8, 0x00, 0x01, 'b' as u8,
+            0x00, 0x04, 't' as u8, 'e' as u8, 's' as u8, 't' as u8]);
+
+        let mut opts = ClientOptions::new();
+        opts.set_protocol(Protocol::MQTT(5));
+        opts.set_connect_user_property("a".to_owned(), "b".to_owned());
+        opts.set_client_id("test".to_owned());
+        opts.set_connect_properties(vec![Property::SessionExpiryInterval(60)]);
+        assert_eq!(opts._generate_connect_packet().properties, vec![Property::SessionExpiryInterval(60)]);
+    }
+
+    #[test]
+    fn client_session_expiry_test() {
+        let mut opts = ClientOptions::new();
+        opts.set_protocol(Protocol::MQTT(5));
+        opts.set_client_id("test".to_owned());
+        opts.set_session_expiry(Duration::from_secs(3600));
+        assert_eq!(opts._generate_connect_packet().properties, vec![Property::SessionExpiryInterval(3600)]);
+
+        // 0xFFFFFFFF never expires
+        opts.set_session_expiry(Duration::from_secs(u64::MAX));
+        assert_eq!(opts._generate_connect_packet().properties, vec![Property::SessionExpiryInterval(u32::MAX)]);
+
+        // replaces the one among the CONNECT properties
+        opts.set_connect_properties(vec![Property::SessionExpiryInterval(60), Property::ReceiveMaximum(10)]);
+        opts.set_session_expiry(Duration::from_secs(3600));
+        assert_eq!(opts._generate_connect_packet().properties,
+                   vec![Property::ReceiveMaximum(10), Property::SessionExpiryInterval(3600)]);
+    }
+
+    #[test]
+    fn client_timeout_rejected_test() {
+        let mut stream = MockStream::with_vec(vec![0b00100000, 0x02, 0x00, 0x00]);
+        stream.set_reject_timeouts(true);
+        let mut netopt = NetworkOptions::new();
+        netopt.attach(stream.clone());
+        match ClientOptions::new().connect("127.0.0.1:1883", netopt) {
+            Err(Error::Io(ref err)) => assert_eq!(err.kind(), io::ErrorKind::InvalidInput),
+            Err(err) => panic!("expected Io, got {:?}", err),
+            Ok(_) => panic!("expected Io")
+        }
+        // nothing was sent over the socket it gave up on
+        assert!(stream.take_vec().is_empty());
+    }
+
+    #[test]
+    fn client_effective_keep_alive_test() {
+        let client = ClientOptions::new().build_offline().unwrap();
+        assert_eq!(client.effective_keep_alive(), Some(Duration::from_secs(30)));
+
+        let mut opts = ClientOptions::new();
+        opts.set_keep_alive(0);
+        let client = opts.build_offline().unwrap();
+        assert_eq!(client.effective_keep_alive(), None);
+    }
+
+    #[test]
+    fn client_server_keep_alive_test() {
+        let mut opts = ClientOptions::new();
+        opts.set_protocol(Protocol::MQTT(5));
+        let clock = MockClock::new();
+        opts.clock = clock.clone();
+        // the broker wants a PINGREQ every 5 seconds instead of 30
+        let mut stream = MockStream::with_vec(vec![0x20, 0x06, 0x00, 0x00, 0x03, 0x13, 0x00, 0x05]);
+        let mut netopt = NetworkOptions::new();
+        netopt.attach(stream.clone());
+        let mut client = opts.connect("127.0.0.1:1883", netopt).unwrap();
+        stream.take_vec();
+        stream.set_silent(true);
+        assert_eq!(client.effective_keep_alive(), Some(Duration::from_secs(5)));
+
+        let start = clock.now();
+        match client.accept() {
+            Err(Error::Timeout) => (),
+            other => panic!("expected Timeout, got {:?}", other)
+        }
+        assert_eq!(clock.now() - start, Duration::from_secs(5));
+        client._keep_alive().unwrap();
+        assert_eq!(stream.take_vec(), vec![0xC0, 0x00]);
+
+        // a CONNACK without it goes back to the requested one
+        client.terminate();
+        stream.set_silent(false);
+        stream.next_vec(vec![0x20, 0x03, 0x00, 0x00, 0x00]);
+        client.reconnect().unwrap();
+        assert_eq!(client.effective_keep_alive(), Some(Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn client_stability_window_test() {
+        let mut opts = ClientOptions::new();
+        opts.set_keep_alive(0);
+        opts.set_reconnect(ReconnectMethod::ReconnectAfter(Duration::from_secs(10)));
+        opts.set_stability_window(Duration::from_millis(30));
+        let clock = MockClock::new();
+        opts.clock = clock.clone();
+        let (mut client, mut stream) = mock_client(opts);
+        stream.set_silent(true);
+        client.reconnect_attempts = 2;
+
+        // an accepted CONNACK alone doesn't count
+        client.terminate();
+        stream.next_vec(vec![0x20, 0x02, 0x00, 0x00, 0xD0, 0x00]);
+        client.reconnect().unwrap();
+        assert!(client.accept().unwrap().is_none());
+        assert_eq!(client.reconnect_attempts, 2);
+
+        clock.advance(Duration::from_millis(29));
+        stream.next_vec(vec![0xD0, 0x00]);
+        assert!(client.accept().unwrap().is_none());
+        assert_eq!(client.reconnect_attempts, 2);
+
+        clock.advance(Duration::from_millis(1));
+        stream.next_vec(vec![0xD0, 0x00]);
+        assert!(client.accept().unwrap().is_none());
+        assert_eq!(client.reconnect_attempts, 0);
+    }
+
+    #[test]
+    fn client_stability_window_default_test() {
+        let mut opts = ClientOptions::new();
+        opts.set_keep_alive(0);
+        opts.set_reconnect(ReconnectMethod::ExponentialBackoff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(60),
+            jitter: Duration::from_secs(0),
+            max_attempts: None
+        });
+        let clock = MockClock::new();
+        opts.clock = clock.clone();
+        let (client, _) = mock_client(opts);
+
+        // as long as the longest backoff, not the first one
+        clock.advance(Duration::from_secs(59));
+        assert!(!client._is_stable());
+        clock.advance(Duration::from_secs(1));
+        assert!(client._is_stable());
+    }
+
+    #[test]
+    fn client_reconnect_storm_test() {
+        let mut opts = ClientOptions::new();
+        opts.set_reconnect(ReconnectMethod::ReconnectAfter(Duration::from_millis(20)));
+        opts.set_max_reconnect_attempts(5);
+        opts.set_client_id("test".to_owned());
+        let clock = MockClock::new();
+        opts.clock = clock.clone();
+        let (mut client, mut stream) = mock_client(opts);
+
+        // every attempt waits out the delay instead of spinning
+        let start = clock.now();
+        assert!(client.await().is_err());
+        assert_eq!(clock.now() - start, Duration::from_millis(100));
+        let connect = vec![0x10, 16,
+            0x00, 0x04, 'M' as u8, 'Q' as u8, 'T' as u8, 'T' as u8, 0x04, 0x02, 0x00, 30,
+            0x00, 0x04, 't' as u8, 'e' as u8, 's' as u8, 't' as u8];
+        assert_eq!(stream.take_vec(), connect.repeat(5));
+
+        // without reconnecting there is nothing to wait for
+        client.set_reconnect(ReconnectMethod::ForeverDisconnect);
+        let start = clock.now();
+        assert!(client.await().is_err());
+        assert_eq!(clock.now(), start);
+    }
+
+    #[test]
+    fn client_last_disconnect_reason_test() {
+        let (mut client, _) = mock_client(ClientOptions::new());
+        assert!(client.last_disconnect_reason().is_none());
+
+        match client.accept() {
+            Err(Error::Disconnected) => (),
+            other => panic!("expected Disconnected, got {:?}", other)
+        }
+        match client.last_disconnect_reason() {
+            Some(&Error::Mqtt(MQError::UnexpectedEof)) => (),
+            other => panic!("expected UnexpectedEof, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn client_incomplete_packet_test() {
+        let (mut client, mut stream) = mock_client(ClientOptions::new());
+        stream.next_vec(vec![0x30, 0x86]);
+        match client.accept() {
+            Err(Error::Disconnected) => (),
+            other => panic!("expected Disconnected, got {:?}", other)
+        }
+        match client.last_disconnect_reason() {
+            Some(&Error::Mqtt(MQError::IncompletePacket)) => (),
+            other => panic!("expected IncompletePacket, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn client_reason_string_test() {
+        let mut opts = ClientOptions::new();
+        opts.set_protocol(Protocol::MQTT(5));
+        let mut netopt = NetworkOptions::new();
+        // CONNACK Not authorized, reason "no"
+        netopt.attach(MockStream::with_vec(vec![0x20, 0x08, 0x00, 0x87, 0x05, 0x1F, 0x00, 0x02, 'n' as u8, 'o' as u8]));
+        match opts.connect("127.0.0.1:1883", netopt) {
+            Err(Error::ConnectionRefused { code: ConnectReturnCode::NotAuthorized, reason_code: Some(0x87), reason: Some(ref reason) }) => {
+                assert_eq!(reason, "no")
+            }
+            Err(err) => panic!("expected ConnectionRefused, got {:?}", err),
+            Ok(_) => panic!("expected ConnectionRefused")
+        }
+
+        let mut opts = ClientOptions::new();
+        opts.set_protocol(Protocol::MQTT(5));
+        let mut stream = MockStream::with_vec(vec![0x20, 0x03, 0x00, 0x00, 0x00]);
+        let mut netopt = NetworkOptions::new();
+        netopt.attach(stream.clone());
+        let mut client = opts.connect("127.0.0.1:1883", netopt).unwrap();
+        // DISCONNECT Server shutting down, reason "no"
+        stream.next_vec(vec![0xe0, 0x07, 0x8B, 0x05, 0x1F, 0x00, 0x02, 'n' as u8, 'o' as u8]);
+        match client.accept() {
+            Err(Error::Disconnected) => (),
+            other => panic!("expected Disconnected, got {:?}", other)
+        }
+        match client.last_disconnect_reason() {
+            Some(&Error::ServerDisconnect { code: 0x8B, reason: Some(ref reason) }) => assert_eq!(reason, "no"),
+            other => panic!("expected ServerDisconnect, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn client_session_taken_over_test() {
+        let mut opts = ClientOptions::new();
+        opts.set_protocol(Protocol::MQTT(5));
+        opts.set_reconnect(ReconnectMethod::ReconnectAfter(Duration::from_millis(1)));
+        let mut stream = MockStream::with_vec(vec![0x20, 0x03, 0x00, 0x00, 0x00]);
+        let mut netopt = NetworkOptions::new();
+        netopt.attach(stream.clone());
+        let mut client = opts.connect("127.0.0.1:1883", netopt).unwrap();
+        // DISCONNECT Session taken over
+        stream.next_vec(vec![0xe0, 0x01, 0x8E]);
+        match client.accept() {
+            Err(Error::SessionTakenOver) => (),
+            other => panic!("expected SessionTakenOver, got {:?}", other)
+        }
+        // no reconnect
+        stream.take_vec();
+        match client.accept() {
+            Err(Error::Disconnected) => (),
+            other => panic!("expected Disconnected, got {:?}", other)
+        }
+        assert!(stream.take_vec().is_empty());
+        match client.last_disconnect_reason() {
+            Some(&Error::SessionTakenOver) => (),
+            other => panic!("expected SessionTakenOver, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn client_takeover_detection_test() {
+        let mut opts = ClientOptions::new();
+        opts.set_keep_alive(0);
+        opts.set_max_reconnect_attempts(1);
+        let (mut client, mut stream) = mock_client(opts);
+
+        // the broker drops us right after every CONNACK
+        for _ in 0..2 {
+            stream.next_vec(Vec::new());
+            match client.accept() {
+                Err(Error::Disconnected) => (),
+                other => panic!("expected Disconnected, got {:?}", other)
+            }
+            stream.next_vec(vec![0x20, 0x02, 0x00, 0x00]);
+            client.reconnect().unwrap();
+        }
+        stream.next_vec(Vec::new());
+        match client.accept() {
+            Err(Error::SessionTakenOver) => (),
+            other => panic!("expected SessionTakenOver, got {:?}", other)
+        }
+
+        // no reconnect after a takeover
+        client.set_reconnect(ReconnectMethod::ReconnectAfter(Duration::from_millis(1)));
+        stream.take_vec();
+        assert!(client.accept().is_err());
+        assert!(stream.take_vec().is_empty());
+
+        // unless told otherwise
+        client.set_takeover(TakeoverMethod::Reconnect);
+        assert!(client.accept().is_err());
+        assert!(!stream.take_vec().is_empty());
+    }
+
+    #[test]
+    fn client_unexpected_packet_test() {
+        let connect = vec![0x10, 12, 0x00, 0x04, 'M' as u8, 'Q' as u8, 'T' as u8, 'T' as u8,
+                           0x04, 0x02, 0x00, 0x3c, 0x00, 0x00];
+
+        let (mut client, mut stream) = mock_client(ClientOptions::new());
+        stream.next_vec(connect.clone());
+        match client.accept() {
+            Err(Error::Disconnected) => (),
+            other => panic!("expected Disconnected, got {:?}", other)
+        }
+        match client.last_disconnect_reason() {
+            Some(&Error::UnexpectedPacket(_)) => (),
+            other => panic!("expected UnexpectedPacket, got {:?}", other)
+        }
+
+        let mut opts = ClientOptions::new();
+        opts.set_unexpected_packet(UnexpectedPacketMethod::Ignore);
+        let (mut client, mut stream) = mock_client(opts);
+        stream.next_vec(connect);
+        assert!(client.accept().unwrap().is_none());
+        assert_eq!(client.core.state, ClientState::Connected);
+
+        // a PINGREQ is dropped whatever the policy
+        let (mut client, mut stream) = mock_client(ClientOptions::new());
+        stream.next_vec(vec![0xc0, 0x00]);
+        assert!(client.accept().unwrap().is_none());
+        assert_eq!(client.core.state, ClientState::Connected);
+        assert!(stream.take_vec().is_empty());
+    }
+
+    #[test]
+    fn client_keep_alive_disabled_test() {
+        let mut opts = ClientOptions::new();
+        opts.set_keep_alive(0);
+        assert!(opts.keep_alive.is_none());
+        let (mut client, mut stream) = mock_client(opts);
+        stream.set_silent(true);
+        assert!(client.core.poll_event().is_some());
+        match client.poll(Duration::from_millis(20)) {
+            Ok(Event::Idle) => (),
+            other => panic!("expected Idle, got {:?}", other)
+        }
+        // no PINGREQ
+        assert!(stream.take_vec().is_empty());
+        assert_eq!(client.core.state, ClientState::Connected);
+    }
+
+    #[test]
+    fn client_poll_test() {
+        let (mut client, mut stream) = mock_client(ClientOptions::new());
+        stream.set_silent(true);
+        // the first CONNACK, polled for or not
+        match client.poll(Duration::from_millis(0)) {
+            Ok(Event::Connected) => (),
+            other => panic!("expected Connected, got {:?}", other)
+        }
+        match client.poll(Duration::from_millis(0)) {
+            Ok(Event::Idle) => (),
+            other => panic!("expected Idle, got {:?}", other)
+        }
+
+        client.publish("a/b", vec![0x01], PubOpt::at_least_once()).unwrap();
+        client.subscribe("a/+").unwrap();
+        stream.take_vec();
+        stream.next_vec(vec![0b01000000, 0x02, 0x00, 0x01,
+                             0b00110000, 6, 0x00, 0x03, 'a' as u8, '/' as u8, 'b' as u8, 0x02,
+                             0x90, 0x03, 0x00, 0x02, 0x00]);
+        assert_eq!(client.pending_publishes(), 1);
+        match client.poll(Duration::from_secs(1)) {
+            Ok(Event::PubAck(pid)) => assert_eq!(pid.0, 1),
+            other => panic!("expected PubAck, got {:?}", other)
+        }
+        assert_eq!(client.pending_publishes(), 0);
+        match client.poll(Duration::from_secs(1)) {
+            Ok(Event::Message(message)) => assert_eq!(*message.payload, vec![0x02]),
+            other => panic!("expected Message, got {:?}", other)
+        }
+        match client.poll(Duration::from_secs(1)) {
+            Ok(Event::SubAck(pid, codes)) => {
+                assert_eq!(pid.0, 2);
+                assert_eq!(codes, vec![SubscribeReturnCodes::Success(QoS::AtMostOnce)]);
+            }
+            other => panic!("expected SubAck, got {:?}", other)
+        }
+
+        // the broker went away
+        stream.set_silent(false);
+        match client.poll(Duration::from_secs(1)) {
+            Ok(Event::Disconnected) => (),
+            other => panic!("expected Disconnected, got {:?}", other)
+        }
+        match client.poll(Duration::from_secs(1)) {
+            Err(Error::Disconnected) => (),
+            other => panic!("expected Disconnected error, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn client_flush_threshold_test() {
+        let mut opts = ClientOptions::new();
+        opts.set_flush_threshold(8);
+        let (mut client, mut stream) = mock_client(opts);
+
+        // 8 bytes fit under the threshold, the next publish goes over it
+        client._publish("a/b", vec![0x01], PubOpt::at_most_once()).unwrap();
+        assert!(stream.take_vec().is_empty());
+        client._publish("a/b", vec![0x02], PubOpt::at_most_once()).unwrap();
+        assert_eq!(stream.take_vec().len(), 16);
+
+        client._publish("a/b", vec![0x03], PubOpt::at_most_once()).unwrap();
+        assert!(stream.take_vec().is_empty());
+        client.flush().unwrap();
+        assert_eq!(stream.take_vec().len(), 8);
+    }
+
+    #[test]
+    fn client_id_prefix_test() {
+        let mut opts = ClientOptions::new();
+        opts.generate_client_id();
+        let id = opts.client_id.clone().unwrap();
+        assert!(id.starts_with("mqttc_"));
+        assert_eq!(id.len(), 22);
+
+        opts.set_client_id_prefix("fleet-".to_owned());
+        opts.generate_client_id();
+        let id = opts.client_id.clone().unwrap();
+        assert!(id.starts_with("fleet-"));
+        assert!(u64::from_str_radix(&id[6..], 16).is_ok());
+    }
+
+    #[test]
+    fn client_id_source_test() {
+        let mut opts = ClientOptions::new();
+        opts.set_client_id_source(|| 0xdeadbeef);
+        opts.generate_client_id();
+        assert_eq!(opts.client_id, Some("mqttc_00000000deadbeef".to_owned()));
+
+        let ids: Vec<_> = (0..2).map(|_| {
+            let mut rng = StdRng::seed_from_u64(7);
+            let mut opts = ClientOptions::new();
+            opts.set_client_id_source(move || rng.gen());
+            opts.generate_client_id();
+            opts.client_id.unwrap()
+        }).collect();
+        assert_eq!(ids[0], ids[1]);
+    }
+
+    #[test]
+    fn client_connect_transport_test() {
+        let mut stream = MockStream::with_vec(vec![0b00100000, 0x02, 0x01, 0x00]);
+        let mut netopt = NetworkOptions::new();
+        netopt.attach(stream.clone());
+        let mut opts = ClientOptions::new();
+        opts.set_client_id("test".to_owned());
+        let mut client = opts.connect_transport("127.0.0.1:1883", netopt).unwrap();
+        assert!(stream.take_vec().is_empty());
+
+        client.handshake().unwrap();
+        assert!(client.session_present());
+        assert_eq!(stream.take_vec().len(), 18);
+        match client.handshake() {
+            Err(Error::AlreadyConnected) => (),
+            other => panic!("expected AlreadyConnected, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn client_interleaved_acks_test() {
+        let (mut client, mut stream) = mock_client(ClientOptions::new());
+        client.subscribe("a").unwrap();
+        client.subscribe("b").unwrap();
+        client.unsubscribe("c").unwrap();
+        client.unsubscribe("d").unwrap();
+        stream.take_vec();
+
+        // acks of the second requests overtake the first ones
+        stream.next_vec(vec![0xb0, 0x02, 0x00, 0x04,
+                             0x90, 0x03, 0x00, 0x02, 0x01,
+                             0xb0, 0x02, 0x00, 0x03,
+                             0x90, 0x03, 0x00, 0x01, 0x00]);
+        for _ in 0..4 {
+            assert!(client.accept().unwrap().is_none());
+        }
+        assert_eq!(client.core.subscriptions.get("a").unwrap().qos, QoS::AtMostOnce);
+        assert_eq!(client.core.subscriptions.get("b").unwrap().qos, QoS::AtLeastOnce);
+        assert!(client.core.await_suback.is_empty());
+        assert!(client.core.await_unsuback.is_empty());
+    }
+
+    #[test]
+    fn client_uptime_test() {
+        let mut opts = ClientOptions::new();
+        let clock = MockClock::new();
+        opts.clock = clock.clone();
+        let (mut client, mut stream) = mock_client(opts);
+        assert_eq!(client.connected_since(), Some(clock.now()));
+        clock.advance(Duration::from_millis(10));
+        assert_eq!(client.total_uptime(), Duration::from_millis(10));
+        client.terminate();
+        assert!(client.connected_since().is_none());
+        assert_eq!(client.total_uptime(), Duration::from_millis(10));
+
+        // the time spent disconnected doesn't count
+        clock.advance(Duration::from_millis(10));
+        assert_eq!(client.total_uptime(), Duration::from_millis(10));
+        stream.next_vec(vec![0b00100000, 0x02, 0x00, 0x00]);
+        client.reconnect().unwrap();
+        clock.advance(Duration::from_millis(5));
+        assert_eq!(client.total_uptime(), Duration::from_millis(15));
+    }
+
+    #[test]
+    fn client_subscribe_sync_test() {
+        let (mut client, mut stream) = mock_client(ClientOptions::new());
+        client.subscribe("a").unwrap();
+        stream.take_vec();
+        // the earlier subscription is acked meanwhile
+        stream.next_vec(vec![0x90, 0x03, 0x00, 0x01, 0x00,
+                             0x90, 0x03, 0x00, 0x02, 0x80]);
+        let code = client.subscribe_sync("b/#", QoS::ExactlyOnce, Duration::from_secs(1)).unwrap();
+        assert_eq!(code, SubscribeReturnCodes::Failure);
+        assert!(client.core.subscriptions.contains_key("a"));
+        assert!(!client.core.subscriptions.contains_key("b/#"));
+    }
+
+    #[test]
+    fn client_second_connect_test() {
+        let (mut client, mut stream) = mock_client(ClientOptions::new());
+        match client._handshake() {
+            Err(Error::AlreadyConnected) => (),
+            other => panic!("expected AlreadyConnected, got {:?}", other)
+        }
+        match client._connect() {
+            Err(Error::AlreadyConnected) => (),
+            other => panic!("expected AlreadyConnected, got {:?}", other)
+        }
+        assert_eq!(client.core.state, ClientState::Connected);
+        assert!(stream.take_vec().is_empty());
+    }
+
+    #[test]
+    fn client_poll_decoded_test() {
+        let mut codecs = CodecRegistry::new();
+        codecs.register("a/+", |payload: &[u8]| -> Result<u8, Error> { Ok(payload[0]) }).unwrap();
+        let mut opts = ClientOptions::new();
+        opts.set_codecs(codecs);
+        let (mut client, mut stream) = mock_client(opts);
+        assert!(client.core.poll_event().is_some());
+
+        stream.next_vec(vec![0b00110000, 6, 0x00, 0x03, 'a' as u8, '/' as u8, 'b' as u8, 0x07,
+                             0b00110000, 4, 0x00, 0x01, 'b' as u8, 0x08]);
+        match client.poll(Duration::from_secs(1)) {
+            Ok(Event::Decoded(message, value)) => {
+                assert_eq!(message.topic.path, "a/b");
+                assert_eq!(*value.downcast::<u8>().unwrap(), 7);
+            }
+            other => panic!("expected Decoded, got {:?}", other)
+        }
+        match client.poll(Duration::from_secs(1)) {
+            Ok(Event::Message(message)) => assert_eq!(message.topic.path, "b"),
+            other => panic!("expected Message, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn client_into_options_test() {
+        let (client, _) = mock_client(ClientOptions::new());
+        let opts = client.into_options();
+        let client_id = opts.client_id.clone();
+        assert!(client_id.is_some());
+
+        let (client, _) = mock_client(opts);
+        assert_eq!(client.core.opts.client_id, client_id);
+        assert_eq!(client.core.state, ClientState::Connected);
+    }
+
+    // A mock clock which starts listening on `socket` once it slept
+    // `sleeps` times, until then connecting to it is refused
+    struct ListenClock {
+        clock: Arc<MockClock>,
+        socket: Socket,
+        sleeps: Mutex<u32>,
+        listening: Mutex<Sender<()>>
+    }
+
+    impl Clock for ListenClock {
+        fn now(&self) -> Instant {
+            self.clock.now()
+        }
+
+        fn sleep(&self, dur: Duration) {
+            self.clock.sleep(dur);
+            let mut sleeps = self.sleeps.lock().unwrap();
+            *sleeps -= 1;
+            if *sleeps == 0 {
+                self.socket.listen(1).unwrap();
+                self.listening.lock().unwrap().send(()).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn client_first_connect_retry_test() {
+        // bound but not listening: the port is held, connecting is refused
+        let socket = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+        socket.bind(&"127.0.0.1:0".parse::<SocketAddr>().unwrap().into()).unwrap();
+        let addr = socket.local_addr().unwrap().as_socket().unwrap();
+
+        // without reconnecting the first failure is final
+        let mut opts = ClientOptions::new();
+        opts.set_reconnect(ReconnectMethod::ForeverDisconnect);
+        assert!(opts.connect(addr, NetworkOptions::new()).is_err());
+
+        let (tx, rx) = mpsc::channel();
+        let listener: TcpListener = socket.try_clone().unwrap().into();
+        let broker = thread::spawn(move || {
+            rx.recv().unwrap();
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(&[0b00100000, 0x02, 0x00, 0x00]).unwrap();
+            let mut connect = [0; 2];
+            stream.read_exact(&mut connect).unwrap();
+        });
+
+        let mock = MockClock::new();
+        let start = mock.now();
+        let mut opts = ClientOptions::new();
+        opts.clock = Arc::new(ListenClock {
+            clock: mock.clone(),
+            socket,
+            sleeps: Mutex::new(3),
+            listening: Mutex::new(tx)
+        });
+        opts.set_reconnect(ReconnectMethod::ReconnectAfter(Duration::from_secs(20)));
+        opts.set_max_reconnect_attempts(5);
+        let client = opts.connect(addr, NetworkOptions::new()).unwrap();
+        assert_eq!(client.core.state, ClientState::Connected);
+        // three refused attempts, each followed by a wait
+        assert_eq!(mock.now() - start, Duration::from_secs(60));
+        broker.join().unwrap();
+    }
+
+    #[test]
+    fn client_peer_addr_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let broker = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(&[0b00100000, 0x02, 0x00, 0x00]).unwrap();
+            // until the client goes away
+            let _ = stream.read_to_end(&mut Vec::new());
+        });
+
+        let client = ClientOptions::new().connect(addr, NetworkOptions::new()).unwrap();
+        assert_eq!(client.peer_addr(), addr);
+        assert_eq!(client.broker_addr(), addr);
+        assert_eq!(client.transport_kind(), TransportKind::Tcp);
+        drop(client);
+        broker.join().unwrap();
+    }
+
+    // Keeps messages in memory and counts how many were put
+    struct CountingStore {
+        messages: HashMap<PacketIdentifier, Box<Message>>,
+        puts: Arc<Mutex<usize>>
+    }
+
+    impl Store for CountingStore {
+        fn put(&mut self, message: Box<Message>) -> store::Result<()> {
+            *self.puts.lock().unwrap() += 1;
+            self.messages.insert(message.pid.unwrap(), message);
+            Ok(())
+        }
+
+        fn get(&mut self, pid: PacketIdentifier) -> store::Result<Box<Message>> {
+            self.messages.get(&pid).cloned().ok_or(store::Error::NotFound(pid))
+        }
+
+        fn delete(&mut self, pid: PacketIdentifier) -> store::Result<()> {
+            self.messages.remove(&pid).map(|_| ()).ok_or(store::Error::NotFound(pid))
+        }
+
+        fn keys(&self) -> store::Result<Vec<PacketIdentifier>> {
+            let mut keys: Vec<PacketIdentifier> = self.messages.keys().cloned().collect();
+            keys.sort();
+            Ok(keys)
+        }
+    }
+
+    #[test]
+    fn client_retained_delivery_test() {
+        let mut opts = ClientOptions::new();
+        opts.set_incomming_store(Box::new(CountingStore { messages: HashMap::new(), puts: Arc::new(Mutex::new(0)) }));
+        let (mut client, mut stream) = mock_client(opts);
+
+        stream.next_vec(vec![0b00110001, 4, 0x00, 0x01, 'a' as u8, 0x01]);
+        assert!(client.accept().unwrap().unwrap().retain);
+        stream.next_vec(vec![0b00110000, 4, 0x00, 0x01, 'a' as u8, 0x02]);
+        assert!(!client.accept().unwrap().unwrap().retain);
+
+        // a QoS 2 message keeps it through the store
+        stream.next_vec(vec![0b00110101, 6, 0x00, 0x01, 'a' as u8, 0x00, 0x07, 0x03]);
+        assert!(client.accept().unwrap().is_none());
+        stream.next_vec(vec![0x62, 0x02, 0x00, 0x07]);
+        assert!(client.accept().unwrap().unwrap().retain);
+    }
+
+    #[test]
+    fn client_qos2_duplicate_publish_test() {
+        let puts = Arc::new(Mutex::new(0));
+        let mut opts = ClientOptions::new();
+        opts.set_incomming_store(Box::new(CountingStore { messages: HashMap::new(), puts: puts.clone() }));
+        let (mut client, mut stream) = mock_client(opts);
+
+        let publish = vec![0b00110100, 6, 0x00, 0x01, 'a' as u8, 0x00, 0x05, 0x01];
+        let mut dup = publish.clone();
+        dup[0] |= 0b00001000;
+        stream.next_vec([publish, dup].concat());
+        assert!(client.accept().unwrap().is_none());
+        assert!(client.accept().unwrap().is_none());
+        assert_eq!(*puts.lock().unwrap(), 1);
+        assert_eq!(stream.take_vec(), vec![0x50, 0x02, 0x00, 0x05, 0x50, 0x02, 0x00, 0x05]);
+
+        stream.next_vec(vec![0x62, 0x02, 0x00, 0x05]);
+        let message = client.accept().unwrap().unwrap();
+        assert_eq!(*message.payload, vec![0x01]);
+        assert!(client.core.incomming_rec.is_empty());
+        client.complete(PacketIdentifier(5)).unwrap();
+        assert_eq!(stream.take_vec(), vec![0x70, 0x02, 0x00, 0x05]);
+        assert_eq!(client.pending_incoming(), 0);
+        assert!(client.core.incomming_pids.is_empty());
+    }
+
+    #[test]
+    fn client_unsubscribe_matching_test() {
+        let (mut client, mut stream) = mock_client(ClientOptions::new());
+        client.subscribe("a/b").unwrap();
+        client.subscribe("a/+").unwrap();
+        client.subscribe("c").unwrap();
+        stream.next_vec(vec![0x90, 0x03, 0x00, 0x01, 0x00,
+                             0x90, 0x03, 0x00, 0x02, 0x00,
+                             0x90, 0x03, 0x00, 0x03, 0x00]);
+        for _ in 0..3 {
+            client.accept().unwrap();
+        }
+        stream.take_vec();
+
+        let filters = client.unsubscribe_matching("a/#").unwrap();
+        assert_eq!(filters, vec!["a/+".to_owned(), "a/b".to_owned()]);
+        assert_eq!(stream.take_vec(), vec![
+            0xa2, 0x07, 0x00, 0x04, 0x00, 0x03, 'a' as u8, '/' as u8, '+' as u8,
+            0xa2, 0x07, 0x00, 0x05, 0x00, 0x03, 'a' as u8, '/' as u8, 'b' as u8]);
+
+        stream.next_vec(vec![0xb0, 0x02, 0x00, 0x04, 0xb0, 0x02, 0x00, 0x05]);
+        client.accept().unwrap();
+        client.accept().unwrap();
+        assert_eq!(client.core.subscriptions.keys().collect::<Vec<_>>(), vec!["c"]);
+    }
+
+    #[test]
+    fn client_unsubscribe_matching_wildcards_test() {
+        let (mut client, mut stream) = mock_client(ClientOptions::new());
+        client.subscribe("a/#").unwrap();
+        client.subscribe("a/b").unwrap();
+        stream.next_vec(vec![0x90, 0x03, 0x00, 0x01, 0x00,
+                             0x90, 0x03, 0x00, 0x02, 0x00]);
+        client.accept().unwrap();
+        client.accept().unwrap();
+        stream.take_vec();
+
+        // a/+ is narrower than a/#, which stays
+        let filters = client.unsubscribe_matching("a/+").unwrap();
+        assert_eq!(filters, vec!["a/b".to_owned()]);
+        assert_eq!(stream.take_vec(), vec![
+            0xa2, 0x07, 0x00, 0x03, 0x00, 0x03, 'a' as u8, '/' as u8, 'b' as u8]);
+    }
+
+    #[test]
+    fn client_collect_test() {
+        let mut opts = ClientOptions::new();
+        opts.set_keep_alive(0);
+        let (mut client, mut stream) = mock_client(opts);
+        stream.set_silent(true);
+        stream.next_vec(vec![
+            0x90, 0x03, 0x00, 0x01, 0x00, // SUBACK
+            0x30, 0x04, 0x00, 0x01, 'x' as u8, 0x01,
+            0x30, 0x06, 0x00, 0x03, 'a' as u8, '/' as u8, 'b' as u8, 0x02,
+            0x30, 0x06, 0x00, 0x03, 'a' as u8, '/' as u8, 'c' as u8, 0x03
+        ]);
+        let messages = client.collect("a/+", 2, Duration::from_secs(1)).unwrap();
+        assert_eq!(messages.iter().map(|m| m.payload[0]).collect::<Vec<u8>>(), vec![0x02, 0x03]);
+        assert_eq!(stream.take_vec(), vec![
+            0x82, 0x08, 0x00, 0x01, 0x00, 0x03, 'a' as u8, '/' as u8, '+' as u8, 0x01,
+            0xA2, 0x07, 0x00, 0x02, 0x00, 0x03, 'a' as u8, '/' as u8, '+' as u8
+        ]);
+        // not for the collected filter
+        assert_eq!(client.accept().unwrap().unwrap().topic.path(), "x");
+
+        // the timeout hands out what arrived
+        stream.next_vec(vec![
+            0x90, 0x03, 0x00, 0x03, 0x00,
+            0x30, 0x06, 0x00, 0x03, 'a' as u8, '/' as u8, 'b' as u8, 0x04
+        ]);
+        let messages = client.collect("a/+", 2, Duration::from_millis(50)).unwrap();
+        assert_eq!(messages.len(), 1);
+    }
+
+    #[test]
+    fn client_subscribe_no_topics_test() {
+        let (mut client, mut stream) = mock_client(ClientOptions::new());
+        match client.subscribe(Vec::<SubscribeTopic>::new()) {
+            Err(Error::Mqtt(MQError::IncorrectPacketFormat)) => (),
+            other => panic!("expected IncorrectPacketFormat, got {:?}", other)
+        }
+        match client.unsubscribe(Vec::<String>::new()) {
+            Err(Error::Mqtt(MQError::IncorrectPacketFormat)) => (),
+            other => panic!("expected IncorrectPacketFormat, got {:?}", other)
+        }
+        assert!(stream.take_vec().is_empty());
+        assert!(client.core.await_suback.is_empty());
+        assert!(client.core.await_unsuback.is_empty());
+    }
+
+    #[test]
+    fn client_puback_ordering_test() {
+        let mut opts = ClientOptions::new();
+        opts.set_keep_alive(0);
+        opts.set_outgoing_store(Box::new(CountingStore { messages: HashMap::new(), puts: Arc::new(Mutex::new(0)) }));
+        let (mut client, mut stream) = mock_client(opts);
+        stream.set_silent(true);
+        for _ in 0..2 {
+            client.publish("a", "x", PubOpt::at_least_once()).unwrap();
+        }
+        // the second publish acknowledged first
+        stream.next_vec(vec![0x40, 0x02, 0x00, 0x02]);
+        match client.accept() {
+            Err(Error::ProtocolViolation) => (),
+            other => panic!("expected ProtocolViolation, got {:?}", other)
+        }
+        assert_eq!(client.pending_publishes(), 2);
+        stream.next_vec(vec![0x40, 0x02, 0x00, 0x01, 0x40, 0x02, 0x00, 0x02]);
+        assert!(client.accept().unwrap().is_none());
+        assert!(client.accept().unwrap().is_none());
+        assert_eq!(client.pending_publishes(), 0);
+
+        let mut opts = ClientOptions::new();
+        opts.set_keep_alive(0);
+        opts.set_strict_ordering(false);
+        opts.set_outgoing_store(Box::new(CountingStore { messages: HashMap::new(), puts: Arc::new(Mutex::new(0)) }));
+        let (mut client, mut stream) = mock_client(opts);
+        stream.set_silent(true);
+        for _ in 0..2 {
+            client.publish("a", "x", PubOpt::at_least_once()).unwrap();
+        }
+        stream.next_vec(vec![0x40, 0x02, 0x00, 0x02]);
+        assert!(client.accept().unwrap().is_none());
+        assert_eq!(client.pending_publishes(), 1);
+        stream.next_vec(vec![0x40, 0x02, 0x00, 0x01]);
+        assert!(client.accept().unwrap().is_none());
+        assert_eq!(client.pending_publishes(), 0);
+    }
+
+    #[test]
+    fn client_outgoing_store_test() {
+        let mut opts = ClientOptions::new();
+        opts.set_keep_alive(0);
+        opts.set_outgoing_store(Box::new(CountingStore { messages: HashMap::new(), puts: Arc::new(Mutex::new(0)) }));
+        let mut client = opts.build_offline().unwrap();
+        assert!(client.incomming_store().is_none());
+
+        for _ in 0..2 {
+            client.publish("a", "x", PubOpt::exactly_once()).unwrap();
+        }
+        assert_eq!(client.outgoing_store().unwrap().keys().unwrap(), vec![PacketIdentifier(1), PacketIdentifier(2)]);
+        client.feed(vec![0x50, 0x02, 0x00, 0x01]);
+        assert!(client.accept().unwrap().is_none());
+        assert_eq!(client.outgoing_store().unwrap().keys().unwrap(), vec![PacketIdentifier(2)]);
+    }
+
+    // Refuses everything
+    struct FailingStore;
+
+    impl Store for FailingStore {
+        fn put(&mut self, message: Box<Message>) -> store::Result<()> {
+            Err(store::Error::Unavailable(message.pid.unwrap()))
+        }
+
+        fn get(&mut self, pid: PacketIdentifier) -> store::Result<Box<Message>> {
+            Err(store::Error::Unavailable(pid))
+        }
+
+        fn delete(&mut self, pid: PacketIdentifier) -> store::Result<()> {
+            Err(store::Error::Unavailable(pid))
+        }
+    }
+
+    #[test]
+    fn client_store_error_test() {
+        let mut opts = ClientOptions::new();
+        opts.set_keep_alive(0);
+        opts.set_outgoing_store(Box::new(FailingStore));
+        opts.set_incomming_store(Box::new(FailingStore));
+        let mut client = opts.build_offline().unwrap();
+        assert!(client.outgoing_store().unwrap().keys().is_err());
+
+        let err = client.publish("a", "x", PubOpt::exactly_once()).unwrap_err();
+        assert_eq!(err.to_string(), "Failed to persist outgoing pid 1: Packet 1 unavailable");
+        client.feed(vec![0x34, 0x06, 0x00, 0x01, 'a' as u8, 0x00, 0x07, 'x' as u8]);
+        let err = client.accept().unwrap_err();
+        assert_eq!(err.to_string(), "Failed to persist incoming pid 7: Packet 7 unavailable");
+    }
+
+    // Hands out the message of pid 1 for every pid
+    struct MixedUpStore(Option<Box<Message>>);
+
+    impl Store for MixedUpStore {
+        fn put(&mut self, message: Box<Message>) -> store::Result<()> {
+            if self.0.is_none() {
+                self.0 = Some(message);
+            }
+            Ok(())
+        }
+
+        fn get(&mut self, pid: PacketIdentifier) -> store::Result<Box<Message>> {
+            self.0.clone().ok_or(store::Error::NotFound(pid))
+        }
+
+        fn delete(&mut self, _: PacketIdentifier) -> store::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn client_store_mismatch_test() {
+        let mut opts = ClientOptions::new();
+        opts.set_keep_alive(0);
+        opts.set_incomming_store(Box::new(MixedUpStore(None)));
+        let mut client = opts.build_offline().unwrap();
+
+        client.feed(vec![0x34, 0x06, 0x00, 0x01, 'a' as u8, 0x00, 0x01, 'x' as u8,
+                         0x62, 0x02, 0x00, 0x01,
+                         0x34, 0x06, 0x00, 0x01, 'a' as u8, 0x00, 0x02, 'y' as u8,
+                         0x62, 0x02, 0x00, 0x02]);
+        assert!(client.accept().unwrap().is_none());
+        assert_eq!(*client.accept().unwrap().unwrap().payload, vec!['x' as u8]);
+        client.complete(PacketIdentifier(1)).unwrap();
+        assert!(client.accept().unwrap().is_none());
+        let err = client.accept().unwrap_err();
+        assert_eq!(err.to_string(), "Failed to load incoming pid 2: Packet 2 mismatched");
+    }
+
+    // Records what was on the wire when asked to sync
+    struct SyncStore {
+        stream: MockStream,
+        written_at_sync: Arc<Mutex<Vec<Vec<u8>>>>
+    }
+
+    impl Store for SyncStore {
+        fn put(&mut self, _: Box<Message>) -> store::Result<()> {
+            Ok(())
+        }
+
+        fn get(&mut self, pid: PacketIdentifier) -> store::Result<Box<Message>> {
+            Err(store::Error::NotFound(pid))
+        }
+
+        fn delete(&mut self, _: PacketIdentifier) -> store::Result<()> {
+            Ok(())
+        }
+
+        fn sync(&mut self) -> store::Result<()> {
+            self.written_at_sync.lock().unwrap().push(self.stream.take_vec());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn client_store_sync_test() {
+        let written_at_sync = Arc::new(Mutex::new(Vec::new()));
+        let mut stream = MockStream::with_vec(vec![0b00100000, 0x02, 0x00, 0x00]);
+        let mut opts = ClientOptions::new();
+        opts.set_keep_alive(0);
+        opts.set_outgoing_store(Box::new(SyncStore { stream: stream.clone(), written_at_sync: written_at_sync.clone() }));
+        opts.set_incomming_store(Box::new(SyncStore { stream: stream.clone(), written_at_sync: written_at_sync.clone() }));
+        let mut netopt = NetworkOptions::new();
+        netopt.attach(stream.clone());
+        let mut client = opts.connect("127.0.0.1:1883", netopt).unwrap();
+        stream.take_vec();
+
+        client.publish("a", "x", PubOpt::exactly_once()).unwrap();
+        stream.next_vec(vec![0x34, 0x06, 0x00, 0x01, 'a' as u8, 0x00, 0x07, 'x' as u8]);
+        assert!(client.accept().unwrap().is_none());
+
+        // neither the PUBLISH nor the PUBREC went out before their sync
+        let written = written_at_sync.lock().unwrap();
+        assert!(written[0].is_empty());
+        assert_eq!(written[1], vec![0x34, 0x06, 0x00, 0x01, 'a' as u8, 0x00, 0x01, 'x' as u8]);
+        assert_eq!(stream.take_vec(), vec![0x50, 0x02, 0x00, 0x07]);
+    }
+
+    #[test]
+    fn client_cancel_publish_test() {
+        let mut opts = ClientOptions::new();
+        opts.set_keep_alive(0);
+        opts.set_outgoing_store(Box::new(CountingStore { messages: HashMap::new(), puts: Arc::new(Mutex::new(0)) }));
+        let mut client = opts.build_offline().unwrap();
+        client.publish("a", "x", PubOpt::exactly_once()).unwrap();
+        client.publish("b", "y", PubOpt::exactly_once()).unwrap();
+        assert_eq!(client.pending_publishes(), 2);
+
+        let stuck = PacketIdentifier(1);
+        assert!(client.cancel_publish(stuck).unwrap());
+        assert!(!client.cancel_publish(stuck).unwrap());
+        assert_eq!(client.pending_publishes(), 1);
+        assert!(client.outgoing_store().unwrap().keys().unwrap() == vec![PacketIdentifier(2)]);
+
+        // the next PUBREC is taken for the remaining message
+        client.take_written();
+        client.feed(vec![0x50, 0x02, 0x00, 0x02]);
+        assert!(client.accept().unwrap().is_none());
+        assert_eq!(client.take_written(), vec![0x62, 0x02, 0x00, 0x02]);
+        assert!(client.cancel_publish(PacketIdentifier(2)).unwrap());
+        assert_eq!(client.pending_publishes(), 0);
+    }
+
+    #[test]
+    fn client_cancel_publish_late_ack_test() {
+        let mut opts = ClientOptions::new();
+        opts.set_keep_alive(0);
+        opts.set_outgoing_store(Box::new(CountingStore { messages: HashMap::new(), puts: Arc::new(Mutex::new(0)) }));
+        let mut client = opts.build_offline().unwrap();
+        for topic in &["a", "b", "c"] {
+            client.publish(*topic, "x", PubOpt::exactly_once()).unwrap();
+        }
+        assert!(client.cancel_publish(PacketIdentifier(1)).unwrap());
+        client.take_written();
+        client.core.events.clear();
+
+        // the broker answers the cancelled publish anyway
+        client.feed(vec![0x50, 0x02, 0x00, 0x01]);
+        match client.accept() {
+            Err(Error::PacketIdentifierError(PacketIdentifierError::UnhandledPubrec(PacketIdentifier(1)))) => (),
+            other => panic!("expected UnhandledPubrec, got {:?}", other)
+        }
+        assert!(client.take_written().is_empty());
+        assert_eq!(client.pending_publishes(), 2);
+        assert!(client.outgoing_store().unwrap().keys().unwrap().len() == 2);
+
+        client.feed(vec![0x50, 0x02, 0x00, 0x02, 0x50, 0x02, 0x00, 0x03]);
+        assert!(client.accept().unwrap().is_none());
+        assert!(client.accept().unwrap().is_none());
+        assert_eq!(client.take_written(), vec![0x62, 0x02, 0x00, 0x02, 0x62, 0x02, 0x00, 0x03]);
+
+        // the PUBCOMP of a cancelled publish doesn't complete another
+        assert!(client.cancel_publish(PacketIdentifier(3)).unwrap());
+        client.feed(vec![0x70, 0x02, 0x00, 0x03]);
+        match client.accept() {
+            Err(Error::PacketIdentifierError(PacketIdentifierError::UnhandledPubcomp(PacketIdentifier(3)))) => (),
+            other => panic!("expected UnhandledPubcomp, got {:?}", other)
+        }
+        assert_eq!(client.pending_publishes(), 1);
+        assert!(client.core.events.is_empty());
+
+        client.feed(vec![0x70, 0x02, 0x00, 0x02]);
+        assert!(client.accept().unwrap().is_none());
+        assert_eq!(client.pending_publishes(), 0);
+        match client.core.events.pop_front() {
+            Some(Event::PubAck(PacketIdentifier(2))) => (),
+            other => panic!("expected PubAck(2), got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn client_ping_and_wait_test() {
+        let mut opts = ClientOptions::new();
+        opts.set_keep_alive(0);
+        let mut client = opts.build_offline().unwrap();
+        client.take_written();
+
+        // a message on the way is kept
+        client.feed(vec![0x30, 0x04, 0x00, 0x01, 'b' as u8, 0x01, 0xD0, 0x00]);
+        client.ping_and_wait(Duration::from_secs(1)).unwrap();
+        assert_eq!(client.take_written(), vec![0xC0, 0x00]);
+        assert_eq!(client.accept().unwrap().unwrap().topic.path(), "b");
+
+        match client.ping_and_wait(Duration::from_millis(20)) {
+            Err(Error::Timeout) => (),
+            other => panic!("expected Timeout, got {:?}", other)
+        }
+
+        // the answer to the PINGREQ which timed out isn't taken for the next's
+        client.feed(vec![0xD0, 0x00]);
+        match client.ping_and_wait(Duration::from_millis(20)) {
+            Err(Error::Timeout) => (),
+            other => panic!("expected Timeout, got {:?}", other)
+        }
+        client.feed(vec![0xD0, 0x00, 0xD0, 0x00]);
+        client.ping_and_wait(Duration::from_secs(1)).unwrap();
+    }
+
+    #[test]
+    fn client_circuit_breaker_test() {
+        let mut opts = ClientOptions::new();
+        opts.set_circuit_breaker(2, Duration::from_secs(60), Duration::from_millis(50));
+        let clock = MockClock::new();
+        opts.clock = clock.clone();
+        let (mut client, mut stream) = mock_client(opts);
+
+        for _ in 0..2 {
+            client.publish("a", "x", PubOpt::at_most_once()).unwrap();
+            // dropped right after
+            stream.next_vec(Vec::new());
+            match client.accept() {
+                Err(Error::Disconnected) => (),
+                other => panic!("expected Disconnected, got {:?}", other)
+            }
+            stream.next_vec(vec![0x20, 0x02, 0x00, 0x00]);
+            client.reconnect().unwrap();
+        }
+        match client.publish("a", "x", PubOpt::at_most_once()) {
+            Err(Error::CircuitOpen) => (),
+            other => panic!("expected CircuitOpen, got {:?}", other)
+        }
+
+        clock.advance(Duration::from_millis(49));
+        assert!(client.publish("a", "x", PubOpt::at_most_once()).is_err());
+        clock.advance(Duration::from_millis(1));
+        client.publish("a", "x", PubOpt::at_most_once()).unwrap();
+        // only the trial until the broker answers
+        assert!(client.publish("a", "x", PubOpt::at_most_once()).is_err());
+        stream.next_vec(vec![0xD0, 0x00]);
+        assert!(client.accept().unwrap().is_none());
+        client.publish("a", "x", PubOpt::at_most_once()).unwrap();
+    }
+
+    #[test]
+    fn client_publish_with_timeout_test() {
+        let mut opts = ClientOptions::new();
+        opts.set_keep_alive(0);
+        opts.set_outgoing_store(Box::new(CountingStore { messages: HashMap::new(), puts: Arc::new(Mutex::new(0)) }));
+        let (mut client, mut stream) = mock_client(opts);
+        stream.set_silent(true);
+
+        client.publish_with_timeout("a", "x", PubOpt::at_most_once(), Duration::from_secs(0)).unwrap();
+
+        // a message on the way is kept
+        stream.next_vec(vec![0x30, 0x04, 0x00, 0x01, 'b' as u8, 0x01, 0x40, 0x02, 0x00, 0x01]);
+        client.publish_with_timeout("a", "x", PubOpt::at_least_once(), Duration::from_secs(1)).unwrap();
+        assert_eq!(client.pending_publishes(), 0);
+        assert_eq!(client.accept().unwrap().unwrap().topic.path(), "b");
+
+        stream.next_vec(vec![0x50, 0x02, 0x00, 0x02, 0x70, 0x02, 0x00, 0x02]);
+        client.publish_with_timeout("a", "x", PubOpt::exactly_once(), Duration::from_secs(1)).unwrap();
+        assert_eq!(client.pending_publishes(), 0);
+
+        stream.next_vec(Vec::new());
+        match client.publish_with_timeout("a", "x", PubOpt::at_least_once(), Duration::from_millis(20)) {
+            Err(Error::Timeout) => (),
+            other => panic!("expected Timeout, got {:?}", other)
+        }
+        assert_eq!(client.pending_publishes(), 1);
+    }
+
+    #[test]
+    fn client_publish_stream_test() {
+        let (mut client, mut stream) = mock_client(ClientOptions::new());
+        let payload = vec![0x5A; 1000];
+        client.publish_stream("ota", io::Cursor::new(payload.clone()), 1000, PubOpt::at_most_once()).unwrap();
+        let sent = stream.take_vec();
+        assert_eq!(&sent[..8], &[0x30, 0xED, 0x07, 0x00, 0x03, 'o' as u8, 't' as u8, 'a' as u8]);
+        assert_eq!(&sent[8..], &payload[..]);
+
+        // nothing to send again from
+        for pubopt in [PubOpt::at_least_once(), PubOpt::exactly_once()] {
+            match client.publish_stream("ota", io::Cursor::new(payload.clone()), 1000, pubopt) {
+                Err(Error::UnsupportedFeature) => (),
+                other => panic!("expected UnsupportedFeature, got {:?}", other)
+            }
+        }
+        assert!(stream.take_vec().is_empty());
+        assert_eq!(client.pending_publishes(), 0);
+
+        // the reader ran dry part way through the packet
+        match client.publish_stream("ota", io::Cursor::new(vec![0; 10]), 1000, PubOpt::at_most_once()) {
+            Err(Error::Disconnected) => (),
+            other => panic!("expected Disconnected, got {:?}", other)
+        }
+        assert_eq!(client.core.state, ClientState::Disconnected);
+    }
+
+    #[test]
+    fn client_publish_rate_limit_test() {
+        let mut opts = ClientOptions::new();
+        opts.set_publish_rate_limit(2);
+        opts.set_rate_limit_method(RateLimitMethod::Fail);
+        let clock = MockClock::new();
+        opts.clock = clock.clone();
+        let (mut client, _) = mock_client(opts);
+        client.publish("a", "", PubOpt::at_most_once()).unwrap();
+        client.publish("a", "", PubOpt::at_most_once()).unwrap();
+        match client.publish("a", "", PubOpt::at_most_once()) {
+            Err(Error::RateLimited) => (),
+            other => panic!("expected RateLimited, got {:?}", other)
+        }
+        clock.advance(Duration::from_millis(500));
+        client.publish("a", "", PubOpt::at_most_once()).unwrap();
+
+        // blocking waits for the next token
+        let mut opts = ClientOptions::new();
+        opts.set_publish_rate_limit(50);
+        let clock = MockClock::new();
+        opts.clock = clock.clone();
+        let (mut client, _) = mock_client(opts);
+        let start = clock.now();
+        for _ in 0..50 {
+            client.publish("a", "", PubOpt::at_most_once()).unwrap();
+        }
+        assert_eq!(clock.now(), start);
+        client.publish("a", "", PubOpt::at_most_once()).unwrap();
+        assert_eq!(clock.now() - start, Duration::from_millis(20));
+    }
+
+    #[test]
+    fn client_subscribe_if_new_test() {
+        let topic = |path: &str, qos| SubscribeTopic { topic_path: path.to_owned(), qos, no_local: false, retain_as_published: false, retain_handling: RetainHandling::SendAtSubscribe };
+        let (mut client, mut stream) = mock_client(ClientOptions::new());
+        client.subscribe(vec![topic("a", QoS::AtMostOnce), topic("b", QoS::AtLeastOnce)]).unwrap();
+        stream.next_vec(vec![0x90, 0x04, 0x00, 0x01, 0x00, 0x01]);
+        client.accept().unwrap();
+        stream.take_vec();
+
+        let sent = client.subscribe_if_new(vec![topic("a", QoS::AtMostOnce),
+                                                topic("b", QoS::AtMostOnce)]).unwrap();
+        assert!(sent.is_empty());
+        assert!(stream.take_vec().is_empty());
+
+        let sent = client.subscribe_if_new(vec![topic("a", QoS::AtLeastOnce),
+                                                topic("b", QoS::AtLeastOnce),
+                                                topic("c", QoS::AtMostOnce)]).unwrap();
+        let filters: Vec<&str> = sent.iter().map(|topic| topic.topic_path.as_str()).collect();
+        assert_eq!(filters, vec!["a", "c"]);
+        assert_eq!(stream.take_vec()[0], 0x82);
+    }
+
+    #[test]
+    fn client_messages_test() {
+        let (mut client, mut stream) = mock_client(ClientOptions::new());
+        stream.next_vec(vec![0b00110000, 4, 0x00, 0x01, 'a' as u8, 0x01,
+                             0b00110000, 4, 0x00, 0x01, 'a' as u8, 0x02]);
+        let payloads: Vec<Vec<u8>> = client.messages()
+                                           .map(|message| (*message.unwrap().payload).clone())
+                                           .collect();
+        assert_eq!(payloads, vec![vec![0x01], vec![0x02]]);
+        assert!(client.last_disconnect_reason().is_some());
+    }
 }
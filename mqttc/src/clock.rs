@@ -0,0 +1,61 @@
+use std::thread;
+use std::time::{Duration, Instant};
+#[cfg(test)]
+use std::sync::{Arc, Mutex};
+
+/// Where the client reads the time and waits. Timeouts, backoff, uptime,
+/// the rate limit and the circuit breaker all go through it, so tests can
+/// run them on a clock which only moves when told to.
+pub(crate) trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    fn sleep(&self, dur: Duration);
+
+    /// A read gave up after `timeout` without data. The system clock moved
+    /// on meanwhile, a mock clock has to be moved.
+    fn read_timed_out(&self, _timeout: Duration) {}
+}
+
+pub(crate) struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, dur: Duration) {
+        thread::sleep(dur)
+    }
+}
+
+/// Stands still until `advance`d, sleeping and read timeouts advance it
+#[cfg(test)]
+pub(crate) struct MockClock {
+    now: Mutex<Instant>
+}
+
+#[cfg(test)]
+impl MockClock {
+    pub fn new() -> Arc<MockClock> {
+        Arc::new(MockClock { now: Mutex::new(Instant::now()) })
+    }
+
+    pub fn advance(&self, dur: Duration) {
+        *self.now.lock().unwrap() += dur;
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+
+    fn sleep(&self, dur: Duration) {
+        self.advance(dur)
+    }
+
+    fn read_timed_out(&self, timeout: Duration) {
+        self.advance(timeout)
+    }
+}
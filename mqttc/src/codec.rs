@@ -0,0 +1,131 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::error;
+use std::result;
+use mqtt3::{Message, ToTopicPath, TopicPath};
+use error::{Error, Result};
+
+/// Value produced by a decoder, to be downcast to its concrete type
+pub type Decoded = Box<dyn Any + Send>;
+
+pub type Decoder = Box<dyn Fn(&[u8]) -> result::Result<Decoded, Box<dyn error::Error + Send + Sync>> + Send>;
+
+/// Payload decoders by topic filter. `Client::poll` runs the first decoder
+/// whose filter matches a message and reports `Event::Decoded`, messages
+/// without a decoder stay `Event::Message`.
+///
+/// Decoders registered for an MQTT 5 subscription identifier (see
+/// `Client::subscribe_with_id`) are looked up by the identifier the broker
+/// echoes, before any topic matching.
+pub struct CodecRegistry {
+    decoders: Vec<(TopicPath, Decoder)>,
+    by_id: HashMap<u32, Decoder>
+}
+
+impl CodecRegistry {
+    pub fn new() -> CodecRegistry {
+        CodecRegistry {
+            decoders: Vec::new(),
+            by_id: HashMap::new()
+        }
+    }
+
+    pub fn register<T, F, V, E>(&mut self, filter: T, decoder: F) -> Result<&mut CodecRegistry>
+        where T: ToTopicPath,
+              F: Fn(&[u8]) -> result::Result<V, E> + Send + 'static,
+              V: Any + Send,
+              E: Into<Box<dyn error::Error + Send + Sync>>
+    {
+        let filter = filter.to_topic_path()?;
+        self.decoders.push((filter, Box::new(move |payload: &[u8]| {
+            match decoder(payload) {
+                Ok(value) => Ok(Box::new(value) as Decoded),
+                Err(err) => Err(err.into())
+            }
+        })));
+        Ok(self)
+    }
+
+    pub fn register_id<F, V, E>(&mut self, subscription_id: u32, decoder: F) -> &mut CodecRegistry
+        where F: Fn(&[u8]) -> result::Result<V, E> + Send + 'static,
+              V: Any + Send,
+              E: Into<Box<dyn error::Error + Send + Sync>>
+    {
+        self.by_id.insert(subscription_id, Box::new(move |payload: &[u8]| {
+            match decoder(payload) {
+                Ok(value) => Ok(Box::new(value) as Decoded),
+                Err(err) => Err(err.into())
+            }
+        }));
+        self
+    }
+
+    /// None when neither a subscription identifier nor a filter matches
+    pub fn decode(&self, message: &Message) -> Option<Result<Decoded>> {
+        message.subscription_ids.iter()
+            .filter_map(|id| self.by_id.get(id))
+            .chain(self.decoders.iter()
+                .filter(|(filter, _)| filter.is_match(&message.topic))
+                .map(|(_, decoder)| decoder))
+            .next()
+            .map(|decoder| decoder(&message.payload).map_err(Error::Decode))
+    }
+}
+
+impl Default for CodecRegistry {
+    fn default() -> CodecRegistry {
+        CodecRegistry::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::error::Error;
+    use std::str;
+    use std::sync::Arc;
+    use mqtt3::{Message, QoS, TopicPath};
+    use super::CodecRegistry;
+
+    fn message(topic: &str, payload: &[u8]) -> Message {
+        Message {
+            topic: Arc::new(TopicPath::from(topic)),
+            qos: QoS::AtMostOnce,
+            retain: false,
+            pid: None,
+            expiry: None,
+            utf8: false,
+            subscription_ids: Vec::new(),
+            payload: Arc::new(payload.to_vec())
+        }
+    }
+
+    #[test]
+    fn decode_test() {
+        let mut codecs = CodecRegistry::new();
+        codecs.register("sensors/+/temp", |payload: &[u8]| -> Result<f64, Box<dyn Error + Send + Sync>> {
+            Ok(str::from_utf8(payload)?.parse::<f64>()?)
+        }).unwrap();
+
+        let value = codecs.decode(&message("sensors/a/temp", b"21.5")).unwrap().unwrap();
+        assert_eq!(*value.downcast::<f64>().unwrap(), 21.5);
+        assert!(codecs.decode(&message("sensors/a/temp", b"warm")).unwrap().is_err());
+        assert!(codecs.decode(&message("sensors/a/humidity", b"40")).is_none());
+    }
+
+    #[test]
+    fn decode_by_subscription_id_test() {
+        let mut codecs = CodecRegistry::new();
+        codecs.register("#", |payload: &[u8]| -> Result<usize, Box<dyn Error + Send + Sync>> {
+            Ok(payload.len())
+        }).unwrap();
+        codecs.register_id(3, |payload: &[u8]| -> Result<u8, Box<dyn Error + Send + Sync>> {
+            Ok(payload[0])
+        });
+
+        let mut msg = message("a", &[0x07, 0x08]);
+        msg.subscription_ids = vec![2, 3];
+        assert_eq!(*codecs.decode(&msg).unwrap().unwrap().downcast::<u8>().unwrap(), 0x07);
+        msg.subscription_ids = vec![2];
+        assert_eq!(*codecs.decode(&msg).unwrap().unwrap().downcast::<usize>().unwrap(), 2);
+    }
+}
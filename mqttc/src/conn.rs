@@ -1,36 +1,144 @@
-use mqtt3::{MqttRead, MqttWrite};
-use std::io::{self, Read, Write};
-use std::net::Shutdown;
+use mqtt3::{self, MqttRead, MqttWrite, Packet, Protocol, Publish};
+use std::io::{self, BufRead, BufReader, Cursor, Read, Write};
+use std::sync::Arc;
 use std::time::Duration;
-use netopt::{NetworkStream};
+use netopt::{NetworkReader, NetworkStream};
 
 pub struct Connection {
-    stream: NetworkStream
+    // Buffered so the many small fields of a packet don't cost a read each
+    stream: NetworkReader,
+    // Encoded packets the socket hasn't accepted yet. Packets are always
+    // written here whole, so a partial socket write never breaks framing.
+    pending: Vec<u8>,
+    reuse_payloads: bool,
+    // Payload of the last PUBLISH read. Once nobody else holds it, its
+    // buffer takes the next payload.
+    lent: Option<Arc<Vec<u8>>>,
+    spare: Vec<u8>
 }
 
+// A buffer grown by a rare large payload isn't kept around
+const MAX_SPARE: usize = 64 * 1024;
+
 impl Connection {
     pub fn new(stream: NetworkStream) -> io::Result<Connection> {
         Ok(Connection {
-            stream: stream
+            stream: BufReader::new(stream),
+            pending: Vec::new(),
+            reuse_payloads: false,
+            lent: None,
+            spare: Vec::new()
         })
     }
 
+    /// Reads every PUBLISH payload into the buffer of the previous one if
+    /// that message was dropped by then. The last payload is shared with
+    /// the connection until the next read, so `Arc::get_mut` fails on it.
+    pub fn set_reuse_payloads(&mut self, reuse: bool) {
+        self.reuse_payloads = reuse;
+        if !reuse {
+            self.lent = None;
+            self.spare = Vec::new();
+        }
+    }
+
+    /// Reads a packet like `read_packet_with`, reusing payload buffers
+    /// when `set_reuse_payloads` is on
+    pub fn read_packet_reusing(&mut self, protocol: Protocol) -> mqtt3::Result<Packet> {
+        if let Some(payload) = self.lent.take() {
+            if let Ok(buf) = Arc::try_unwrap(payload) {
+                if buf.capacity() <= MAX_SPARE {
+                    self.spare = buf;
+                }
+            }
+        }
+        let packet = self.stream.read_packet_into(protocol, &mut self.spare)?;
+        if self.reuse_payloads {
+            if let Packet::Publish(ref publish) = packet {
+                self.lent = Some(publish.payload.clone());
+            }
+        }
+        Ok(packet)
+    }
+
+    /// Replaces the stream after a reconnect. The buffers are kept for the
+    /// new stream but emptied: bytes of the old session mean nothing to it.
+    pub fn reconnect_with(&mut self, stream: NetworkStream) {
+        *self.stream.get_mut() = stream;
+        let unread = self.stream.buffer().len();
+        self.stream.consume(unread);
+        self.pending.clear();
+    }
+
+    pub fn get_ref(&self) -> &NetworkStream {
+        self.stream.get_ref()
+    }
+
     pub fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
-        self.stream.set_read_timeout(dur)
+        self.stream.get_ref().set_read_timeout(dur)
     }
 
-    pub fn terminate(&self) -> io::Result<()> {
-        self.stream.shutdown(Shutdown::Both)
+    /// Number of bytes waiting for the next flush
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Encodes `packet` and buffers it for the next flush. A packet the
+    /// encoder refuses, e.g. for a string too long, leaves nothing behind.
+    pub fn buffer_packet(&mut self, packet: &Packet, protocol: Protocol) -> mqtt3::Result<()> {
+        let mut encoded = Cursor::new(Vec::new());
+        encoded.write_packet_with(packet, protocol)?;
+        self.pending.extend_from_slice(encoded.get_ref());
+        Ok(())
+    }
+
+    /// Writes a PUBLISH straight to the stream, `len` bytes of payload are
+    /// copied from `payload` in chunks instead of being buffered. Pending
+    /// bytes go out first. An error may leave a partial packet on the wire.
+    pub fn write_publish_stream<R: Read>(&mut self,
+                                         publish: &Publish,
+                                         payload: &mut R,
+                                         len: usize,
+                                         protocol: Protocol)
+                                         -> mqtt3::Result<()> {
+        self.flush()?;
+        let mut header = Cursor::new(Vec::new());
+        header.write_publish_header(publish, len, protocol)?;
+        let stream = self.stream.get_mut();
+        stream.write_all(header.get_ref())?;
+        let copied = io::copy(&mut payload.take(len as u64), stream)?;
+        if copied < len as u64 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "payload shorter than its length").into());
+        }
+        stream.flush()?;
+        Ok(())
+    }
+
+    pub fn terminate(&mut self) -> io::Result<()> {
+        self.stream.get_mut().terminate()
     }
 }
 
 impl Write for Connection {
     fn write(&mut self, msg: &[u8]) -> io::Result<usize> {
-        self.stream.write(msg)
+        self.pending.extend_from_slice(msg);
+        Ok(msg.len())
     }
 
+    /// Writes out the pending bytes. On `WouldBlock` the bytes which weren't
+    /// accepted stay buffered and the next flush continues from there.
     fn flush(&mut self) -> io::Result<()> {
-        self.stream.flush()
+        while !self.pending.is_empty() {
+            match self.stream.get_mut().write(&self.pending) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write pending bytes")),
+                Ok(n) => {
+                    self.pending.drain(..n);
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e)
+            }
+        }
+        self.stream.get_mut().flush()
     }
 }
 
@@ -42,3 +150,135 @@ impl Read for Connection {
 
 impl MqttRead for Connection {}
 impl MqttWrite for Connection {}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Cursor, ErrorKind, Write};
+    use std::sync::Arc;
+    use mqtt3::{self, MqttRead, MqttWrite, Packet, PacketIdentifier, Protocol, Publish, QoS};
+    use netopt::NetworkStream;
+    use netopt::mock::MockStream;
+    use super::Connection;
+
+    #[test]
+    fn flush_would_block_test() {
+        let mut mock = MockStream::new();
+        mock.set_write_limit(Some(3));
+        let mut conn = Connection::new(NetworkStream::Mock(mock.clone())).unwrap();
+        conn.write_packet(&Packet::Puback(PacketIdentifier(10))).unwrap();
+        conn.write_packet(&Packet::Pingreq).unwrap();
+
+        assert_eq!(conn.flush().unwrap_err().kind(), ErrorKind::WouldBlock);
+        assert_eq!(conn.pending(), 3);
+        assert_eq!(mock.take_vec(), vec![0x40, 0x02, 0x00]);
+
+        mock.set_write_limit(None);
+        conn.flush().unwrap();
+        assert_eq!(conn.pending(), 0);
+        assert_eq!(mock.take_vec(), vec![0x0A, 0xC0, 0x00]);
+    }
+
+    #[test]
+    fn buffer_packet_refused_test() {
+        let mut mock = MockStream::new();
+        let mut conn = Connection::new(NetworkStream::Mock(mock.clone())).unwrap();
+        let publish = Packet::Publish(Box::new(Publish {
+            dup: false,
+            qos: QoS::AtMostOnce,
+            retain: false,
+            topic_name: "a".repeat(70000),
+            pid: None,
+            properties: Vec::new(),
+            payload: Arc::new(vec![0x01])
+        }));
+        match conn.buffer_packet(&publish, Protocol::MQTT(4)) {
+            Err(mqtt3::MQError::PayloadTooLong) => (),
+            other => panic!("expected PayloadTooLong, got {:?}", other)
+        }
+        // no partial packet in front of the next one
+        assert_eq!(conn.pending(), 0);
+        conn.buffer_packet(&Packet::Pingreq, Protocol::MQTT(4)).unwrap();
+        conn.flush().unwrap();
+        assert_eq!(mock.take_vec(), vec![0xC0, 0x00]);
+    }
+
+    #[test]
+    fn write_publish_stream_test() {
+        let payload: Vec<u8> = (0..20000).map(|i| i as u8).collect();
+        let publish = Box::new(Publish {
+            dup: false,
+            qos: QoS::AtLeastOnce,
+            retain: false,
+            topic_name: "ota".to_owned(),
+            pid: Some(PacketIdentifier(3)),
+            properties: Vec::new(),
+            payload: Arc::new(payload.clone())
+        });
+        let mut expected = Cursor::new(Vec::new());
+        expected.write_packet(&Packet::Publish(publish.clone())).unwrap();
+
+        let mut mock = MockStream::new();
+        let mut conn = Connection::new(NetworkStream::Mock(mock.clone())).unwrap();
+        conn.write_packet(&Packet::Pingreq).unwrap();
+        conn.write_publish_stream(&publish, &mut Cursor::new(payload), 20000, Protocol::MQTT(4)).unwrap();
+        assert_eq!(conn.pending(), 0);
+        assert_eq!(mock.take_vec(), [vec![0xC0, 0x00], expected.into_inner()].concat());
+
+        let err = conn.write_publish_stream(&publish, &mut Cursor::new(vec![0; 10]), 20000, Protocol::MQTT(4));
+        assert!(err.is_err());
+    }
+
+    #[test]
+    fn read_buffered_test() {
+        let mut mock = MockStream::with_vec(vec![0x40, 0x02, 0x00, 0x0A, 0xD0, 0x00]);
+        let mut conn = Connection::new(NetworkStream::Mock(mock.clone())).unwrap();
+        assert_eq!(conn.read_packet().unwrap(), Packet::Puback(PacketIdentifier(10)));
+        // the rest came in with the first read
+        mock.next_vec(Vec::new());
+        assert_eq!(conn.read_packet().unwrap(), Packet::Pingresp);
+    }
+
+    #[test]
+    fn reuse_payloads_test() {
+        let publish = [0x30, 0x05, 0x00, 0x01, 'a' as u8, 0x01, 0x02];
+        let mock = MockStream::with_vec(publish.repeat(3));
+        let mut conn = Connection::new(NetworkStream::Mock(mock.clone())).unwrap();
+        conn.set_reuse_payloads(true);
+        let payload = |packet| match packet {
+            Packet::Publish(publish) => publish.payload,
+            packet => panic!("expected PUBLISH, got {:?}", packet)
+        };
+
+        let first = payload(conn.read_packet_reusing(Protocol::MQTT(4)).unwrap());
+        let ptr = first.as_ptr();
+        drop(first);
+        let second = payload(conn.read_packet_reusing(Protocol::MQTT(4)).unwrap());
+        assert_eq!(*second, vec![0x01, 0x02]);
+        assert_eq!(second.as_ptr(), ptr);
+
+        // a payload still held isn't written over
+        let third = payload(conn.read_packet_reusing(Protocol::MQTT(4)).unwrap());
+        assert!(third.as_ptr() != ptr);
+        assert_eq!(*second, vec![0x01, 0x02]);
+    }
+
+    #[test]
+    fn reconnect_with_test() {
+        let mut mock = MockStream::new();
+        mock.set_write_limit(Some(0));
+        let mut conn = Connection::new(NetworkStream::Mock(mock.clone())).unwrap();
+        conn.write_packet(&Packet::Pingreq).unwrap();
+        assert!(conn.flush().is_err());
+        let capacity = conn.pending.capacity();
+
+        let mut fresh = MockStream::new();
+        conn.reconnect_with(NetworkStream::Mock(fresh.clone()));
+        assert_eq!(conn.pending(), 0);
+        assert_eq!(conn.pending.capacity(), capacity);
+
+        conn.write_packet(&Packet::Pingresp).unwrap();
+        conn.flush().unwrap();
+        assert_eq!(fresh.take_vec(), vec![0xD0, 0x00]);
+        assert!(mock.take_vec().is_empty());
+    }
+}
@@ -1,4 +1,5 @@
 use std::result;
+use std::error;
 use std::io;
 use std::fmt;
 use thiserror::Error;
@@ -26,20 +27,67 @@ pub enum Error {
     HandshakeFailed,
     #[error("Protocol Violation")]
     ProtocolViolation,
+    /// A packet only a client may send came from the server
+    #[error("Unexpected packet: {0}")]
+    UnexpectedPacket(String),
     #[error("Disconnected")]
     Disconnected,
     #[error("Timeout")]
     Timeout,
-    #[error("`{0}`")]
+    /// Paused without keep alive, `accept` has nothing to wait for
+    #[error("Paused")]
+    Paused,
+    #[error("Rate Limited")]
+    RateLimited,
+    #[error("Circuit Open")]
+    CircuitOpen,
+    /// Too many SUBSCRIBEs and UNSUBSCRIBEs wait for their acks
+    #[error("Inflight Limit Reached")]
+    InflightLimit,
+    /// Another client connected with the same client id
+    #[error("Session Taken Over")]
+    SessionTakenOver,
+    /// A SUBACK whose return codes don't match the topics of its SUBSCRIBE
+    #[error("SUBACK pid {} has {got} return codes for {expected} topics", .pid.0)]
+    SubackMismatch { pid: PacketIdentifier, expected: usize, got: usize },
+    #[error("Unhandled packet identifier: {0}")]
     PacketIdentifierError(#[from] PacketIdentifierError),
-    #[error("Connection Refused")]
-    ConnectionRefused(#[from] ConnectReturnCode),
-    #[error("`{0}`")]
+    /// `reason_code` is the MQTT 5 one as sent, several map to one `code`
+    #[error("Connection refused: {code}{}", reason_suffix(.reason))]
+    ConnectionRefused { code: ConnectReturnCode, reason_code: Option<u8>, reason: Option<String> },
+    /// DISCONNECT sent by the server, the reason is MQTT 5 only
+    #[error("Disconnected by server: {code:#04x}{}", reason_suffix(.reason))]
+    ServerDisconnect { code: u8, reason: Option<String> },
+    #[error("Storage error: {0}")]
     Storage(#[from] StorageError),
-    #[error("`{0}`")]
+    /// A store call of the QoS 1 or 2 flows failed, e.g. "persist outgoing"
+    #[error("Failed to {operation} pid {}: {source}", .pid.0)]
+    StoreFailed { operation: &'static str, pid: PacketIdentifier, #[source] source: StorageError },
+    #[error("MQTT protocol error: {0}")]
     Mqtt(#[from] MqttError),
-    #[error("`{0}`")]
-    Io(#[from] io::Error)
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Decode error: {0}")]
+    Decode(#[source] Box<dyn error::Error + Send + Sync>)
+}
+
+impl From<ConnectReturnCode> for Error {
+    fn from(code: ConnectReturnCode) -> Error {
+        Error::ConnectionRefused { code, reason_code: None, reason: None }
+    }
+}
+
+impl Error {
+    pub(crate) fn store_failed(operation: &'static str, pid: PacketIdentifier) -> impl FnOnce(StorageError) -> Error {
+        move |source| Error::StoreFailed { operation, pid, source }
+    }
+}
+
+fn reason_suffix(reason: &Option<String>) -> String {
+    match *reason {
+        Some(ref reason) => format!(" ({})", reason),
+        None => String::new()
+    }
 }
 
 #[derive(Debug, Error)]
@@ -47,7 +95,9 @@ pub enum PacketIdentifierError {
     UnhandledPuback(PacketIdentifier),
     UnhandledPubrec(PacketIdentifier),
     UnhandledPubrel(PacketIdentifier),
-    UnhandledPubcomp(PacketIdentifier)
+    UnhandledPubcomp(PacketIdentifier),
+    UnhandledSuback(PacketIdentifier),
+    UnhandledUnsuback(PacketIdentifier)
 }
 
 impl fmt::Display for PacketIdentifierError {
@@ -56,7 +106,45 @@ impl fmt::Display for PacketIdentifierError {
             PacketIdentifierError::UnhandledPuback(PacketIdentifier(pi)) => fmt::write(f, format_args!("{:?}", pi)),
             PacketIdentifierError::UnhandledPubrec(PacketIdentifier(pi)) => fmt::write(f, format_args!("{:?}", pi)),
             PacketIdentifierError::UnhandledPubrel(PacketIdentifier(pi)) => fmt::write(f, format_args!("{:?}", pi)),
-            PacketIdentifierError::UnhandledPubcomp(PacketIdentifier(pi)) => fmt::write(f, format_args!("{:?}", pi))
+            PacketIdentifierError::UnhandledPubcomp(PacketIdentifier(pi)) => fmt::write(f, format_args!("{:?}", pi)),
+            PacketIdentifierError::UnhandledSuback(PacketIdentifier(pi)) => fmt::write(f, format_args!("{:?}", pi)),
+            PacketIdentifierError::UnhandledUnsuback(PacketIdentifier(pi)) => fmt::write(f, format_args!("{:?}", pi))
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::error::Error as StdError;
+    use std::io;
+    use mqtt3::{ConnectReturnCode, MQError};
+    use super::Error;
+
+    #[test]
+    fn error_source_test() {
+        let err = Error::from(io::Error::new(io::ErrorKind::ConnectionReset, "reset"));
+        assert_eq!(err.to_string(), "I/O error: reset");
+        assert!(err.source().unwrap().downcast_ref::<io::Error>().is_some());
+
+        let err = Error::from(MQError::UnexpectedEof);
+        assert_eq!(err.to_string(), "MQTT protocol error: Unexpected EOF");
+        assert!(err.source().unwrap().downcast_ref::<MQError>().is_some());
+
+        let err = Error::from(MQError::from(io::Error::other("boom")));
+        let inner = err.source().unwrap().source().unwrap();
+        assert!(inner.downcast_ref::<io::Error>().is_some());
+    }
+
+    #[test]
+    fn error_reason_string_test() {
+        let err = Error::ConnectionRefused {
+            code: ConnectReturnCode::RefusedProtocolVersion,
+            reason_code: Some(0x84),
+            reason: Some("use 3.1.1".to_owned())
+        };
+        assert_eq!(err.to_string(), "Connection refused: RefusedProtocolVersion (use 3.1.1)");
+
+        let err = Error::ServerDisconnect { code: 0x8E, reason: None };
+        assert_eq!(err.to_string(), "Disconnected by server: 0x8e");
+    }
+}
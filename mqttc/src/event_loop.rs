@@ -0,0 +1,223 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use mqtt3::Message;
+use error::{Error, Result};
+use {Client, Event};
+
+/// Runs a client and calls back on what happens, instead of an `await`
+/// loop. Callbacks get the client, so they can publish or subscribe.
+///
+/// ```no_run
+/// # extern crate mqttc;
+/// # extern crate netopt;
+/// use mqttc::{ClientOptions, EventLoop, PubSub};
+/// use netopt::NetworkOptions;
+/// # fn main() {
+/// let client = ClientOptions::new().connect("127.0.0.1:1883", NetworkOptions::new()).unwrap();
+/// let mut events = EventLoop::new(client);
+/// events.on_connect(|client| { client.subscribe("a/#").unwrap(); })
+///       .on_message(|_, message| println!("{}", message.topic_str()))
+///       .on_error(|err| println!("{}", err));
+/// let handle = events.spawn();
+/// # }
+/// ```
+pub struct EventLoop {
+    client: Client,
+    stop: Arc<AtomicBool>,
+    on_message: Option<MessageCallback>,
+    on_connect: Option<ClientCallback>,
+    on_disconnect: Option<ClientCallback>,
+    on_error: Option<ErrorCallback>
+}
+
+type MessageCallback = Box<dyn FnMut(&mut Client, &Message) + Send>;
+type ClientCallback = Box<dyn FnMut(&mut Client) + Send>;
+type ErrorCallback = Box<dyn FnMut(&Error) + Send>;
+
+/// Stops an `EventLoop` from anywhere, a callback or another thread
+#[derive(Clone)]
+pub struct Stopper(Arc<AtomicBool>);
+
+impl Stopper {
+    pub fn stop(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+// How long a poll waits before checking for stop
+const TICK: Duration = Duration::from_millis(100);
+
+impl EventLoop {
+    pub fn new(client: Client) -> EventLoop {
+        EventLoop {
+            client,
+            stop: Arc::new(AtomicBool::new(false)),
+            on_message: None,
+            on_connect: None,
+            on_disconnect: None,
+            on_error: None
+        }
+    }
+
+    /// Every message, decoded ones included
+    pub fn on_message<F>(&mut self, callback: F) -> &mut EventLoop
+        where F: FnMut(&mut Client, &Message) + Send + 'static
+    {
+        self.on_message = Some(Box::new(callback));
+        self
+    }
+
+    /// Every CONNACK accepted, the one of the first connection included
+    pub fn on_connect<F>(&mut self, callback: F) -> &mut EventLoop
+        where F: FnMut(&mut Client) + Send + 'static
+    {
+        self.on_connect = Some(Box::new(callback));
+        self
+    }
+
+    pub fn on_disconnect<F>(&mut self, callback: F) -> &mut EventLoop
+        where F: FnMut(&mut Client) + Send + 'static
+    {
+        self.on_disconnect = Some(Box::new(callback));
+        self
+    }
+
+    /// Errors the loop goes on after, and the one it ends with
+    pub fn on_error<F>(&mut self, callback: F) -> &mut EventLoop
+        where F: FnMut(&Error) + Send + 'static
+    {
+        self.on_error = Some(Box::new(callback));
+        self
+    }
+
+    pub fn stopper(&self) -> Stopper {
+        Stopper(self.stop.clone())
+    }
+
+    /// Polls until stopped, or until the connection is gone for good as
+    /// `Error::Disconnected`, reconnecting meanwhile like `await` does.
+    /// The client is given back either way, e.g. for `into_options` or
+    /// `last_disconnect_reason`.
+    pub fn run(mut self) -> (Client, Result<()>) {
+        while !self.stop.load(Ordering::SeqCst) {
+            match self.client.poll(TICK) {
+                Ok(Event::Message(message)) |
+                Ok(Event::Decoded(message, _)) => {
+                    if let Some(ref mut callback) = self.on_message {
+                        callback(&mut self.client, &message);
+                    }
+                }
+                Ok(Event::Connected) => {
+                    if let Some(ref mut callback) = self.on_connect {
+                        callback(&mut self.client);
+                    }
+                }
+                Ok(Event::Disconnected) => {
+                    if let Some(ref mut callback) = self.on_disconnect {
+                        callback(&mut self.client);
+                    }
+                }
+                Ok(_) => (),
+                Err(err) => {
+                    if let Some(ref mut callback) = self.on_error {
+                        callback(&err);
+                    }
+                    if let Error::Disconnected = err {
+                        return (self.client, Err(err));
+                    }
+                }
+            }
+        }
+        (self.client, Ok(()))
+    }
+
+    /// Runs the loop on a thread of its own
+    pub fn spawn(self) -> thread::JoinHandle<(Client, Result<()>)> {
+        thread::spawn(move || self.run())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+    use error::Error;
+    use {ClientOptions, PubSub, PubOpt};
+    use super::EventLoop;
+
+    #[test]
+    fn event_loop_test() {
+        let mut opts = ClientOptions::new();
+        opts.set_keep_alive(0);
+        let mut client = opts.build_offline().unwrap();
+        client.feed(vec![0b00110000, 4, 0x00, 0x01, 'a' as u8, 'x' as u8]);
+
+        let topics = Arc::new(Mutex::new(Vec::new()));
+        let mut events = EventLoop::new(client.into_inner());
+        let stopper = events.stopper();
+        let seen = topics.clone();
+        events.on_message(move |client, message| {
+            seen.lock().unwrap().push(message.topic_str().to_owned());
+            client.publish("b", "y", PubOpt::at_most_once()).unwrap();
+            stopper.stop();
+        });
+        let (client, result) = events.run();
+        result.unwrap();
+        assert_eq!(*topics.lock().unwrap(), vec!["a".to_owned()]);
+        assert!(client.is_connected());
+    }
+
+    #[test]
+    fn event_loop_connect_test() {
+        let mut opts = ClientOptions::new();
+        opts.set_keep_alive(0);
+        let client = opts.build_offline().unwrap();
+
+        let connects = Arc::new(Mutex::new(0));
+        let mut events = EventLoop::new(client.into_inner());
+        let stopper = events.stopper();
+        let seen = connects.clone();
+        // the first connection is reported as well
+        events.on_connect(move |client| {
+            *seen.lock().unwrap() += 1;
+            client.subscribe("a/#").unwrap();
+            stopper.stop();
+        });
+        let (_, result) = events.run();
+        result.unwrap();
+        assert_eq!(*connects.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn event_loop_disconnect_test() {
+        let mut opts = ClientOptions::new();
+        opts.set_keep_alive(0);
+        let mut client = opts.build_offline().unwrap();
+        // an ack nothing waits for, then the server goes away
+        client.feed(vec![0x50, 0x02, 0x00, 0x09, 0xE0, 0x00]);
+
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut events = EventLoop::new(client.into_inner());
+        let seen = log.clone();
+        events.on_disconnect(move |client| {
+            assert!(!client.is_connected());
+            seen.lock().unwrap().push("disconnect".to_owned());
+        });
+        let seen = log.clone();
+        events.on_error(move |err| seen.lock().unwrap().push(err.to_string()));
+        let (client, result) = events.run();
+        match result {
+            Err(Error::Disconnected) => (),
+            other => panic!("expected Disconnected, got {:?}", other)
+        }
+        assert_eq!(*log.lock().unwrap(), vec!["Unhandled packet identifier: 9".to_owned(),
+                                              "disconnect".to_owned(),
+                                              Error::Disconnected.to_string()]);
+        // the client is back for a look at what happened
+        match client.last_disconnect_reason() {
+            Some(&Error::ServerDisconnect { code: 0x00, .. }) => (),
+            other => panic!("expected ServerDisconnect, got {:?}", other)
+        }
+    }
+}
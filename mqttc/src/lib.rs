@@ -4,14 +4,38 @@ extern crate byteorder;
 extern crate mqtt3;
 extern crate netopt;
 extern crate thiserror;
+#[cfg(feature = "async")]
+extern crate tokio;
+#[cfg(feature = "sled")]
+extern crate sled;
+#[cfg(test)]
+extern crate socket2;
 
 mod error;
 mod sub;
 mod client;
+mod session;
 mod conn;
+mod codec;
+mod bucket;
+mod breaker;
+mod clock;
+mod pool;
+mod offline;
+mod event_loop;
 pub mod store;
+#[cfg(feature = "async")]
+mod async_client;
+#[cfg(feature = "sled")]
+mod sled_store;
 
 pub use conn::Connection;
+pub use codec::{CodecRegistry, Decoded};
+pub use pool::ClientPool;
+pub use offline::OfflineClient;
+pub use event_loop::{EventLoop, Stopper};
+#[cfg(feature = "async")]
+pub use async_client::AsyncClient;
 
 pub use error::{
     Error,
@@ -28,10 +52,15 @@ pub use client::{
     ClientOptions
 };
 
+pub use session::ClientCore;
+
+pub use mqtt3::{ConnectReturnCode, Property};
+
+use std::cmp;
 use std::sync::Arc;
 use std::ops;
 use std::time::Duration;
-use mqtt3::{QoS, ToTopicPath};
+use mqtt3::{Message, PacketIdentifier, QoS, SubscribeReturnCodes, ToTopicPath};
 
 const MAX_QOS: QoS = mqtt3::QoS::AtLeastOnce;
 
@@ -49,16 +78,102 @@ pub enum ClientState {
     Disconnected
 }
 
+/// What `Client::poll` observed
+#[derive(Debug)]
+pub enum Event {
+    Message(Box<Message>),
+    /// A message whose payload went through a registered decoder
+    Decoded(Box<Message>, Decoded),
+    /// CONNACK accepted, after a reconnect too
+    Connected,
+    /// The connection dropped
+    Disconnected,
+    /// A QoS 1 or 2 publish was acknowledged (PUBACK or PUBCOMP)
+    PubAck(PacketIdentifier),
+    SubAck(PacketIdentifier, Vec<SubscribeReturnCodes>),
+    /// Nothing happened before the timeout
+    Idle
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ReconnectMethod {
     ForeverDisconnect,
-    ReconnectAfter(Duration)
+    ReconnectAfter(Duration),
+    /// Waits `initial` before the first attempt and twice as long before
+    /// each next one, up to `max`, plus a random part of `jitter` so that
+    /// clients dropped together don't come back together. `max_attempts`
+    /// overrides `ClientOptions::set_max_reconnect_attempts`. A CONNACK
+    /// starts over from `initial`.
+    ExponentialBackoff {
+        initial: Duration,
+        max: Duration,
+        jitter: Duration,
+        max_attempts: Option<u32>
+    }
+}
+
+impl ReconnectMethod {
+    /// The wait before attempt `attempt`, counted from 0, jitter left out.
+    /// None without reconnecting.
+    pub fn delay(&self, attempt: u32) -> Option<Duration> {
+        match *self {
+            ReconnectMethod::ForeverDisconnect => None,
+            ReconnectMethod::ReconnectAfter(delay) => Some(delay),
+            ReconnectMethod::ExponentialBackoff { initial, max, .. } => {
+                let factor = 1u32.checked_shl(attempt).unwrap_or(0);
+                Some(match initial.checked_mul(factor) {
+                    Some(delay) if factor > 0 => cmp::min(delay, max),
+                    _ => max,
+                })
+            }
+        }
+    }
+}
+
+/// What `publish` does once the publish rate limit is reached
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitMethod {
+    /// Waits for the next token
+    Block,
+    /// Returns `Error::RateLimited`
+    Fail
+}
+
+/// What the client does with a packet a server must never send while
+/// connected: CONNECT, SUBSCRIBE and UNSUBSCRIBE. A PINGREQ is only
+/// logged and dropped either way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnexpectedPacketMethod {
+    /// Drops the connection, `last_disconnect_reason` holds
+    /// `Error::UnexpectedPacket`
+    Disconnect,
+    /// Logs and drops the packet
+    Ignore
+}
+
+/// What the client does once another client with the same client id took
+/// its session over
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TakeoverMethod {
+    /// Stays disconnected, `last_disconnect_reason` holds
+    /// `Error::SessionTakenOver`
+    Disconnect,
+    /// Reconnects as `ReconnectMethod` says
+    Reconnect
 }
 
+/// QoS, retain flag and payload format of a publish. It's `Copy`, build it
+/// once and pass it to every publish call.
+///
+/// The presets combine with `|`, `&`, `^`, `-` and `!`: QoS 1 without
+/// retain is `PubOpt::at_least_once()`, QoS 1 with retain is
+/// `PubOpt::at_least_once() | PubOpt::retain()`, and `opt - PubOpt::retain()`
+/// clears the flag again.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct PubOpt(u8);
 
 impl PubOpt {
+    /// Same as combining the presets by hand
     pub fn new(qos: QoS, retain: bool) -> PubOpt {
         let mut opt = PubOpt(qos.to_u8());
         if retain {
@@ -67,31 +182,43 @@ impl PubOpt {
         opt
     }
 
+    /// QoS 0, not retained
     #[inline]
     pub fn at_most_once() -> PubOpt {
         PubOpt(0x00)
     }
 
+    /// QoS 1, not retained
     #[inline]
     pub fn at_least_once() -> PubOpt {
         PubOpt(0x01)
     }
 
+    /// QoS 2, not retained
     #[inline]
     pub fn exactly_once() -> PubOpt {
         PubOpt(0x02)
     }
 
+    /// Retain flag alone, to be combined with one of the QoS presets (QoS 0 otherwise)
     #[inline]
     pub fn retain() -> PubOpt {
         PubOpt(0x04)
     }
 
+    /// Flags the payload as UTF-8 text (MQTT 5 Payload Format Indicator),
+    /// to be combined with one of the QoS presets
+    #[inline]
+    pub fn utf8() -> PubOpt {
+        PubOpt(0x08)
+    }
+
     #[inline]
     pub fn bits(&self) -> u8 {
         self.0
     }
 
+    /// The highest QoS set, QoS 2 wins if both QoS bits are set
     pub fn qos(&self) -> QoS {
         if (self.0 & PubOpt::exactly_once().bits()) != 0 {
             return QoS::ExactlyOnce;
@@ -105,6 +232,10 @@ impl PubOpt {
     pub fn is_retain(&self) -> bool {
         (self.0 & PubOpt::retain().bits()) != 0
     }
+
+    pub fn is_utf8(&self) -> bool {
+        (self.0 & PubOpt::utf8().bits()) != 0
+    }
 }
 
 
@@ -149,7 +280,7 @@ impl ops::Not for PubOpt {
 
     #[inline]
     fn not(self) -> PubOpt {
-        PubOpt(!self.bits() & 0b111)
+        PubOpt(!self.bits() & 0b1111)
     }
 }
 
@@ -185,9 +316,27 @@ impl ToPayload for Arc<Vec<u8>> {
 
 #[cfg(test)]
 mod test {
-    use super::PubOpt;
+    use std::time::Duration;
+    use super::{PubOpt, ReconnectMethod};
     use mqtt3::QoS;
 
+    #[test]
+    fn reconnect_delay_test() {
+        assert_eq!(ReconnectMethod::ForeverDisconnect.delay(0), None);
+        let after = ReconnectMethod::ReconnectAfter(Duration::from_secs(2));
+        assert_eq!(after.delay(7), Some(Duration::from_secs(2)));
+
+        let backoff = ReconnectMethod::ExponentialBackoff {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(1),
+            jitter: Duration::from_secs(0),
+            max_attempts: None
+        };
+        let delays: Vec<_> = (0..5).map(|attempt| backoff.delay(attempt).unwrap().as_millis()).collect();
+        assert_eq!(delays, vec![100, 200, 400, 800, 1000]);
+        assert_eq!(backoff.delay(40), Some(Duration::from_secs(1)));
+    }
+
     #[test]
     fn pubopt_test() {
         let pubopt = PubOpt::at_least_once();
@@ -225,5 +374,10 @@ mod test {
         let pubopt = PubOpt::new(QoS::AtMostOnce, true);
         assert_eq!(pubopt.qos(), QoS::AtMostOnce);
         assert!(pubopt.is_retain());
+
+        let pubopt = PubOpt::at_least_once() | PubOpt::utf8();
+        assert_eq!(pubopt.qos(), QoS::AtLeastOnce);
+        assert!(pubopt.is_utf8());
+        assert!(!(pubopt - PubOpt::utf8()).is_utf8());
     }
 }
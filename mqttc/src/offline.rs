@@ -0,0 +1,87 @@
+use std::ops::{Deref, DerefMut};
+use netopt::mock::MockStream;
+use Client;
+
+/// A client without a broker, made by `ClientOptions::build_offline`.
+///
+/// Whatever the client sends stays in memory for `take_written`, so tests
+/// can compare it with the exact bytes expected on the wire. The CONNACK
+/// is made up and the CONNECT is the first thing written. Reads time out
+/// unless the broker's side is fed with `feed`.
+pub struct OfflineClient {
+    client: Client,
+    stream: MockStream
+}
+
+impl OfflineClient {
+    pub(crate) fn new(client: Client, stream: MockStream) -> OfflineClient {
+        OfflineClient {
+            client,
+            stream
+        }
+    }
+
+    /// Bytes written since the last call
+    pub fn take_written(&mut self) -> Vec<u8> {
+        self.stream.take_vec()
+    }
+
+    /// Replaces whatever is left to read with packets from the broker
+    pub fn feed(&mut self, bytes: Vec<u8>) {
+        self.stream.next_vec(bytes);
+    }
+
+    pub fn into_inner(self) -> Client {
+        self.client
+    }
+}
+
+impl Deref for OfflineClient {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        &self.client
+    }
+}
+
+impl DerefMut for OfflineClient {
+    fn deref_mut(&mut self) -> &mut Client {
+        &mut self.client
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use mqtt3::{Protocol, QoS};
+    use {ClientOptions, PubSub, PubOpt};
+
+    #[test]
+    fn offline_client_test() {
+        let mut opts = ClientOptions::new();
+        opts.set_client_id("test".to_owned());
+        let mut client = opts.build_offline().unwrap();
+        let connect = vec![0x10, 16,
+            0x00, 0x04, 'M' as u8, 'Q' as u8, 'T' as u8, 'T' as u8, 0x04, 0x02, 0x00, 30,
+            0x00, 0x04, 't' as u8, 'e' as u8, 's' as u8, 't' as u8];
+        assert_eq!(client.take_written(), connect);
+
+        client.publish("a", "x", PubOpt::at_most_once()).unwrap();
+        assert_eq!(client.take_written(), vec![0x30, 0x04, 0x00, 0x01, 'a' as u8, 'x' as u8]);
+        client.subscribe(("a", QoS::AtMostOnce)).unwrap();
+        assert_eq!(client.take_written(), vec![0x82, 0x06, 0x00, 0x01, 0x00, 0x01, 'a' as u8, 0x00]);
+
+        // nothing to read
+        assert!(client.accept().is_err());
+        client.feed(vec![0x30, 0x04, 0x00, 0x01, 'a' as u8, 'y' as u8]);
+        assert_eq!(*client.accept().unwrap().unwrap().payload, vec!['y' as u8]);
+    }
+
+    #[test]
+    fn offline_client_mqtt5_test() {
+        let mut opts = ClientOptions::new();
+        opts.set_protocol(Protocol::MQTT(5));
+        let mut client = opts.build_offline().unwrap();
+        assert!(client.is_connected());
+        assert_eq!(client.take_written()[0], 0x10);
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::time::{Duration, Instant};
+use mqtt3::ToTopicPath;
+use netopt::{NetworkOptions, ToHostName};
+use error::Result;
+use {Client, ClientOptions, PubSub, PubOpt, ToPayload};
+
+/// Connected clients kept for reuse by short lived publishers, keyed by
+/// broker address.
+///
+/// Idle clients don't send PINGREQs, so entries idle longer than the idle
+/// timeout (20 seconds by default, keep it below the keep alive) are closed
+/// instead of handed out. At most `max_idle` clients per broker are kept,
+/// any client put back beyond that is closed.
+pub struct ClientPool {
+    new_options: Box<dyn Fn() -> (ClientOptions, NetworkOptions) + Send>,
+    max_idle: usize,
+    idle_timeout: Duration,
+    idle: HashMap<SocketAddr, Vec<(Client, Instant)>>
+}
+
+impl ClientPool {
+    /// `new_options` is called for every new connection. Leave the client
+    /// id unset so that each one gets its own.
+    pub fn new<F>(new_options: F) -> ClientPool
+        where F: Fn() -> (ClientOptions, NetworkOptions) + Send + 'static
+    {
+        ClientPool {
+            new_options: Box::new(new_options),
+            max_idle: 4,
+            idle_timeout: Duration::from_secs(20),
+            idle: HashMap::new()
+        }
+    }
+
+    /// Clients kept per broker, 4 by default
+    pub fn set_max_idle(&mut self, max_idle: usize) -> &mut ClientPool {
+        self.max_idle = max_idle;
+        self
+    }
+
+    pub fn set_idle_timeout(&mut self, idle_timeout: Duration) -> &mut ClientPool {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// A connected client for `addr`, an idle one if there is one. A dead
+    /// idle client is reconnected first, a new client is connected when
+    /// none is left.
+    pub fn get<A: ToSocketAddrs + ToHostName>(&mut self, addr: A) -> Result<Client> {
+        let resolved = addr.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "no address resolved")
+        })?;
+        let idle_timeout = self.idle_timeout;
+        if let Some(clients) = self.idle.get_mut(&resolved) {
+            while let Some((mut client, since)) = clients.pop() {
+                if client.now().saturating_duration_since(since) >= idle_timeout {
+                    let _ = client.disconnect();
+                    continue;
+                }
+                if client.is_connected() || client.reconnect().is_ok() {
+                    return Ok(client);
+                }
+            }
+        }
+        let (opts, netopt) = (self.new_options)();
+        opts.connect(addr, netopt)
+    }
+
+    /// Gives a client back. Disconnected clients and clients beyond
+    /// `max_idle` are dropped.
+    pub fn put(&mut self, client: Client) {
+        if !client.is_connected() {
+            return;
+        }
+        let clients = self.idle.entry(client.broker_addr()).or_default();
+        if clients.len() < self.max_idle {
+            let now = client.now();
+            clients.push((client, now));
+        } else {
+            let _ = client.disconnect();
+        }
+    }
+
+    /// Publishes with a client of the pool, which goes back to it after
+    pub fn publish<A, T, P>(&mut self, addr: A, topic: T, payload: P, pubopt: PubOpt) -> Result<()>
+        where A: ToSocketAddrs + ToHostName,
+              T: ToTopicPath,
+              P: ToPayload
+    {
+        let mut client = self.get(addr)?;
+        let result = client.publish(topic, payload, pubopt);
+        self.put(client);
+        result
+    }
+
+    /// Idle clients over all brokers
+    pub fn idle(&self) -> usize {
+        self.idle.values().map(|clients| clients.len()).sum()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use netopt::NetworkOptions;
+    use netopt::mock::MockStream;
+    use clock::MockClock;
+    use error::Error;
+    use super::ClientPool;
+    use {ClientOptions, PubOpt};
+
+    fn mock_pool(clock: Arc<MockClock>) -> (ClientPool, Arc<AtomicUsize>) {
+        let connects = Arc::new(AtomicUsize::new(0));
+        let counter = connects.clone();
+        let pool = ClientPool::new(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let mut netopt = NetworkOptions::new();
+            netopt.attach(MockStream::with_vec(vec![0x20, 0x02, 0x00, 0x00]));
+            let mut opts = ClientOptions::new();
+            opts.clock = clock.clone();
+            (opts, netopt)
+        });
+        (pool, connects)
+    }
+
+    #[test]
+    fn pool_reuse_test() {
+        let (mut pool, connects) = mock_pool(MockClock::new());
+        pool.publish("127.0.0.1:1883", "a/b", "x", PubOpt::at_most_once()).unwrap();
+        pool.publish("127.0.0.1:1883", "a/b", "y", PubOpt::at_most_once()).unwrap();
+        assert_eq!(connects.load(Ordering::SeqCst), 1);
+        assert_eq!(pool.idle(), 1);
+
+        // a dropped connection isn't taken back
+        let mut client = pool.get("127.0.0.1:1883").unwrap();
+        client.terminate();
+        pool.put(client);
+        assert_eq!(pool.idle(), 0);
+    }
+
+    #[test]
+    fn pool_eviction_test() {
+        let clock = MockClock::new();
+        let (mut pool, connects) = mock_pool(clock.clone());
+        pool.set_max_idle(1);
+        let first = pool.get("127.0.0.1:1883").unwrap();
+        let second = pool.get("127.0.0.1:1883").unwrap();
+        pool.put(first);
+        pool.put(second);
+        assert_eq!(pool.idle(), 1);
+        assert_eq!(connects.load(Ordering::SeqCst), 2);
+
+        // still fresh
+        clock.advance(Duration::from_secs(19));
+        let client = pool.get("127.0.0.1:1883").unwrap();
+        pool.put(client);
+        assert_eq!(connects.load(Ordering::SeqCst), 2);
+
+        clock.advance(Duration::from_secs(20));
+        pool.get("127.0.0.1:1883").unwrap();
+        assert_eq!(connects.load(Ordering::SeqCst), 3);
+        assert_eq!(pool.idle(), 0);
+    }
+
+    #[test]
+    fn pool_unresolved_test() {
+        let (mut pool, connects) = mock_pool(MockClock::new());
+        let none: &[::std::net::SocketAddr] = &[];
+        match pool.get(none) {
+            Err(Error::Io(ref err)) => assert_eq!(err.kind(), ::std::io::ErrorKind::InvalidInput),
+            other => panic!("expected Io, got {:?}", other.map(|_| ()))
+        }
+        assert_eq!(connects.load(Ordering::SeqCst), 0);
+    }
+}
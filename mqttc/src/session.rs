@@ -0,0 +1,912 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use mqtt3::{self, Message, Packet, Property, Disconnect, ConnectReturnCode, PacketIdentifier, QoS};
+use mqtt3::{SubscribeReturnCodes, SubscribeTopic, ToTopicPath};
+use error::{Error, Result};
+use sub::Subscription;
+use client::ClientOptions;
+use {ClientState, Event, UnexpectedPacketMethod, PubOpt, ToPayload, ToSubTopics, ToUnSubTopics};
+use store;
+
+// Events nobody polls for don't pile up past this, the oldest go first
+const MAX_EVENTS: usize = 1024;
+
+/// The protocol side of a client without any I/O: queues, packet
+/// identifiers, subscriptions and stores. Packets from the broker go in
+/// with `handle`, the packets to send come out of `poll_outgoing`. It
+/// never reads the clock, the driver passes the time in.
+/// `Client` drives it over a blocking socket, anything else able to move
+/// packets can too.
+///
+/// ```
+/// # extern crate mqtt3;
+/// # extern crate mqttc;
+/// # use mqtt3::{Connack, ConnectReturnCode, Packet, PacketIdentifier, QoS};
+/// # use std::time::Instant;
+/// # use mqttc::{ClientCore, ClientOptions, PubOpt};
+/// # fn main() {
+/// let mut core = ClientCore::new(ClientOptions::new());
+/// core.connect().unwrap();
+/// assert!(match core.poll_outgoing() { Some(Packet::Connect(_)) => true, _ => false });
+/// core.handle(Packet::Connack(Connack {
+///     session_present: false,
+///     code: ConnectReturnCode::Accepted,
+///     reason_code: None,
+///     reason: None,
+///     server_keep_alive: None
+/// }), Instant::now()).unwrap();
+///
+/// let pid = core.publish("a/b", "hello", PubOpt::at_least_once(), Instant::now()).unwrap().unwrap();
+/// assert!(match core.poll_outgoing() { Some(Packet::Publish(_)) => true, _ => false });
+/// core.handle(Packet::Puback(pid), Instant::now()).unwrap();
+/// assert_eq!(core.pending_publishes(), 0);
+/// # }
+/// ```
+pub struct ClientCore {
+    pub(crate) opts: ClientOptions,
+    pub(crate) state: ClientState,
+    pub(crate) session_present: bool,
+    // MQTT 5 Server Keep Alive of the last CONNACK
+    server_keep_alive: Option<u16>,
+    // Packets waiting for the driver
+    outbox: VecDeque<Packet>,
+
+    // Queues
+    pub(crate) last_pid: PacketIdentifier,
+    pub(crate) await_ping: bool,
+    // PINGREQs sent and PINGRESPs received, tell `ping_and_wait` which
+    // PINGRESP is its own
+    pub(crate) pingreqs: u64,
+    pub(crate) pingresps: u64,
+    pub(crate) incomming_pub: VecDeque<Box<Message>>, // QoS 1
+    pub(crate) incomming_rec: VecDeque<Box<Message>>, // QoS 2
+    pub(crate) incomming_rel: VecDeque<PacketIdentifier>, // QoS 2
+    pub(crate) incomming_pids: HashSet<PacketIdentifier>, // QoS 2 received until PUBCOMP
+    pub(crate) outgoing_ack: VecDeque<Box<Message>>, // QoS 1
+    pub(crate) outgoing_rec: VecDeque<Box<Message>>, // QoS 2
+    pub(crate) outgoing_comp: VecDeque<PacketIdentifier>, // QoS 2
+    // QoS 1 and 2 in the order they were published until fully acknowledged,
+    // what's sent again after reconnecting goes in this order
+    pub(crate) outgoing_order: VecDeque<PacketIdentifier>,
+    pub(crate) await_suback: VecDeque<Box<mqtt3::Subscribe>>,
+    pub(crate) await_unsuback: VecDeque<Box<mqtt3::Unsubscribe>>,
+    pub(crate) last_suback: Option<(PacketIdentifier, Vec<SubscribeReturnCodes>)>,
+    pub(crate) events: VecDeque<Event>,
+    // Subscriptions
+    pub(crate) subscriptions: HashMap<String, Subscription>,
+    // Topics published to lately, with the echo window on
+    recent_publishes: HashMap<String, Instant>,
+    // What the outgoing store held from before was taken back
+    restored: bool,
+}
+
+impl ClientCore {
+    pub fn new(mut opts: ClientOptions) -> ClientCore {
+        if opts.client_id == None {
+            opts.generate_client_id();
+        }
+        ClientCore {
+            opts: opts,
+            state: ClientState::Disconnected,
+            session_present: false,
+            server_keep_alive: None,
+            outbox: VecDeque::new(),
+            last_pid: PacketIdentifier::zero(),
+            await_ping: false,
+            pingreqs: 0,
+            pingresps: 0,
+            incomming_pub: VecDeque::new(),
+            incomming_rec: VecDeque::new(),
+            incomming_rel: VecDeque::new(),
+            incomming_pids: HashSet::new(),
+            outgoing_ack: VecDeque::new(),
+            outgoing_rec: VecDeque::new(),
+            outgoing_comp: VecDeque::new(),
+            outgoing_order: VecDeque::new(),
+            await_suback: VecDeque::new(),
+            await_unsuback: VecDeque::new(),
+            last_suback: None,
+            events: VecDeque::new(),
+            subscriptions: HashMap::new(),
+            recent_publishes: HashMap::new(),
+            restored: false,
+        }
+    }
+
+    /// Next packet to send, in the order they were produced
+    pub fn poll_outgoing(&mut self) -> Option<Packet> {
+        self.outbox.pop_front()
+    }
+
+    /// Next acknowledgement or connection change, the first CONNACK
+    /// included. Only the latest events are kept while nobody polls.
+    pub fn poll_event(&mut self) -> Option<Event> {
+        self.events.pop_front()
+    }
+
+    pub fn state(&self) -> ClientState {
+        self.state
+    }
+
+    pub fn session_present(&self) -> bool {
+        self.session_present
+    }
+
+    /// Interval PINGREQs are due at: the MQTT 5 Server Keep Alive of the
+    /// CONNACK when there was one, the configured keep alive otherwise.
+    /// None when keep alive is off.
+    pub fn keep_alive(&self) -> Option<Duration> {
+        match self.server_keep_alive {
+            Some(0) => None,
+            Some(secs) => Some(Duration::from_secs(secs as u64)),
+            None => self.opts.keep_alive,
+        }
+    }
+
+    pub fn options(&self) -> &ClientOptions {
+        &self.opts
+    }
+
+    pub fn into_options(self) -> ClientOptions {
+        self.opts
+    }
+
+    /// QoS 1 and 2 messages sent but not fully acknowledged yet
+    pub fn pending_publishes(&self) -> usize {
+        self.outgoing_ack.len() + self.outgoing_rec.len() + self.outgoing_comp.len()
+    }
+
+    /// QoS 1 and 2 messages received whose acknowledgement isn't over yet
+    pub fn pending_incoming(&self) -> usize {
+        self.incomming_pub.len() + self.incomming_rec.len() + self.incomming_rel.len()
+    }
+
+    /// Nothing is awaited from the broker
+    pub fn is_normalized(&self) -> bool {
+        (self.state == ClientState::Connected) && (!self.await_ping) &&
+        (self.outgoing_ack.len() == 0) && (self.outgoing_rec.len() == 0) &&
+        (self.incomming_pub.len() == 0) && (self.incomming_rec.len() == 0) &&
+        (self.incomming_rel.len() == 0) && (self.await_suback.len() == 0) &&
+        (self.await_unsuback.len() == 0)
+    }
+
+    /// Starts the handshake on a fresh connection with a CONNECT. The
+    /// first one takes back the publishes a previous run left in the
+    /// outgoing store, a resumed session sends them again.
+    pub fn connect(&mut self) -> Result<()> {
+        // a second CONNECT on a live session is a protocol violation
+        if self.state == ClientState::Connected {
+            return Err(Error::AlreadyConnected);
+        }
+        if !self.restored {
+            self._restore_outgoing()?;
+        }
+        self.state = ClientState::Handshake;
+        let connect = self.opts._generate_connect_packet();
+        debug!("       Connect {}", connect.client_id);
+        self._send(Packet::Connect(connect));
+        Ok(())
+    }
+
+    /// Takes a packet from the broker. A message is returned once it can
+    /// be handed to the application, QoS 2 on PUBREL. `now` is when it
+    /// arrived.
+    pub fn handle(&mut self, packet: Packet, now: Instant) -> Result<Option<Box<Message>>> {
+        trace!("{}", packet);
+        match self.state {
+            ClientState::Handshake => {
+                match packet {
+                    Packet::Connack(ref connack) => {
+                        if connack.code == ConnectReturnCode::Accepted {
+                            self.session_present = connack.session_present;
+                            self.server_keep_alive = connack.server_keep_alive;
+                            self.state = ClientState::Connected;
+                            self._event(Event::Connected);
+                            info!("    Connection accepted, {}", self.opts.protocol);
+                            // the session outlived the connection, so did
+                            // what it left unacknowledged
+                            if self.session_present {
+                                self.retransmit()?;
+                            } else {
+                                self._discard_session()?;
+                            }
+                            Ok(None)
+                        } else {
+                            Err(Error::ConnectionRefused {
+                                code: connack.code,
+                                reason_code: connack.reason_code,
+                                reason: connack.reason.clone()
+                            })
+                        }
+                    }
+                    _ => Err(Error::HandshakeFailed),
+                }
+            }
+            ClientState::Connected => {
+                match packet {
+                    Packet::Connack(_) => Err(Error::AlreadyConnected),
+                    Packet::Publish(ref publish) => {
+                        let message = Message::from_pub(publish.clone())?;
+                        self._handle_message(message, now)
+                    }
+                    Packet::Puback(pid) => {
+                        let position = self.outgoing_ack.iter().position(|message| message.pid == Some(pid));
+                        match position {
+                            // an older publish is still unacknowledged
+                            Some(i) if i > 0 && self.opts.strict_ordering => Err(Error::ProtocolViolation),
+                            Some(i) => {
+                                self.outgoing_ack.remove(i);
+                                if let Some(ref mut store) = self.opts.outgoing_store {
+                                    store.delete(pid).map_err(Error::store_failed("delete outgoing", pid))?;
+                                }
+                                self._acknowledged(pid);
+                                self._event(Event::PubAck(pid));
+                                Ok(None)
+                            }
+                            None => Err(Error::PacketIdentifierError(crate::error::PacketIdentifierError::UnhandledPuback(pid)))
+                        }
+                    }
+                    Packet::Pubrec(pid) => {
+                        // a cancelled publish may still be answered, so
+                        // match by pid rather than by position
+                        let position = self.outgoing_rec.iter().position(|message| message.pid == Some(pid));
+                        match position {
+                            Some(i) => {
+                                self.outgoing_rec.remove(i);
+                                self._send(Packet::Pubrel(pid));
+
+                                self.outgoing_comp.push_back(pid);
+                                if let Some(ref mut store) = self.opts.outgoing_store {
+                                    store.delete(pid).map_err(Error::store_failed("delete outgoing", pid))?;
+                                } else {
+                                    return Err(Error::IncommingStorageAbsent);
+                                }
+
+                                Ok(None)
+                            }
+                            None => Err(Error::PacketIdentifierError(crate::error::PacketIdentifierError::UnhandledPubrec(pid)))
+                        }
+                    }
+                    Packet::Pubrel(pid) => {
+                        if let Some(message) = self.incomming_rec.pop_front() {
+                            if message.pid == Some(pid) {
+                                let message = if let Some(ref mut store) = self.opts
+                                                                               .incomming_store {
+                                    store.get(pid).map_err(Error::store_failed("load incoming", pid))?
+                                } else {
+                                    return Err(Error::IncommingStorageAbsent);
+                                };
+                                // a broken store mustn't deliver another message
+                                if message.pid != Some(pid) {
+                                    return Err(Error::store_failed("load incoming", pid)(store::Error::Mismatch(pid)));
+                                }
+                                self.incomming_rel.push_back(pid);
+                                if self._is_echo(&message, now) {
+                                    self.complete(pid)?;
+                                    return Ok(None);
+                                }
+                                Ok(Some(message))
+                            } else {
+                                Err(Error::PacketIdentifierError(crate::error::PacketIdentifierError::UnhandledPubrel(pid)))
+                            }
+                        } else {
+                            Err(Error::PacketIdentifierError(crate::error::PacketIdentifierError::UnhandledPubrel(pid)))
+                        }
+                    }
+                    Packet::Pubcomp(pid) => {
+                        let position = self.outgoing_comp.iter().position(|&comp| comp == pid);
+                        match position {
+                            Some(i) => {
+                                self.outgoing_comp.remove(i);
+                                self._acknowledged(pid);
+                                self._event(Event::PubAck(pid));
+                                Ok(None)
+                            }
+                            None => Err(Error::PacketIdentifierError(crate::error::PacketIdentifierError::UnhandledPubcomp(pid)))
+                        }
+                    }
+                    Packet::Suback(ref suback) => {
+                        // acks of different requests may come in any order
+                        let position = self.await_suback
+                                           .iter()
+                                           .position(|subscribe| subscribe.pid == suback.pid);
+                        if let Some(subscribe) = position.and_then(|i| self.await_suback.remove(i)) {
+                            if subscribe.topics.len() == suback.return_codes.len() {
+                                let iter = suback.return_codes.iter().zip(&subscribe.topics);
+                                for (ref code, ref sub_topic) in iter {
+                                    match **code {
+                                        SubscribeReturnCodes::Success(qos) => {
+                                            let sub = Subscription {
+                                                pid: subscribe.pid,
+                                                topic_path: sub_topic.topic_path
+                                                                          .to_topic_path()?,
+                                                qos: qos,
+                                                id: subscription_id(&subscribe.properties),
+                                                no_local: sub_topic.no_local,
+                                                retain_as_published: sub_topic.retain_as_published,
+                                                retain_handling: sub_topic.retain_handling,
+                                            };
+                                            self.subscriptions
+                                                .insert(sub_topic.topic_path.clone(), sub);
+                                        }
+                                        // Failure, Refused and whatever
+                                        // refusal mqtt3 knows next
+                                        _ => {
+                                            // ignore subscription
+                                        }
+                                    }
+                                }
+                                self._event(Event::SubAck(suback.pid,
+                                                          suback.return_codes.clone()));
+                                self.last_suback = Some((suback.pid,
+                                                         suback.return_codes.clone()));
+                                Ok(None)
+                            } else {
+                                Err(Error::SubackMismatch {
+                                    pid: suback.pid,
+                                    expected: subscribe.topics.len(),
+                                    got: suback.return_codes.len()
+                                })
+                            }
+                        } else {
+                            Err(Error::PacketIdentifierError(crate::error::PacketIdentifierError::UnhandledSuback(suback.pid)))
+                        }
+                    }
+                    Packet::Unsuback(pid) => {
+                        let position = self.await_unsuback
+                                           .iter()
+                                           .position(|unsubscribe| unsubscribe.pid == pid);
+                        if let Some(unsubscribe) = position.and_then(|i| self.await_unsuback.remove(i)) {
+                            for topic in unsubscribe.topics.iter() {
+                                self.subscriptions.remove(topic);
+                            }
+                            Ok(None)
+                        } else {
+                            Err(Error::PacketIdentifierError(crate::error::PacketIdentifierError::UnhandledUnsuback(pid)))
+                        }
+                    }
+                    Packet::Pingresp => {
+                        self.await_ping = false;
+                        self.pingresps += 1;
+                        Ok(None)
+                    }
+                    Packet::Disconnect(ref disconnect) => {
+                        Err(Error::ServerDisconnect {
+                            code: disconnect.reason_code,
+                            reason: disconnect.reason.clone()
+                        })
+                    }
+                    // harmless, there is no answer a client could give
+                    Packet::Pingreq => {
+                        warn!("Ignoring {}", packet);
+                        Ok(None)
+                    }
+                    // only ever sent to a server, and AUTH as enhanced
+                    // authentication isn't supported
+                    Packet::Connect(_) |
+                    Packet::Subscribe(_) |
+                    Packet::Unsubscribe(_) |
+                    Packet::Auth(_) => {
+                        match self.opts.unexpected_packet {
+                            UnexpectedPacketMethod::Disconnect => {
+                                Err(Error::UnexpectedPacket(packet.to_string()))
+                            }
+                            UnexpectedPacketMethod::Ignore => {
+                                warn!("Ignoring {}", packet);
+                                Ok(None)
+                            }
+                        }
+                    }
+                }
+            }
+            ClientState::Disconnected => Err(Error::ConnectionAbort),
+        }
+    }
+
+    /// Queues a PUBLISH sent at `now`
+    pub fn publish<T: ToTopicPath, P: ToPayload>(&mut self,
+                                                 topic: T,
+                                                 payload: P,
+                                                 pubopt: PubOpt,
+                                                 now: Instant)
+                                                 -> Result<Option<PacketIdentifier>> {
+        let mut message = self._message(topic, payload.to_payload(), pubopt)?;
+        self._track_publish(&mut message, now)?;
+
+        debug!("       Publish {} {} > {} bytes",
+               message.qos.to_u8(),
+               message.topic_str(),
+               message.payload.len());
+        self._send(Packet::Publish(message.to_pub(None, false)));
+        Ok(message.pid)
+    }
+
+    pub fn subscribe<S: ToSubTopics>(&mut self, subs: S, id: Option<u32>) -> Result<PacketIdentifier> {
+        let mut topics: Vec<SubscribeTopic> = subs.to_subscribe_topics()?.collect();
+        if topics.is_empty() {
+            return Err(Error::Mqtt(mqtt3::MQError::IncorrectPacketFormat));
+        }
+        self._check_inflight_subscribes()?;
+        if self.opts.no_local {
+            for topic in topics.iter_mut() {
+                topic.no_local = true;
+            }
+        }
+        let subscribe = Box::new(mqtt3::Subscribe {
+            pid: self._next_pid(),
+            topics: topics,
+            properties: id.map(Property::SubscriptionIdentifier).into_iter().collect(),
+        });
+        debug!("     Subscribe {:?}", subscribe.topics);
+        let pid = subscribe.pid;
+        self.await_suback.push_back(subscribe.clone());
+        self._send(Packet::Subscribe(subscribe));
+        Ok(pid)
+    }
+
+    pub fn unsubscribe<U: ToUnSubTopics>(&mut self, unsubs: U) -> Result<()> {
+        let topics: Vec<String> = unsubs.to_unsubscribe_topics()?.collect();
+        if topics.is_empty() {
+            return Err(Error::Mqtt(mqtt3::MQError::IncorrectPacketFormat));
+        }
+        self._check_inflight_subscribes()?;
+        let unsubscribe = Box::new(mqtt3::Unsubscribe {
+            pid: self._next_pid(),
+            topics: topics,
+        });
+        debug!("   Unsubscribe {:?}", unsubscribe.topics);
+        self.await_unsuback.push_back(unsubscribe.clone());
+        self._send(Packet::Unsubscribe(unsubscribe));
+        Ok(())
+    }
+
+    /// Subscribes again to everything held, for a session the broker
+    /// didn't keep
+    pub fn resubscribe(&mut self) {
+        // one SUBSCRIBE per subscription identifier
+        let mut by_id: HashMap<Option<u32>, Vec<SubscribeTopic>> = HashMap::new();
+        for sub in self.subscriptions.values() {
+            by_id.entry(sub.id).or_insert_with(Vec::new).push(sub.to_subscribe_topic());
+        }
+        let mut ids: Vec<Option<u32>> = by_id.keys().cloned().collect();
+        ids.sort();
+        for id in ids {
+            let subs = by_id.remove(&id).unwrap();
+            if self.subscribe(subs, id).is_err() {
+                return;
+            }
+        }
+    }
+
+    pub fn ping(&mut self) {
+        debug!("       Pingreq");
+        self.await_ping = true;
+        self.pingreqs += 1;
+        self._send(Packet::Pingreq);
+    }
+
+    /// Sends again what the broker hasn't acknowledged, after reconnecting
+    /// to a session which outlived its connection. In the order of the
+    /// original publishes: the QoS 1 and 2 publishes of the outgoing store
+    /// with DUP set, PUBRELs for those waiting for PUBCOMP. Without a store
+    /// QoS 1 publishes are sent from memory.
+    pub fn retransmit(&mut self) -> Result<()> {
+        for pid in self.outgoing_order.clone() {
+            let pending = self.outgoing_ack.iter()
+                                           .chain(self.outgoing_rec.iter())
+                                           .find(|message| message.pid == Some(pid))
+                                           .cloned();
+            let message = match (pending, self.opts.outgoing_store.as_mut()) {
+                (Some(_), Some(store)) => store.get(pid).map_err(Error::store_failed("load outgoing", pid))?,
+                (Some(message), None) => message,
+                (None, _) => {
+                    self._send(Packet::Pubrel(pid));
+                    continue;
+                }
+            };
+            debug!("    Retransmit {}", message.topic_str());
+            self._send(Packet::Publish(message.to_pub(None, true)));
+        }
+        Ok(())
+    }
+
+    // The publishes a previous run left in the outgoing store are in
+    // flight again, in packet identifier order as the publish order is
+    // gone with that run. QoS 2 ones past PUBREC aren't stored any more.
+    // Stores which can't list their messages serve this run only.
+    fn _restore_outgoing(&mut self) -> Result<()> {
+        self.restored = true;
+        let store = match self.opts.outgoing_store {
+            Some(ref mut store) => store,
+            None => return Ok(())
+        };
+        let pids = match store.keys() {
+            Ok(pids) => pids,
+            Err(store::Error::Unsupported) => return Ok(()),
+            Err(err) => return Err(Error::Storage(err))
+        };
+        for pid in pids {
+            let message = store.get(pid).map_err(Error::store_failed("load outgoing", pid))?;
+            if message.pid != Some(pid) {
+                return Err(Error::store_failed("load outgoing", pid)(store::Error::Mismatch(pid)));
+            }
+            match message.qos {
+                QoS::AtMostOnce => continue,
+                QoS::AtLeastOnce => self.outgoing_ack.push_back(message),
+                QoS::ExactlyOnce => self.outgoing_rec.push_back(message)
+            }
+            debug!("       Restore {}", pid.0);
+            self.outgoing_order.push_back(pid);
+            // new publishes go past the restored ones
+            if pid > self.last_pid {
+                self.last_pid = pid;
+            }
+        }
+        Ok(())
+    }
+
+    // The broker started a new session, nothing in flight is awaited any
+    // more. Messages leave the stores, apart from the incoming ones handed
+    // out which `complete` deletes.
+    fn _discard_session(&mut self) -> Result<()> {
+        let outgoing = self.outgoing_ack.drain(..).chain(self.outgoing_rec.drain(..));
+        for pid in outgoing.filter_map(|message| message.pid) {
+            if let Some(ref mut store) = self.opts.outgoing_store {
+                store.delete(pid).map_err(Error::store_failed("delete outgoing", pid))?;
+            }
+        }
+        self.outgoing_comp.clear();
+        self.outgoing_order.clear();
+        for pid in self.incomming_rec.drain(..).filter_map(|message| message.pid) {
+            self.incomming_pids.remove(&pid);
+            if let Some(ref mut store) = self.opts.incomming_store {
+                store.delete(pid).map_err(Error::store_failed("delete incoming", pid))?;
+            }
+        }
+        self.incomming_pub.clear();
+        Ok(())
+    }
+
+    // Fully acknowledged or given up on
+    fn _acknowledged(&mut self, pid: PacketIdentifier) {
+        if let Some(i) = self.outgoing_order.iter().position(|&order| order == pid) {
+            self.outgoing_order.remove(i);
+        }
+    }
+
+    pub fn disconnect(&mut self) {
+        self._send(Packet::Disconnect(Disconnect::default()));
+    }
+
+    /// Sends the PUBCOMP of a QoS 2 message handed out on PUBREL
+    pub fn complete(&mut self, pid: PacketIdentifier) -> Result<()> {
+        let same_pid = self.incomming_rel.pop_back();
+        if same_pid == Some(pid) {
+            self._send(Packet::Pubcomp(pid));
+            self.incomming_pids.remove(&pid);
+
+            if let Some(ref mut store) = self.opts.incomming_store {
+                store.delete(pid).map_err(Error::store_failed("delete incoming", pid))?;
+                Ok(())
+            } else {
+                return Err(Error::IncommingStorageAbsent);
+            }
+        } else {
+            Err(Error::ProtocolViolation)
+        }
+    }
+
+    /// Gives up on a QoS 2 publish the broker never acknowledged, whether it
+    /// waits for PUBREC or PUBCOMP. The message leaves the outgoing store and
+    /// stops counting against the inflight window. Returns whether `pid` was
+    /// pending at all.
+    pub fn cancel_publish(&mut self, pid: PacketIdentifier) -> Result<bool> {
+        if let Some(i) = self.outgoing_rec.iter().position(|message| message.pid == Some(pid)) {
+            self.outgoing_rec.remove(i);
+            self._acknowledged(pid);
+            if let Some(ref mut store) = self.opts.outgoing_store {
+                store.delete(pid).map_err(Error::store_failed("delete outgoing", pid))?;
+            }
+            return Ok(true);
+        }
+        // past PUBREC the store no longer has it
+        if let Some(i) = self.outgoing_comp.iter().position(|&comp| comp == pid) {
+            self.outgoing_comp.remove(i);
+            self._acknowledged(pid);
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// The connection is gone: whatever is awaited from it is forgotten,
+    /// unsent packets included
+    pub fn disconnected(&mut self) {
+        self.outbox.clear();
+        self.await_unsuback.clear();
+        self.await_suback.clear();
+        self.await_ping = false;
+        // PINGREQs on the way are never answered
+        self.pingresps = self.pingreqs;
+        if self.state == ClientState::Connected {
+            self._event(Event::Disconnected);
+        }
+        self.state = ClientState::Disconnected;
+    }
+
+    pub(crate) fn _message<T: ToTopicPath>(&self, topic: T, payload: Arc<Vec<u8>>, pubopt: PubOpt) -> Result<Box<Message>> {
+        Ok(Box::new(Message {
+            topic: Arc::new(topic.to_topic_name()?),
+            qos: pubopt.qos(),
+            retain: pubopt.is_retain(),
+            pid: None,
+            expiry: self.opts.message_expiry,
+            utf8: pubopt.is_utf8(),
+            subscription_ids: Vec::new(),
+            payload: payload,
+        }))
+    }
+
+    // Assigns the packet identifier and queues the message for its acks
+    pub(crate) fn _track_publish(&mut self, message: &mut Box<Message>, now: Instant) -> Result<()> {
+        if let Some(window) = self.opts.echo_window {
+            self.recent_publishes.retain(|_, sent| now.duration_since(*sent) < window);
+            self.recent_publishes.insert(message.topic_str().to_owned(), now);
+        }
+        match message.qos {
+            QoS::AtMostOnce => (),
+            QoS::AtLeastOnce => {
+                let pid = self._next_pid();
+                message.pid = Some(pid);
+                // stored when there is a store, so it outlives the process
+                if let Some(ref mut store) = self.opts.outgoing_store {
+                    store.put(message.clone()).map_err(Error::store_failed("persist outgoing", pid))?;
+                    store.sync().map_err(Error::store_failed("sync outgoing", pid))?;
+                }
+                self.outgoing_ack.push_back(message.clone());
+                self.outgoing_order.push_back(pid);
+            }
+            QoS::ExactlyOnce => {
+                let pid = self._next_pid();
+                message.pid = Some(pid);
+                if let Some(ref mut store) = self.opts.outgoing_store {
+                    store.put(message.clone()).map_err(Error::store_failed("persist outgoing", pid))?;
+                    store.sync().map_err(Error::store_failed("sync outgoing", pid))?;
+                } else {
+                    return Err(Error::OutgoingStorageAbsent);
+                }
+                self.outgoing_rec.push_back(message.clone());
+                self.outgoing_order.push_back(pid);
+            }
+        }
+        Ok(())
+    }
+
+    // A message on a topic we published to within the echo window,
+    // most likely our own coming back
+    fn _is_echo(&self, message: &Message, now: Instant) -> bool {
+        match (self.opts.echo_window, self.recent_publishes.get(message.topic_str())) {
+            (Some(window), Some(sent)) if now.duration_since(*sent) < window => {
+                debug!("    Dropping echo on {}", message.topic_str());
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn _handle_message(&mut self, message: Box<Message>, now: Instant) -> Result<Option<Box<Message>>> {
+        debug!("       Publish {} {} < {} bytes",
+               message.qos.to_u8(),
+               message.topic_str(),
+               message.payload.len());
+        if self.opts.validate_utf8 && message.utf8 && message.payload_str().is_none() {
+            warn!("  Payload of {} is flagged UTF-8 but isn't", message.topic_str());
+        }
+        match message.qos {
+            QoS::AtMostOnce if self._is_echo(&message, now) => Ok(None),
+            QoS::AtMostOnce => Ok(Some(message)),
+            QoS::AtLeastOnce => {
+                self.incomming_pub.push_back(message.clone());
+                let pid = message.pid.unwrap();
+                // debug!("        Puback {}", pid.0);
+                self._send(Packet::Puback(pid));
+                // FIXME: can be repeated
+                let _ = self.incomming_pub.pop_front();
+
+                if self._is_echo(&message, now) {
+                    return Ok(None);
+                }
+                Ok(Some(message))
+            }
+            QoS::ExactlyOnce => {
+                let pid = message.pid.unwrap();
+                // a redelivery of a message already stored, only the PUBREC
+                // is repeated
+                if !self.incomming_pids.insert(pid) {
+                    self._send(Packet::Pubrec(pid));
+                    return Ok(None);
+                }
+                self.incomming_rec.push_back(message.clone());
+
+                if let Some(ref mut store) = self.opts.incomming_store {
+                    store.put(message).map_err(Error::store_failed("persist incoming", pid))?;
+                    store.sync().map_err(Error::store_failed("sync incoming", pid))?;
+                } else {
+                    return Err(Error::IncommingStorageAbsent);
+                }
+
+                self._send(Packet::Pubrec(pid));
+
+                Ok(None)
+            }
+        }
+    }
+
+    fn _check_inflight_subscribes(&self) -> Result<()> {
+        if self.await_suback.len() + self.await_unsuback.len() >= self.opts.max_inflight_subscribes {
+            return Err(Error::InflightLimit);
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn _send(&mut self, packet: Packet) {
+        self.outbox.push_back(packet);
+    }
+
+    pub(crate) fn _event(&mut self, event: Event) {
+        if self.events.len() >= MAX_EVENTS {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+
+    #[inline]
+    fn _next_pid(&mut self) -> PacketIdentifier {
+        self.last_pid = self.last_pid.next();
+        self.last_pid
+    }
+}
+
+fn subscription_id(properties: &[Property]) -> Option<u32> {
+    properties.iter().filter_map(|property| {
+        match *property {
+            Property::SubscriptionIdentifier(id) => Some(id),
+            _ => None
+        }
+    }).next()
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use mqtt3::{Connack, ConnectReturnCode, Packet, PacketIdentifier, Publish, QoS, Suback, SubscribeReturnCodes};
+    use error::{Error, PacketIdentifierError};
+    use {ClientOptions, ClientState, Event, PubOpt};
+    use super::{ClientCore, MAX_EVENTS};
+
+    fn connected() -> ClientCore {
+        let mut core = ClientCore::new(ClientOptions::new());
+        core.connect().unwrap();
+        core.poll_outgoing().unwrap();
+        core.handle(Packet::Connack(Connack {
+            session_present: false,
+            code: ConnectReturnCode::Accepted,
+            reason_code: None,
+            reason: None,
+            server_keep_alive: None
+        }), Instant::now()).unwrap();
+        core
+    }
+
+    #[test]
+    fn core_subscribe_test() {
+        let mut core = connected();
+        match core.poll_event() {
+            Some(Event::Connected) => (),
+            other => panic!("expected Connected, got {:?}", other)
+        }
+        assert!(core.poll_event().is_none());
+        let pid = core.subscribe(("a/+", QoS::AtLeastOnce), None).unwrap();
+        match core.poll_outgoing() {
+            Some(Packet::Subscribe(ref subscribe)) => assert_eq!(subscribe.pid, pid),
+            other => panic!("expected SUBSCRIBE, got {:?}", other)
+        }
+        assert!(core.poll_outgoing().is_none());
+
+        let codes = vec![SubscribeReturnCodes::Success(QoS::AtMostOnce)];
+        core.handle(Packet::Suback(Box::new(Suback { pid, return_codes: codes.clone() })), Instant::now()).unwrap();
+        assert_eq!(core.subscriptions["a/+"].qos, QoS::AtMostOnce);
+        match core.poll_event() {
+            Some(Event::SubAck(ack_pid, ref ack_codes)) => {
+                assert_eq!(ack_pid, pid);
+                assert_eq!(*ack_codes, codes);
+            }
+            other => panic!("expected SubAck, got {:?}", other)
+        }
+
+        // the same packet again isn't awaited any more
+        match core.handle(Packet::Suback(Box::new(Suback { pid, return_codes: codes })), Instant::now()) {
+            Err(Error::PacketIdentifierError(PacketIdentifierError::UnhandledSuback(ack_pid))) => assert_eq!(ack_pid, pid),
+            other => panic!("expected UnhandledSuback, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn core_suback_mismatch_test() {
+        let mut core = connected();
+        let pid = core.subscribe(vec!["a", "b"], None).unwrap();
+        let codes = vec![SubscribeReturnCodes::Success(QoS::AtMostOnce)];
+        match core.handle(Packet::Suback(Box::new(Suback { pid, return_codes: codes })), Instant::now()) {
+            Err(Error::SubackMismatch { pid: ack_pid, expected: 2, got: 1 }) => assert_eq!(ack_pid, pid),
+            other => panic!("expected SubackMismatch, got {:?}", other)
+        }
+
+        match core.handle(Packet::Unsuback(PacketIdentifier(9)), Instant::now()) {
+            Err(Error::PacketIdentifierError(PacketIdentifierError::UnhandledUnsuback(PacketIdentifier(9)))) => (),
+            other => panic!("expected UnhandledUnsuback, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn core_disconnected_test() {
+        let mut core = connected();
+        core.ping();
+        core.subscribe("a", None).unwrap();
+        core.disconnected();
+        assert_eq!(core.state(), ClientState::Disconnected);
+        assert!(core.poll_outgoing().is_none());
+        assert!(core.await_suback.is_empty());
+        match core.handle(Packet::Puback(PacketIdentifier(1)), Instant::now()) {
+            Err(Error::ConnectionAbort) => (),
+            other => panic!("expected ConnectionAbort, got {:?}", other)
+        }
+
+        // a refused CONNACK leaves the state alone
+        core.connect().unwrap();
+        let refused = Packet::Connack(Connack {
+            session_present: false,
+            code: ConnectReturnCode::NotAuthorized,
+            reason_code: None,
+            reason: None,
+            server_keep_alive: None
+        });
+        assert!(core.handle(refused, Instant::now()).is_err());
+        assert_eq!(core.state(), ClientState::Handshake);
+    }
+
+    #[test]
+    fn core_echo_window_test() {
+        let mut core = connected();
+        core.opts.set_echo_window(Some(Duration::from_secs(1)));
+        let incoming = || Packet::Publish(Box::new(Publish {
+            dup: false,
+            qos: QoS::AtMostOnce,
+            retain: false,
+            topic_name: "a/b".to_owned(),
+            pid: None,
+            properties: Vec::new(),
+            payload: Arc::new(b"hello".to_vec())
+        }));
+        let sent = Instant::now();
+        core.publish("a/b", "hello", PubOpt::at_most_once(), sent).unwrap();
+        // only the time passed in counts
+        assert!(core.handle(incoming(), sent + Duration::from_millis(999)).unwrap().is_none());
+        assert!(core.handle(incoming(), sent + Duration::from_secs(1)).unwrap().is_some());
+    }
+
+    #[test]
+    fn core_event_limit_test() {
+        let mut core = connected();
+        for pid in 1..(MAX_EVENTS as u16 + 10) {
+            core._event(Event::PubAck(PacketIdentifier(pid)));
+        }
+        assert_eq!(core.events.len(), MAX_EVENTS);
+        // the oldest, Connected included, made room
+        match core.poll_event() {
+            Some(Event::PubAck(PacketIdentifier(10))) => (),
+            other => panic!("expected PubAck(10), got {:?}", other)
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use std::io::Cursor;
+use std::path::Path;
+use sled;
+use mqtt3::{Message, MqttRead, MqttWrite, Packet, PacketIdentifier, Property, Protocol};
+use store::{Error, Result, Store};
+
+/// A `Store` on sled, which survives crashes: messages are kept as MQTT 5
+/// PUBLISH packets under their big endian packet identifier, each `put`
+/// an atomic insert and `sync` a flush to disk.
+///
+/// ```no_run
+/// # extern crate mqttc;
+/// # extern crate sled;
+/// use mqttc::ClientOptions;
+/// use mqttc::store::SledStore;
+/// # fn main() {
+/// let db = sled::open("mqttc.db").unwrap();
+/// let mut opts = ClientOptions::new();
+/// opts.set_incomming_store(Box::new(SledStore::open_tree(&db, "incomming").unwrap()));
+/// opts.set_outgoing_store(Box::new(SledStore::open_tree(&db, "outgoing").unwrap()));
+/// # }
+/// ```
+pub struct SledStore {
+    tree: sled::Tree
+}
+
+impl SledStore {
+    /// Opens the database at `path` and keeps the messages in its default
+    /// tree, one database per store
+    pub fn open<P: AsRef<Path>>(path: P) -> sled::Result<SledStore> {
+        let db = sled::open(path)?;
+        Ok(SledStore::from_tree((*db).clone()))
+    }
+
+    /// Keeps the messages in the tree `name`, so incoming and outgoing
+    /// stores can share a database
+    pub fn open_tree(db: &sled::Db, name: &str) -> sled::Result<SledStore> {
+        Ok(SledStore::from_tree(db.open_tree(name)?))
+    }
+
+    pub fn from_tree(tree: sled::Tree) -> SledStore {
+        SledStore { tree: tree }
+    }
+
+    fn _key(pid: PacketIdentifier) -> [u8; 2] {
+        let PacketIdentifier(pid) = pid;
+        [(pid >> 8) as u8, pid as u8]
+    }
+}
+
+impl Store for SledStore {
+    fn put(&mut self, message: Box<Message>) -> Result<()> {
+        let pid = message.pid.ok_or(Error::Unsupported)?;
+        let mut publish = message.to_pub(None, false);
+        publish.properties.extend(message.subscription_ids.iter().map(|&id| Property::SubscriptionIdentifier(id)));
+        let mut value = Cursor::new(Vec::new());
+        value.write_packet_with(&Packet::Publish(publish), Protocol::MQTT(5))
+             .map_err(|_| Error::Unavailable(pid))?;
+        self.tree.insert(SledStore::_key(pid), value.into_inner()).map_err(|err| Error::Backend(err.to_string()))?;
+        Ok(())
+    }
+
+    fn get(&mut self, pid: PacketIdentifier) -> Result<Box<Message>> {
+        let value = self.tree.get(SledStore::_key(pid))
+                             .map_err(|err| Error::Backend(err.to_string()))?
+                             .ok_or(Error::NotFound(pid))?;
+        match Cursor::new(value.to_vec()).read_packet_with(Protocol::MQTT(5)) {
+            Ok(Packet::Publish(publish)) => Message::from_pub(publish).map_err(|_| Error::Unavailable(pid)),
+            _ => Err(Error::Unavailable(pid))
+        }
+    }
+
+    fn delete(&mut self, pid: PacketIdentifier) -> Result<()> {
+        self.tree.remove(SledStore::_key(pid))
+                 .map_err(|err| Error::Backend(err.to_string()))?
+                 .ok_or(Error::NotFound(pid))?;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.tree.flush().map_err(|err| Error::Backend(err.to_string()))?;
+        Ok(())
+    }
+
+    fn keys(&self) -> Result<Vec<PacketIdentifier>> {
+        self.tree.iter().keys().map(|key| {
+            let key = key.map_err(|err| Error::Backend(err.to_string()))?;
+            Ok(PacketIdentifier((key[0] as u16) << 8 | key[1] as u16))
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use sled;
+    use mqtt3::{Message, PacketIdentifier, QoS, TopicPath};
+    use store::{Error, Store};
+    use super::SledStore;
+
+    #[test]
+    fn sled_store_test() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mut store = SledStore::open_tree(&db, "outgoing").unwrap();
+        for &pid in &[0x0102, 0x0001] {
+            store.put(Box::new(Message {
+                topic: Arc::new(TopicPath::from("a/b")),
+                qos: QoS::ExactlyOnce,
+                retain: false,
+                pid: Some(PacketIdentifier(pid)),
+                expiry: Some(Duration::from_secs(60)),
+                utf8: true,
+                subscription_ids: vec![7],
+                payload: Arc::new(vec![0x01, 0x02])
+            })).unwrap();
+        }
+        store.sync().unwrap();
+        assert_eq!(store.keys().unwrap(), vec![PacketIdentifier(0x0001), PacketIdentifier(0x0102)]);
+
+        let message = store.get(PacketIdentifier(0x0102)).unwrap();
+        assert_eq!(message.topic_str(), "a/b");
+        assert_eq!(message.qos, QoS::ExactlyOnce);
+        assert_eq!(message.pid, Some(PacketIdentifier(0x0102)));
+        assert_eq!(message.expiry, Some(Duration::from_secs(60)));
+        assert!(message.utf8);
+        assert_eq!(message.subscription_ids, vec![7]);
+        assert_eq!(*message.payload, vec![0x01, 0x02]);
+
+        store.delete(PacketIdentifier(0x0102)).unwrap();
+        match store.get(PacketIdentifier(0x0102)) {
+            Err(Error::NotFound(PacketIdentifier(0x0102))) => (),
+            other => panic!("expected NotFound, got {:?}", other.map(|m| m.pid))
+        }
+        assert!(db.open_tree("incomming").unwrap().iter().next().is_none());
+    }
+}
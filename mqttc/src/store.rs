@@ -2,20 +2,44 @@ use std::result;
 use std::error;
 use std::fmt;
 use mqtt3::{Message, PacketIdentifier};
+#[cfg(feature = "sled")]
+pub use sled_store::SledStore;
 
 pub type Result<T> = result::Result<T, Error>;
 
+/// Messages of the QoS 2 flows, and outgoing QoS 1 ones. The client
+/// persists before it sends: a message is `put` and `sync`ed before its
+/// PUBLISH (outgoing) or PUBREC (incoming) is written to the socket, so a
+/// crash can't lose a message the peer already knows about. An outgoing
+/// store able to list its `keys` is read back on the first connect, what
+/// a previous run left there is sent again on a resumed session.
 pub trait Store {
     fn put(&mut self, message: Box<Message>) -> Result<()>;
     fn get(&mut self, pid: PacketIdentifier) -> Result<Box<Message>>;
     fn delete(&mut self, pid: PacketIdentifier) -> Result<()>;
-//    fn iter() -> Iterator<Message>;
+
+    /// Makes the messages put so far durable, e.g. fsync for a file store.
+    /// Nothing to do for stores in memory.
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Packet identifiers of the stored messages, for inspection. Stores
+    /// which can't list them needn't implement it.
+    fn keys(&self) -> Result<Vec<PacketIdentifier>> {
+        Err(Error::Unsupported)
+    }
 }
 
 #[derive(Debug)]
 pub enum Error {
     NotFound(PacketIdentifier),
-    Unavailable(PacketIdentifier)
+    Unavailable(PacketIdentifier),
+    /// `get` returned the message of another packet identifier
+    Mismatch(PacketIdentifier),
+    Unsupported,
+    /// The storage underneath failed, e.g. the disk of a database
+    Backend(String)
 }
 
 impl fmt::Display for Error {
@@ -25,6 +49,10 @@ impl fmt::Display for Error {
                 fmt::write(f, format_args!("Packet {} not found", packet_identifier)),
             Error::Unavailable(PacketIdentifier(packet_identifier)) =>
                 fmt::write(f, format_args!("Packet {} unavailable", packet_identifier)),
+            Error::Mismatch(PacketIdentifier(packet_identifier)) =>
+                fmt::write(f, format_args!("Packet {} mismatched", packet_identifier)),
+            Error::Unsupported => f.write_str("Unsupported by the store"),
+            Error::Backend(ref err) => fmt::write(f, format_args!("Store backend failed: {}", err)),
         }
     }
 }
@@ -34,6 +62,9 @@ impl error::Error for Error {
         match *self {
             Error::NotFound(PacketIdentifier(_)) =>  "Packet not found",
             Error::Unavailable(PacketIdentifier(_)) => "Packet unavailable",
+            Error::Mismatch(PacketIdentifier(_)) => "Packet mismatched",
+            Error::Unsupported => "Unsupported by the store",
+            Error::Backend(_) => "Store backend failed",
         }
     }
 
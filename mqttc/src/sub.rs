@@ -2,21 +2,42 @@ use std::option;
 use std::vec;
 use {MAX_QOS};
 use error::Result;
-use mqtt3::{SubscribeTopic, TopicPath, PacketIdentifier, QoS};
+use mqtt3::{RetainHandling, SubscribeTopic, TopicPath, PacketIdentifier, QoS};
 
 #[derive(Debug, Clone)]
 pub struct Subscription {
     pub pid: PacketIdentifier,
     pub topic_path: TopicPath,
-    pub qos: QoS
+    pub qos: QoS,
+    // MQTT 5 Subscription Identifier, sent again on resubscribe
+    pub id: Option<u32>,
+    pub no_local: bool,
+    pub retain_as_published: bool,
+    pub retain_handling: RetainHandling
 }
 
 impl Subscription {
     pub fn to_subscribe_topic(&self) -> SubscribeTopic {
-        SubscribeTopic { topic_path: self.topic_path.path(), qos: self.qos }
+        SubscribeTopic { topic_path: self.topic_path.path(), qos: self.qos, no_local: self.no_local,
+                         retain_as_published: self.retain_as_published, retain_handling: self.retain_handling }
     }
 }
 
+/// Topics of a SUBSCRIBE. Filters given without a QoS subscribe with QoS 1.
+///
+/// | Type                    | Subscribes to            |
+/// |-------------------------|--------------------------|
+/// | `&str`, `String`        | one filter               |
+/// | `(&str, QoS)`, `(String, QoS)` | one filter at that QoS |
+/// | `Vec<&str>`, `&[&str]`  | every filter             |
+/// | `SubscribeTopic`, `Vec<SubscribeTopic>` | as given  |
+///
+/// ```
+/// use mqttc::ToSubTopics;
+///
+/// let topics: Vec<_> = vec!["a/+", "b/#"].to_subscribe_topics().unwrap().collect();
+/// assert_eq!(topics[1].topic_path, "b/#");
+/// ```
 pub trait ToSubTopics {
     type Iter: Iterator<Item=SubscribeTopic>;
     fn to_subscribe_topics(&self) -> Result<Self::Iter>;
@@ -39,7 +60,38 @@ impl ToSubTopics for Vec<SubscribeTopic> {
 impl<'a> ToSubTopics for &'a str {
     type Iter = option::IntoIter<SubscribeTopic>;
     fn to_subscribe_topics(&self) -> Result<Self::Iter> {
-        Ok(Some(SubscribeTopic { topic_path: self.to_string(), qos: MAX_QOS }).into_iter())
+        Ok(Some(SubscribeTopic { topic_path: self.to_string(), qos: MAX_QOS, no_local: false, retain_as_published: false, retain_handling: RetainHandling::SendAtSubscribe }).into_iter())
+    }
+}
+
+impl ToSubTopics for String {
+    type Iter = option::IntoIter<SubscribeTopic>;
+    fn to_subscribe_topics(&self) -> Result<Self::Iter> {
+        self.as_str().to_subscribe_topics()
+    }
+}
+
+impl ToSubTopics for (&str, QoS) {
+    type Iter = option::IntoIter<SubscribeTopic>;
+    fn to_subscribe_topics(&self) -> Result<Self::Iter> {
+        Ok(Some(SubscribeTopic { topic_path: self.0.to_string(), qos: self.1, no_local: false, retain_as_published: false, retain_handling: RetainHandling::SendAtSubscribe }).into_iter())
+    }
+}
+
+impl ToSubTopics for Vec<&str> {
+    type Iter = vec::IntoIter<SubscribeTopic>;
+    fn to_subscribe_topics(&self) -> Result<Self::Iter> {
+        self.as_slice().to_subscribe_topics()
+    }
+}
+
+impl ToSubTopics for &[&str] {
+    type Iter = vec::IntoIter<SubscribeTopic>;
+    fn to_subscribe_topics(&self) -> Result<Self::Iter> {
+        let topics: Vec<SubscribeTopic> = self.iter().map(|topic| {
+            SubscribeTopic { topic_path: topic.to_string(), qos: MAX_QOS, no_local: false, retain_as_published: false, retain_handling: RetainHandling::SendAtSubscribe }
+        }).collect();
+        Ok(topics.into_iter())
     }
 }
 
@@ -47,10 +99,19 @@ impl ToSubTopics for (String, QoS) {
     type Iter = option::IntoIter<SubscribeTopic>;
     fn to_subscribe_topics(&self) -> Result<Self::Iter> {
         let (ref topic_path, qos): (String, QoS) = *self;
-        Ok(Some(SubscribeTopic { topic_path: topic_path.clone(), qos: qos }).into_iter())
+        Ok(Some(SubscribeTopic { topic_path: topic_path.clone(), qos, no_local: false, retain_as_published: false, retain_handling: RetainHandling::SendAtSubscribe }).into_iter())
     }
 }
 
+/// Filters of an UNSUBSCRIBE: `&str`, `String`, `Vec<&str>`, `&[&str]`
+/// or `Vec<String>`.
+///
+/// ```
+/// use mqttc::ToUnSubTopics;
+///
+/// let topics: Vec<_> = String::from("a/+").to_unsubscribe_topics().unwrap().collect();
+/// assert_eq!(topics, vec!["a/+".to_string()]);
+/// ```
 pub trait ToUnSubTopics {
     type Iter: Iterator<Item=String>;
     fn to_unsubscribe_topics(&self) -> Result<Self::Iter>;
@@ -69,3 +130,46 @@ impl<'a> ToUnSubTopics for &'a str {
         Ok(Some(self.to_string()).into_iter())
     }
 }
+
+impl ToUnSubTopics for String {
+    type Iter = option::IntoIter<String>;
+    fn to_unsubscribe_topics(&self) -> Result<Self::Iter> {
+        Ok(Some(self.clone()).into_iter())
+    }
+}
+
+impl ToUnSubTopics for Vec<&str> {
+    type Iter = vec::IntoIter<String>;
+    fn to_unsubscribe_topics(&self) -> Result<Self::Iter> {
+        self.as_slice().to_unsubscribe_topics()
+    }
+}
+
+impl ToUnSubTopics for &[&str] {
+    type Iter = vec::IntoIter<String>;
+    fn to_unsubscribe_topics(&self) -> Result<Self::Iter> {
+        let topics: Vec<String> = self.iter().map(|topic| topic.to_string()).collect();
+        Ok(topics.into_iter())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use mqtt3::QoS;
+    use super::{ToSubTopics, ToUnSubTopics};
+
+    #[test]
+    fn to_sub_topics_test() {
+        let topics = ["a", "b"];
+        assert_eq!((&topics[..]).to_subscribe_topics().unwrap().count(), 2);
+        assert_eq!(vec!["a", "b", "c"].to_subscribe_topics().unwrap().count(), 3);
+        let topic = ("a", QoS::ExactlyOnce).to_subscribe_topics().unwrap().next().unwrap();
+        assert_eq!(topic.qos, QoS::ExactlyOnce);
+        let topic = String::from("a").to_subscribe_topics().unwrap().next().unwrap();
+        assert_eq!(topic.topic_path, "a");
+
+        assert_eq!(vec!["a", "b"].to_unsubscribe_topics().unwrap().collect::<Vec<_>>(),
+                   vec!["a".to_string(), "b".to_string()]);
+        assert_eq!((&topics[..]).to_unsubscribe_topics().unwrap().count(), 2);
+    }
+}
@@ -1,27 +1,46 @@
 #[cfg(feature = "ssl")]
 extern crate openssl;
+#[cfg(feature = "rustls")]
+extern crate rustls;
+#[cfg(feature = "rustls")]
+extern crate rustls_pemfile;
+#[cfg(feature = "rustls")]
+extern crate webpki_roots;
+extern crate socket2;
+extern crate rand;
+
+#[cfg(all(feature = "ssl", feature = "rustls"))]
+compile_error!("the ssl and rustls features are exclusive, build with --no-default-features --features rustls");
 
 #[cfg(feature = "ssl")]
 mod ssl;
+#[cfg(all(feature = "rustls", not(feature = "ssl")))]
+#[path = "tls_rustls.rs"]
+mod ssl;
 mod tcp;
+mod ws;
 pub mod mock;
 
 pub use tcp::{
     NetworkOptions,
     NetworkListener,
     NetworkStream,
+    TlsInfo,
+    ToHostName,
+    TransportKind,
     NetworkWriter,
     NetworkReader
 };
+pub use ws::{WsOptions, WsStream};
 
-#[cfg(feature = "ssl")]
+#[cfg(any(feature = "ssl", feature = "rustls"))]
 pub use ssl::{
     SslContext,
     SslStream,
     SslError
 };
 
-#[cfg(not(feature = "ssl"))]
+#[cfg(not(any(feature = "ssl", feature = "rustls")))]
 pub mod ssl {
     use mock::MockStream;
     use std::net::TcpStream;
@@ -52,5 +71,33 @@ pub mod ssl {
         pub fn connect(&self, _: TcpStream) -> Result<SslStream, io::Error> {
             panic!("ssl disabled");
         }
+
+        pub fn with_client_auth(&self, _: Option<&Path>, _: Option<(&Path, &Path)>) -> io::Result<SslContext> {
+            panic!("ssl disabled");
+        }
+
+        pub fn alpn(self, _: &[&str]) -> SslContext {
+            panic!("ssl disabled");
+        }
+
+        pub fn server_name(self, _: &str) -> SslContext {
+            panic!("ssl disabled");
+        }
+
+        pub(crate) fn with_host(self, _: &str) -> SslContext {
+            panic!("ssl disabled");
+        }
+    }
+
+    pub fn tls_version(_: &SslStream) -> Option<&'static str> {
+        None
+    }
+
+    pub fn cipher(_: &SslStream) -> Option<&'static str> {
+        None
+    }
+
+    pub fn peer_certificate(_: &SslStream) -> Option<Vec<u8>> {
+        None
     }
 }
@@ -1,3 +1,4 @@
+use std::cmp;
 use std::io::{self, Cursor, Read, Write};
 use std::net::{SocketAddr, Shutdown};
 use std::time::Duration;
@@ -8,21 +9,33 @@ pub type MockCursor = Cursor<Vec<u8>>;
 #[derive(Clone)]
 pub struct MockStream {
     reader: Arc<Mutex<MockCursor>>,
-    writer: Arc<Mutex<MockCursor>>
+    writer: Arc<Mutex<MockCursor>>,
+    // How many bytes can be written before `WouldBlock`, unlimited if None
+    write_limit: Arc<Mutex<Option<usize>>>,
+    // Reads past the data time out instead of reporting EOF
+    silent: Arc<Mutex<bool>>,
+    // Setting a timeout fails
+    reject_timeouts: Arc<Mutex<bool>>
 }
 
 impl MockStream {
     pub fn new() -> MockStream {
         MockStream {
             reader: Arc::new(Mutex::new(MockCursor::new(Vec::new()))),
-            writer: Arc::new(Mutex::new(MockCursor::new(Vec::new())))
+            writer: Arc::new(Mutex::new(MockCursor::new(Vec::new()))),
+            write_limit: Arc::new(Mutex::new(None)),
+            silent: Arc::new(Mutex::new(false)),
+            reject_timeouts: Arc::new(Mutex::new(false))
         }
     }
 
     pub fn with_vec(vec: Vec<u8>) -> MockStream {
         MockStream {
             reader: Arc::new(Mutex::new(MockCursor::new(vec))),
-            writer: Arc::new(Mutex::new(MockCursor::new(Vec::new())))
+            writer: Arc::new(Mutex::new(MockCursor::new(Vec::new()))),
+            write_limit: Arc::new(Mutex::new(None)),
+            silent: Arc::new(Mutex::new(false)),
+            reject_timeouts: Arc::new(Mutex::new(false))
         }
     }
 
@@ -41,6 +54,24 @@ impl MockStream {
         cursor.get_mut().extend_from_slice(vec.as_slice());
     }
 
+    /// Simulates a full socket buffer: after `limit` more bytes every write
+    /// fails with `WouldBlock` until the limit is raised or removed.
+    pub fn set_write_limit(&mut self, limit: Option<usize>) {
+        *self.write_limit.lock().unwrap() = limit;
+    }
+
+    /// Simulates a peer which stays silent: once the data is read, every
+    /// read fails with `WouldBlock` like a timed out socket instead of EOF.
+    pub fn set_silent(&mut self, silent: bool) {
+        *self.silent.lock().unwrap() = silent;
+    }
+
+    /// Simulates a socket refusing its timeouts, as some platforms do for
+    /// values they don't support
+    pub fn set_reject_timeouts(&mut self, reject: bool) {
+        *self.reject_timeouts.lock().unwrap() = reject;
+    }
+
     pub fn swap(&mut self) {
         let mut cur_write = self.writer.lock().unwrap();
         let mut cur_read = self.reader.lock().unwrap();
@@ -70,11 +101,19 @@ impl MockStream {
     }
 
     pub fn set_read_timeout(&self, _: Option<Duration>) -> io::Result<()> {
-        unimplemented!()
+        self.check_timeout()
     }
 
     pub fn set_write_timeout(&self, _: Option<Duration>) -> io::Result<()> {
-        unimplemented!()
+        self.check_timeout()
+    }
+
+    fn check_timeout(&self) -> io::Result<()> {
+        if *self.reject_timeouts.lock().unwrap() {
+            Err(io::Error::new(io::ErrorKind::InvalidInput, "timeout rejected"))
+        } else {
+            Ok(())
+        }
     }
 
     pub fn get_ref(&self) -> Self {
@@ -84,7 +123,16 @@ impl MockStream {
 
 impl Write for MockStream {
     fn write(&mut self, msg: &[u8]) -> io::Result<usize> {
-        self.writer.lock().unwrap().write(msg)
+        let mut limit = self.write_limit.lock().unwrap();
+        let len = match *limit {
+            Some(0) => return Err(io::Error::new(io::ErrorKind::WouldBlock, "mock write buffer is full")),
+            Some(left) => cmp::min(left, msg.len()),
+            None => msg.len()
+        };
+        if let Some(ref mut left) = *limit {
+            *left -= len;
+        }
+        self.writer.lock().unwrap().write(&msg[..len])
     }
 
     fn flush(&mut self) -> io::Result<()> {
@@ -94,13 +142,17 @@ impl Write for MockStream {
 
 impl Read for MockStream {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.reader.lock().unwrap().read(buf)
+        let len = self.reader.lock().unwrap().read(buf)?;
+        if len == 0 && !buf.is_empty() && *self.silent.lock().unwrap() {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "mock peer is silent"));
+        }
+        Ok(len)
     }
 }
 
 #[cfg(test)]
 mod test {
-    use std::io::{Read, Write};
+    use std::io::{ErrorKind, Read, Write};
     use super::MockStream;
 
     #[test]
@@ -127,6 +179,28 @@ mod test {
         assert_eq!(clonned.take_vec(), vec![6,7]);
     }
 
+    #[test]
+    fn write_limit_test() {
+        let mut mock = MockStream::new();
+        mock.set_write_limit(Some(2));
+        assert_eq!(mock.write(&[1,2,3]).unwrap(), 2);
+        assert_eq!(mock.write(&[3]).unwrap_err().kind(), ErrorKind::WouldBlock);
+        mock.set_write_limit(None);
+        assert_eq!(mock.write(&[3]).unwrap(), 1);
+        assert_eq!(mock.take_vec(), vec![1,2,3]);
+    }
+
+    #[test]
+    fn silent_test() {
+        let mut mock = MockStream::with_vec(vec![1]);
+        mock.set_silent(true);
+        let mut buf = [0; 2];
+        assert_eq!(mock.read(&mut buf).unwrap(), 1);
+        assert_eq!(mock.read(&mut buf).unwrap_err().kind(), ErrorKind::WouldBlock);
+        mock.set_silent(false);
+        assert_eq!(mock.read(&mut buf).unwrap(), 0);
+    }
+
     #[test]
     fn swap_test() {
         let mut mock = MockStream::new();
@@ -1,29 +1,51 @@
-use std::net::TcpStream;
+use std::net::{IpAddr, TcpStream};
+use std::fs;
 use std::io;
 use std::sync::Arc;
 use std::path::Path;
+use openssl::pkey::{PKey, Private};
 use openssl::ssl::{self, SslFiletype, SslMethod, SslVerifyMode};
+use openssl::x509::X509;
+use openssl::x509::store::X509StoreBuilder;
 
 pub type SslStream = ssl::SslStream<TcpStream>;
 pub type SslError = ssl::Error;
 
+/// TLS on OpenSSL. Connecting sends `server_name` as SNI and verifies the
+/// certificate against it, else the host name or IP connected to. The default
+/// context verifies servers against the system's CAs.
 #[derive(Debug, Clone)]
 pub struct SslContext {
-    inner: Arc<ssl::SslContext>
+    inner: Arc<ssl::SslContext>,
+    server_name: Option<String>,
+    // ALPN wire format, length prefixed
+    alpn: Vec<u8>,
+    client_auth: Option<Arc<ClientAuth>>
+}
+
+// CAs and certificate of `with_client_auth`, set on each connection
+#[derive(Debug)]
+struct ClientAuth {
+    ca: Option<Vec<X509>>,
+    cert: Option<(Vec<X509>, PKey<Private>)>
 }
 
 impl Default for SslContext {
     fn default() -> SslContext {
-        SslContext {
-            inner: Arc::new(ssl::SslContext::builder(SslMethod::tls()).unwrap().build())
-        }
+        let mut ctx = ssl::SslContext::builder(SslMethod::tls()).unwrap();
+        ctx.set_default_verify_paths().unwrap();
+        ctx.set_verify(SslVerifyMode::PEER);
+        SslContext::new(ctx.build())
     }
 }
 
 impl SslContext {
     pub fn new(context: ssl::SslContext) -> Self {
         SslContext {
-            inner: Arc::new(context)
+            inner: Arc::new(context),
+            server_name: None,
+            alpn: Vec::new(),
+            client_auth: None
         }
     }
 
@@ -34,7 +56,7 @@ impl SslContext {
         ctx.set_certificate_file(cert.as_ref(), SslFiletype::PEM)?;
         ctx.set_private_key_file(key.as_ref(), SslFiletype::PEM)?;
         ctx.set_verify(SslVerifyMode::NONE);
-        Ok(SslContext { inner: Arc::new(ctx.build()) })
+        Ok(SslContext::new(ctx.build()))
     }
 
     pub fn with_cert_and_key_and_ca<C, K, A>(cert: C, key: K, ca: A) -> Result<SslContext, SslError>
@@ -45,7 +67,57 @@ impl SslContext {
         ctx.set_private_key_file(key.as_ref(), SslFiletype::PEM)?;
         ctx.set_ca_file(ca.as_ref())?;
         ctx.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
-        Ok(SslContext { inner: Arc::new(ctx.build()) })
+        Ok(SslContext::new(ctx.build()))
+    }
+
+    /// This context for connecting, verifying the server against `ca`,
+    /// the context's CAs without it, and presenting `cert` for mutual TLS.
+    /// Files are PEM or DER, a PEM certificate may be followed by its chain.
+    pub fn with_client_auth(&self, ca: Option<&Path>, cert: Option<(&Path, &Path)>) -> io::Result<SslContext> {
+        let ca = match ca {
+            Some(ca) => Some(load_certs(ca)?),
+            None => None
+        };
+        let cert = match cert {
+            Some((cert, key)) => {
+                let chain = load_certs(cert)?;
+                let key = load_key(key)?;
+                let leaf = chain.first().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no certificate found"))?;
+                if !leaf.public_key().map_err(invalid)?.public_eq(&key) {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "private key does not match the certificate"));
+                }
+                Some((chain, key))
+            }
+            None => None
+        };
+        let mut context = self.clone();
+        context.client_auth = Some(Arc::new(ClientAuth { ca, cert }));
+        Ok(context)
+    }
+
+    /// Protocols offered by ALPN when connecting, most preferred first,
+    /// e.g. "x-amzn-mqtt-ca" for AWS IoT on port 443
+    pub fn alpn(mut self, protocols: &[&str]) -> SslContext {
+        self.alpn.clear();
+        for protocol in protocols {
+            self.alpn.push(protocol.len() as u8);
+            self.alpn.extend_from_slice(protocol.as_bytes());
+        }
+        self
+    }
+
+    /// The host name sent as SNI and verified against the certificate
+    pub fn server_name(mut self, name: &str) -> SslContext {
+        self.server_name = Some(name.to_owned());
+        self
+    }
+
+    // The host connected to, the server name unless one is set
+    pub(crate) fn with_host(mut self, host: &str) -> SslContext {
+        if self.server_name.is_none() {
+            self.server_name = Some(host.to_owned());
+        }
+        self
     }
 
     pub fn accept(&self, stream: TcpStream) -> Result<SslStream, io::Error> {
@@ -56,9 +128,79 @@ impl SslContext {
     }
 
     pub fn connect(&self, stream: TcpStream) -> Result<SslStream, io::Error> {
-        match ssl::Ssl::new(&*self.inner)?.connect(stream) {
+        let mut ssl = ssl::Ssl::new(&self.inner)?;
+        if let Some(ref auth) = self.client_auth {
+            if let Some(ref ca) = auth.ca {
+                let mut store = X509StoreBuilder::new()?;
+                for cert in ca {
+                    store.add_cert(cert.clone())?;
+                }
+                ssl.set_verify_cert_store(store.build())?;
+            }
+            ssl.set_verify(SslVerifyMode::PEER);
+            if let Some((ref chain, ref key)) = auth.cert {
+                ssl.set_certificate(&chain[0])?;
+                for cert in &chain[1..] {
+                    ssl.add_chain_cert(cert.clone())?;
+                }
+                ssl.set_private_key(key)?;
+            }
+        }
+        if !self.alpn.is_empty() {
+            ssl.set_alpn_protos(&self.alpn)?;
+        }
+        match self.server_name {
+            Some(ref name) => match name.parse::<IpAddr>() {
+                Ok(ip) => ssl.param_mut().set_ip(ip)?,
+                Err(_) => {
+                    ssl.set_hostname(name)?;
+                    ssl.param_mut().set_host(name)?;
+                }
+            },
+            // connected by IP, the certificate has to be issued for it
+            None => ssl.param_mut().set_ip(stream.peer_addr()?.ip())?
+        }
+        match ssl.connect(stream) {
             Ok(stream) => Ok(stream),
             Err(err) => Err(io::Error::new(io::ErrorKind::ConnectionAborted, err).into())
         }
     }
 }
+
+pub fn tls_version(stream: &SslStream) -> Option<&'static str> {
+    Some(stream.ssl().version_str())
+}
+
+pub fn cipher(stream: &SslStream) -> Option<&'static str> {
+    stream.ssl().current_cipher().map(|cipher| cipher.name())
+}
+
+pub fn peer_certificate(stream: &SslStream) -> Option<Vec<u8>> {
+    stream.ssl().peer_certificate().and_then(|cert| cert.to_der().ok())
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<X509>> {
+    let bytes = fs::read(path)?;
+    if is_pem(&bytes) {
+        X509::stack_from_pem(&bytes).map_err(invalid)
+    } else {
+        X509::from_der(&bytes).map(|cert| vec![cert]).map_err(invalid)
+    }
+}
+
+fn load_key(path: &Path) -> io::Result<PKey<Private>> {
+    let bytes = fs::read(path)?;
+    if is_pem(&bytes) {
+        PKey::private_key_from_pem(&bytes).map_err(invalid)
+    } else {
+        PKey::private_key_from_der(&bytes).map_err(invalid)
+    }
+}
+
+fn is_pem(bytes: &[u8]) -> bool {
+    String::from_utf8_lossy(&bytes[..bytes.len().min(64)]).trim_start().starts_with("-----BEGIN")
+}
+
+fn invalid<E: ::std::error::Error + Send + Sync + 'static>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
@@ -1,26 +1,44 @@
-use std::net::{TcpListener, TcpStream, SocketAddr, ToSocketAddrs, Shutdown, SocketAddrV4, Ipv4Addr};
+use std::net::{TcpListener, TcpStream, SocketAddr, ToSocketAddrs, Shutdown, SocketAddrV4, SocketAddrV6};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::io::{self, Read, Write, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
 use std::time::Duration;
+use socket2::{Socket, Domain, Type, Protocol};
 
-use ssl::{SslContext, SslStream};
+use ssl::{self, SslContext, SslStream};
 use mock::MockStream;
+use ws::{WsOptions, WsStream};
 
 use NetworkStream::{
     Tcp,
     Ssl,
+    Ws,
+    Wss,
     Mock
 };
 
 pub struct NetworkOptions {
     ssl: Option<SslContext>,
-    mock: Option<MockStream>
+    mock: Option<MockStream>,
+    source: Option<SocketAddr>,
+    ws: Option<WsOptions>,
+    ca: Option<PathBuf>,
+    client_cert: Option<(PathBuf, PathBuf)>,
+    server_name: Option<String>,
+    alpn: Vec<String>
 }
 
 impl NetworkOptions {
     pub fn new() -> NetworkOptions {
         NetworkOptions {
             ssl: None::<SslContext>,
-            mock: None::<MockStream>
+            mock: None::<MockStream>,
+            source: None,
+            ws: None,
+            ca: None,
+            client_cert: None,
+            server_name: None,
+            alpn: Vec::new()
         }
     }
 
@@ -32,6 +50,69 @@ impl NetworkOptions {
         self.ssl = Some(ssl); self
     }
 
+    /// Outbound connections leave from `addr`, which picks the interface on
+    /// multi-homed hosts. Port 0 lets the OS choose the port.
+    pub fn bind_source(&mut self, addr: SocketAddr) -> &mut NetworkOptions {
+        self.source = Some(addr); self
+    }
+
+    /// Speaks MQTT over WebSocket, upgrading at `path` of `host` (the Host
+    /// header, e.g. "broker.example.com"). Over TLS too when `tls` is set.
+    pub fn websocket(&mut self, host: &str, path: &str) -> &mut NetworkOptions {
+        self.ws = Some(WsOptions { host: host.to_owned(), path: path.to_owned() }); self
+    }
+
+    /// Mutual TLS: the certificate and private key presented to the
+    /// server, PEM or DER. Implies TLS, loaded on every connect so renewed
+    /// files are picked up.
+    pub fn client_cert<C: AsRef<Path>, K: AsRef<Path>>(&mut self, cert: C, key: K) -> &mut NetworkOptions {
+        self.client_cert = Some((cert.as_ref().to_path_buf(), key.as_ref().to_path_buf())); self
+    }
+
+    /// CA certificates the server's must be signed by, PEM (a bundle) or
+    /// DER, instead of the default ones. Implies TLS.
+    pub fn ca_file<A: AsRef<Path>>(&mut self, ca: A) -> &mut NetworkOptions {
+        self.ca = Some(ca.as_ref().to_path_buf()); self
+    }
+
+    /// The host name TLS sends as SNI and verifies the certificate
+    /// against, instead of the one connected to
+    pub fn server_name(&mut self, name: &str) -> &mut NetworkOptions {
+        self.server_name = Some(name.to_owned()); self
+    }
+
+    /// Protocols TLS offers by ALPN, most preferred first, e.g.
+    /// "x-amzn-mqtt-ca" for AWS IoT on port 443
+    pub fn alpn(&mut self, protocols: &[&str]) -> &mut NetworkOptions {
+        self.alpn = protocols.iter().map(|protocol| protocol.to_string()).collect(); self
+    }
+
+    // The context `tls` set, with the files of `ca_file` and `client_cert`,
+    // the ALPN protocols and the server name, `host` when none is set
+    fn _ssl(&self, host: Option<&str>) -> io::Result<Option<SslContext>> {
+        let mut ssl = if self.ca.is_none() && self.client_cert.is_none() {
+            match self.ssl {
+                Some(ref ssl) => ssl.clone(),
+                None => return Ok(None)
+            }
+        } else {
+            let ssl = self.ssl.clone().unwrap_or_default();
+            let ca = self.ca.as_deref();
+            let cert = self.client_cert.as_ref().map(|(cert, key)| (cert.as_path(), key.as_path()));
+            ssl.with_client_auth(ca, cert)?
+        };
+        if !self.alpn.is_empty() {
+            let alpn: Vec<&str> = self.alpn.iter().map(|protocol| protocol.as_str()).collect();
+            ssl = ssl.alpn(&alpn);
+        }
+        if let Some(ref name) = self.server_name {
+            ssl = ssl.server_name(name);
+        } else if let Some(host) = host {
+            ssl = ssl.with_host(host);
+        }
+        Ok(Some(ssl))
+    }
+
     pub fn bind<A: ToSocketAddrs>(&self, addr: A) -> io::Result<NetworkListener> {
         Ok(NetworkListener {
             tcp: TcpListener::bind(addr)?,
@@ -42,19 +123,140 @@ impl NetworkOptions {
         })
     }
 
-    pub fn connect<A: ToSocketAddrs>(&self, addr: A) -> io::Result<NetworkStream> {
+    pub fn connect<A: ToSocketAddrs + ToHostName>(&self, addr: A) -> io::Result<NetworkStream> {
+        let host = addr.host_name();
+        self.connect_to(addr, host.as_deref())
+    }
+
+    /// Connects to `addr` as `host`, the name TLS verifies unless
+    /// `server_name` is set. For going back to an address resolved before.
+    pub fn connect_to<A: ToSocketAddrs>(&self, addr: A, host: Option<&str>) -> io::Result<NetworkStream> {
         if let Some(ref mock) = self.mock {
             return Ok(NetworkStream::Mock(mock.clone()));
         };
 
-        let stream = TcpStream::connect(addr)?;
-        match self.ssl {
-            Some(ref ssl) => Ok(NetworkStream::Ssl(ssl.connect(stream)?)),
-            None => Ok(NetworkStream::Tcp(stream))
+        // bad certificate files fail before connecting
+        let ssl = self._ssl(host)?;
+        let stream = match self.source {
+            Some(source) => connect_from(source, addr)?,
+            None => TcpStream::connect(addr)?
+        };
+        match (&ssl, &self.ws) {
+            (Some(ssl), Some(ws)) => Ok(NetworkStream::Wss(WsStream::connect(ssl.connect(stream)?, ws)?)),
+            (Some(ssl), None) => Ok(NetworkStream::Ssl(ssl.connect(stream)?)),
+            (None, Some(ws)) => Ok(NetworkStream::Ws(WsStream::connect(stream, ws)?)),
+            (None, None) => Ok(NetworkStream::Tcp(stream))
         }
     }
 }
 
+/// Addresses which may name the host connected to. TLS sends the name as
+/// SNI and verifies the certificate against it, an IP address is verified
+/// as such.
+pub trait ToHostName {
+    fn host_name(&self) -> Option<String>;
+}
+
+// Host names only, IPv6 literals come in brackets
+fn host_name(host: &str) -> Option<String> {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    match host.parse::<IpAddr>() {
+        Ok(_) => None,
+        Err(_) => Some(host.to_owned())
+    }
+}
+
+impl ToHostName for str {
+    fn host_name(&self) -> Option<String> {
+        self.rsplit_once(':').and_then(|(host, _)| host_name(host))
+    }
+}
+
+impl ToHostName for String {
+    fn host_name(&self) -> Option<String> {
+        self.as_str().host_name()
+    }
+}
+
+impl ToHostName for (&str, u16) {
+    fn host_name(&self) -> Option<String> {
+        host_name(self.0)
+    }
+}
+
+impl ToHostName for (String, u16) {
+    fn host_name(&self) -> Option<String> {
+        host_name(&self.0)
+    }
+}
+
+impl<T: ToHostName + ?Sized> ToHostName for &T {
+    fn host_name(&self) -> Option<String> {
+        (**self).host_name()
+    }
+}
+
+impl ToHostName for SocketAddr {
+    fn host_name(&self) -> Option<String> {
+        None
+    }
+}
+
+impl ToHostName for SocketAddrV4 {
+    fn host_name(&self) -> Option<String> {
+        None
+    }
+}
+
+impl ToHostName for SocketAddrV6 {
+    fn host_name(&self) -> Option<String> {
+        None
+    }
+}
+
+impl ToHostName for (IpAddr, u16) {
+    fn host_name(&self) -> Option<String> {
+        None
+    }
+}
+
+impl ToHostName for (Ipv4Addr, u16) {
+    fn host_name(&self) -> Option<String> {
+        None
+    }
+}
+
+impl ToHostName for (Ipv6Addr, u16) {
+    fn host_name(&self) -> Option<String> {
+        None
+    }
+}
+
+impl ToHostName for &[SocketAddr] {
+    fn host_name(&self) -> Option<String> {
+        None
+    }
+}
+
+// TcpStream::connect can't bind the socket before connecting
+fn connect_from<A: ToSocketAddrs>(source: SocketAddr, addr: A) -> io::Result<TcpStream> {
+    let mut last_err = None;
+    for addr in addr.to_socket_addrs()? {
+        // a source address only reaches peers of its own family
+        if addr.is_ipv4() != source.is_ipv4() {
+            continue;
+        }
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        match socket.bind(&source.into()).and_then(|_| socket.connect(&addr.into())) {
+            Ok(_) => return Ok(socket.into()),
+            Err(e) => last_err = Some(e)
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "no address of the source address family")
+    }))
+}
+
 pub struct NetworkListener {
     tcp: TcpListener,
     ssl: Option<SslContext>,
@@ -75,17 +277,54 @@ impl NetworkListener {
     }
 }
 
+/// What the TLS handshake settled on
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsInfo {
+    pub version: &'static str,
+    pub cipher: Option<&'static str>,
+    /// DER encoded
+    pub peer_certificate: Option<Vec<u8>>
+}
+
 pub enum NetworkStream {
     Tcp(TcpStream),
     Ssl(SslStream),
+    Ws(WsStream<TcpStream>),
+    Wss(WsStream<SslStream>),
     Mock(MockStream)
 }
 
+/// Which transport a `NetworkStream` runs on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportKind {
+    Tcp,
+    Ssl,
+    Ws,
+    Wss,
+    Mock
+}
+
 impl NetworkStream {
+    pub fn kind(&self) -> TransportKind {
+        match *self {
+            Tcp(_) => TransportKind::Tcp,
+            Ssl(_) => TransportKind::Ssl,
+            Ws(_) => TransportKind::Ws,
+            Wss(_) => TransportKind::Wss,
+            Mock(_) => TransportKind::Mock
+        }
+    }
+
+    pub fn is_mock(&self) -> bool {
+        self.kind() == TransportKind::Mock
+    }
+
     pub fn peer_addr(&self) -> io::Result<SocketAddr> {
         match *self {
             Tcp(ref s) => s.peer_addr(),
             Ssl(ref s) => s.get_ref().peer_addr(),
+            Ws(ref s) => s.get_ref().peer_addr(),
+            Wss(ref s) => s.get_ref().get_ref().peer_addr(),
             Mock(_) => Ok(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127,0,0,1), 80)))
         }
     }
@@ -94,23 +333,79 @@ impl NetworkStream {
         match *self {
             Tcp(ref s) => s.shutdown(how),
             Ssl(ref s) => s.get_ref().shutdown(how),
+            Ws(ref s) => s.get_ref().shutdown(how),
+            Wss(ref s) => s.get_ref().get_ref().shutdown(how),
             Mock(_) => Ok(())
         }
     }
 
+    /// Ends the connection: a WebSocket gets its close frame first, then
+    /// the socket is shut down both ways
+    pub fn terminate(&mut self) -> io::Result<()> {
+        // the socket goes down even if the close frame doesn't get out
+        let _ = match *self {
+            Ws(ref mut s) => s.close(),
+            Wss(ref mut s) => s.close(),
+            _ => Ok(())
+        };
+        self.shutdown(Shutdown::Both)
+    }
+
     pub fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
         match *self {
             Tcp(ref s) => s.set_read_timeout(dur),
             Ssl(ref s) => s.get_ref().set_read_timeout(dur),
-            Mock(_) => Ok(())
+            Ws(ref s) => s.get_ref().set_read_timeout(dur),
+            Wss(ref s) => s.get_ref().get_ref().set_read_timeout(dur),
+            Mock(ref s) => s.set_read_timeout(dur)
+        }
+    }
+
+    /// Negotiated TLS version, None for plain TCP
+    pub fn tls_version(&self) -> Option<&'static str> {
+        match *self {
+            Ssl(ref s) => ssl::tls_version(s),
+            Wss(ref s) => ssl::tls_version(s.get_ref()),
+            _ => None
+        }
+    }
+
+    /// Negotiated cipher suite, None for plain TCP
+    pub fn cipher(&self) -> Option<&'static str> {
+        match *self {
+            Ssl(ref s) => ssl::cipher(s),
+            Wss(ref s) => ssl::cipher(s.get_ref()),
+            _ => None
+        }
+    }
+
+    /// DER encoded certificate of the peer, None for plain TCP or when
+    /// the peer didn't present one
+    pub fn peer_certificate(&self) -> Option<Vec<u8>> {
+        match *self {
+            Ssl(ref s) => ssl::peer_certificate(s),
+            Wss(ref s) => ssl::peer_certificate(s.get_ref()),
+            _ => None
         }
     }
 
+    pub fn tls_info(&self) -> Option<TlsInfo> {
+        self.tls_version().map(|version| {
+            TlsInfo {
+                version,
+                cipher: self.cipher(),
+                peer_certificate: self.peer_certificate()
+            }
+        })
+    }
+
     pub fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
         match *self {
             Tcp(ref s) => s.set_write_timeout(dur),
             Ssl(ref s) => s.get_ref().set_write_timeout(dur),
-            Mock(_) => Ok(())
+            Ws(ref s) => s.get_ref().set_write_timeout(dur),
+            Wss(ref s) => s.get_ref().get_ref().set_write_timeout(dur),
+            Mock(ref s) => s.set_write_timeout(dur)
         }
     }
 }
@@ -120,6 +415,8 @@ impl Read for NetworkStream {
         match *self {
             Tcp(ref mut s) => s.read(buf),
             Ssl(ref mut s) => s.read(buf),
+            Ws(ref mut s) => s.read(buf),
+            Wss(ref mut s) => s.read(buf),
             Mock(ref mut s) => s.read(buf)
         }
     }
@@ -130,6 +427,8 @@ impl Write for NetworkStream {
         match *self {
             Tcp(ref mut s) => s.write(buf),
             Ssl(ref mut s) => s.write(buf),
+            Ws(ref mut s) => s.write(buf),
+            Wss(ref mut s) => s.write(buf),
             Mock(ref mut s) => s.write(buf)
         }
     }
@@ -138,6 +437,8 @@ impl Write for NetworkStream {
         match *self {
             Tcp(ref mut s) => s.flush(),
             Ssl(ref mut s) => s.flush(),
+            Ws(ref mut s) => s.flush(),
+            Wss(ref mut s) => s.flush(),
             Mock(ref mut s) => s.flush()
         }
     }
@@ -148,10 +449,12 @@ pub type NetworkWriter = BufWriter<NetworkStream>;
 
 #[cfg(test)]
 mod test {
-    use std::net::Shutdown;
+    use std::net::{Shutdown, TcpListener};
     use std::io::{Read, Write};
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
+    use std::io::ErrorKind;
     use std::thread;
-    use super::NetworkOptions;
+    use super::{NetworkOptions, ToHostName, TransportKind};
     use mock::MockStream;
 
     #[test]
@@ -171,6 +474,103 @@ mod test {
         assert_eq!(req, vec![0, 1, 2, 3, 4, 5]);
     }
 
+    #[test]
+    fn tcp_bind_source_test() {
+        let mut listener = NetworkOptions::new().bind("127.0.0.1:0").unwrap();
+        let addr = listener.tcp.local_addr().unwrap();
+        // a port nobody else holds
+        let source = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+        let client = thread::spawn(move || {
+            let mut options = NetworkOptions::new();
+            options.bind_source(source);
+            options.connect(addr).unwrap()
+        });
+
+        let (_, addr) = listener.accept().unwrap();
+        assert_eq!(addr, source);
+        drop(client.join().unwrap());
+
+        // no IPv6 peer for an IPv4 source
+        let mut options = NetworkOptions::new();
+        options.bind_source(source);
+        assert!(options.connect(("::1", addr.port())).is_err());
+    }
+
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
+    #[test]
+    fn tls_client_cert_test() {
+        use ssl::SslContext;
+        let fixture = |name: &str| format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name);
+        let server = SslContext::with_cert_and_key_and_ca(fixture("server.pem"), fixture("server.key"), fixture("ca.pem")).unwrap();
+        let mut listener = NetworkOptions::new().tls(server).bind("127.0.0.1:0").unwrap();
+        let port = listener.tcp.local_addr().unwrap().port();
+        let broker = thread::spawn(move || {
+            (0..2).map(|_| {
+                let (mut stream, _) = listener.accept().unwrap();
+                stream.write_all(&[0x20, 0x02, 0x00, 0x00]).unwrap();
+                stream.flush().unwrap();
+                stream.tls_info().unwrap().peer_certificate.unwrap()
+            }).collect::<Vec<_>>()
+        });
+
+        // PEM, verifying the name the address has
+        let mut options = NetworkOptions::new();
+        options.ca_file(fixture("ca.pem"));
+        options.client_cert(fixture("client.pem"), fixture("client.key"));
+        let mut client = options.connect(("localhost", port)).unwrap();
+        let mut connack = [0; 4];
+        client.read_exact(&mut connack).unwrap();
+        assert_eq!(connack, [0x20, 0x02, 0x00, 0x00]);
+
+        // DER, on top of the server name `tls` set
+        let mut options = NetworkOptions::new();
+        options.tls(SslContext::default().server_name("localhost"));
+        options.ca_file(fixture("ca.pem"));
+        options.client_cert(fixture("client.der"), fixture("client.key.der"));
+        let mut client = options.connect(("127.0.0.1", port)).unwrap();
+        client.read_exact(&mut connack).unwrap();
+        assert_eq!(connack, [0x20, 0x02, 0x00, 0x00]);
+
+        let der = ::std::fs::read(fixture("client.der")).unwrap();
+        assert_eq!(broker.join().unwrap(), vec![der.clone(), der]);
+
+        // the missing file is found out before connecting
+        let mut options = NetworkOptions::new();
+        options.client_cert("/nonexistent/client.pem", "/nonexistent/client.key");
+        assert_eq!(options.connect("127.0.0.1:1").err().unwrap().kind(), ErrorKind::NotFound);
+    }
+
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
+    #[test]
+    fn tls_ip_verify_test() {
+        use ssl::SslContext;
+        let fixture = |name: &str| format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name);
+        let server = SslContext::with_cert_and_key_and_ca(fixture("server.pem"), fixture("server.key"), fixture("ca.pem")).unwrap();
+        let mut listener = NetworkOptions::new().tls(server).bind("127.0.0.1:0").unwrap();
+        let port = listener.tcp.local_addr().unwrap().port();
+        let broker = thread::spawn(move || listener.accept().is_err());
+
+        // a trusted certificate issued for localhost only
+        let mut options = NetworkOptions::new();
+        options.ca_file(fixture("ca.pem"));
+        options.client_cert(fixture("client.pem"), fixture("client.key"));
+        assert!(options.connect(("127.0.0.1", port)).is_err());
+        assert!(broker.join().unwrap());
+    }
+
+    #[test]
+    fn tcp_host_name_test() {
+        assert_eq!("broker.example.com:8883".host_name(), Some("broker.example.com".to_owned()));
+        assert_eq!(("localhost", 1883).host_name(), Some("localhost".to_owned()));
+        // IPs are verified as such
+        assert_eq!("127.0.0.1:1883".host_name(), None);
+        assert_eq!("[::1]:1883".host_name(), None);
+        assert_eq!(("::1", 1883).host_name(), None);
+        let addr: ::std::net::SocketAddr = "127.0.0.1:1883".parse().unwrap();
+        assert_eq!(addr.host_name(), None);
+    }
+
     #[test]
     fn tcp_attach_test() {
         let mock = MockStream::with_vec(vec![0xFE, 0xFD]);
@@ -180,5 +580,8 @@ mod test {
         let mut buf = Vec::new();
         client.read_to_end(&mut buf).unwrap();
         assert_eq!(buf, vec![0xFE, 0xFD]);
+        assert!(client.tls_info().is_none());
+        assert_eq!(client.kind(), TransportKind::Mock);
+        assert!(client.is_mock());
     }
 }
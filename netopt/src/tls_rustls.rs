@@ -0,0 +1,315 @@
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::Arc;
+use rustls::{self, Certificate, ClientConfig, ClientConnection, OwnedTrustAnchor, PrivateKey, RootCertStore,
+             ServerConfig, ServerConnection, ServerName, StreamOwned};
+use rustls::server::AllowAnyAuthenticatedClient;
+use rustls_pemfile::{self, Item};
+use webpki_roots;
+
+pub type SslError = io::Error;
+
+/// TLS on rustls, for builds without a C toolchain. Connects trusting the
+/// webpki roots unless configured otherwise; the server name sent as SNI
+/// and checked against the certificate is `server_name`, else the host
+/// name connected to, else the peer's IP address. Accepting needs a
+/// certificate and key.
+#[derive(Clone)]
+pub struct SslContext {
+    client: Arc<ClientConfig>,
+    server: Option<Arc<ServerConfig>>,
+    server_name: Option<String>
+}
+
+impl Default for SslContext {
+    fn default() -> SslContext {
+        SslContext::new(ClientConfig::builder()
+                            .with_safe_defaults()
+                            .with_root_certificates(webpki_roots())
+                            .with_no_client_auth())
+    }
+}
+
+impl SslContext {
+    pub fn new(client: ClientConfig) -> Self {
+        SslContext {
+            client: Arc::new(client),
+            server: None,
+            server_name: None
+        }
+    }
+
+    pub fn with_cert_and_key<C, K>(cert: C, key: K) -> Result<SslContext, SslError>
+    where C: AsRef<Path>, K: AsRef<Path> {
+        let server = ServerConfig::builder()
+                         .with_safe_defaults()
+                         .with_no_client_auth()
+                         .with_single_cert(load_certs(cert)?, load_key(key)?)
+                         .map_err(invalid)?;
+        let mut context = SslContext::default();
+        context.server = Some(Arc::new(server));
+        Ok(context)
+    }
+
+    /// Both ways authenticated by `ca`: accepted clients must present a
+    /// certificate it signed, and connecting presents `cert` to a server
+    /// it must have signed
+    pub fn with_cert_and_key_and_ca<C, K, A>(cert: C, key: K, ca: A) -> Result<SslContext, SslError>
+    where C: AsRef<Path>, K: AsRef<Path>, A: AsRef<Path> {
+        let mut roots = RootCertStore::empty();
+        for cert in load_certs(ca)? {
+            roots.add(&cert).map_err(invalid)?;
+        }
+        let certs = load_certs(cert)?;
+        let key = load_key(key)?;
+        let server = ServerConfig::builder()
+                         .with_safe_defaults()
+                         .with_client_cert_verifier(Arc::new(AllowAnyAuthenticatedClient::new(roots.clone())))
+                         .with_single_cert(certs.clone(), key.clone())
+                         .map_err(invalid)?;
+        let client = ClientConfig::builder()
+                         .with_safe_defaults()
+                         .with_root_certificates(roots)
+                         .with_client_auth_cert(certs, key)
+                         .map_err(invalid)?;
+        Ok(SslContext {
+            client: Arc::new(client),
+            server: Some(Arc::new(server)),
+            server_name: None
+        })
+    }
+
+    /// Connects verifying the server against `ca`, the webpki roots
+    /// without it, and presents `cert` for mutual TLS. Files are PEM or
+    /// DER, a PEM certificate may be followed by its chain. ALPN and the
+    /// server name are kept.
+    pub fn with_client_auth(&self, ca: Option<&Path>, cert: Option<(&Path, &Path)>) -> io::Result<SslContext> {
+        let roots = match ca {
+            Some(ca) => {
+                let mut roots = RootCertStore::empty();
+                for cert in load_certs(ca)? {
+                    roots.add(&cert).map_err(invalid)?;
+                }
+                roots
+            }
+            None => webpki_roots()
+        };
+        let builder = ClientConfig::builder().with_safe_defaults().with_root_certificates(roots);
+        let mut client = match cert {
+            Some((cert, key)) => builder.with_client_auth_cert(load_certs(cert)?, load_key(key)?).map_err(invalid)?,
+            None => builder.with_no_client_auth()
+        };
+        client.alpn_protocols = self.client.alpn_protocols.clone();
+        Ok(SslContext {
+            client: Arc::new(client),
+            server: self.server.clone(),
+            server_name: self.server_name.clone()
+        })
+    }
+
+    /// Protocols offered by ALPN, most preferred first, e.g.
+    /// "x-amzn-mqtt-ca" for AWS IoT on port 443
+    pub fn alpn(mut self, protocols: &[&str]) -> SslContext {
+        let protocols: Vec<Vec<u8>> = protocols.iter().map(|p| p.as_bytes().to_vec()).collect();
+        Arc::make_mut(&mut self.client).alpn_protocols = protocols.clone();
+        if let Some(ref mut server) = self.server {
+            Arc::make_mut(server).alpn_protocols = protocols;
+        }
+        self
+    }
+
+    /// The host name sent as SNI and verified against the certificate
+    pub fn server_name(mut self, name: &str) -> SslContext {
+        self.server_name = Some(name.to_owned());
+        self
+    }
+
+    // The host connected to, the server name unless one is set
+    pub(crate) fn with_host(mut self, host: &str) -> SslContext {
+        if self.server_name.is_none() {
+            self.server_name = Some(host.to_owned());
+        }
+        self
+    }
+
+    pub fn accept(&self, stream: TcpStream) -> Result<SslStream, io::Error> {
+        let server = match self.server {
+            Some(ref server) => server.clone(),
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "no certificate to accept with"))
+        };
+        let conn = ServerConnection::new(server).map_err(aborted)?;
+        let mut stream = StreamOwned::new(conn, stream);
+        while stream.conn.is_handshaking() {
+            stream.conn.complete_io(&mut stream.sock).map_err(aborted)?;
+        }
+        Ok(SslStream::Server(stream))
+    }
+
+    pub fn connect(&self, stream: TcpStream) -> Result<SslStream, io::Error> {
+        let name = match self.server_name {
+            Some(ref name) => ServerName::try_from(name.as_str()).map_err(invalid)?,
+            None => ServerName::IpAddress(stream.peer_addr()?.ip())
+        };
+        let conn = ClientConnection::new(self.client.clone(), name).map_err(aborted)?;
+        let mut stream = StreamOwned::new(conn, stream);
+        while stream.conn.is_handshaking() {
+            stream.conn.complete_io(&mut stream.sock).map_err(aborted)?;
+        }
+        Ok(SslStream::Client(stream))
+    }
+}
+
+/// A connection made or accepted by `SslContext`
+pub enum SslStream {
+    Client(StreamOwned<ClientConnection, TcpStream>),
+    Server(StreamOwned<ServerConnection, TcpStream>)
+}
+
+impl SslStream {
+    pub fn get_ref(&self) -> &TcpStream {
+        match *self {
+            SslStream::Client(ref s) => s.get_ref(),
+            SslStream::Server(ref s) => s.get_ref()
+        }
+    }
+
+    fn common(&self) -> &rustls::CommonState {
+        match *self {
+            SslStream::Client(ref s) => &s.conn,
+            SslStream::Server(ref s) => &s.conn
+        }
+    }
+}
+
+impl Read for SslStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            SslStream::Client(ref mut s) => s.read(buf),
+            SslStream::Server(ref mut s) => s.read(buf)
+        }
+    }
+}
+
+impl Write for SslStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            SslStream::Client(ref mut s) => s.write(buf),
+            SslStream::Server(ref mut s) => s.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            SslStream::Client(ref mut s) => s.flush(),
+            SslStream::Server(ref mut s) => s.flush()
+        }
+    }
+}
+
+pub fn tls_version(stream: &SslStream) -> Option<&'static str> {
+    stream.common().protocol_version().and_then(|version| version.as_str())
+}
+
+pub fn cipher(stream: &SslStream) -> Option<&'static str> {
+    stream.common().negotiated_cipher_suite().and_then(|suite| suite.suite().as_str())
+}
+
+pub fn peer_certificate(stream: &SslStream) -> Option<Vec<u8>> {
+    stream.common().peer_certificates().and_then(|certs| certs.first()).map(|cert| cert.0.clone())
+}
+
+fn webpki_roots() -> RootCertStore {
+    let mut roots = RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(anchor.subject,
+                                                             anchor.spki,
+                                                             anchor.name_constraints)
+    }));
+    roots
+}
+
+fn load_certs<P: AsRef<Path>>(path: P) -> io::Result<Vec<Certificate>> {
+    let bytes = fs::read(path)?;
+    if !is_pem(&bytes) {
+        return Ok(vec![Certificate(bytes)]);
+    }
+    Ok(rustls_pemfile::certs(&mut &bytes[..])?.into_iter().map(Certificate).collect())
+}
+
+// The first private key of the file, PKCS#8, RSA or EC
+fn load_key<P: AsRef<Path>>(path: P) -> io::Result<PrivateKey> {
+    let bytes = fs::read(path)?;
+    if !is_pem(&bytes) {
+        return Ok(PrivateKey(bytes));
+    }
+    for item in rustls_pemfile::read_all(&mut &bytes[..])? {
+        match item {
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => ()
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::InvalidData, "no private key found"))
+}
+
+fn is_pem(bytes: &[u8]) -> bool {
+    String::from_utf8_lossy(&bytes[..bytes.len().min(64)]).trim_start().starts_with("-----BEGIN")
+}
+
+fn invalid<E: ::std::error::Error + Send + Sync + 'static>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+fn aborted<E: ::std::error::Error + Send + Sync + 'static>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionAborted, err)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use tcp::NetworkOptions;
+    use super::{SslContext, SslStream};
+
+    const FIXTURES: &'static str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+
+    #[test]
+    fn tls_alpn_server_name_test() {
+        let server = SslContext::with_cert_and_key(format!("{}/server.pem", FIXTURES),
+                                                   format!("{}/server.key", FIXTURES)).unwrap().alpn(&["mqtt"]);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let broker = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut stream = server.accept(stream).unwrap();
+            let negotiated = match stream {
+                SslStream::Server(ref s) => (s.conn.server_name().map(|name| name.to_owned()),
+                                             s.conn.alpn_protocol().map(|protocol| protocol.to_vec())),
+                SslStream::Client(_) => unreachable!()
+            };
+            stream.write_all(&[0x20, 0x02, 0x00, 0x00]).unwrap();
+            stream.flush().unwrap();
+
+            // the certificate isn't for the name the second client wants
+            let (stream, _) = listener.accept().unwrap();
+            assert!(server.accept(stream).is_err());
+            negotiated
+        });
+
+        let mut options = NetworkOptions::new();
+        options.ca_file(format!("{}/ca.pem", FIXTURES));
+        options.alpn(&["mqtt"]);
+        // the name comes from the address, not the IP it resolves to
+        let mut client = options.connect(("localhost", port)).unwrap();
+        let mut connack = [0; 4];
+        client.read_exact(&mut connack).unwrap();
+        assert_eq!(connack, [0x20, 0x02, 0x00, 0x00]);
+
+        options.server_name("broker.example.com");
+        assert!(options.connect(("localhost", port)).is_err());
+        assert_eq!(broker.join().unwrap(), (Some("localhost".to_owned()), Some(b"mqtt".to_vec())));
+    }
+}
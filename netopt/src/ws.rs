@@ -0,0 +1,463 @@
+use std::cmp;
+use std::io::{self, Read, Write};
+use rand::{self, Rng};
+
+// Appended to the key for Sec-WebSocket-Accept, RFC 6455 1.3
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+// A response head longer than this isn't a WebSocket upgrade
+const MAX_RESPONSE: usize = 8 * 1024;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+/// Where to upgrade to WebSocket, the Host header and the request path
+/// (e.g. "/mqtt")
+#[derive(Debug, Clone, PartialEq)]
+pub struct WsOptions {
+    pub host: String,
+    pub path: String
+}
+
+/// MQTT over WebSocket, the client side. What's written up to a flush
+/// goes out as one masked binary message. Reads hand out the payloads of
+/// binary messages as a byte stream, answering pings on the way; a close
+/// reads as EOF.
+pub struct WsStream<S> {
+    inner: S,
+    // Raw bytes read from `inner` and not handed out yet, so a read
+    // timing out in the middle of a frame header loses nothing
+    rbuf: Vec<u8>,
+    // Payload bytes of the current data frame still in `rbuf` or on the wire
+    remaining: usize,
+    // Masking key of the current frame and how far into the payload it is
+    mask: Option<[u8; 4]>,
+    offset: usize,
+    closed: bool,
+    // Our close frame is out, nothing may follow it
+    close_sent: bool,
+    // Written since the last flush
+    wbuf: Vec<u8>,
+    // Encoded frames `inner` hasn't accepted yet
+    out: Vec<u8>
+}
+
+impl<S: Read + Write> WsStream<S> {
+    /// Performs the HTTP upgrade with `Sec-WebSocket-Protocol: mqtt`
+    pub fn connect(inner: S, options: &WsOptions) -> io::Result<WsStream<S>> {
+        WsStream::connect_with_key(inner, options, &base64(&random_bytes()))
+    }
+
+    fn connect_with_key(mut inner: S, options: &WsOptions, key: &str) -> io::Result<WsStream<S>> {
+        let request = format!("GET {} HTTP/1.1\r\n\
+                               Host: {}\r\n\
+                               Upgrade: websocket\r\n\
+                               Connection: Upgrade\r\n\
+                               Sec-WebSocket-Key: {}\r\n\
+                               Sec-WebSocket-Version: 13\r\n\
+                               Sec-WebSocket-Protocol: mqtt\r\n\r\n",
+                              options.path, options.host, key);
+        inner.write_all(request.as_bytes())?;
+        inner.flush()?;
+
+        // byte by byte, what follows the head belongs to the first frame
+        let mut head = Vec::new();
+        let mut byte = [0u8; 1];
+        while !head.ends_with(b"\r\n\r\n") {
+            if head.len() >= MAX_RESPONSE {
+                return Err(invalid("WebSocket upgrade response too long"));
+            }
+            match inner.read(&mut byte)? {
+                0 => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "WebSocket upgrade response cut short")),
+                _ => head.push(byte[0])
+            }
+        }
+        let head = String::from_utf8_lossy(&head);
+        let mut lines = head.split("\r\n");
+        let status = lines.next().unwrap_or("");
+        if status.split(' ').nth(1) != Some("101") {
+            return Err(io::Error::new(io::ErrorKind::ConnectionRefused,
+                                      format!("WebSocket upgrade refused: {}", status)));
+        }
+        let mut accept = None;
+        let mut protocol = None;
+        for line in lines {
+            let mut parts = line.splitn(2, ':');
+            let name = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+            let value = parts.next().unwrap_or("").trim();
+            match name.as_str() {
+                "sec-websocket-accept" => accept = Some(value.to_owned()),
+                "sec-websocket-protocol" => protocol = Some(value.to_owned()),
+                _ => ()
+            }
+        }
+        if accept != Some(base64(&sha1(format!("{}{}", key, GUID).as_bytes()))) {
+            return Err(invalid("WebSocket upgrade with a wrong Sec-WebSocket-Accept"));
+        }
+        if protocol.as_deref() != Some("mqtt") {
+            return Err(invalid("WebSocket upgrade without the mqtt subprotocol"));
+        }
+        Ok(WsStream {
+            inner,
+            rbuf: Vec::new(),
+            remaining: 0,
+            mask: None,
+            offset: 0,
+            closed: false,
+            close_sent: false,
+            wbuf: Vec::new(),
+            out: Vec::new()
+        })
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// Sends what's written so far and a close frame with status 1000,
+    /// normal closure. Writing after it fails.
+    pub fn close(&mut self) -> io::Result<()> {
+        if self.close_sent {
+            return Ok(());
+        }
+        self.flush()?;
+        self.close_sent = true;
+        self._send_frame(OP_CLOSE, &[0x03, 0xE8])
+    }
+
+    // Reads more raw bytes into `rbuf`
+    fn _fill(&mut self) -> io::Result<usize> {
+        let mut chunk = [0u8; 4096];
+        let n = self.inner.read(&mut chunk)?;
+        self.rbuf.extend_from_slice(&chunk[..n]);
+        Ok(n)
+    }
+
+    // Takes the next frame header off `rbuf`, None until it's complete.
+    // Control frames are taken whole and handled here.
+    fn _next_frame(&mut self) -> io::Result<Option<()>> {
+        if self.rbuf.len() < 2 {
+            return Ok(None);
+        }
+        let opcode = self.rbuf[0] & 0x0F;
+        let masked = self.rbuf[1] & 0x80 != 0;
+        let (len, mut pos) = match self.rbuf[1] & 0x7F {
+            126 if self.rbuf.len() >= 4 => (((self.rbuf[2] as usize) << 8) | self.rbuf[3] as usize, 4),
+            127 if self.rbuf.len() >= 10 => {
+                let mut len = 0u64;
+                for byte in &self.rbuf[2..10] {
+                    len = (len << 8) | *byte as u64;
+                }
+                (len as usize, 10)
+            }
+            126 | 127 => return Ok(None),
+            len => (len as usize, 2)
+        };
+        let mask = if masked {
+            if self.rbuf.len() < pos + 4 {
+                return Ok(None);
+            }
+            let mask = [self.rbuf[pos], self.rbuf[pos + 1], self.rbuf[pos + 2], self.rbuf[pos + 3]];
+            pos += 4;
+            Some(mask)
+        } else {
+            None
+        };
+        match opcode {
+            OP_BINARY | OP_CONTINUATION => {
+                self.rbuf.drain(..pos);
+                self.remaining = len;
+                self.mask = mask;
+                self.offset = 0;
+                Ok(Some(()))
+            }
+            OP_CLOSE | OP_PING | OP_PONG => {
+                if self.rbuf.len() < pos + len {
+                    return Ok(None);
+                }
+                let mut payload: Vec<u8> = self.rbuf.drain(..pos + len).skip(pos).collect();
+                if let Some(mask) = mask {
+                    unmask(&mut payload, mask, 0);
+                }
+                match opcode {
+                    OP_PING => self._send_frame(OP_PONG, &payload)?,
+                    OP_CLOSE => self.closed = true,
+                    _ => ()
+                }
+                Ok(Some(()))
+            }
+            OP_TEXT => Err(invalid("WebSocket text message, MQTT takes binary ones")),
+            _ => Err(invalid("unknown WebSocket opcode"))
+        }
+    }
+
+    // Queues a masked frame and writes out as much as `inner` takes
+    fn _send_frame(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        self.out.push(0x80 | opcode);
+        match payload.len() {
+            len if len < 126 => self.out.push(0x80 | len as u8),
+            len if len <= 0xFFFF => {
+                self.out.push(0x80 | 126);
+                self.out.extend_from_slice(&[(len >> 8) as u8, len as u8]);
+            }
+            len => {
+                self.out.push(0x80 | 127);
+                for i in (0..8).rev() {
+                    self.out.push(((len as u64) >> (i * 8)) as u8);
+                }
+            }
+        }
+        let mask = random_bytes();
+        let mask = [mask[0], mask[1], mask[2], mask[3]];
+        self.out.extend_from_slice(&mask);
+        let start = self.out.len();
+        self.out.extend_from_slice(payload);
+        unmask(&mut self.out[start..], mask, 0);
+        self._write_out()
+    }
+
+    fn _write_out(&mut self) -> io::Result<()> {
+        while !self.out.is_empty() {
+            match self.inner.write(&self.out) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write WebSocket frame")),
+                Ok(n) => {
+                    self.out.drain(..n);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e)
+            }
+        }
+        self.inner.flush()
+    }
+}
+
+impl<S: Read + Write> Read for WsStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.closed || buf.is_empty() {
+                return Ok(0);
+            }
+            if self.remaining > 0 {
+                if self.rbuf.is_empty() && self._fill()? == 0 {
+                    return Ok(0);
+                }
+                let n = cmp::min(cmp::min(buf.len(), self.remaining), self.rbuf.len());
+                for (i, byte) in self.rbuf.drain(..n).enumerate() {
+                    buf[i] = byte;
+                }
+                if let Some(mask) = self.mask {
+                    unmask(&mut buf[..n], mask, self.offset);
+                }
+                self.offset += n;
+                self.remaining -= n;
+                return Ok(n);
+            }
+            if self._next_frame()?.is_none() && self._fill()? == 0 {
+                return Ok(0);
+            }
+        }
+    }
+}
+
+impl<S: Read + Write> Write for WsStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.wbuf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    /// Sends what was written as one binary message. On `WouldBlock` the
+    /// rest of the frame stays queued and the next flush continues it.
+    fn flush(&mut self) -> io::Result<()> {
+        if self.wbuf.is_empty() {
+            return self._write_out();
+        }
+        if self.close_sent {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "WebSocket is closed"));
+        }
+        let payload = ::std::mem::take(&mut self.wbuf);
+        self._send_frame(OP_BINARY, &payload)
+    }
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// Masking is its own inverse
+fn unmask(data: &mut [u8], mask: [u8; 4], offset: usize) {
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte ^= mask[(offset + i) % 4];
+    }
+}
+
+// RFC 6455 10.3: masks must not be predictable from earlier frames
+fn random_bytes() -> [u8; 16] {
+    let mut rng = rand::thread_rng();
+    let mut bytes = [0u8; 16];
+    for chunk in bytes.chunks_mut(8) {
+        let n: u64 = rng.gen();
+        for (i, byte) in chunk.iter_mut().enumerate() {
+            *byte = (n >> (i * 8)) as u8;
+        }
+    }
+    bytes
+}
+
+fn base64(data: &[u8]) -> String {
+    const CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let n = (chunk[0] as u32) << 16 |
+                (*chunk.get(1).unwrap_or(&0) as u32) << 8 |
+                *chunk.get(2).unwrap_or(&0) as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(CHARS[(n >> (18 - i * 6)) as usize & 0x3F] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+// Only for Sec-WebSocket-Accept, which RFC 6455 defines with SHA-1
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    let bits = (data.len() as u64) * 8;
+    for i in (0..8).rev() {
+        message.push((bits >> (i * 8)) as u8);
+    }
+    for block in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for (word, bytes) in w.iter_mut().zip(block.chunks(4)) {
+            *word = (bytes[0] as u32) << 24 | (bytes[1] as u32) << 16 |
+                    (bytes[2] as u32) << 8 | bytes[3] as u32;
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let (mut a, mut b, mut c, mut d, mut e) = (h[0], h[1], h[2], h[3], h[4]);
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6)
+            };
+            let t = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = t;
+        }
+        h[0] = h[0].wrapping_add(a);
+        h[1] = h[1].wrapping_add(b);
+        h[2] = h[2].wrapping_add(c);
+        h[3] = h[3].wrapping_add(d);
+        h[4] = h[4].wrapping_add(e);
+    }
+    let mut digest = [0u8; 20];
+    for (i, word) in h.iter().enumerate() {
+        for j in 0..4 {
+            digest[i * 4 + j] = (word >> (24 - j * 8)) as u8;
+        }
+    }
+    digest
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use mock::MockStream;
+    use super::{base64, sha1, unmask, WsOptions, WsStream, GUID};
+
+    #[test]
+    fn ws_accept_test() {
+        // RFC 6455 1.3
+        let key = "dGhlIHNhbXBsZSBub25jZQ==";
+        assert_eq!(base64(&sha1(format!("{}{}", key, GUID).as_bytes())), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        assert_eq!(base64(b"ab"), "YWI=");
+        assert_eq!(base64(b"a"), "YQ==");
+    }
+
+    #[test]
+    fn ws_stream_test() {
+        let options = WsOptions { host: "broker".to_owned(), path: "/mqtt".to_owned() };
+        let response = "HTTP/1.1 101 Switching Protocols\r\n\
+                        Upgrade: websocket\r\n\
+                        Connection: Upgrade\r\n\
+                        Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\
+                        sec-websocket-protocol: mqtt\r\n\r\n";
+        // a CONNACK split over a binary and a continuation frame, a ping
+        // between them, then a close
+        let frames = vec![0x02, 0x02, 0x20, 0x02,
+                          0x89, 0x01, 0x07,
+                          0x80, 0x02, 0x00, 0x00,
+                          0x88, 0x00];
+        let mut mock = MockStream::with_vec([response.as_bytes(), &frames[..]].concat());
+        let mut ws = WsStream::connect_with_key(mock.clone(), &options, "dGhlIHNhbXBsZSBub25jZQ==").unwrap();
+        let request = String::from_utf8(mock.take_vec()).unwrap();
+        assert!(request.starts_with("GET /mqtt HTTP/1.1\r\nHost: broker\r\n"));
+        assert!(request.contains("Sec-WebSocket-Protocol: mqtt\r\n"));
+
+        let mut buf = Vec::new();
+        ws.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, vec![0x20, 0x02, 0x00, 0x00]);
+        // the pong echoes the ping
+        let pong = mock.take_vec();
+        assert_eq!(&pong[..2], &[0x8A, 0x81]);
+        let mut payload = pong[6..].to_vec();
+        unmask(&mut payload, [pong[2], pong[3], pong[4], pong[5]], 0);
+        assert_eq!(payload, vec![0x07]);
+
+        // one message per flush, masked
+        ws.write_all(&[0xC0]).unwrap();
+        ws.write_all(&[0x00]).unwrap();
+        ws.flush().unwrap();
+        let frame = mock.take_vec();
+        assert_eq!(&frame[..2], &[0x82, 0x82]);
+        let mut payload = frame[6..].to_vec();
+        unmask(&mut payload, [frame[2], frame[3], frame[4], frame[5]], 0);
+        assert_eq!(payload, vec![0xC0, 0x00]);
+
+        // closing sends 1000, normal closure, once
+        ws.close().unwrap();
+        ws.close().unwrap();
+        let frame = mock.take_vec();
+        assert_eq!(frame.len(), 8);
+        assert_eq!(&frame[..2], &[0x88, 0x82]);
+        let mut payload = frame[6..].to_vec();
+        unmask(&mut payload, [frame[2], frame[3], frame[4], frame[5]], 0);
+        assert_eq!(payload, vec![0x03, 0xE8]);
+        ws.write_all(&[0xC0]).unwrap();
+        assert!(ws.flush().is_err());
+    }
+
+    #[test]
+    fn ws_refused_test() {
+        let options = WsOptions { host: "broker".to_owned(), path: "/".to_owned() };
+        let mock = MockStream::with_vec(b"HTTP/1.1 404 Not Found\r\n\r\n".to_vec());
+        assert!(WsStream::connect(mock, &options).is_err());
+
+        // no mqtt subprotocol
+        let response = "HTTP/1.1 101 Switching Protocols\r\n\
+                        Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n";
+        let mock = MockStream::with_vec(response.as_bytes().to_vec());
+        assert!(WsStream::connect_with_key(mock, &options, "dGhlIHNhbXBsZSBub25jZQ==").is_err());
+    }
+}
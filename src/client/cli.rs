@@ -1,7 +1,7 @@
 use std::process::exit;
 use getopts::Options;
 use openssl::ssl::{SslMethod, SslContext, SslFiletype, SslVerifyMode};
-use mqtt3::{LastWill, SubscribeTopic, QoS, Protocol};
+use mqtt3::{LastWill, RetainHandling, SubscribeTopic, QoS, Protocol};
 use super::command::{Command, SubscribeCommand, PublishCommand};
 
 pub struct CLI {
@@ -296,9 +296,10 @@ impl CLI {
         let last_will = if will_topic.is_some() && will_message.is_some() {
             Some(LastWill {
                 topic: will_topic.unwrap(),
-                message: will_message.unwrap(),
+                message: will_message.unwrap().into_bytes(),
                 qos: will_qos.map_or(QoS::AtMostOnce, |s| self.parse_qos(s)),
-                retain: will_retain
+                retain: will_retain,
+                properties: Vec::new()
             })
         } else {
             if !will_topic.is_none() || !will_topic.is_none() {
@@ -347,9 +348,9 @@ impl CLI {
 
         let qos = matches.opt_str("q").map_or(QoS::ExactlyOnce, |s| self.parse_qos(s));
         let topics = if !matches.free.is_empty() {
-            matches.free.iter().map(|topic| SubscribeTopic { topic_path: topic.clone(), qos: qos} ).collect()
+            matches.free.iter().map(|topic| SubscribeTopic { topic_path: topic.clone(), qos, no_local: false, retain_as_published: false, retain_handling: RetainHandling::SendAtSubscribe } ).collect()
         } else {
-            default.topics.iter().map(|topic| SubscribeTopic { topic_path: topic.topic_path.clone(), qos: qos} ).collect()
+            default.topics.iter().map(|topic| SubscribeTopic { topic_path: topic.topic_path.clone(), qos, no_local: false, retain_as_published: false, retain_handling: RetainHandling::SendAtSubscribe } ).collect()
         };
 
         SubscribeCommand {
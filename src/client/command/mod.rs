@@ -37,4 +37,8 @@ impl store::Store for LocalStorage {
         self.0.remove(&pid);
         Ok(())
     }
+
+    fn keys(&self) -> store::Result<Vec<PacketIdentifier>> {
+        Ok(self.0.keys().cloned().collect())
+    }
 }
@@ -3,7 +3,7 @@ use term;
 use openssl::ssl;
 use std::time::Duration;
 use std::process::exit;
-use mqtt3::{self, LastWill, SubscribeTopic, QoS, Protocol};
+use mqtt3::{self, LastWill, RetainHandling, SubscribeTopic, QoS, Protocol};
 use netopt::{NetworkOptions, SslContext};
 use mqttc::store;
 use mqttc::{PubSub, ClientOptions, ReconnectMethod, Error};
@@ -46,7 +46,7 @@ pub struct SubscribeCommand {
 impl Default for SubscribeCommand {
     fn default() -> SubscribeCommand {
         SubscribeCommand {
-            topics: vec![SubscribeTopic { topic_path: "#".to_string(), qos: QoS::ExactlyOnce }],
+            topics: vec![SubscribeTopic { topic_path: "#".to_string(), qos: QoS::ExactlyOnce, no_local: false, retain_as_published: false, retain_handling: RetainHandling::SendAtSubscribe }],
             address: "localhost".to_string(),
             port: 1414,
             clean_session: true,
@@ -167,13 +167,18 @@ impl Command for SubscribeCommand {
                                 exit(64);
                             }
                         },
-                        Error::Storage(ref err) => match *err {
+                        Error::StoreFailed { ref source, .. } => match *source {
                             store::Error::NotFound(pid) => {
                                 // we have lost something
                                 let _ = client.complete(pid);
                             },
                             store::Error::Unavailable(_) => {
                                 // do nothing, just wait next pubrel
+                            },
+                            store::Error::Mismatch(_) |
+                            store::Error::Unsupported |
+                            store::Error::Backend(_) => {
+                                print_error(e.to_string())
                             }
                         },
                         Error::Disconnected | Error::ConnectionAbort => {